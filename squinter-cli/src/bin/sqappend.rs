@@ -0,0 +1,58 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read};
+use std::path::PathBuf;
+
+use anyhow::{self, Context};
+use clap::Parser;
+use squinter::squashfs::cpio::append_cpio;
+use squinter::squashfs::pseudo;
+use squinter::squashfs::tar::append_tar;
+use squinter::squashfs::writer::SquashFSWriter;
+
+/// Add the contents of a tar or cpio archive to an existing SquashFS image, in place
+#[derive(Parser, Debug)]
+struct Cli {
+    /// The SquashFS image to modify
+    image: PathBuf,
+
+    /// The archive to add (default: stdin). Entries replace any existing entries at the same paths.
+    #[arg(short, long)]
+    input: Option<PathBuf>,
+
+    /// Read the archive as newc cpio rather than tar
+    #[arg(long)]
+    cpio: bool,
+
+    /// A pseudo file definition to apply after the archive (may be repeated)
+    #[arg(short, long)]
+    pseudo: Vec<String>,
+
+    /// A file of pseudo file definitions to apply after the archive (may be repeated)
+    #[arg(long = "pf")]
+    pseudo_file: Vec<PathBuf>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    let input: Box<dyn Read> = match &cli.input {
+        Some(p) => Box::new(BufReader::new(File::open(p).context("Failed to open archive")?)),
+        None => Box::new(io::stdin().lock()),
+    };
+    let image = OpenOptions::new().read(true).write(true).open(&cli.image)
+        .context("Failed to open SquashFS")?;
+    let mut writer = SquashFSWriter::append(image)
+        .context("Failed to read SquashFS")?;
+    if cli.cpio {
+        append_cpio(&mut writer, input).context("Failed to add cpio archive")?;
+    } else {
+        append_tar(&mut writer, input).context("Failed to add tar archive")?;
+    }
+    let defs = pseudo::load(&cli.pseudo_file, &cli.pseudo)
+        .context("Failed to read pseudo definitions")?;
+    for def in defs {
+        def.apply(&mut writer).with_context(|| format!("Failed to apply pseudo definition for '{}'", def.path))?;
+    }
+    writer.finish().context("Failed to write SquashFS")?;
+    Ok(())
+}
@@ -3,15 +3,20 @@ use std::path::PathBuf;
 
 use anyhow::{self, Context};
 use clap::{Args, Parser, Subcommand};
-use squinter::squashfs::{self, Inode, SquashFS};
-use squinter::squashfs::metadata::InodeExtendedInfo;
-use termion;
+use squinter::squashfs::{self, Inode, SquashFS, UnionFS};
+use squinter::squashfs::nested::{self, ReadSeek};
 
 #[derive(Parser, Debug)]
 struct Cli {
-    /// The SquashFS Filesystem to operate on
+    /// The SquashFS Filesystem to operate on. Images stored inside other images can be reached
+    /// with '::', as in outer.sqfs::/images/app.sqfs
     filesystem: PathBuf,
 
+    /// Images to stack on top of the filesystem as read-only overlay layers, from the bottom up.
+    /// Whiteouts and opaque directories in each layer hide the contents of the layers beneath it.
+    #[arg(short, long = "overlay")]
+    overlays: Vec<PathBuf>,
+
     /// The command to execute
     #[command(subcommand)]
     command: Command,
@@ -23,6 +28,10 @@ enum Command {
     Cat(CatArgs),
     /// List files
     Ls(LsArgs),
+    /// Write a directory tree to stdout as a tar archive
+    Tar(TarArgs),
+    /// Write a directory tree to stdout as a newc cpio archive
+    Cpio(CpioArgs),
 }
 
 #[derive(Args, Debug)]
@@ -37,33 +46,86 @@ struct LsArgs {
     files: Vec<PathBuf>,
 }
 
+#[derive(Args, Debug)]
+struct TarArgs {
+    #[arg(default_value = "/")]
+    path: PathBuf,
+}
+
+#[derive(Args, Debug)]
+struct CpioArgs {
+    #[arg(default_value = "/")]
+    path: PathBuf,
+}
+
 fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
 
-    let mut sqfs = SquashFS::open(&cli.filesystem)
+    let mut sqfs = nested::open(&cli.filesystem)
         .context("Failed to open SquashFS")?;
     match &cli.command {
-        Command::Cat(args) => { cmd_cat(&mut sqfs, &cli, &args) },
-        Command::Ls(args) => { cmd_ls(&mut sqfs, &cli, &args) }
+        Command::Cat(args) => { cmd_cat(&mut open_union(sqfs, &cli)?, &cli, args) },
+        Command::Ls(args) => { cmd_ls(&mut open_union(sqfs, &cli)?, &cli, args) },
+        Command::Tar(args) => {
+            anyhow::ensure!(cli.overlays.is_empty(), "Overlays cannot be archived directly; combine them with sqmerge first");
+            cmd_tar(&mut sqfs, &cli, args)
+        },
+        Command::Cpio(args) => {
+            anyhow::ensure!(cli.overlays.is_empty(), "Overlays cannot be archived directly; combine them with sqmerge first");
+            cmd_cpio(&mut sqfs, &cli, args)
+        },
+    }
+}
+
+/// Stack any overlay images on top of the filesystem. Without overlays, the union behaves exactly
+/// like the filesystem itself.
+fn open_union(sqfs: SquashFS<Box<dyn ReadSeek>>, cli: &Cli) -> anyhow::Result<UnionFS<Box<dyn ReadSeek>>> {
+    let mut layers = vec![sqfs];
+    for overlay in &cli.overlays {
+        layers.push(nested::open(overlay)
+            .with_context(|| format!("Failed to open overlay {}", overlay.display()))?);
     }
+    Ok(UnionFS::new(layers)?)
 }
 
-fn cmd_cat<R: Read+Seek>(sqfs: &mut SquashFS<R>, _cli: &Cli, args: &CatArgs) -> anyhow::Result<()> {
+fn cmd_cat<R: Read+Seek>(union: &mut UnionFS<R>, _cli: &Cli, args: &CatArgs) -> anyhow::Result<()> {
     for file_arg in &args.files {
         // The requested path may include symlinks, so we can't just look it up as-is. We need to
         // resolve the path first.
-        let resolved = squashfs::path::canonicalize(sqfs, file_arg, "/")?;
-        let inode = sqfs.inode_from_path(&resolved)
+        let resolved = squashfs::path::canonicalize_union(union, file_arg, "/")?;
+        let inode = union.inode_from_path(&resolved)
             .context("Cannot open inode")?;
         if !inode.is_dir() {
-            let mut reader = sqfs.open_file_inode(&inode)?;
+            let mut reader = union.open_file(&resolved)?;
             std::io::copy(&mut reader, &mut std::io::stdout())?;
         }
     }
     Ok(())
 }
 
-fn cmd_ls<R: Read+Seek>(sqfs: &mut SquashFS<R>, _cli: &Cli, args: &LsArgs) -> anyhow::Result<()> {
+fn cmd_tar<R: Read+Seek>(sqfs: &mut SquashFS<R>, _cli: &Cli, args: &TarArgs) -> anyhow::Result<()> {
+    // The requested path may include symlinks, so we can't just look it up as-is. We need to
+    // resolve the path first.
+    let resolved = squashfs::path::canonicalize(sqfs, &args.path, "/")?;
+    let stdout = std::io::BufWriter::new(std::io::stdout().lock());
+    squashfs::tar::squashfs_to_tar(sqfs, &resolved, stdout)
+        .context("Failed to write tar archive")?
+        .flush()?;
+    Ok(())
+}
+
+fn cmd_cpio<R: Read+Seek>(sqfs: &mut SquashFS<R>, _cli: &Cli, args: &CpioArgs) -> anyhow::Result<()> {
+    // The requested path may include symlinks, so we can't just look it up as-is. We need to
+    // resolve the path first.
+    let resolved = squashfs::path::canonicalize(sqfs, &args.path, "/")?;
+    let stdout = std::io::BufWriter::new(std::io::stdout().lock());
+    squashfs::cpio::squashfs_to_cpio(sqfs, &resolved, stdout)
+        .context("Failed to write cpio archive")?
+        .flush()?;
+    Ok(())
+}
+
+fn cmd_ls<R: Read+Seek>(union: &mut UnionFS<R>, _cli: &Cli, args: &LsArgs) -> anyhow::Result<()> {
     let mut first = true;
     let single_path = args.files.len() == 1;
 
@@ -72,19 +134,19 @@ fn cmd_ls<R: Read+Seek>(sqfs: &mut SquashFS<R>, _cli: &Cli, args: &LsArgs) -> an
     for file_arg in &args.files {
         // The requested path may include symlinks, so we can't just look it up as-is. We need to
         // resolve the path first.
-        let resolved = squashfs::path::canonicalize(sqfs, file_arg, "/")?;
-        match sqfs.inode_from_path(&resolved) {
+        let resolved = squashfs::path::canonicalize_union(union, file_arg, "/")?;
+        match union.inode_from_path(&resolved) {
             Ok(inode) => {
                 if !inode.is_dir() {
-                    files.push((file_arg.to_str().unwrap().to_string(), inode));
+                    files.push((file_arg.to_string_lossy().into_owned(), inode));
                 }
             },
             Err(e) => {
-                std::io::stderr().write_all(&format!("cannot access '{}': {}\n", file_arg.to_str().unwrap(), e).as_bytes())?;
+                std::io::stderr().write_all(format!("cannot access '{}': {}\n", file_arg.display(), e).as_bytes())?;
             }
         }
     }
-    if files.len() > 0 {
+    if !files.is_empty() {
         if args.long {
             display_files_long(files)?;
         } else {
@@ -97,17 +159,18 @@ fn cmd_ls<R: Read+Seek>(sqfs: &mut SquashFS<R>, _cli: &Cli, args: &LsArgs) -> an
     for file_arg in &args.files {
         // The requested path may include symlinks, so we can't just look it up as-is. We need to
         // resolve the path first.
-        let resolved = squashfs::path::canonicalize(sqfs, file_arg, "/")?;
-        match sqfs.inode_from_path(&resolved) {
+        let resolved = squashfs::path::canonicalize_union(union, file_arg, "/")?;
+        match union.inode_from_path(&resolved) {
             Ok(inode) => {
                 if inode.is_dir() {
-                    let files: Vec<(String, Inode)> = sqfs.read_dir_inode(&inode)?
-                        .map(|de| (de.file_name(), sqfs.inode_from_entryref(de.inode_ref()).unwrap()))
-                        .collect();
-                    if !first { println!(""); }
+                    let files: Vec<(String, Inode)> = union.read_dir(&resolved)?
+                        .map(|de| union.inode_from_dirent(&de).map(|inode| (de.file_name(), inode)))
+                        .collect::<Result<_, _>>()
+                        .with_context(|| format!("Failed to read {}", file_arg.display()))?;
+                    if !first { println!(); }
                     if !single_path {
                         // Note: the header is what the user entered, not the resolved version
-                        println!("{}:", file_arg.to_str().unwrap());
+                        println!("{}:", file_arg.display());
                     }
                     if args.long {
                         display_files_long(files)?;
@@ -118,7 +181,7 @@ fn cmd_ls<R: Read+Seek>(sqfs: &mut SquashFS<R>, _cli: &Cli, args: &LsArgs) -> an
                 }
             },
             Err(e) => {
-                std::io::stderr().write_all(format!("cannot access '{}': {}", file_arg.to_str().unwrap(), e).as_bytes())?;
+                std::io::stderr().write_all(format!("cannot access '{}': {}", file_arg.display(), e).as_bytes())?;
             }
         }
     }
@@ -141,7 +204,7 @@ fn display_files(files: Vec<(String, Inode)>) -> anyhow::Result<()> {
         let min_columns = term_width / (*lengths.iter().max().unwrap() + 2);
         let max_columns = term_width / (*lengths.iter().min().unwrap() + 2);
 
-        let mut columns = max_columns as usize;
+        let mut columns = max_columns;
         let mut col_widths: Vec<usize> = Vec::new();
         let mut files_per_column = 0;
         while columns >= min_columns {
@@ -149,20 +212,20 @@ fn display_files(files: Vec<(String, Inode)>) -> anyhow::Result<()> {
             // Figure out the width of each column
             col_widths = (0..columns).map(|c|
                 lengths.iter()
-                    .skip((files_per_column * c) as usize)
-                    .take(files_per_column as usize)
+                    .skip(files_per_column * c)
+                    .take(files_per_column)
                     .map(|l| *l + 3)
                     .max()
                     .unwrap_or(0)
                 ).collect();
-            if col_widths.iter().map(|l| *l).sum::<usize>() <= term_width {
+            if col_widths.iter().copied().sum::<usize>() <= term_width {
                 break;
             }
             columns -= 1;
         }
 
         for row in 0..files_per_column {
-            for (n, (filename,_)) in files.iter().skip((row) as usize).step_by(files_per_column as usize).enumerate() {
+            for (n, (filename,_)) in files.iter().skip(row).step_by(files_per_column).enumerate() {
                 if n != 0 {
                     print!("   ");
                 }
@@ -199,13 +262,13 @@ fn display_files_long(files: Vec<(String, Inode)>) -> anyhow::Result<()> {
                 } else {
                     String::from("-")
                 };
-        let link_postfix = match &inode.extended_info {
-            InodeExtendedInfo::BasicSymlink(i) => {
+        let link_postfix = match inode.symlink_target() {
+            Some(t) => {
                 let mut s = String::from(" -> ");
-                s.push_str(i.target_path.to_str()?);
+                s.push_str(&t.to_string_lossy());
                 s
             }
-            _ => { String::new() }
+            None => { String::new() }
         };
         println!("{mode_str}  {size_str:>size_len$}  {filename}{link_postfix}");
     }
@@ -0,0 +1,39 @@
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use anyhow::{self, Context};
+use clap::Parser;
+use squinter::check;
+use squinter::squashfs::nested;
+
+/// Check the consistency of a SquashFS image
+#[derive(Parser, Debug)]
+struct Cli {
+    /// The SquashFS image to check
+    image: PathBuf,
+
+    /// Only print problems, not the summary
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+// As fsck reports errors that were left uncorrected
+const PROBLEMS_FOUND: u8 = 4;
+
+fn main() -> anyhow::Result<ExitCode> {
+    let cli = Cli::parse();
+
+    let mut sqfs = nested::open(&cli.image)
+        .context("Failed to open SquashFS")?;
+    let report = check::check(&mut sqfs)
+        .context("Failed to check SquashFS")?;
+
+    for finding in &report.findings {
+        println!("{finding}");
+    }
+    if !cli.quiet {
+        println!("{}: {} inodes, {} blocks, {} problems",
+            cli.image.display(), report.inodes, report.blocks, report.findings.len());
+    }
+    Ok(if report.is_clean() { ExitCode::SUCCESS } else { ExitCode::from(PROBLEMS_FOUND) })
+}
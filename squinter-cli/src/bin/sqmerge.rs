@@ -0,0 +1,63 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use anyhow::{self, Context};
+use clap::Parser;
+use squinter::squashfs::{Compressor, SquashFS};
+use squinter::squashfs::merge::merge;
+use squinter::squashfs::writer::WriterOptions;
+
+/// Merge several SquashFS images into one, honouring OCI and overlayfs whiteouts
+#[derive(Parser, Debug)]
+struct Cli {
+    /// The SquashFS image to create
+    output: PathBuf,
+
+    /// The images to merge, lowest precedence first
+    #[arg(required = true)]
+    layers: Vec<PathBuf>,
+
+    /// The compressor to use (default: same as the first layer)
+    #[arg(short, long)]
+    compressor: Option<Compressor>,
+
+    /// The data block size in bytes (default: same as the first layer)
+    #[arg(short, long)]
+    block_size: Option<u32>,
+
+    /// The modification time to record in the superblock (default: same as the last layer)
+    #[arg(long)]
+    mod_time: Option<u32>,
+
+    /// Do not pack file tail-ends into fragment blocks
+    #[arg(long)]
+    no_fragments: bool,
+
+    /// Write an NFS export table
+    #[arg(long)]
+    exports: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    let mut layers = Vec::with_capacity(cli.layers.len());
+    for path in &cli.layers {
+        layers.push(SquashFS::open(path).with_context(|| format!("Failed to open SquashFS '{}'", path.display()))?);
+    }
+    let (first, last) = (&layers[0], &layers[layers.len() - 1]);
+    let opts = WriterOptions {
+        compressor: cli.compressor.unwrap_or(first.compressor()),
+        block_size: cli.block_size.unwrap_or(first.block_size()),
+        mod_time: cli.mod_time.unwrap_or(last.mod_time()),
+        no_fragments: cli.no_fragments,
+        export_table: cli.exports,
+        ..Default::default()
+    };
+
+    let output = BufWriter::new(File::create(&cli.output).context("Failed to create SquashFS")?);
+    merge(&mut layers, output, opts)
+        .context("Failed to merge SquashFS images")?;
+    Ok(())
+}
@@ -0,0 +1,113 @@
+use std::fs::OpenOptions;
+use std::io::Seek;
+use std::path::PathBuf;
+
+use anyhow::{self, Context};
+use clap::{Args, Parser, Subcommand};
+use squinter::squashfs::patch::ImagePatcher;
+
+/// Change ownership, permissions, timestamps, and extended attributes in a SquashFS image, in place
+#[derive(Parser, Debug)]
+struct Cli {
+    /// The SquashFS image to modify
+    image: PathBuf,
+
+    /// The change to make
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Set the owning user and group
+    Chown(ChownArgs),
+    /// Set the permission bits
+    Chmod(ChmodArgs),
+    /// Set the modification time
+    SetMtime(SetMtimeArgs),
+    /// Set an extended attribute
+    SetXattr(SetXattrArgs),
+    /// Remove an extended attribute
+    RemoveXattr(RemoveXattrArgs),
+}
+
+#[derive(Args, Debug)]
+struct ChownArgs {
+    /// The new owner, as UID:GID
+    owner: String,
+    files: Vec<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct ChmodArgs {
+    /// The new permission bits, in octal
+    mode: String,
+    files: Vec<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct SetMtimeArgs {
+    /// The new modification time, in seconds since the UNIX epoch
+    mtime: u32,
+    files: Vec<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct SetXattrArgs {
+    /// The attribute name, including its namespace (e.g. user.comment)
+    name: String,
+    value: String,
+    files: Vec<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct RemoveXattrArgs {
+    /// The attribute name, including its namespace (e.g. user.comment)
+    name: String,
+    files: Vec<PathBuf>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    let image = OpenOptions::new().read(true).write(true).open(&cli.image)
+        .context("Failed to open SquashFS")?;
+    let mut patcher = ImagePatcher::open(image)
+        .context("Failed to read SquashFS")?;
+    match &cli.command {
+        Command::Chown(args) => {
+            let (uid, gid) = args.owner.split_once(':')
+                .context("Owner must be given as UID:GID")?;
+            let (uid, gid) = (uid.parse().context("Invalid UID")?, gid.parse().context("Invalid GID")?);
+            for f in &args.files {
+                patcher.chown(f, uid, gid).with_context(|| format!("Cannot change owner of '{}'", f.display()))?;
+            }
+        },
+        Command::Chmod(args) => {
+            let mode = u16::from_str_radix(&args.mode, 8).context("Mode must be an octal number")?;
+            for f in &args.files {
+                patcher.chmod(f, mode).with_context(|| format!("Cannot change mode of '{}'", f.display()))?;
+            }
+        },
+        Command::SetMtime(args) => {
+            for f in &args.files {
+                patcher.set_mtime(f, args.mtime).with_context(|| format!("Cannot change mtime of '{}'", f.display()))?;
+            }
+        },
+        Command::SetXattr(args) => {
+            for f in &args.files {
+                patcher.set_xattr(f, &args.name, &args.value).with_context(|| format!("Cannot set xattr of '{}'", f.display()))?;
+            }
+        },
+        Command::RemoveXattr(args) => {
+            for f in &args.files {
+                patcher.remove_xattr(f, &args.name).with_context(|| format!("Cannot remove xattr of '{}'", f.display()))?;
+            }
+        },
+    }
+    let mut image = patcher.finish().context("Failed to write SquashFS")?;
+    // The rebuilt tables may be shorter than the originals
+    let end = image.stream_position()?;
+    image.set_len(end)?;
+    Ok(())
+}
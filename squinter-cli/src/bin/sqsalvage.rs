@@ -0,0 +1,48 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use anyhow::{self, Context};
+use clap::Parser;
+use squinter::squashfs::nested;
+use squinter::squashfs::salvage::salvage;
+use squinter::squashfs::writer::WriterOptions;
+
+/// Recover whatever can still be read from a damaged SquashFS image into a new image
+#[derive(Parser, Debug)]
+struct Cli {
+    /// The damaged SquashFS image to read
+    image: PathBuf,
+
+    /// The SquashFS image to create
+    output: PathBuf,
+
+    /// Only print what could not be recovered, not the summary
+    #[arg(short, long)]
+    quiet: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    let mut sqfs = nested::open(&cli.image)
+        .context("Failed to open SquashFS")?;
+    let opts = WriterOptions {
+        compressor: sqfs.compressor(),
+        block_size: sqfs.block_size(),
+        mod_time: sqfs.mod_time(),
+        ..Default::default()
+    };
+
+    let output = BufWriter::new(File::create(&cli.output).context("Failed to create SquashFS")?);
+    let (_, report) = salvage(&mut sqfs, output, opts)
+        .context("Failed to salvage SquashFS")?;
+    for loss in &report.losses {
+        println!("{loss}");
+    }
+    if !cli.quiet {
+        println!("{}: {} inodes recovered, {} in lost+found, {} losses",
+            cli.image.display(), report.inodes, report.orphans, report.losses.len());
+    }
+    Ok(())
+}
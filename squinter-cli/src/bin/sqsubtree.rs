@@ -0,0 +1,58 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use anyhow::{self, Context};
+use clap::Parser;
+use squinter::squashfs::{self, SquashFS};
+use squinter::squashfs::subtree::{export_subtree, SubtreeOptions};
+
+/// Create a new SquashFS image from a directory in an existing image, without recompressing data
+#[derive(Parser, Debug)]
+struct Cli {
+    /// The SquashFS image to read
+    image: PathBuf,
+
+    /// The directory to become the root of the new image
+    path: PathBuf,
+
+    /// The SquashFS image to create
+    output: PathBuf,
+
+    /// Only copy entries matching this pattern (may be repeated)
+    #[arg(short, long)]
+    include: Vec<String>,
+
+    /// Do not copy entries matching this pattern (may be repeated)
+    #[arg(short, long)]
+    exclude: Vec<String>,
+
+    /// The modification time to record in the superblock (default: same as the source image)
+    #[arg(long)]
+    mod_time: Option<u32>,
+
+    /// Do not pack file tail-ends into fragment blocks
+    #[arg(long)]
+    no_fragments: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    let mut sqfs = SquashFS::open(&cli.image)
+        .context("Failed to open SquashFS")?;
+    // The requested path may include symlinks, so we can't just look it up as-is. We need to
+    // resolve the path first.
+    let resolved = squashfs::path::canonicalize(&mut sqfs, &cli.path, "/")?;
+    let opts = SubtreeOptions {
+        include: cli.include,
+        exclude: cli.exclude,
+        mod_time: cli.mod_time,
+        no_fragments: cli.no_fragments,
+    };
+
+    let output = BufWriter::new(File::create(&cli.output).context("Failed to create SquashFS")?);
+    export_subtree(&mut sqfs, &resolved, output, &opts)
+        .context("Failed to export subtree")?;
+    Ok(())
+}
@@ -0,0 +1,63 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+use anyhow::{self, Context};
+use clap::Parser;
+use squinter::squashfs::{Compressor, SquashFS};
+use squinter::squashfs::transcode::transcode;
+use squinter::squashfs::writer::WriterOptions;
+
+/// Recompress a SquashFS image with a different compressor
+#[derive(Parser, Debug)]
+struct Cli {
+    /// The SquashFS image to read
+    image: PathBuf,
+
+    /// The SquashFS image to create
+    output: PathBuf,
+
+    /// The compressor to use (gzip, xz, zstd)
+    #[arg(short, long)]
+    compressor: Compressor,
+
+    /// The compression level (gzip only: 1-9)
+    #[arg(short, long)]
+    level: Option<u32>,
+
+    /// The data block size in bytes (default: same as the source image)
+    #[arg(short, long)]
+    block_size: Option<u32>,
+
+    /// Do not pack file tail-ends into fragment blocks
+    #[arg(long)]
+    no_fragments: bool,
+
+    /// Write an NFS export table
+    #[arg(long)]
+    exports: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    if cli.level.is_some() && cli.compressor != Compressor::Gzip {
+        anyhow::bail!("--level is only supported for gzip, not {}", cli.compressor);
+    }
+
+    let mut sqfs = SquashFS::open(&cli.image)
+        .context("Failed to open SquashFS")?;
+    let opts = WriterOptions {
+        compressor: cli.compressor,
+        compression_level: cli.level,
+        block_size: cli.block_size.unwrap_or(sqfs.block_size()),
+        mod_time: sqfs.mod_time(),
+        no_fragments: cli.no_fragments,
+        export_table: cli.exports,
+        ..Default::default()
+    };
+
+    let output = BufWriter::new(File::create(&cli.output).context("Failed to create SquashFS")?);
+    transcode(&mut sqfs, output, opts)
+        .context("Failed to transcode SquashFS")?;
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read};
+use std::path::PathBuf;
+
+use anyhow::{self, Context};
+use clap::Parser;
+use squinter::squashfs::Compressor;
+use squinter::squashfs::cpio::append_cpio;
+use squinter::squashfs::tar::append_tar;
+use squinter::squashfs::pseudo;
+use squinter::squashfs::writer::{SquashFSWriter, WriterOptions};
+
+/// Convert a tar or cpio archive into a SquashFS image
+#[derive(Parser, Debug)]
+struct Cli {
+    /// The SquashFS image to create
+    output: PathBuf,
+
+    /// The archive to convert (default: stdin)
+    #[arg(short, long)]
+    input: Option<PathBuf>,
+
+    /// Read the archive as newc cpio rather than tar
+    #[arg(long)]
+    cpio: bool,
+
+    /// The compressor to use (gzip, xz, zstd)
+    #[arg(short, long)]
+    compressor: Option<Compressor>,
+
+    /// The data block size in bytes
+    #[arg(short, long, default_value_t = 131072)]
+    block_size: u32,
+
+    /// The modification time to record in the superblock
+    #[arg(long, default_value_t = 0)]
+    mod_time: u32,
+
+    /// Do not pack file tail-ends into fragment blocks
+    #[arg(long)]
+    no_fragments: bool,
+
+    /// Write an NFS export table
+    #[arg(long)]
+    exports: bool,
+
+    /// A pseudo file definition to apply after the archive (may be repeated)
+    #[arg(short, long)]
+    pseudo: Vec<String>,
+
+    /// A file of pseudo file definitions to apply after the archive (may be repeated)
+    #[arg(long = "pf")]
+    pseudo_file: Vec<PathBuf>,
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    let mut opts = WriterOptions {
+        block_size: cli.block_size,
+        mod_time: cli.mod_time,
+        no_fragments: cli.no_fragments,
+        export_table: cli.exports,
+        ..Default::default()
+    };
+    if let Some(c) = cli.compressor {
+        opts.compressor = c;
+    }
+
+    let input: Box<dyn Read> = match &cli.input {
+        Some(p) => Box::new(BufReader::new(File::open(p).context("Failed to open archive")?)),
+        None => Box::new(io::stdin().lock()),
+    };
+    let output = BufWriter::new(File::create(&cli.output).context("Failed to create SquashFS")?);
+    let mut writer = SquashFSWriter::new(output, opts)
+        .context("Failed to create SquashFS")?;
+    if cli.cpio {
+        append_cpio(&mut writer, input).context("Failed to convert cpio archive")?;
+    } else {
+        append_tar(&mut writer, input).context("Failed to convert tar archive")?;
+    }
+    let defs = pseudo::load(&cli.pseudo_file, &cli.pseudo)
+        .context("Failed to read pseudo definitions")?;
+    for def in defs {
+        def.apply(&mut writer).with_context(|| format!("Failed to apply pseudo definition for '{}'", def.path))?;
+    }
+    writer.finish()
+        .context("Failed to write SquashFS")?;
+    Ok(())
+}
@@ -1,9 +1,8 @@
-use std::fs::File;
-use std::io::{self, BufReader};
+use std::io;
 use std::path::{Path, PathBuf};
 
 use clap::Parser;
-use squinter::squashfs::{DirEntry, SquashFS};
+use squinter::squashfs::SquashFS;
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -38,79 +37,62 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 fn list_filesystem(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
     let mut sqfs = SquashFS::open(&args.filesystem)?;
-
-    let file_list = if args.files.is_empty() {
-        vec![args.dir.clone()]
-    } else {
-        args.files.iter()
-            .map(|f| args.dir.join(f.strip_prefix("/").unwrap_or(f)))
-            .collect()
-    };
-
-    let root_inode = sqfs.root_inode()?;
-    println!("{}", args.dir.to_str().unwrap());
-    for d in sqfs.read_dir_inode(&root_inode)? {
-        print_and_descend_dir(&mut sqfs, &file_list, &args.dir, &d)?;
+    let file_list = file_list(args);
+
+    println!("{}", args.dir.display());
+    let mut walk = sqfs.walk("/")?;
+    while let Some(entry) = walk.next() {
+        let entry = entry?;
+        let path = dest_path(args, entry.path());
+        if !is_selected(&file_list, &path) {
+            walk.skip_dir();
+            continue;
+        }
+        println!("{}", path.display());
     }
     Ok(())
 }
 
-fn print_and_descend_dir(sqfs: &mut SquashFS<BufReader<File>>, files: &Vec<PathBuf>, parent: &Path, d: &DirEntry) -> Result<(), Box<dyn std::error::Error>> {
-    let path = parent.join(d.file_name());
-
-    if !files.iter().any(|p| path.starts_with(p) || p.starts_with(&path)) {
-        return Ok(());
+fn cat_files(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
+    let mut sqfs = SquashFS::open(&args.filesystem)?;
+    let file_list = file_list(args);
+
+    // The walk borrows the SquashFS, so the files are read once it has finished
+    let mut files = Vec::new();
+    let mut walk = sqfs.walk("/")?;
+    while let Some(entry) = walk.next() {
+        let entry = entry?;
+        if !is_selected(&file_list, &dest_path(args, entry.path())) {
+            walk.skip_dir();
+        } else if entry.inode().is_file() {
+            files.push(entry.dir_entry().inode_ref());
+        }
     }
 
-    println!("{}", path.to_str().unwrap());
-
-    let inode = sqfs.inode_from_entryref(d.inode_ref())?;
-    if inode.is_dir() {
-        for d in sqfs.read_dir_inode(&inode)? {
-            print_and_descend_dir(sqfs, files, &path, &d)?;
-        }
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for inode_ref in files {
+        let inode = sqfs.inode_from_entryref(inode_ref)?;
+        let mut r = sqfs.open_file_inode(&inode)?;
+        io::copy(&mut r, &mut stdout)?;
     }
     Ok(())
 }
 
-fn cat_files(args: &Args) -> Result<(), Box<dyn std::error::Error>> {
-    let mut sqfs = SquashFS::open(&args.filesystem)?;
-
-    let file_list = if args.files.is_empty() {
+/// The paths selected on the command line, beneath the destination directory
+fn file_list(args: &Args) -> Vec<PathBuf> {
+    if args.files.is_empty() {
         vec![args.dir.clone()]
     } else {
-        args.files.iter()
-            .map(|f| args.dir.join(f.strip_prefix("/").unwrap_or(f)))
-            .collect()
-    };
-
-    let root_inode = sqfs.root_inode()?;
-    for d in sqfs.read_dir_inode(&root_inode)? {
-        cat_and_descend_dir(&mut sqfs, &file_list, &args.dir, &d)?;
+        args.files.iter().map(|f| dest_path(args, f)).collect()
     }
-    Ok(())
 }
 
-fn cat_and_descend_dir(sqfs: &mut SquashFS<BufReader<File>>, files: &Vec<PathBuf>, parent: &Path, d: &DirEntry) -> Result<(), Box<dyn std::error::Error>> {
-    let path = parent.join(d.file_name());
-
-    if !files.iter().any(|p| path.starts_with(p) || p.starts_with(&path)) {
-        return Ok(());
-    }
+fn dest_path(args: &Args, path: &Path) -> PathBuf {
+    args.dir.join(path.strip_prefix("/").unwrap_or(path))
+}
 
-    let inode = sqfs.inode_from_entryref(d.inode_ref())?;
-    if inode.is_file() {
-        //eprintln!("File -> {}", path.to_str().unwrap());
-        let mut r = sqfs.open_file_inode(&inode)?;
-        let stdout = io::stdout();
-        let mut stdout = stdout.lock();
-        io::copy(&mut r, &mut stdout)?;
-    }
-    if inode.is_dir() {
-        //eprintln!("Dir -> {}", path.to_str().unwrap());
-        for d in sqfs.read_dir_inode(&inode)? {
-            cat_and_descend_dir(sqfs, files, &path, &d)?;
-        }
-    }
-    Ok(())
+/// Whether a path is one of the selected paths, or leads to or from one of them
+fn is_selected(files: &[PathBuf], path: &Path) -> bool {
+    files.iter().any(|p| path.starts_with(p) || p.starts_with(path))
 }
@@ -2,7 +2,6 @@ use std::time::Duration;
 use std::io::{Read, Seek};
 use std::path::Path;
 
-use anyhow;
 use criterion::{criterion_group, criterion_main, Criterion, BatchSize};
 use test_assets_ureq::{TestAssetDef, dl_test_files_backoff};
 
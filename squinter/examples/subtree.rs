@@ -2,7 +2,6 @@
 use std::env;
 use std::io::{Read, Seek};
 
-use anyhow;
 use squinter::squashfs::{self, SquashFS};
 
 fn main() -> anyhow::Result<()> {
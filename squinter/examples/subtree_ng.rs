@@ -1,7 +1,6 @@
 /// Dump contents of a SquashFS starting at a specified path
 use std::env;
 
-use anyhow;
 use squashfs_ng::read::{self, Archive};
 
 fn main() -> anyhow::Result<()> {
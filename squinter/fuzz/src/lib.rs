@@ -0,0 +1,84 @@
+//! The work done on each input by the fuzz targets. These functions are also run over the corpus
+//! by `squinter/tests/fuzz_corpus.rs`, so that every corpus entry is checked by `cargo test`
+//! without needing cargo-fuzz.
+//!
+//! Errors are expected and ignored. Only panics, hangs, and runaway allocations are failures.
+
+use std::io::{self, Cursor, Read, Seek};
+use std::path::Path;
+
+use squinter::squashfs::{embedded, path, Inode, Limits, SquashFS};
+
+// Bounds on the work done for one input, so that a small image that describes a huge or cyclic
+// tree does not look like a hang
+const MAX_DEPTH: usize = 32;
+const MAX_ENTRIES: usize = 2048;
+const MAX_FILE_READ: u64 = 1 << 20;
+
+fn limits() -> Limits {
+    Limits {
+        max_table_bytes: 16 << 20,
+        max_decompressed_bytes: 64 << 20,
+        ..Default::default()
+    }
+}
+
+/// Open the input as a SquashFS, then walk the entire tree. Every file is read, every symlink is
+/// resolved, and the ids and xattrs of every inode are looked up.
+pub fn read(data: &[u8]) {
+    if let Ok(mut sqfs) = SquashFS::new_with_limits(Cursor::new(data), limits()) {
+        walk_image(&mut sqfs);
+    }
+}
+
+/// Find the image within the input, as for an AppImage or a firmware file, then walk it as
+/// [`read`] does
+pub fn locate(data: &[u8]) {
+    let sqfs = embedded::locate(Cursor::new(data))
+        .map_err(squinter::Error::from)
+        .and_then(|r| SquashFS::new_with_limits(r, limits()));
+    if let Ok(mut sqfs) = sqfs {
+        walk_image(&mut sqfs);
+    }
+}
+
+fn walk_image<R: Read + Seek>(sqfs: &mut SquashFS<R>) {
+    let _ = (sqfs.compressor(), sqfs.block_size(), sqfs.mod_time());
+    if let Ok(root) = sqfs.root_inode() {
+        let mut budget = MAX_ENTRIES;
+        walk(sqfs, Path::new("/"), &root, 0, &mut budget);
+    }
+    for n in 0..4 {
+        let _ = sqfs.inode_from_number(n);
+    }
+}
+
+fn walk<R: Read + Seek>(sqfs: &mut SquashFS<R>, dir_path: &Path, dir: &Inode, depth: usize, budget: &mut usize) {
+    let Ok(entries) = sqfs.read_dir_inode(dir) else { return };
+    for entry in entries {
+        if *budget == 0 {
+            return;
+        }
+        *budget -= 1;
+        let entry_path = dir_path.join(entry.file_name_os());
+        let Ok(inode) = sqfs.inode_from_entryref(entry.inode_ref()) else { continue };
+        visit(sqfs, &entry_path, &inode);
+        if inode.is_dir() && depth < MAX_DEPTH {
+            walk(sqfs, &entry_path, &inode, depth + 1, budget);
+        }
+    }
+}
+
+fn visit<R: Read + Seek>(sqfs: &mut SquashFS<R>, inode_path: &Path, inode: &Inode) {
+    let _ = (inode.mode(), inode.file_size(), inode.link_count(), inode.dev_number());
+    let _ = (inode.uid(sqfs), inode.gid(sqfs), inode.xattrs(sqfs));
+    if inode.is_file() {
+        if let Ok(reader) = sqfs.open_file_inode(inode) {
+            let _ = io::copy(&mut reader.take(MAX_FILE_READ), &mut io::sink());
+        }
+    }
+    if inode.is_symlink() {
+        let _ = path::canonicalize(sqfs, inode_path, "/");
+    }
+    let _ = sqfs.inode_from_path(inode_path);
+}
@@ -0,0 +1,254 @@
+//! The error type for reading SquashFS images
+
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use crate::squashfs::{Compressor, Limit};
+
+/// A Result whose error is a squinter [`Error`]
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The metadata tables of a SquashFS image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataTable {
+    Inode,
+    Directory,
+    Fragment,
+    Export,
+    Id,
+    Xattr,
+}
+
+impl fmt::Display for MetadataTable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            MetadataTable::Inode => "inode",
+            MetadataTable::Directory => "directory",
+            MetadataTable::Fragment => "fragment",
+            MetadataTable::Export => "export",
+            MetadataTable::Id => "id",
+            MetadataTable::Xattr => "xattr",
+        })
+    }
+}
+
+/// The ways that reading a SquashFS image can fail
+///
+/// An Error converts to an [`io::Error`] of the matching [`io::ErrorKind`], so it can be returned
+/// from functions that return [`io::Result`]. Converting that `io::Error` back with
+/// [`Error::from`] recovers the original Error. Errors that parts of this crate report through
+/// `io::Error`, such as those from a file's data reader, can be recovered in the same way.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The underlying reader failed
+    Io(io::Error),
+    /// The superblock is not that of a SquashFS 4.0 image
+    InvalidSuperblock(&'static str),
+    /// The superblock names a compressor that is not known to this crate
+    UnknownCompressor(u16),
+    /// The image uses a compressor that is not enabled in this build
+    UnsupportedCompressor(Compressor),
+    /// Metadata could not be decoded. The offset is that of the metadata block being read, from
+    /// the start of the image.
+    CorruptMetadata { table: MetadataTable, offset: u64 },
+    /// Nothing exists at the path
+    NotFound(PathBuf),
+    /// The path does not name a regular file
+    NotAFile(PathBuf),
+    /// The inode with the given number, which was not looked up by path, is not a regular file
+    InodeNotAFile(u32),
+    /// The path does not name a directory. The path is empty when the directory was not looked
+    /// up by path.
+    NotADirectory(PathBuf),
+    /// The path contains components that cannot be looked up, such as `..`
+    InvalidPath(PathBuf),
+    /// A directory entry's name is `.` or `..`, or contains a `/` or a NUL byte, so it could name
+    /// something outside of its directory
+    InvalidName(Vec<u8>),
+    /// Too many symbolic links were followed while resolving the path
+    SymlinkLoop(PathBuf),
+    /// The directory at the path has already been walked, because it is linked from inside itself
+    /// or from more than one place
+    DirectoryLoop(PathBuf),
+    /// No inode has the given number
+    InvalidInodeNumber(u32),
+    /// The image has no NFS export table, so inodes cannot be looked up by number
+    NoExportTable,
+    /// Reading the image would exceed one of its [`Limits`](crate::squashfs::Limits)
+    LimitExceeded(Limit),
+    /// A block of the image does not match its dm-verity hash. The offset is that of the data
+    /// block being read.
+    VerityMismatch { offset: u64 },
+}
+
+impl Error {
+    /// The kind of io::Error that this Error converts to
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            Error::Io(e) => e.kind(),
+            Error::InvalidSuperblock(_) |
+            Error::UnknownCompressor(_) |
+            Error::CorruptMetadata { .. } |
+            Error::SymlinkLoop(_) |
+            Error::InvalidName(_) |
+            Error::DirectoryLoop(_) |
+            Error::LimitExceeded(_) |
+            Error::VerityMismatch { .. } => io::ErrorKind::InvalidData,
+            Error::UnsupportedCompressor(_) |
+            Error::NoExportTable => io::ErrorKind::Unsupported,
+            Error::NotFound(_) |
+            Error::InvalidInodeNumber(_) => io::ErrorKind::NotFound,
+            Error::NotAFile(_) |
+            Error::InodeNotAFile(_) |
+            Error::NotADirectory(_) |
+            Error::InvalidPath(_) => io::ErrorKind::InvalidInput,
+        }
+    }
+
+    /// Attribute a failure to decode metadata to the table and block being read. Failures of the
+    /// underlying reader, and errors that already carry more detail, are kept as they are.
+    pub(crate) fn in_metadata(table: MetadataTable, offset: u64) -> impl FnOnce(io::Error) -> Error {
+        move |e| match Error::from(e) {
+            Error::Io(e) if matches!(e.kind(), io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof) => {
+                Error::CorruptMetadata { table, offset }
+            },
+            e => e,
+        }
+    }
+
+    /// Attach a path to an error that was reported without one
+    pub(crate) fn at_path(self, path: impl Into<PathBuf>) -> Error {
+        match self {
+            Error::InodeNotAFile(_) => Error::NotAFile(path.into()),
+            Error::NotADirectory(p) if p.as_os_str().is_empty() => Error::NotADirectory(path.into()),
+            e => e,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let path = |f: &mut fmt::Formatter<'_>, msg: &str, p: &PathBuf| {
+            if p.as_os_str().is_empty() {
+                f.write_str(msg)
+            } else {
+                write!(f, "{msg}: {}", p.display())
+            }
+        };
+        match self {
+            Error::Io(e) => e.fmt(f),
+            Error::InvalidSuperblock(reason) => write!(f, "Invalid superblock: {reason}"),
+            Error::UnknownCompressor(id) => write!(f, "Unknown compressor id {id}"),
+            Error::UnsupportedCompressor(c) => write!(f, "The {c} compressor is not enabled"),
+            Error::CorruptMetadata { table, offset } => write!(f, "Corrupt {table} metadata in the block at {offset:#x}"),
+            Error::NotFound(p) => path(f, "No such file or directory", p),
+            Error::NotAFile(p) => path(f, "Not a regular file", p),
+            Error::InodeNotAFile(n) => write!(f, "Inode {n} is not a regular file"),
+            Error::NotADirectory(p) => path(f, "Not a directory", p),
+            Error::InvalidPath(p) => path(f, "Invalid path", p),
+            Error::InvalidName(name) => write!(f, "Invalid directory entry name {:?}", String::from_utf8_lossy(name)),
+            Error::SymlinkLoop(p) => path(f, "Too many levels of symbolic links", p),
+            Error::DirectoryLoop(p) => path(f, "Directory appears more than once in the tree", p),
+            Error::InvalidInodeNumber(n) => write!(f, "No inode with number {n}"),
+            Error::NoExportTable => f.write_str("The SquashFS has no export table"),
+            Error::LimitExceeded(limit) => write!(f, "Exceeded the limit on {limit}"),
+            Error::VerityMismatch { offset } => write!(f, "The block at {offset:#x} failed dm-verity verification"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.downcast::<Error>() {
+            Ok(e) => e,
+            Err(e) => Error::Io(e),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            e => io::Error::new(e.kind(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use std::path::Path;
+    use crate::squashfs::path::canonicalize;
+    use crate::squashfs::test_util::build;
+    use crate::squashfs::writer::Attributes;
+    use crate::squashfs::SquashFS;
+
+    fn image() -> io::Result<Vec<u8>> {
+        let a = |mode| Attributes::new(mode, 0, 0, 0);
+        build(128 * 1024, |w| {
+            w.add_file("/dir/file", a(0o644), &b"data"[..])?;
+            w.add_symlink("/loop1", a(0o777), "loop2/x")?;
+            w.add_symlink("/loop2", a(0o777), "/loop1")
+        })
+    }
+
+    #[test]
+    fn test_errors() -> io::Result<()> {
+        let image = image()?;
+        let mut sqfs = SquashFS::new(Cursor::new(image.clone()))?;
+        assert!(matches!(sqfs.open_file("/dir"), Err(Error::NotAFile(p)) if p == Path::new("/dir")));
+        let dir = sqfs.inode_from_path("/dir")?;
+        let err = sqfs.open_file_inode(&dir).unwrap_err();
+        assert_eq!(err.to_string(), format!("Inode {} is not a regular file", dir.inode_number()));
+        assert!(matches!(sqfs.read_dir("/dir/file"), Err(Error::NotADirectory(p)) if p == Path::new("/dir/file")));
+        assert!(matches!(sqfs.inode_from_path("/dir/file/x"), Err(Error::NotADirectory(_))));
+        assert!(matches!(sqfs.inode_from_path("/dir/missing"), Err(Error::NotFound(p)) if p == Path::new("/dir/missing")));
+        assert!(matches!(sqfs.inode_from_path("/dir/.."), Err(Error::InvalidPath(_))));
+        assert!(matches!(canonicalize(&mut sqfs, "/loop1/y", "/"), Err(Error::SymlinkLoop(_))));
+        assert!(matches!(sqfs.inode_from_number(1), Err(Error::NoExportTable)));
+
+        let mut corrupt = image.clone();
+        corrupt[20..22].copy_from_slice(&99u16.to_le_bytes());
+        assert!(matches!(SquashFS::new(Cursor::new(corrupt)), Err(Error::UnknownCompressor(99))));
+
+        // A metadata block that claims to be larger than the maximum size
+        let inode_table = sqfs.superblock().inode_table;
+        let mut corrupt = image;
+        corrupt[inode_table as usize..][..2].copy_from_slice(&0xFFFFu16.to_le_bytes());
+        let mut sqfs = SquashFS::new(Cursor::new(corrupt))?;
+        let err = sqfs.root_inode().unwrap_err();
+        assert!(matches!(err, Error::CorruptMetadata { table: MetadataTable::Inode, offset } if offset == inode_table));
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn test_io_round_trip() {
+        let e = io::Error::from(Error::CorruptMetadata { table: MetadataTable::Inode, offset: 0x60 });
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(e.to_string(), "Corrupt inode metadata in the block at 0x60");
+        assert!(matches!(Error::from(e), Error::CorruptMetadata { table: MetadataTable::Inode, offset: 0x60 }));
+
+        let e = io::Error::from(Error::Io(io::Error::new(io::ErrorKind::PermissionDenied, "denied")));
+        assert_eq!(e.kind(), io::ErrorKind::PermissionDenied);
+        assert!(e.get_ref().is_some_and(|e| !e.is::<Error>()));
+
+        let e = Error::in_metadata(MetadataTable::Directory, 8)(io::Error::from(Error::UnsupportedCompressor(Compressor::Lz4)));
+        assert!(matches!(e, Error::UnsupportedCompressor(Compressor::Lz4)));
+        let e = Error::in_metadata(MetadataTable::Directory, 8)(io::Error::from(io::ErrorKind::UnexpectedEof));
+        assert!(matches!(e, Error::CorruptMetadata { table: MetadataTable::Directory, offset: 8 }));
+    }
+}
@@ -24,15 +24,66 @@
 //! }
 //! ```
 //! 
+//! # Errors
+//! Reading an image returns a [`Error`] that identifies what went wrong, such as the metadata table
+//! and offset of corrupt metadata, or the path that is not a file. It converts to an
+//! [`std::io::Error`] of the matching kind, so `?` works in functions that return
+//! [`std::io::Result`], as in the example above.
+//! 
+//! Whole trees are best walked with [`squashfs::SquashFS::walk`], which stops at directories that
+//! a corrupt image links into more than one place, rather than walking them without end.
+//! 
+//! # Layered Images
+//! [`squashfs::UnionFS`] presents a stack of images as a single read-only tree, in the same way as
+//! overlayfs, without writing a merged image. Lookups return the same [`squashfs::DirEntry`] and
+//! [`squashfs::Inode`] types as a single image.
+//! 
+//! # Nested Images
+//! An image stored as a file inside another image can be opened with
+//! [`squashfs::SquashFS::open_nested`]. [`squashfs::nested::open`] opens images nested to any
+//! depth from a single path, such as `outer.sqfs::/images/app.sqfs`.
+//! 
+//! Images that do not start at the beginning of a file can be opened with
+//! [`squashfs::SquashFS::new_at_offset`], and [`squashfs::embedded`] finds the image within
+//! AppImages, snap packages, and firmware files, such as OpenWrt's uImage, FIT, and sysupgrade
+//! images, without hard-coded offsets. [`squashfs::partition`] lists the partitions of GPT and MBR disk
+//! images and opens the image in one of them.
+//! 
+//! # Checking Images
+//! [`check::check`] verifies a whole image, decompressing every block and cross-checking the
+//! directory tree against its inodes, and returns a list of the problems found. Images from
+//! untrusted sources can be opened with [`squashfs::SquashFS::new_with_limits`] to bound the
+//! resources that reading them may use. When an image is too damaged to read normally,
+//! [`squashfs::salvage::salvage`] copies whatever can still be recovered from it into a new image.
+//! 
+//! # Creating Images
+//! New images can be built with [`squashfs::writer::SquashFSWriter`], or converted directly from a
+//! tar archive with [`squashfs::tar::tar_to_squashfs`]. Images can be exported as a tar archive
+//! with [`squashfs::tar::squashfs_to_tar`]. The [`squashfs::cpio`] module provides the same
+//! conversions for newc cpio archives, and [`squashfs::transcode::transcode`] recompresses an
+//! existing image with a different compressor. [`squashfs::subtree::export_subtree`] creates an
+//! image from a directory in an existing image without recompressing its data, and
+//! [`squashfs::writer::SquashFSWriter::append`] adds to an existing image in place.
+//! [`squashfs::patch::ImagePatcher`] changes attributes in an existing image without touching its
+//! data, and [`squashfs::pseudo`] applies mksquashfs pseudo file definitions to an image being
+//! built. [`squashfs::merge::merge`] combines layered images, honouring OCI and overlayfs
+//! whiteouts. Compression is available for any of the compressors that are enabled for reading.
+//! 
 //! # Feature Flags
 //! Squinter uses feature flags to select compression algorithms for inclusion:
 //! - **gzip** - Include support for GZIP compression via flate2 (default)
 //! - **xz** - Include support for XZ compression via lzma-rs (default)
 //! - **zstd** - Include support for ZSTD compression via ruzstd (default)
+//! - **tar** - Include conversion between tar archives and SquashFS via the tar crate (default)
+//! - **verity** - Include dm-verity verification of images via the sha2 crate (default)
 //! 
 //! ### no_std support
 //! Squinter does not currently support no_std, but it doesn't have any deep dependencies on std,
 //! either. no_std could likely be implemented with minimal effort.
 //! 
 
+mod error;
 pub mod squashfs;
+
+pub use error::{Error, MetadataTable, Result};
+pub use squashfs::check;
@@ -6,6 +6,7 @@ use std::io::{self, Cursor, Read, Seek, SeekFrom};
 
 use byteorder::{LittleEndian, ReadBytesExt};
 
+use super::limits::Budget;
 use super::metadata::EntryReference;
 use super::readermux::{ReaderClient, ReaderMux};
 use super::compressed::CompressedBlockReader;
@@ -15,24 +16,20 @@ use super::superblock::Compressor;
 /// external reader, but the inner reader is read and appended to the Cursor whenever the requested
 /// read would advance beyond the end of the Cursor's data.
 /// When BorrowedBuf comes off nightly, it may be appropriate to use for this.
+/// Data read from the inner reader is counted against the image's decompression limit.
 #[derive(Debug)]
 pub struct CachingReader<R> {
     inner: R,
     cache: Cursor<Vec<u8>>,
+    budget: Budget,
 }
 
 impl<R:Read> CachingReader<R> {
-    pub fn new(inner: R) -> Self {
+    pub(crate) fn new_with_capacity(inner: R, cap: usize, budget: Budget) -> Self {
         Self {
-            inner: inner,
-            cache: Cursor::new(Vec::new()),
-        }
-    }
-
-    pub fn new_with_capacity(inner: R, cap: usize) -> Self {
-        Self {
-            inner: inner,
+            inner,
             cache: Cursor::new(Vec::with_capacity(cap)),
+            budget,
         }
     }
 }
@@ -40,14 +37,15 @@ impl<R:Read> CachingReader<R> {
 impl<R:Read> Read for CachingReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
         let initial_pos = self.cache.position();
-        let end_pos = initial_pos + (buf.len() as u64);
+        let end_pos = initial_pos.saturating_add(buf.len() as u64);
 
         // If we need to read more inner data, first do that and add it to the cache
         if end_pos > self.cache.get_ref().len() as u64 {
             let target_read_len = end_pos - (self.cache.get_ref().len() as u64);
             self.cache.seek(SeekFrom::End(0))?;
-            io::copy(&mut self.inner.by_ref().take(target_read_len), &mut self.cache)?;
+            let n = io::copy(&mut self.inner.by_ref().take(target_read_len), &mut self.cache)?;
             self.cache.set_position(initial_pos);
+            self.budget.decompress(n)?;
         }
 
         self.cache.read(buf)
@@ -68,6 +66,7 @@ impl<R> Seek for CachingReader<R> {
 pub struct FragmentBlockCache<R: Read+Seek> {
     inner: ReaderMux<R>,
     compressor: Compressor,
+    budget: Budget,
     block_readers: HashMap<u64, ReaderMux<CachingReader<CompressedBlockReader<ReaderClient<R>>>>>,
 }
 
@@ -75,25 +74,28 @@ impl<R:Read+Seek> FragmentBlockCache<R> {
     /// Create a new instance of the cache, backed by the provided reader for compressed blocks.
     /// All blocks are expected to be either uncompressed or compressed with the specified
     /// compressor.
-    pub fn new(inner: R, compressor: Compressor) -> Self {
+    pub(crate) fn new(inner: R, compressor: Compressor, budget: Budget) -> Self {
         Self {
             inner: ReaderMux::new(inner),
             compressor,
+            budget,
             block_readers: HashMap::new(),
         }
     }
 
     /// Create a new FragmentReader that is backed by this cache. The new reader will be limited to
-    /// only read the portion of the block specified by offset and len.
-    pub fn get_fragment_reader(&mut self, block_addr: u64, block_size: u64, block_uncompressed_size: u64, offset: u64, len: u64)
+    /// only read the portion of the block specified by offset and len. Blocks that are stored
+    /// uncompressed (is_compressed == false) are read directly.
+    #[allow(clippy::type_complexity)]
+    pub fn get_fragment_reader(&mut self, block_addr: u64, block_size: u64, is_compressed: bool, block_uncompressed_size: u64, offset: u64, len: u64)
         -> io::Result<FragmentReader<ReaderClient<CachingReader<CompressedBlockReader<ReaderClient<R>>>>>>
     {
-        let block_reader = self.get_or_create_block_reader(block_addr, block_size, block_uncompressed_size)?;
+        let block_reader = self.get_or_create_block_reader(block_addr, block_size, is_compressed, block_uncompressed_size)?;
         FragmentReader::new(block_reader.client(), offset, len)
     }
     
     /// Create and return a new CompressedBlockReader for the specified block in the backing reader
-    fn get_or_create_block_reader(&mut self, block_addr: u64, block_size: u64, uncompressed_size: u64)
+    fn get_or_create_block_reader(&mut self, block_addr: u64, block_size: u64, is_compressed: bool, uncompressed_size: u64)
         -> io::Result<&mut ReaderMux<CachingReader<CompressedBlockReader<ReaderClient<R>>>>>
     {
         match self.block_readers.entry(block_addr) {
@@ -101,20 +103,21 @@ impl<R:Read+Seek> FragmentBlockCache<R> {
                 Ok(e.into_mut())
             }
             Entry::Vacant(e) => {
-                let r = Self::create_block_reader(&mut self.inner, self.compressor, block_addr, block_size, uncompressed_size)?;
+                let compressor = if is_compressed { self.compressor } else { Compressor::None };
+                let r = Self::create_block_reader(&mut self.inner, compressor, block_addr, block_size, uncompressed_size, &self.budget)?;
                 Ok(e.insert(r))
             }
         }
     }
 
     /// Create and return a new CompressedBlockReader for the specified block in the backing reader
-    fn create_block_reader(reader_mux: &mut ReaderMux<R>, compressor: Compressor, block_addr: u64, block_size: u64, uncompressed_size: u64)
+    fn create_block_reader(reader_mux: &mut ReaderMux<R>, compressor: Compressor, block_addr: u64, block_size: u64, uncompressed_size: u64, budget: &Budget)
         -> io::Result<ReaderMux<CachingReader<CompressedBlockReader<ReaderClient<R>>>>>
     {
         let mut client_reader = reader_mux.client();
         client_reader.seek(SeekFrom::Start(block_addr))?;
         let compressed_reader = CompressedBlockReader::new(client_reader, compressor, block_size, uncompressed_size)?;
-        let caching_reader = CachingReader::new_with_capacity(compressed_reader, uncompressed_size as usize);
+        let caching_reader = CachingReader::new_with_capacity(compressed_reader, uncompressed_size as usize, budget.clone());
         Ok(ReaderMux::new(caching_reader))
     }
 }
@@ -156,32 +159,27 @@ impl<R:Read+Seek> Read for FragmentReader<R> {
 
 impl<R:Seek> Seek for FragmentReader<R> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        match pos {
-            SeekFrom::Start(p) => {
-                let n = self.inner.seek(SeekFrom::Start(self.offset+p))?;
-                assert!(n >= self.offset);
-                self.pos = n - self.offset;
-                Ok(self.pos)
-            },
-            SeekFrom::End(p) => {
-                assert!(self.len as i64 + p >= 0);
-                let inner_pos = (self.offset+self.len) as i64 + p;
-                let n = self.inner.seek(SeekFrom::Start(inner_pos as u64))?;
-                assert!(n >= self.offset);
-                self.pos = n - self.offset;
-                Ok(self.pos)
-            },
-            SeekFrom::Current(p) => {
-                assert!(self.pos as i64 + p >= 0);
-                let n = self.inner.seek(SeekFrom::Current(p))?;
-                assert!(n >= self.offset);
-                self.pos = n - self.offset;
-                Ok(self.pos)
-            },
-        }
+        let p = seek_position(pos, self.pos, self.len)?;
+        let inner_pos = self.offset.checked_add(p)
+            .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek to a negative or overflowing position"))?;
+        self.inner.seek(SeekFrom::Start(inner_pos))?;
+        self.pos = p;
+        Ok(self.pos)
     }
 }
 
+/// The position that a seek leads to within a reader of `len` bytes that is currently at
+/// `current`. As with std::fs::File, seeking before the start or beyond u64::MAX fails with
+/// InvalidInput, while seeking past the end is allowed.
+pub(crate) fn seek_position(pos: SeekFrom, current: u64, len: u64) -> io::Result<u64> {
+    let p = match pos {
+        SeekFrom::Start(p) => Some(p),
+        SeekFrom::End(p) => len.checked_add_signed(p),
+        SeekFrom::Current(p) => current.checked_add_signed(p),
+    };
+    p.ok_or(io::Error::new(io::ErrorKind::InvalidInput, "Invalid seek to a negative or overflowing position"))
+}
+
 /// A store of metadata blocks. Users can request specific data by block address.
 /// The cache will provide a reader that is backed by the memory buffer and will read additional
 /// data from the inner reader as needed to fulfill reads. Provided readers will automatically
@@ -190,6 +188,7 @@ impl<R:Seek> Seek for FragmentReader<R> {
 pub struct MetadataBlockCache<R: Read+Seek> {
     inner: RefCell<ReaderMux<R>>,
     compressor: Compressor,
+    budget: Budget,
     block_readers: RefCell<HashMap<u64, MetadataBlockReaderMux<ReaderClient<R>>>>,
 }
 
@@ -197,10 +196,11 @@ impl<R:Read+Seek> MetadataBlockCache<R> {
     
     /// Create a new MetadataBlockCache backed by the specified reader. Any compressed blocks will
     /// use the specified compressor.
-    pub fn new(inner: R, compressor: Compressor) -> Self {
+    pub(crate) fn new(inner: R, compressor: Compressor, budget: Budget) -> Self {
         Self {
             inner: RefCell::new(ReaderMux::new(inner)),
             compressor,
+            budget,
             block_readers: RefCell::new(HashMap::new()),
         }
     }
@@ -221,6 +221,7 @@ impl<R:Read+Seek> MetadataBlockCache<R> {
                     self.inner.borrow_mut().client(),
                     block_addr,
                     self.compressor,
+                    self.budget.clone(),
                 )?;
                 e.insert(r)
             }
@@ -241,7 +242,7 @@ struct MetadataBlockReaderMux<R:Read+Seek> {
 }
 
 impl<R:Read+Seek> MetadataBlockReaderMux<R> {
-    fn new(mut inner: R, block_addr: u64, compressor: Compressor) -> io::Result<Self> {
+    fn new(mut inner: R, block_addr: u64, compressor: Compressor, budget: Budget) -> io::Result<Self> {
         // SquashFS Metadata block size is fixed by the specification
         const METADATA_UNCOMPRESSED_BLOCK_SIZE: u16 = 8192;
 
@@ -253,8 +254,7 @@ impl<R:Read+Seek> MetadataBlockReaderMux<R> {
         let compressed: bool = header & 0x8000 == 0;
         
         if block_size > METADATA_UNCOMPRESSED_BLOCK_SIZE {
-            eprintln!("Metadata block size too big -- {}", block_size);
-            return Err(io::Error::from(io::ErrorKind::InvalidData));
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Metadata block size too big -- {block_size}")));
         }
         
         let compressor = if compressed {
@@ -264,7 +264,7 @@ impl<R:Read+Seek> MetadataBlockReaderMux<R> {
         };
 
         let compressed_reader = CompressedBlockReader::new(inner, compressor, block_size.into(), METADATA_UNCOMPRESSED_BLOCK_SIZE.into())?;
-        let caching_reader = CachingReader::new_with_capacity(compressed_reader, METADATA_UNCOMPRESSED_BLOCK_SIZE.into());
+        let caching_reader = CachingReader::new_with_capacity(compressed_reader, METADATA_UNCOMPRESSED_BLOCK_SIZE.into(), budget);
         let reader_mux = ReaderMux::new(caching_reader);
         Ok(
             Self {
@@ -310,7 +310,7 @@ impl<R:Read+Seek> MetadataBlockReader<R> {
     
     // Don't forget about the 2-byte header
     pub fn next_block_addr(&self) -> u64 {
-        self.block_addr + 2 + self.block_size as u64
+        self.block_addr.saturating_add(2 + self.block_size as u64)
     }
 }
 
@@ -340,7 +340,7 @@ pub struct MetadataReader<'a, R:Read+Seek> {
 
 impl<'a, R:Read+Seek> MetadataReader<'a, R> {
     pub fn new(cache: &'a MetadataBlockCache<R>, section_start: u64, section_end: Option<u64>, entry_addr: EntryReference) -> io::Result<Self> {
-        let mut inner = cache.get_block_reader(section_start + entry_addr.location())?;
+        let mut inner = cache.get_block_reader(block_addr(section_start, entry_addr)?)?;
         inner.seek(SeekFrom::Current(entry_addr.offset().into()))?;
         Ok(Self {
             cache,
@@ -351,18 +351,25 @@ impl<'a, R:Read+Seek> MetadataReader<'a, R> {
     }
     
     pub fn seek_ref(&mut self, addr: EntryReference) -> io::Result<()> {
-        if self.section_start + addr.location() != self.inner.block_addr() {
-            self.inner = self.cache.get_block_reader(self.section_start + addr.location())?;
+        let block_addr = block_addr(self.section_start, addr)?;
+        if block_addr != self.inner.block_addr() {
+            self.inner = self.cache.get_block_reader(block_addr)?;
         }
         self.inner.seek(SeekFrom::Start(addr.offset().into()))?;
         Ok(())
     }
 }
 
+// The disk address of the metadata block that an EntryReference within a section refers to
+fn block_addr(section_start: u64, addr: EntryReference) -> io::Result<u64> {
+    section_start.checked_add(addr.location())
+        .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Metadata reference is out of range"))
+}
+
 impl<'a, R:Read+Seek> Read for MetadataReader<'a, R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let size = self.inner.read(buf)?;
-        if size == 0 && buf.len() != 0 {
+        if size == 0 && !buf.is_empty() {
             // This must be the end of the block. Potentially start a new one.
             let done = self.section_end.is_some_and(|x| x <= self.inner.next_block_addr());
             if done {
@@ -392,7 +399,7 @@ mod tests {
     fn test_caching_reader() -> io::Result<()> {
         let data: Vec<u8> = (0..=255).collect();
         let backing_reader = Cursor::new((0..=255).collect::<Vec<u8>>());
-        let mut caching_reader = CachingReader::new(backing_reader);
+        let mut caching_reader = CachingReader::new_with_capacity(backing_reader, 0, Budget::default());
         let mut buf = [0; 8];
 
         assert_eq!(caching_reader.read(&mut buf)?, buf.len());
@@ -424,11 +431,11 @@ mod tests {
     fn test_block_cache() -> io::Result<()> {
         let data: Vec<u8> = (0..=255).collect();
         let backing_reader = Cursor::new((0..=255).collect::<Vec<u8>>());
-        let mut block_cache = FragmentBlockCache::new(backing_reader, Compressor::None);
+        let mut block_cache = FragmentBlockCache::new(backing_reader, Compressor::None, Budget::default());
         let mut buf = [0; 8];
 
-        let mut frag_reader1 = block_cache.get_fragment_reader(32, 32, 32, 8, 16)?;
-        let mut frag_reader2 = block_cache.get_fragment_reader(0, 32, 32, 0, 32)?;
+        let mut frag_reader1 = block_cache.get_fragment_reader(32, 32, true, 32, 8, 16)?;
+        let mut frag_reader2 = block_cache.get_fragment_reader(0, 32, true, 32, 0, 32)?;
 
         assert_eq!(frag_reader1.read(&mut buf)?, buf.len());
         println!("Read {:?}", &buf);
@@ -446,6 +453,14 @@ mod tests {
         println!("Read {:?}", &buf);
         assert!(buf.iter().eq(data[8..16].iter()));
 
+        // Seeks are relative to the fragment, and may not go before its start
+        assert_eq!(frag_reader1.seek(SeekFrom::End(-4))?, 12);
+        assert_eq!(frag_reader1.read(&mut buf)?, 4);
+        assert!(buf.iter().take(4).eq(data[52..56].iter()));
+        assert_eq!(frag_reader1.seek(SeekFrom::Current(-17)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(frag_reader1.seek(SeekFrom::End(-17)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(frag_reader1.seek(SeekFrom::Start(u64::MAX)).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+
         Ok(())
     }
 
@@ -0,0 +1,600 @@
+//! Consistency checking of a whole image, as fsck does for a filesystem
+//!
+//! [`check`] decompresses every metadata, data, and fragment block of an image, and cross-checks
+//! the directory tree against the inodes that it refers to. Problems are collected into a
+//! [`Report`] rather than ending the check, so that one corrupt file does not hide another.
+//!
+//! ```rust
+//! use squinter::squashfs::{check, SquashFS};
+//! fn verify_before_flashing() -> squinter::Result<bool> {
+//!     let mut sqfs = SquashFS::open("rootfs.squashfs")?;
+//!     let report = check::check(&mut sqfs)?;
+//!     for finding in &report.findings {
+//!         eprintln!("{finding}");
+//!     }
+//!     Ok(report.is_clean())
+//! }
+//! ```
+
+use std::cmp::{min, Ordering};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use super::compressed::CompressedBlockReader;
+use super::metadata::{self, FragmentEntry, Inode, InodeExtendedInfo, METADATA_BLOCK_SIZE};
+use super::superblock::Compressor;
+use super::SquashFS;
+use crate::{Error, MetadataTable, Result};
+
+/// The outcome of checking an image
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Every problem found, in the order in which they were found
+    pub findings: Vec<Finding>,
+    /// The number of inodes reached from the root directory
+    pub inodes: u32,
+    /// The number of data and fragment blocks decompressed
+    pub blocks: u64,
+}
+
+impl Report {
+    /// Whether the image passed every check
+    pub fn is_clean(&self) -> bool {
+        self.findings.is_empty()
+    }
+}
+
+/// A problem found in an image, along with the path of the entry that it concerns, if any
+#[derive(Debug)]
+pub struct Finding {
+    pub path: Option<PathBuf>,
+    pub problem: Problem,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(p) => write!(f, "{}: {}", p.display(), self.problem),
+            None => write!(f, "{}", self.problem),
+        }
+    }
+}
+
+/// The problems that [`check`] looks for
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Problem {
+    /// Part of the image could not be read, so whatever it holds was not checked
+    Unreadable(Error),
+    /// A metadata block decompressed to a size other than 8 KiB without being the last block of
+    /// its table, or a lookup table block decompressed to a size other than its entries need
+    MetadataBlockSize { table: MetadataTable, offset: u64, size: u64, expected: u64 },
+    /// A data block of a file could not be decompressed
+    CorruptDataBlock { offset: u64 },
+    /// A data block of a file decompressed to a size other than the block size and file size imply
+    DataBlockSize { offset: u64, size: u64, expected: u64 },
+    /// A file's tail-end is in a fragment that is not in the fragment table
+    FragmentIndex(u32),
+    /// A fragment block could not be decompressed
+    CorruptFragment(u32),
+    /// A file's tail-end extends past the end of its fragment block
+    FragmentTooShort { index: u32, size: u64, expected: u64 },
+    /// An inode number is not between 1 and the superblock's inode count
+    InodeNumber(u32),
+    /// A directory entry records a different inode number than the inode it refers to
+    EntryInodeNumber { entry: u32, inode: u32 },
+    /// A directory entry records a different type than the inode it refers to
+    EntryType { entry: u16, inode: u16 },
+    /// A directory entry does not sort after the entry before it
+    UnsortedEntry,
+    /// A directory entry has the same name as the entry before it
+    DuplicateEntry,
+    /// A uid or gid index is beyond the end of the id table
+    IdIndex(u16),
+    /// A directory's parent inode number is not that of the directory containing it
+    ParentInode { parent: u32, expected: u32 },
+    /// A link count does not match the number of links found to the inode
+    LinkCount { link_count: u32, expected: u32 },
+    /// A file's data blocks overlap those of another file without being a duplicate of them
+    OverlappingData { range: Range<u64>, other: PathBuf },
+    /// A directory, or its listing, has already been found elsewhere in the tree, so the tree has
+    /// a hard-linked directory or a loop
+    RepeatedDirectory { first: PathBuf },
+    /// A directory has an entry named `.` or `..`, or whose name contains a `/` or a NUL byte. The
+    /// entry is left out of the directory.
+    InvalidName(Vec<u8>),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Unreadable(e) => write!(f, "{e}"),
+            Problem::MetadataBlockSize { table, offset, size, expected } =>
+                write!(f, "The {table} table block at offset {offset} decompresses to {size} bytes rather than {expected}"),
+            Problem::CorruptDataBlock { offset } => write!(f, "The data block at offset {offset} could not be decompressed"),
+            Problem::DataBlockSize { offset, size, expected } =>
+                write!(f, "The data block at offset {offset} decompresses to {size} bytes rather than {expected}"),
+            Problem::FragmentIndex(index) => write!(f, "Fragment {index} is not in the fragment table"),
+            Problem::CorruptFragment(index) => write!(f, "Fragment block {index} could not be decompressed"),
+            Problem::FragmentTooShort { index, size, expected } =>
+                write!(f, "The tail-end needs {expected} bytes of fragment block {index}, which has {size}"),
+            Problem::InodeNumber(n) => write!(f, "Inode number {n} is out of range"),
+            Problem::EntryInodeNumber { entry, inode } =>
+                write!(f, "The directory entry has inode number {entry}, but the inode has {inode}"),
+            Problem::EntryType { entry, inode } =>
+                write!(f, "The directory entry has type {entry}, but the inode has type {inode}"),
+            Problem::UnsortedEntry => f.write_str("The directory entry is out of order"),
+            Problem::DuplicateEntry => f.write_str("The directory has more than one entry with this name"),
+            Problem::IdIndex(index) => write!(f, "Id index {index} is beyond the end of the id table"),
+            Problem::ParentInode { parent, expected } =>
+                write!(f, "The parent inode number is {parent} rather than {expected}"),
+            Problem::LinkCount { link_count, expected } =>
+                write!(f, "The link count is {link_count} rather than {expected}"),
+            Problem::OverlappingData { range, other } =>
+                write!(f, "The data at offsets {}..{} overlaps the data of {}", range.start, range.end, other.display()),
+            Problem::RepeatedDirectory { first } => write!(f, "The directory is also linked at {}", first.display()),
+            Problem::InvalidName(name) =>
+                write!(f, "The directory has an entry with the invalid name {:?}", String::from_utf8_lossy(name)),
+        }
+    }
+}
+
+/// Check the consistency of every part of an image that can be reached from its superblock.
+///
+/// Only failures to read the superblock's own tables are returned as errors. Everything else is
+/// reported as a [`Finding`], and the check carries on with whatever can still be read.
+pub fn check<R: Read + Seek>(sqfs: &mut SquashFS<R>) -> Result<Report> {
+    let mut checker = Checker::new(sqfs);
+    checker.check_metadata()?;
+    checker.check_tree();
+    checker.check_links();
+    checker.check_overlaps();
+    Ok(checker.report)
+}
+
+struct Checker<'a, R: Read + Seek> {
+    sqfs: &'a mut SquashFS<R>,
+    compressor: Compressor,
+    block_size: u32,
+    inode_count: u32,
+    id_count: u16,
+    report: Report,
+    fragments: Option<Vec<FragmentEntry>>,
+    // The size that each fragment block decompresses to, or None if it could not be
+    fragment_sizes: HashMap<u32, Option<u64>>,
+    // Where each directory inode, and each non-empty directory listing, was first found
+    dirs: HashMap<u32, PathBuf>,
+    listings: HashMap<(u64, u16), PathBuf>,
+    // The link count of each non-directory inode, the links found to it, and where it was first found
+    links: BTreeMap<u32, (u32, u32, PathBuf)>,
+    data: Vec<(Range<u64>, PathBuf)>,
+}
+
+impl<'a, R: Read + Seek> Checker<'a, R> {
+    fn new(sqfs: &'a mut SquashFS<R>) -> Self {
+        let sb = sqfs.superblock();
+        Self {
+            compressor: sb.compressor,
+            block_size: sb.block_size,
+            inode_count: sb.inode_count,
+            id_count: sb.id_count,
+            sqfs,
+            report: Report::default(),
+            fragments: None,
+            fragment_sizes: HashMap::new(),
+            dirs: HashMap::new(),
+            listings: HashMap::new(),
+            links: BTreeMap::new(),
+            data: Vec::new(),
+        }
+    }
+
+    fn find(&mut self, path: Option<&Path>, problem: Problem) {
+        self.report.findings.push(Finding { path: path.map(Path::to_path_buf), problem });
+    }
+
+    /// Check that every metadata block decompresses to the size that its table implies
+    fn check_metadata(&mut self) -> Result<()> {
+        let sb = self.sqfs.superblock();
+        let (inode_table, dir_table, bytes_used) = (sb.inode_table, sb.dir_table, sb.bytes_used);
+        let mut lookups = vec![(MetadataTable::Id, sb.id_table, u64::from(sb.id_count) * 4)];
+        if sb.frag_table != u64::MAX {
+            lookups.push((MetadataTable::Fragment, sb.frag_table, u64::from(sb.frag_count) * 16));
+        }
+        if sb.export_table != u64::MAX {
+            lookups.push((MetadataTable::Export, sb.export_table, u64::from(sb.inode_count) * 8));
+        }
+        let xattr_table = (sb.xattr_table != u64::MAX).then_some(sb.xattr_table);
+
+        // Each table's blocks are followed by the blocks of the next table, or by the list of
+        // block locations of a lookup table. Collect where all of these start, so that the end of
+        // the tables that are read from start to end can be found.
+        let mut bounds = vec![inode_table, dir_table, bytes_used];
+        let mut kv_start = None;
+        if let Some(xattr_table) = xattr_table {
+            let (start, count) = self.read_xattr_header(xattr_table)
+                .map_err(Error::in_metadata(MetadataTable::Xattr, xattr_table))?;
+            kv_start = Some(start);
+            bounds.extend([xattr_table, start]);
+            lookups.push((MetadataTable::Xattr, xattr_table.saturating_add(16), u64::from(count) * 16));
+        }
+        let mut lookup_blocks = Vec::new();
+        for (table, index, table_size) in lookups {
+            let blocks = self.read_block_list(index, table_size)
+                .map_err(Error::in_metadata(table, index))?;
+            bounds.push(index);
+            bounds.extend(blocks.first());
+            lookup_blocks.push((table, blocks, table_size));
+        }
+        let end = |start: u64| bounds.iter().copied().filter(|b| *b > start).min().unwrap_or(bytes_used);
+
+        self.check_table(MetadataTable::Inode, inode_table, end(inode_table));
+        self.check_table(MetadataTable::Directory, dir_table, end(dir_table));
+        if let Some(start) = kv_start {
+            self.check_table(MetadataTable::Xattr, start, end(start));
+        }
+        let block_size = u64::from(METADATA_BLOCK_SIZE);
+        for (table, blocks, table_size) in lookup_blocks {
+            for (n, offset) in blocks.into_iter().enumerate() {
+                let expected = min(block_size, table_size - n as u64 * block_size);
+                match self.read_metadata_block(offset) {
+                    Ok((_, size)) if size == expected => {},
+                    Ok((_, size)) => self.find(None, Problem::MetadataBlockSize { table, offset, size, expected }),
+                    Err(e) => self.find(None, Problem::Unreadable(Error::in_metadata(table, offset)(e))),
+                }
+            }
+        }
+
+        if self.sqfs.superblock().frag_table != u64::MAX {
+            match self.sqfs.fragment_entries() {
+                Ok(f) => self.fragments = Some(f),
+                Err(e) => {
+                    let offset = self.sqfs.superblock().frag_table;
+                    self.find(None, Problem::Unreadable(Error::in_metadata(MetadataTable::Fragment, offset)(e)));
+                },
+            }
+        } else {
+            self.fragments = Some(Vec::new());
+        }
+        Ok(())
+    }
+
+    /// Check the blocks of a table that is read from start to end, such as the inode table. Every
+    /// block but the last must be full.
+    fn check_table(&mut self, table: MetadataTable, start: u64, end: u64) {
+        let mut offset = start;
+        while offset < end {
+            let (disk_size, size) = match self.read_metadata_block(offset) {
+                Ok(b) => b,
+                Err(e) => {
+                    self.find(None, Problem::Unreadable(Error::in_metadata(table, offset)(e)));
+                    return;
+                },
+            };
+            let next = offset + disk_size;
+            if next > end {
+                self.find(None, Problem::Unreadable(Error::CorruptMetadata { table, offset }));
+                return;
+            }
+            let expected = u64::from(METADATA_BLOCK_SIZE);
+            if next < end && size != expected {
+                self.find(None, Problem::MetadataBlockSize { table, offset, size, expected });
+            }
+            offset = next;
+        }
+    }
+
+    /// Read the metadata block at the offset, returning its size on disk and its decompressed size
+    fn read_metadata_block(&mut self, offset: u64) -> io::Result<(u64, u64)> {
+        let mut r = self.sqfs.raw_reader();
+        r.seek(SeekFrom::Start(offset))?;
+        let mut buf = vec![0; METADATA_BLOCK_SIZE.into()];
+        let (disk_size, size) = metadata::read_metadata_block(&mut r, &self.compressor, &mut buf)?;
+        Ok((disk_size as u64, size as u64))
+    }
+
+    /// Read the locations of the blocks of a lookup table whose entries total `table_size` bytes
+    fn read_block_list(&mut self, index: u64, table_size: u64) -> io::Result<Vec<u64>> {
+        let count = table_size.div_ceil(METADATA_BLOCK_SIZE.into());
+        self.sqfs.limits().check_table(count, 8)?;
+        let mut r = self.sqfs.raw_reader();
+        r.seek(SeekFrom::Start(index))?;
+        (0..count).map(|_| r.read_u64::<LittleEndian>()).collect()
+    }
+
+    /// Read the start of the xattr key/value blocks and the number of xattr sets
+    fn read_xattr_header(&mut self, xattr_table: u64) -> io::Result<(u64, u32)> {
+        let mut r = self.sqfs.raw_reader();
+        r.seek(SeekFrom::Start(xattr_table))?;
+        Ok((r.read_u64::<LittleEndian>()?, r.read_u32::<LittleEndian>()?))
+    }
+
+    fn check_tree(&mut self) {
+        let root = match self.sqfs.root_inode() {
+            Ok(root) => root,
+            Err(e) => return self.find(None, Problem::Unreadable(e)),
+        };
+        // As mksquashfs records it
+        let root_parent = self.inode_count.wrapping_add(1);
+        self.check_dir(Path::new("/"), &root, root_parent, 0);
+    }
+
+    fn check_dir(&mut self, path: &Path, dir: &Inode, parent: u32, depth: usize) {
+        // A directory that is linked more than once is checked where it is first found
+        if let Some(first) = self.dirs.get(&dir.inode_number()) {
+            let first = first.clone();
+            return self.find(Some(path), Problem::RepeatedDirectory { first });
+        }
+        self.dirs.insert(dir.inode_number(), path.to_path_buf());
+        self.check_inode(path, dir);
+        if let Err(e) = self.sqfs.limits().check_depth(depth) {
+            return self.find(Some(path), Problem::Unreadable(e.into()));
+        }
+        let dir_parent = match &dir.extended_info {
+            InodeExtendedInfo::BasicDir(d) => d.parent_inode,
+            InodeExtendedInfo::ExtDir(d) => d.parent_inode,
+            _ => parent,
+        };
+        if dir_parent != parent {
+            self.find(Some(path), Problem::ParentInode { parent: dir_parent, expected: parent });
+        }
+
+        let entries: Vec<_> = match self.sqfs.read_dir_inode_lenient(dir) {
+            Ok(entries) => {
+                for name in entries.invalid_names() {
+                    self.find(Some(path), Problem::InvalidName(name.clone()));
+                }
+                entries.collect()
+            },
+            Err(e) => return self.find(Some(path), Problem::Unreadable(e)),
+        };
+        // So is a directory whose listing is that of one already found. Empty directories may
+        // share a listing, but cannot lead anywhere.
+        if let (false, Ok(listing)) = (entries.is_empty(), metadata::DirTable::entryref_from_inode(dir)) {
+            let key = (listing.location(), listing.offset());
+            if let Some(first) = self.listings.get(&key) {
+                let first = first.clone();
+                return self.find(Some(path), Problem::RepeatedDirectory { first });
+            }
+            self.listings.insert(key, path.to_path_buf());
+        }
+        let mut subdirs = Vec::new();
+        for (n, e) in entries.iter().enumerate() {
+            let child = path.join(e.file_name_os());
+            if n > 0 {
+                match entries[n - 1].file_name_bytes().cmp(e.file_name_bytes()) {
+                    Ordering::Less => {},
+                    Ordering::Equal => self.find(Some(&child), Problem::DuplicateEntry),
+                    Ordering::Greater => self.find(Some(&child), Problem::UnsortedEntry),
+                }
+            }
+            let inode = match self.sqfs.inode_from_entryref(e.inode_ref()) {
+                Ok(inode) => inode,
+                Err(e) => {
+                    self.find(Some(&child), Problem::Unreadable(e));
+                    continue;
+                },
+            };
+            if e.inode_num() != inode.inode_number() {
+                self.find(Some(&child), Problem::EntryInodeNumber { entry: e.inode_num(), inode: inode.inode_number() });
+            }
+            if e.inode_type() != inode.inode_type.to_basic() {
+                let (entry, inode) = (e.inode_type().into(), inode.inode_type.into());
+                self.find(Some(&child), Problem::EntryType { entry, inode });
+            }
+            if inode.is_dir() {
+                subdirs.push((child, inode));
+            } else if let Some((_, found, _)) = self.links.get_mut(&inode.inode_number()) {
+                *found += 1;
+            } else {
+                self.links.insert(inode.inode_number(), (inode.link_count(), 1, child.clone()));
+                self.check_inode(&child, &inode);
+            }
+        }
+
+        // Directories are linked from their parent, from '.', and from the '..' of each
+        // subdirectory
+        let expected = 2 + subdirs.len() as u32;
+        if dir.link_count() != expected {
+            self.find(Some(path), Problem::LinkCount { link_count: dir.link_count(), expected });
+        }
+        for (child, inode) in subdirs {
+            self.check_dir(&child, &inode, dir.inode_number(), depth + 1);
+        }
+    }
+
+    fn check_inode(&mut self, path: &Path, inode: &Inode) {
+        self.report.inodes += 1;
+        if inode.inode_number() == 0 || inode.inode_number() > self.inode_count {
+            self.find(Some(path), Problem::InodeNumber(inode.inode_number()));
+        }
+        for index in [inode.uid_index, inode.gid_index] {
+            if index >= self.id_count {
+                self.find(Some(path), Problem::IdIndex(index));
+            }
+        }
+        if let Some((blocks_start, block_sizes)) = inode.data_blocks() {
+            let file_size = inode.file_size().unwrap_or(0);
+            self.check_data(path, blocks_start, block_sizes, file_size);
+            if let Some((index, offset)) = inode.fragment() {
+                let tail_len = file_size % u64::from(self.block_size);
+                self.check_fragment(path, index, u64::from(offset) + tail_len);
+            }
+        }
+    }
+
+    /// Check that each data block of a file decompresses to a full block, or to the rest of the
+    /// file for the last one
+    fn check_data(&mut self, path: &Path, blocks_start: u64, block_sizes: &[u32], file_size: u64) {
+        let block_size = u64::from(self.block_size);
+        let mut offset = blocks_start;
+        for (n, b) in block_sizes.iter().enumerate() {
+            let disk_len = u64::from(b & 0xFFFFFF);
+            // A sparse block, which has no data on disk
+            if disk_len == 0 {
+                continue;
+            }
+            let expected = min(block_size, file_size.saturating_sub(n as u64 * block_size));
+            match self.block_len(offset, *b) {
+                Ok(size) if size == expected => {},
+                Ok(size) => self.find(Some(path), Problem::DataBlockSize { offset, size, expected }),
+                Err(_) => self.find(Some(path), Problem::CorruptDataBlock { offset }),
+            }
+            offset = offset.saturating_add(disk_len);
+        }
+        if offset > blocks_start {
+            self.data.push((blocks_start..offset, path.to_path_buf()));
+        }
+    }
+
+    /// Check that a file's tail-end, which ends `end` bytes into a fragment block, fits in the block
+    fn check_fragment(&mut self, path: &Path, index: u32, end: u64) {
+        let Some(entry) = self.fragments.as_ref().map(|f| f.get(index as usize)) else {
+            // The fragment table could not be read
+            return;
+        };
+        let Some(&FragmentEntry { start, size }) = entry else {
+            return self.find(Some(path), Problem::FragmentIndex(index));
+        };
+        let frag_size = match self.fragment_sizes.get(&index) {
+            Some(s) => *s,
+            None => {
+                let s = self.block_len(start, size).ok();
+                if s.is_none() {
+                    self.find(Some(path), Problem::CorruptFragment(index));
+                }
+                self.fragment_sizes.insert(index, s);
+                s
+            },
+        };
+        if let Some(frag_size) = frag_size.filter(|s| end > *s) {
+            self.find(Some(path), Problem::FragmentTooShort { index, size: frag_size, expected: end });
+        }
+    }
+
+    /// Decompress the data or fragment block at the offset, whose size is given as it is stored in
+    /// an inode or the fragment table, and return its decompressed size
+    fn block_len(&mut self, offset: u64, size: u32) -> io::Result<u64> {
+        let compressor = if size & 0x1000000 == 0 { self.compressor } else { Compressor::None };
+        let mut r = self.sqfs.raw_reader();
+        r.seek(SeekFrom::Start(offset))?;
+        // Allow one byte more than a block, so that an oversized block is seen as such
+        let limit = u64::from(self.block_size) + 1;
+        let mut reader = CompressedBlockReader::new(r, compressor, (size & 0xFFFFFF).into(), limit)?;
+        self.report.blocks += 1;
+        io::copy(&mut reader, &mut io::sink())
+    }
+
+    /// Check the link count of every inode other than a directory against the links found to it
+    fn check_links(&mut self) {
+        for (link_count, found, path) in std::mem::take(&mut self.links).into_values() {
+            if link_count != found {
+                self.find(Some(&path), Problem::LinkCount { link_count, expected: found });
+            }
+        }
+    }
+
+    /// Check that no two files claim the same data, unless they are duplicates that share all of it
+    fn check_overlaps(&mut self) {
+        let mut data = std::mem::take(&mut self.data);
+        data.sort_by_key(|(range, _)| (range.start, range.end));
+        // The range that reaches furthest of those seen so far
+        let mut furthest: Option<(Range<u64>, PathBuf)> = None;
+        for (range, path) in data {
+            if let Some((claimed, other)) = &furthest {
+                if range.start < claimed.end && range != *claimed {
+                    let problem = Problem::OverlappingData { range: range.clone(), other: other.clone() };
+                    self.find(Some(&path), problem);
+                }
+            }
+            if furthest.as_ref().is_none_or(|(claimed, _)| range.end > claimed.end) {
+                furthest = Some((range, path));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::squashfs::test_util::build;
+    use crate::squashfs::writer::Attributes;
+
+    fn image() -> io::Result<Vec<u8>> {
+        let a = |mode| Attributes::new(mode, 0, 0, 0);
+        let data: Vec<u8> = (0..300_000u32).map(|n| (n % 251) as u8).collect();
+        build(128 * 1024, |w| {
+            w.add_file("/bin/tool", a(0o755), &data[..])?;
+            w.add_hard_link("/bin/alias", "/bin/tool")?;
+            w.add_file("/etc/config", a(0o644), &b"config"[..])?;
+            w.add_symlink("/etc/link", a(0o777), "config")?;
+            w.add_dir("/var/empty", a(0o755))
+        })
+    }
+
+    #[test]
+    fn test_check_clean() -> io::Result<()> {
+        let mut sqfs = SquashFS::new(Cursor::new(image()?))?;
+        let report = check(&mut sqfs)?;
+        assert!(report.is_clean(), "{:?}", report.findings);
+        assert_eq!(report.inodes, 8);
+        // Two full blocks, and the fragment block holding the tail-ends
+        assert_eq!(report.blocks, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_findings() -> io::Result<()> {
+        let image = image()?;
+        let mut sqfs = SquashFS::new(Cursor::new(image.clone()))?;
+        let (blocks_start, _) = sqfs.inode_from_path("/bin/tool")?.data_blocks().map(|(s, b)| (s, b.to_vec())).unwrap();
+
+        // Damage the first data block of the file
+        let mut corrupt = image.clone();
+        corrupt[blocks_start as usize] ^= 0xFF;
+        let mut sqfs = SquashFS::new(Cursor::new(corrupt))?;
+        let report = check(&mut sqfs)?;
+        assert_eq!(report.findings.len(), 1);
+        let finding = &report.findings[0];
+        assert_eq!(finding.path.as_deref(), Some(Path::new("/bin/alias")));
+        assert!(matches!(finding.problem, Problem::CorruptDataBlock { offset } if offset == blocks_start));
+
+        // Leave every id index out of range
+        let mut corrupt = image.clone();
+        corrupt[26..28].copy_from_slice(&0u16.to_le_bytes());
+        let mut sqfs = SquashFS::new(Cursor::new(corrupt))?;
+        let report = check(&mut sqfs)?;
+        assert_eq!(report.findings.len(), 16);
+        assert!(report.findings.iter().all(|f| matches!(f.problem, Problem::IdIndex(0))));
+
+        // A directory that loops back to its parent is reported rather than checked again
+        let mut sqfs = SquashFS::new(Cursor::new(crate::squashfs::test_util::loop_image()?))?;
+        let report = check(&mut sqfs)?;
+        let repeated: Vec<_> = report.findings.iter()
+            .filter(|f| matches!(&f.problem, Problem::RepeatedDirectory { first } if first == Path::new("/a")))
+            .collect();
+        assert_eq!(repeated.len(), 1, "{:?}", report.findings);
+        assert_eq!(repeated[0].path.as_deref(), Some(Path::new("/a/c")));
+
+        // An entry with an invalid name is reported, and its siblings are still checked
+        let mut sqfs = SquashFS::new(Cursor::new(crate::squashfs::test_util::invalid_name_image()?))?;
+        assert!(matches!(sqfs.read_dir("/d"), Err(crate::Error::InvalidName(n)) if n == b"b/c"));
+        let dir = sqfs.inode_from_path("/d")?;
+        let names: Vec<_> = sqfs.read_dir_inode_lenient(&dir)?.map(|e| e.file_name()).collect();
+        assert_eq!(names, ["a", "e"]);
+        assert_eq!(sqfs.read_dir_inode_lenient(&dir)?.invalid_names(), [b"b/c"]);
+        let report = check(&mut sqfs)?;
+        let invalid: Vec<_> = report.findings.iter()
+            .filter(|f| matches!(&f.problem, Problem::InvalidName(n) if n == b"b/c"))
+            .collect();
+        assert_eq!(invalid.len(), 1, "{:?}", report.findings);
+        assert_eq!(invalid[0].path.as_deref(), Some(Path::new("/d")));
+        Ok(())
+    }
+}
@@ -1,13 +1,19 @@
 use std::borrow::BorrowMut;
 use std::io::{self, BufReader, Read, Take, Cursor};
+#[cfg(feature = "flate2")]
+use std::io::Write;
 use std::fmt::Debug;
 use std::mem;
 
 #[cfg(feature = "flate2")]
 use flate2::read::ZlibDecoder;
+#[cfg(feature = "flate2")]
+use flate2::write::ZlibEncoder;
+#[cfg(feature = "flate2")]
+use flate2::Compression;
 
 #[cfg(feature = "lzma-rs")]
-use lzma_rs::xz_decompress;
+use lzma_rs::{xz_compress, xz_decompress};
 
 #[cfg(feature = "ruzstd")]
 use ruzstd::decoding::errors::FrameDecoderError;
@@ -15,10 +21,14 @@ use ruzstd::decoding::errors::FrameDecoderError;
 use ruzstd::decoding::BlockDecodingStrategy;
 #[cfg(feature = "ruzstd")]
 use ruzstd::decoding::FrameDecoder;
+#[cfg(feature = "ruzstd")]
+use ruzstd::encoding::CompressionLevel;
 
 use super::superblock::Compressor;
+use crate::Error;
 
 #[allow(dead_code)]
+#[allow(clippy::large_enum_variant)]
 pub enum CompressedBlockReader<R>
 {
     None,
@@ -72,14 +82,12 @@ impl<R: Read> CompressedBlockReader<R> {
                 // TODO: For fragments, this is uncompressing the entire block just for a small portion and then
                 // throwing the rest of the uncompressed data away afterwards. Consider an option to retain uncompressed
                 // data like the CachedMetadataReader does.
-                let buf = Vec::with_capacity(uncompressed_size as usize);
                 let mut buf_reader = BufReader::new(block_reader);
-                let mut buf_writer = Cursor::new(buf);
+                let mut buf_writer = BoundedWriter::new(uncompressed_size);
                 xz_decompress(&mut buf_reader, &mut buf_writer)
                     .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-                buf_writer.set_position(0);
                 let r = buf_reader.into_inner().into_inner();
-                CompressedBlockReader::Buffer((r, buf_writer))
+                CompressedBlockReader::Buffer((r, Cursor::new(buf_writer.buf)))
             },
             #[cfg(feature = "ruzstd")]
             Compressor::Zstd => {
@@ -88,7 +96,7 @@ impl<R: Read> CompressedBlockReader<R> {
                     .take(uncompressed_size);
                 CompressedBlockReader::Zstd(dec)
             },
-            _ => { return Err(io::Error::from(io::ErrorKind::Unsupported)) },
+            _ => { return Err(Error::UnsupportedCompressor(comp).into()) },
         })
     }
 
@@ -130,6 +138,76 @@ impl<R: Read> Read for CompressedBlockReader<R> {
 
 }
 
+/// Compress a single data or metadata block with the specified compressor, replacing the contents
+/// of `out` with the compressed data. Returns false if compression did not make the block smaller,
+/// in which case SquashFS expects the block to be stored uncompressed and the contents of `out`
+/// should be ignored. A compression level can only be specified for gzip.
+// Without any compressors enabled, every match arm returns
+#[allow(unused_variables, unreachable_code)]
+pub(crate) fn compress_block(comp: Compressor, level: Option<u32>, data: &[u8], out: &mut Vec<u8>) -> io::Result<bool> {
+    out.clear();
+    if level.is_some() && comp != Compressor::Gzip {
+        return Err(io::Error::new(io::ErrorKind::Unsupported, format!("Compression levels are not supported for {comp}")));
+    }
+    match comp {
+        #[cfg(feature = "flate2")]
+        Compressor::Gzip => {
+            // mksquashfs defaults to the highest compression level
+            let level = match level {
+                None => Compression::best(),
+                Some(l @ 1..=9) => Compression::new(l),
+                Some(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "gzip compression level must be from 1 to 9")),
+            };
+            let mut enc = ZlibEncoder::new(&mut *out, level);
+            enc.write_all(data)?;
+            enc.finish()?;
+        },
+        #[cfg(feature = "lzma-rs")]
+        Compressor::Xz => {
+            xz_compress(&mut &data[..], out)?;
+        },
+        #[cfg(feature = "ruzstd")]
+        Compressor::Zstd => {
+            // Fastest is the only level that ruzstd currently implements
+            ruzstd::encoding::compress(data, &mut *out, CompressionLevel::Fastest);
+        },
+        _ => { return Err(Error::UnsupportedCompressor(comp).into()) },
+    }
+    Ok(out.len() < data.len())
+}
+
+/// A Writer into a buffer that fails rather than grow the buffer beyond a limit. This bounds the
+/// output of decompressors that write the whole block at once, so that a small block in a corrupt
+/// image cannot expand to an arbitrary size.
+#[cfg(feature = "lzma-rs")]
+struct BoundedWriter {
+    buf: Vec<u8>,
+    limit: u64,
+}
+
+#[cfg(feature = "lzma-rs")]
+impl BoundedWriter {
+    fn new(limit: u64) -> Self {
+        // The limit is the block size, so it is also a sensible preallocation
+        Self { buf: Vec::with_capacity(limit as usize), limit }
+    }
+}
+
+#[cfg(feature = "lzma-rs")]
+impl io::Write for BoundedWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if (self.buf.len() + data.len()) as u64 > self.limit {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Block decompresses to more than the block size"));
+        }
+        self.buf.extend_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 /// This struct is a near-copy of the StreamingDecoder from ruzstd. The only difference is the
 /// removal of the 'Read' constraint on generic R of the struct, which causes this constraint
 /// to propagate all over squinter's structs. Removing the constraint allows the Read constraint
@@ -186,7 +264,7 @@ impl<READ: Read, DEC: BorrowMut<FrameDecoder>> Read for ZstdDecoder<READ, DEC> {
             ) {
                 Ok(_) => { /*Nothing to do*/ }
                 Err(e) => {
-                    let err = io::Error::new(io::ErrorKind::Other, e);
+                    let err = io::Error::other(e);
                     return Err(err);
                 }
             }
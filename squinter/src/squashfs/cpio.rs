@@ -0,0 +1,360 @@
+//! Conversion between cpio archives and SquashFS images
+//!
+//! Only the "new ASCII" (newc) cpio format is supported, which is the format used for Linux
+//! initramfs images. Archives with the CRC variant of the format can also be imported, but their
+//! checksums are not verified.
+//!
+//! In newc archives, every path that refers to the same inode repeats the inode number, and the
+//! file data is stored with only one of those paths. When exporting, the data is stored with the
+//! first path, which is what the Linux kernel expects. When importing, the data may be stored with
+//! any of the paths, as GNU cpio does.
+
+use std::collections::{HashMap, HashSet};
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+
+use super::metadata::{decode_dev, encode_dev};
+use super::walk::Visited;
+use super::writer::{Attributes, SquashFSWriter, WriterOptions};
+use super::{Inode, SquashFS};
+
+const NEWC_MAGIC: &[u8; 6] = b"070701";
+const NEWC_CRC_MAGIC: &[u8; 6] = b"070702";
+const TRAILER: &[u8] = b"TRAILER!!!";
+const HEADER_LEN: usize = 110;
+/// The longest entry name or symlink target accepted when importing, including the NUL that
+/// terminates names, so that a corrupt header cannot make the importer allocate gigabytes
+const MAX_PATH_LEN: u32 = 4096;
+
+// File type bits of the mode field
+const S_IFMT: u32 = 0o170000;
+const S_IFSOCK: u32 = 0o140000;
+const S_IFLNK: u32 = 0o120000;
+const S_IFREG: u32 = 0o100000;
+const S_IFBLK: u32 = 0o060000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFCHR: u32 = 0o020000;
+const S_IFIFO: u32 = 0o010000;
+
+/// The numeric fields of a newc header, in their on-disk order
+#[derive(Debug, Default)]
+struct NewcHeader {
+    ino: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    mtime: u32,
+    file_size: u32,
+    dev_major: u32,
+    dev_minor: u32,
+    rdev_major: u32,
+    rdev_minor: u32,
+    name_size: u32,
+    check: u32,
+}
+
+impl NewcHeader {
+    fn read<R: Read>(r: &mut R) -> io::Result<Self> {
+        let mut buf = [0u8; HEADER_LEN];
+        r.read_exact(&mut buf)?;
+        if &buf[..6] != NEWC_MAGIC && &buf[..6] != NEWC_CRC_MAGIC {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a newc cpio archive"));
+        }
+        let mut fields = [0u32; 13];
+        for (n, f) in fields.iter_mut().enumerate() {
+            let hex = std::str::from_utf8(&buf[6 + n * 8..14 + n * 8])
+                .ok()
+                .and_then(|s| u32::from_str_radix(s, 16).ok())
+                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Invalid cpio header field"))?;
+            *f = hex;
+        }
+        let [ino, mode, uid, gid, nlink, mtime, file_size, dev_major, dev_minor, rdev_major, rdev_minor, name_size, check] = fields;
+        Ok(Self { ino, mode, uid, gid, nlink, mtime, file_size, dev_major, dev_minor, rdev_major, rdev_minor, name_size, check })
+    }
+
+    fn write<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        write!(w, "{}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}{:08X}",
+            std::str::from_utf8(NEWC_MAGIC).unwrap(),
+            self.ino, self.mode, self.uid, self.gid, self.nlink, self.mtime, self.file_size,
+            self.dev_major, self.dev_minor, self.rdev_major, self.rdev_minor, self.name_size, self.check)
+    }
+}
+
+/// The number of padding bytes needed to align len to a 4-byte boundary
+fn padding(len: u64) -> u64 {
+    len.next_multiple_of(4) - len
+}
+
+/// Convert a newc cpio archive into a new SquashFS image. Returns the Writer once the image is
+/// complete.
+pub fn cpio_to_squashfs<R: Read, W: Write + Seek>(r: R, w: W, opts: WriterOptions) -> io::Result<W> {
+    let mut writer = SquashFSWriter::new(w, opts)?;
+    append_cpio(&mut writer, r)?;
+    writer.finish()
+}
+
+/// Add every entry in a newc cpio archive to an image that is being built
+pub fn append_cpio<R: Read, W: Write + Seek>(writer: &mut SquashFSWriter<W>, mut r: R) -> io::Result<()> {
+    // For each multiply-linked inode: the path that holds the data, and the other paths to it
+    let mut links = HashMap::new();
+    loop {
+        let header = NewcHeader::read(&mut r)?;
+        if header.name_size > MAX_PATH_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "cpio entry name is too long"));
+        }
+        let mut name = vec![0; header.name_size as usize];
+        r.read_exact(&mut name)?;
+        io::copy(&mut r.by_ref().take(padding(HEADER_LEN as u64 + u64::from(header.name_size))), &mut io::sink())?;
+        if name.pop() != Some(0) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "cpio entry name is not NUL-terminated"));
+        }
+        if name == TRAILER {
+            break;
+        }
+
+        let attrs = Attributes::new((header.mode & 0o7777) as u16, header.uid, header.gid, header.mtime);
+        let rdev = encode_dev(header.rdev_major, header.rdev_minor);
+        let mut data = r.by_ref().take(header.file_size.into());
+        match header.mode & S_IFMT {
+            S_IFDIR => writer.add_dir_bytes(&name, attrs)?,
+            S_IFREG if header.nlink > 1 => {
+                let key = (header.dev_major, header.dev_minor, header.ino);
+                match links.get_mut(&key) {
+                    None => {
+                        writer.add_file_bytes(&name, attrs, &mut data)?;
+                        links.insert(key, (name, Vec::new()));
+                    },
+                    Some((primary, others)) => {
+                        if header.file_size > 0 {
+                            // This path holds the data, so the earlier paths become links to it
+                            writer.add_file_bytes(&name, attrs, &mut data)?;
+                            others.push(std::mem::replace(primary, name));
+                        } else {
+                            others.push(name);
+                        }
+                    },
+                }
+            },
+            S_IFREG => writer.add_file_bytes(&name, attrs, &mut data)?,
+            S_IFLNK => {
+                if header.file_size >= MAX_PATH_LEN {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                        format!("{}: symlink target is too long", String::from_utf8_lossy(&name))));
+                }
+                let mut target = Vec::with_capacity(header.file_size as usize);
+                data.read_to_end(&mut target)?;
+                writer.add_symlink_bytes(&name, attrs, &target)?;
+            },
+            S_IFCHR => writer.add_char_device_bytes(&name, attrs, rdev)?,
+            S_IFBLK => writer.add_block_device_bytes(&name, attrs, rdev)?,
+            S_IFIFO => writer.add_fifo_bytes(&name, attrs)?,
+            S_IFSOCK => writer.add_socket_bytes(&name, attrs)?,
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                    format!("{}: unsupported file type {:o}", String::from_utf8_lossy(&name), header.mode & S_IFMT)));
+            },
+        }
+        // Skip anything that was not consumed, such as data stored for a hard link that was not
+        // the one chosen to hold the data
+        let remaining = data.limit();
+        if io::copy(&mut data, &mut io::sink())? != remaining {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        io::copy(&mut r.by_ref().take(padding(header.file_size.into())), &mut io::sink())?;
+    }
+
+    for (primary, others) in links.into_values() {
+        for path in others {
+            writer.add_hard_link_bytes(&path, &primary)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write the entry at `path` in a SquashFS image, and everything beneath it if it is a directory,
+/// to a newc cpio archive. Paths in the archive are relative to `path`, which itself appears as
+/// ".". Returns the Writer once the archive is complete.
+pub fn squashfs_to_cpio<R, P, W>(sqfs: &mut SquashFS<R>, path: P, mut w: W) -> io::Result<W>
+where R: Read + Seek,
+      P: AsRef<Path>,
+      W: Write,
+{
+    let inode = sqfs.inode_from_path(&path)?;
+    let mut seen = HashSet::new();
+    if inode.is_dir() {
+        append_inode(sqfs, &mut w, &mut seen, b".", &inode)?;
+        append_dir_contents(sqfs, &mut w, &mut seen, &mut Visited::default(), &mut Vec::new(), &inode)?;
+    } else {
+        let name = path.as_ref().file_name()
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        append_inode(sqfs, &mut w, &mut seen, name.as_encoded_bytes(), &inode)?;
+    }
+    let trailer = NewcHeader { nlink: 1, ..Default::default() };
+    append_entry(&mut w, trailer, TRAILER, io::empty())?;
+    w.flush()?;
+    Ok(w)
+}
+
+fn append_dir_contents<R: Read + Seek, W: Write>(sqfs: &mut SquashFS<R>, w: &mut W,
+    seen: &mut HashSet<u32>, visited: &mut Visited, dir_path: &mut Vec<u8>, dir: &Inode) -> io::Result<()>
+{
+    let entries = sqfs.read_walk_dir(visited, dir_path, dir)?;
+    for e in entries {
+        let parent_len = dir_path.len();
+        dir_path.extend_from_slice(e.file_name_bytes());
+        let inode = sqfs.inode_from_entryref(e.inode_ref())?;
+        append_inode(sqfs, w, seen, dir_path, &inode)?;
+        if inode.is_dir() {
+            dir_path.push(b'/');
+            append_dir_contents(sqfs, w, seen, visited, dir_path, &inode)?;
+        }
+        dir_path.truncate(parent_len);
+    }
+    Ok(())
+}
+
+fn append_inode<R: Read + Seek, W: Write>(sqfs: &mut SquashFS<R>, w: &mut W,
+    seen: &mut HashSet<u32>, path: &[u8], inode: &Inode) -> io::Result<()>
+{
+    let file_type = if inode.is_dir() {
+        S_IFDIR
+    } else if inode.is_file() {
+        S_IFREG
+    } else if inode.is_symlink() {
+        S_IFLNK
+    } else if inode.is_block_dev() {
+        S_IFBLK
+    } else if inode.is_char_dev() {
+        S_IFCHR
+    } else if inode.is_fifo() {
+        S_IFIFO
+    } else {
+        S_IFSOCK
+    };
+    let (rdev_major, rdev_minor) = inode.dev_number().map(decode_dev).unwrap_or_default();
+    let mut header = NewcHeader {
+        ino: inode.inode_number(),
+        mode: file_type | u32::from(inode.permissions()),
+        uid: inode.uid(sqfs)?,
+        gid: inode.gid(sqfs)?,
+        nlink: inode.link_count(),
+        mtime: inode.mtime(),
+        rdev_major,
+        rdev_minor,
+        ..Default::default()
+    };
+
+    if let Some(target) = inode.symlink_target() {
+        let target = target.to_bytes();
+        header.file_size = target.len() as u32;
+        return append_entry(w, header, path, target);
+    }
+    // Only the first path to a multiply-linked file carries its data
+    if inode.is_file() && (inode.link_count() < 2 || seen.insert(inode.inode_number())) {
+        header.file_size = inode.file_size().unwrap_or(0).try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput,
+                format!("{}: files larger than 4GiB cannot be stored in a cpio archive", String::from_utf8_lossy(path))))?;
+        let reader = sqfs.open_file_inode(inode)?;
+        return append_entry(w, header, path, reader);
+    }
+    append_entry(w, header, path, io::empty())
+}
+
+fn append_entry<W: Write, D: Read>(w: &mut W, mut header: NewcHeader, name: &[u8], mut data: D) -> io::Result<()> {
+    header.name_size = name.len() as u32 + 1;
+    header.write(w)?;
+    w.write_all(name)?;
+    w.write_all(&[0])?;
+    w.write_all(&[0; 3][..padding(HEADER_LEN as u64 + u64::from(header.name_size)) as usize])?;
+    let written = io::copy(&mut data, w)?;
+    if written != u64::from(header.file_size) {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "File data length does not match its size"));
+    }
+    w.write_all(&[0; 3][..padding(written) as usize])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::squashfs::test_util::open;
+
+    #[test]
+    fn test_cpio_round_trip() -> io::Result<()> {
+        let mut sqfs = open(128 * 1024, |w| {
+            w.add_dir("/", Attributes::new(0o700, 0, 0, 1000))?;
+            w.add_file("/bin/init", Attributes::new(0o755, 0, 0, 2000), &b"#!/bin/sh\n"[..])?;
+            w.add_hard_link("/bin/init.link", "/bin/init")?;
+            w.add_symlink("/init", Attributes::new(0o777, 0, 0, 0), "bin/init")?;
+            w.add_char_device("/dev/console", Attributes::new(0o600, 0, 5, 0), encode_dev(5, 1))?;
+            w.add_fifo("/run/initctl", Attributes::new(0o600, 0, 0, 0))?;
+            w.add_socket("/run/socket", Attributes::new(0o600, 0, 0, 0))?;
+            w.add_file("/empty", Attributes::new(0o644, 1000, 1000, 0), io::empty())
+        })?;
+
+        let cpio = squashfs_to_cpio(&mut sqfs, "/", Vec::new())?;
+        assert_eq!(&cpio[..6], NEWC_MAGIC);
+        assert_eq!(cpio.len() % 4, 0);
+
+        let image = cpio_to_squashfs(&cpio[..], Cursor::new(Vec::new()), WriterOptions::default())?;
+        let mut sqfs = SquashFS::new(image)?;
+        let root = sqfs.root_inode()?;
+        assert_eq!(root.permissions(), 0o700);
+        assert_eq!(root.mtime(), 1000);
+        let init = sqfs.inode_from_path("/bin/init")?;
+        assert_eq!(init.permissions(), 0o755);
+        assert_eq!(init.mtime(), 2000);
+        assert_eq!(init.link_count(), 2);
+        assert_eq!(init.inode_number(), sqfs.inode_from_path("/bin/init.link")?.inode_number());
+        let mut content = String::new();
+        sqfs.open_file("/bin/init.link")?.read_to_string(&mut content)?;
+        assert_eq!(content, "#!/bin/sh\n");
+        assert_eq!(sqfs.inode_from_path("/init")?.symlink_target().unwrap().to_bytes(), b"bin/init");
+        let console = sqfs.inode_from_path("/dev/console")?;
+        assert_eq!(decode_dev(console.dev_number().unwrap()), (5, 1));
+        assert_eq!(console.gid(&sqfs)?, 5);
+        assert!(sqfs.inode_from_path("/run/initctl")?.is_fifo());
+        assert!(sqfs.inode_from_path("/run/socket")?.is_socket());
+        assert_eq!(sqfs.inode_from_path("/empty")?.uid(&sqfs)?, 1000);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hard_link_data_on_last() -> io::Result<()> {
+        // GNU cpio stores the data for a set of hard links with the last path
+        let mut cpio = Vec::new();
+        let file = NewcHeader { ino: 7, mode: S_IFREG | 0o644, nlink: 2, ..Default::default() };
+        append_entry(&mut cpio, file, b"a", io::empty())?;
+        let file = NewcHeader { ino: 7, mode: S_IFREG | 0o644, nlink: 2, file_size: 5, ..Default::default() };
+        append_entry(&mut cpio, file, b"b", &b"hello"[..])?;
+        append_entry(&mut cpio, NewcHeader { nlink: 1, ..Default::default() }, TRAILER, io::empty())?;
+
+        let image = cpio_to_squashfs(&cpio[..], Cursor::new(Vec::new()), WriterOptions::default())?;
+        let mut sqfs = SquashFS::new(image)?;
+        for path in ["/a", "/b"] {
+            let mut content = String::new();
+            sqfs.open_file(path)?.read_to_string(&mut content)?;
+            assert_eq!(content, "hello");
+            assert_eq!(sqfs.inode_from_path(path)?.link_count(), 2);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_oversized_header() -> io::Result<()> {
+        // Sizes are rejected before anything is allocated for them
+        let mut cpio = Vec::new();
+        NewcHeader { name_size: u32::MAX, ..Default::default() }.write(&mut cpio)?;
+        let err = cpio_to_squashfs(&cpio[..], Cursor::new(Vec::new()), WriterOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut cpio = Vec::new();
+        let link = NewcHeader { mode: S_IFLNK | 0o777, nlink: 1, file_size: u32::MAX, name_size: 2, ..Default::default() };
+        link.write(&mut cpio)?;
+        cpio.extend_from_slice(b"l\0");
+        let err = cpio_to_squashfs(&cpio[..], Cursor::new(Vec::new()), WriterOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+}
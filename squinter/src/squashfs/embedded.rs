@@ -0,0 +1,306 @@
+//! Images embedded in other files
+//!
+//! An image does not have to start at the beginning of a file. [`OffsetReader`] presents the
+//! part of a file after a given offset as if it were on its own, so that
+//! [`SquashFS::new_at_offset`] can read an image from anywhere within a file.
+//!
+//! [`detect`] finds the image in the files that commonly carry one:
+//! - Bare images, which includes snap packages, start with the SquashFS superblock
+//! - AppImages are ELF executables with the image appended after the last ELF section, so the
+//!   image begins where the ELF headers say that the executable ends
+//! - Firmware files, such as those built by OpenWrt, wrap the image in a boot loader container.
+//!   See the [`firmware`] module for the formats that are recognized.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+use super::superblock::MAGIC;
+use super::{firmware, SquashFS};
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const SHT_NOBITS: u64 = 8;
+
+/// The kinds of file that an image can be found in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    /// A file that starts with the image, such as a plain image or a snap package
+    Bare,
+    /// An ELF executable followed by the image, such as an AppImage
+    Elf,
+    /// A legacy U-Boot uImage that holds the image or is followed by it
+    UImage,
+    /// A U-Boot FIT image that holds the image or is followed by it
+    Fit,
+    /// An OpenWrt sysupgrade tar archive
+    Sysupgrade,
+    /// A file without a recognized container, such as a dump of a flash chip, which was searched
+    /// for the image
+    Flash,
+}
+
+/// A reader over the part of an inner reader that follows a fixed offset. Seek positions are
+/// relative to the offset.
+#[derive(Debug)]
+pub struct OffsetReader<R> {
+    inner: R,
+    offset: u64,
+}
+
+impl<R: Seek> OffsetReader<R> {
+    /// Create a new OffsetReader that starts `offset` bytes into the inner reader
+    pub fn new(mut inner: R, offset: u64) -> io::Result<Self> {
+        inner.seek(SeekFrom::Start(offset))?;
+        Ok(Self { inner, offset })
+    }
+
+    /// The offset of the start of this reader within the inner reader
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for OffsetReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<R: Seek> Seek for OffsetReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let inner_pos = match pos {
+            SeekFrom::Start(p) => {
+                let p = self.offset.checked_add(p)
+                    .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "Seek position overflows"))?;
+                self.inner.seek(SeekFrom::Start(p))?
+            },
+            _ => self.inner.seek(pos)?,
+        };
+        inner_pos.checked_sub(self.offset)
+            .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "Seek to a position before the start of the image"))
+    }
+}
+
+impl<R: Read + Seek> SquashFS<OffsetReader<R>> {
+    /// Create a new SquashFS instance from an image that starts `offset` bytes into the provided
+    /// Reader
+    pub fn new_at_offset(r: R, offset: u64) -> crate::Result<Self> {
+        SquashFS::new(OffsetReader::new(r, offset)?)
+    }
+}
+
+/// Open an image from a filepath, whether it is a bare image, a snap package, an AppImage, or a
+/// firmware file
+pub fn open<P>(path: P) -> crate::Result<SquashFS<OffsetReader<BufReader<File>>>>
+where P: AsRef<Path>
+{
+    SquashFS::new(locate(BufReader::new(File::open(path)?))?)
+}
+
+/// Find the image within the contents of the provided Reader, and return a reader over it
+pub fn locate<R: Read + Seek>(mut r: R) -> io::Result<OffsetReader<R>> {
+    let (_, offset) = detect(&mut r)?;
+    OffsetReader::new(r, offset)
+}
+
+/// Identify the kind of file that the Reader contains, and the offset of the image within it
+pub fn detect<R: Read + Seek>(r: &mut R) -> io::Result<(Container, u64)> {
+    let mut magic = [0u8; 4];
+    r.seek(SeekFrom::Start(0))?;
+    r.read_exact(&mut magic)?;
+    if LittleEndian::read_u32(&magic) == MAGIC {
+        return Ok((Container::Bare, 0));
+    }
+    if magic != ELF_MAGIC {
+        return firmware::detect(r);
+    }
+
+    let offset = elf_size(r)?;
+    r.seek(SeekFrom::Start(offset))?;
+    match r.read_exact(&mut magic) {
+        Ok(()) if LittleEndian::read_u32(&magic) == MAGIC => Ok((Container::Elf, offset)),
+        Err(e) if e.kind() != io::ErrorKind::UnexpectedEof => Err(e),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "No SquashFS image found after the ELF executable")),
+    }
+}
+
+/// Calculate the size of an ELF executable from its headers, as the end of whichever of the
+/// headers, sections, or segments extends furthest into the file
+pub fn elf_size<R: Read + Seek>(r: &mut R) -> io::Result<u64> {
+    let mut header = [0u8; 64];
+    r.seek(SeekFrom::Start(0))?;
+    r.read_exact(&mut header[..52])?;
+    if &header[..4] != ELF_MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Not an ELF file"));
+    }
+    // Addresses and offsets are 4 bytes in 32-bit files and 8 bytes in 64-bit files
+    let word = match header[4] {
+        1 => 4,
+        2 => 8,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown ELF class")),
+    };
+    let big_endian = match header[5] {
+        1 => false,
+        2 => true,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown ELF data encoding")),
+    };
+    if word == 8 {
+        r.read_exact(&mut header[52..])?;
+    }
+    let field = |buf: &[u8], offset: usize, len: usize| {
+        if big_endian {
+            BigEndian::read_uint(&buf[offset..offset + len], len)
+        } else {
+            LittleEndian::read_uint(&buf[offset..offset + len], len)
+        }
+    };
+
+    let phoff = field(&header, 0x18 + word, word);
+    let shoff = field(&header, 0x18 + 2 * word, word);
+    let sizes = 0x18 + 3 * word + 4;
+    let ehsize = field(&header, sizes, 2);
+    let phentsize = field(&header, sizes + 2, 2);
+    let phnum = field(&header, sizes + 4, 2);
+    let shentsize = field(&header, sizes + 6, 2);
+    let shnum = field(&header, sizes + 8, 2);
+
+    let mut end = ehsize;
+    // Program headers, with p_offset and p_filesz
+    let mut entry = vec![0u8; 5 * word];
+    for i in 0..phnum {
+        read_entry(r, phoff, i, phentsize, &mut entry)?;
+        end = end.max(field(&entry, word, word).saturating_add(field(&entry, 4 * word, word)));
+    }
+    // Section headers, with sh_type, sh_offset, and sh_size
+    let mut entry = vec![0u8; 8 + 4 * word];
+    for i in 0..shnum {
+        read_entry(r, shoff, i, shentsize, &mut entry)?;
+        if field(&entry, 4, 4) != SHT_NOBITS {
+            end = end.max(field(&entry, 8 + 2 * word, word).saturating_add(field(&entry, 8 + 3 * word, word)));
+        }
+    }
+    if phnum > 0 {
+        end = end.max(phoff.saturating_add(phnum * phentsize));
+    }
+    if shnum > 0 {
+        end = end.max(shoff.saturating_add(shnum * shentsize));
+    }
+    Ok(end)
+}
+
+/// Read the start of an entry in a table of ELF headers, checking that the entries are large
+/// enough to hold the fields used
+fn read_entry<R: Read + Seek>(r: &mut R, table: u64, index: u64, entry_size: u64, buf: &mut [u8]) -> io::Result<()> {
+    if entry_size < buf.len() as u64 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "ELF header table entries are too small"));
+    }
+    r.seek(SeekFrom::Start(table.saturating_add(index * entry_size)))?;
+    r.read_exact(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use byteorder::WriteBytesExt;
+    use crate::squashfs::test_util::build;
+    use crate::squashfs::writer::Attributes;
+
+    fn image() -> io::Result<Vec<u8>> {
+        build(128 * 1024, |w| w.add_file("/AppRun", Attributes::new(0o755, 0, 0, 0), &b"#!/bin/sh\n"[..]))
+    }
+
+    /// Build an ELF file with one segment and two sections, one of which occupies no space. The
+    /// section header table is followed by a section, so the file ends at that section.
+    fn elf<B: ByteOrder>(wide: bool) -> io::Result<Vec<u8>> {
+        let word = |w: &mut Vec<u8>, v: u64| if wide { w.write_u64::<B>(v) } else { w.write_u32::<B>(v as u32) };
+        let (ehsize, phentsize, shentsize): (u16, u16, u16) = if wide { (64, 56, 64) } else { (52, 32, 40) };
+        let phoff = u64::from(ehsize);
+        let shoff = phoff + u64::from(phentsize);
+        let section = shoff + 2 * u64::from(shentsize);
+
+        let mut w = ELF_MAGIC.to_vec();
+        w.extend_from_slice(&[if wide { 2 } else { 1 }, if B::read_u16(&[0, 1]) == 1 { 2 } else { 1 }, 1]);
+        w.resize(16, 0);
+        w.write_u16::<B>(2)?;
+        w.write_u16::<B>(62)?;
+        w.write_u32::<B>(1)?;
+        word(&mut w, 0)?;
+        word(&mut w, phoff)?;
+        word(&mut w, shoff)?;
+        w.write_u32::<B>(0)?;
+        for v in [ehsize, phentsize, 1, shentsize, 2, 0] {
+            w.write_u16::<B>(v)?;
+        }
+        assert_eq!(w.len(), phoff as usize);
+
+        // A loadable segment covering the headers
+        w.write_u32::<B>(1)?;
+        if wide { w.write_u32::<B>(5)?; }
+        word(&mut w, 0)?;
+        word(&mut w, 0)?;
+        word(&mut w, 0)?;
+        word(&mut w, shoff)?;
+        word(&mut w, shoff)?;
+        if !wide { w.write_u32::<B>(5)?; }
+        word(&mut w, 0x1000)?;
+        assert_eq!(w.len(), shoff as usize);
+
+        for (sh_type, size) in [(SHT_NOBITS, 0x10000), (1, 100)] {
+            w.write_u32::<B>(1)?;
+            w.write_u32::<B>(sh_type as u32)?;
+            word(&mut w, 0)?;
+            word(&mut w, 0)?;
+            word(&mut w, section)?;
+            word(&mut w, size)?;
+            w.write_u32::<B>(0)?;
+            w.write_u32::<B>(0)?;
+            word(&mut w, 1)?;
+            word(&mut w, 0)?;
+        }
+        w.resize(section as usize + 100, 0xEE);
+        Ok(w)
+    }
+
+    #[test]
+    fn test_detect() -> io::Result<()> {
+        let image = image()?;
+        assert_eq!(detect(&mut Cursor::new(&image))?, (Container::Bare, 0));
+
+        for runtime in [elf::<LittleEndian>(true)?, elf::<BigEndian>(false)?] {
+            assert_eq!(elf_size(&mut Cursor::new(&runtime))?, runtime.len() as u64);
+            assert_eq!(detect(&mut Cursor::new(&runtime)).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+            let mut appimage = runtime.clone();
+            appimage.extend_from_slice(&image);
+            assert_eq!(detect(&mut Cursor::new(&appimage))?, (Container::Elf, runtime.len() as u64));
+            let mut sqfs = SquashFS::new(locate(Cursor::new(appimage))?)?;
+            let mut data = String::new();
+            sqfs.open_file("/AppRun")?.read_to_string(&mut data)?;
+            assert_eq!(data, "#!/bin/sh\n");
+        }
+        assert_eq!(detect(&mut Cursor::new(b"\0\0\0\0")).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn test_offset_reader() -> io::Result<()> {
+        let mut r = OffsetReader::new(Cursor::new(b"0123456789".to_vec()), 4)?;
+        let mut buf = [0u8; 2];
+        r.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"45");
+        assert_eq!(r.seek(SeekFrom::End(-1))?, 5);
+        assert_eq!(r.seek(SeekFrom::Current(-5))?, 0);
+        assert_eq!(r.seek(SeekFrom::Start(3))?, 3);
+        r.read_exact(&mut buf)?;
+        assert_eq!(&buf, b"78");
+        assert!(r.seek(SeekFrom::Current(-6)).is_err());
+        Ok(())
+    }
+}
@@ -1,11 +1,13 @@
 use std::io;
 use std::io::{Read, Seek, SeekFrom};
 
-use super::block::{CachingReader, FragmentBlockCache, FragmentReader};
+use super::block::{seek_position, CachingReader, FragmentBlockCache, FragmentReader};
+use super::limits::Budget;
 use super::metadata::{self, FragmentEntry, Inode, InodeExtendedInfo, MetadataProvider};
 use super::readermux::{ReaderClient, ReaderMux};
 use super::superblock::{Superblock, Compressor};
 use super::compressed::CompressedBlockReader;
+use crate::{Error, MetadataTable};
 
 #[derive(Debug)]
 #[allow(dead_code)]
@@ -27,6 +29,7 @@ struct TailEnd {
 }
 
 #[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
 enum BlockReader<R> {
     Block(CachingReader<CompressedBlockReader<ReaderClient<R>>>),
     Fragment(FragmentReader<ReaderClient<CachingReader<CompressedBlockReader<ReaderClient<R>>>>>),
@@ -63,113 +66,206 @@ pub struct FileDataReader<R: Read + Seek> {
 }
 
 impl<R: Read + Seek> FileDataReader<R> {
-    pub fn from_inode(inner: R, mp: &MetadataProvider<R>, sb: &Superblock, frag_cache: &mut FragmentBlockCache<R>, inode: &Inode) -> io::Result<Option<Self>>
+    pub(crate) fn from_inode(inner: R, mp: &MetadataProvider<R>, sb: &Superblock, frag_cache: &mut FragmentBlockCache<R>,
+        budget: &Budget, inode: &Inode) -> io::Result<Option<Self>>
+    {
+        match &inode.extended_info {
+            InodeExtendedInfo::BasicFile(i) => {
+                Self::from_block_list(inner, mp, sb, frag_cache, budget, i.blocks_start.into(), i.file_size.into(),
+                    i.frag_index, i.block_offset, &i.block_sizes).map(Some)
+            },
+            InodeExtendedInfo::ExtFile(i) => {
+                Self::from_block_list(inner, mp, sb, frag_cache, budget, i.blocks_start, i.file_size,
+                    i.frag_index, i.block_offset, &i.block_sizes).map(Some)
+            },
+            _ => Ok(None),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn from_block_list(inner: R, mp: &MetadataProvider<R>, sb: &Superblock, frag_cache: &mut FragmentBlockCache<R>, budget: &Budget,
+        blocks_start: u64, file_size: u64, frag_index: u32, frag_offset: u32, block_sizes: &[u32]) -> io::Result<Self>
     {
         let pos = 0;
         let comp = sb.compressor;
         let block_size = sb.block_size;
         let mut inner = ReaderMux::new(inner);
         let mut blocks = Vec::new();
-        match &inode.extended_info {
-            InodeExtendedInfo::BasicFile(i) => {
-                let file_size: u64 = i.file_size.into();
-
-                let mut offset: u64 = i.blocks_start.into();
-                let mut remaining: u64 = file_size;
-                for b in &i.block_sizes {
-                    let data_len = if u64::from(block_size) <= remaining {
-                        block_size
-                    } else {
-                        remaining as u32
-                    };
-                    let is_compressed = (b & 0x1000000) == 0;
-                    let c = if is_compressed { comp } else { Compressor::None };
-                    let disk_len = b & 0xFFFFFF;
-                    let mut client_reader = inner.client();
-                    client_reader.seek(SeekFrom::Start(offset))?;
-                    blocks.push( FileBlockInfo {
-                        disk_offset: offset,
-                        disk_len,
-                        data_offset: 0,
-                        data_len,
-                        is_compressed,
-                        reader: BlockReader::Block(CachingReader::new_with_capacity(CompressedBlockReader::new(client_reader,c, disk_len.into(), data_len.into())?, block_size.try_into().unwrap())),
-                    });
-                    offset += u64::from(*b);
-                    remaining -= u64::from(data_len);
-                }
-                if i.frag_index != u32::MAX {
-                    let f = metadata::FragmentLookupTable::read_one(&mut inner.client(), mp, sb, i.frag_index as usize)?;
-                    blocks.push( FileBlockInfo {
-                        disk_offset: f.start,
-                        disk_len: f.size & 0xFFFFFF,
-                        data_offset: i.block_offset,
-                        data_len: i.file_size % block_size,
-                        is_compressed: (f.size & 0x1000000) == 0,
-                        // Note, block_size is not the uncompressed size; it is the maximum uncompressed size
-                        reader: BlockReader::Fragment(frag_cache.get_fragment_reader(f.start, (f.size & 0xFFFFFF).into(), block_size.into(), i.block_offset.into(), (i.file_size % block_size).into())?),
-                    });
-                }
-                Ok(Some(FileDataReader {
-                    inner, pos, block_size, file_size, blocks, 
-                }))
-            }
-            _ => Ok(None),
+
+        let mut offset: u64 = blocks_start;
+        let mut remaining: u64 = file_size;
+        for b in block_sizes {
+            let data_len = if u64::from(block_size) <= remaining {
+                block_size
+            } else {
+                remaining as u32
+            };
+            let is_compressed = (b & 0x1000000) == 0;
+            let c = if is_compressed { comp } else { Compressor::None };
+            let disk_len = b & 0xFFFFFF;
+            let mut client_reader = inner.client();
+            client_reader.seek(SeekFrom::Start(offset))?;
+            // The cache grows as the block is read, since a file may list far more blocks than it
+            // will ever have read from it
+            let caching_reader = CachingReader::new_with_capacity(
+                CompressedBlockReader::new(client_reader, c, disk_len.into(), data_len.into())?, 0, budget.clone());
+            blocks.push( FileBlockInfo {
+                disk_offset: offset,
+                disk_len,
+                data_offset: 0,
+                data_len,
+                is_compressed,
+                reader: BlockReader::Block(caching_reader),
+            });
+            offset = offset.checked_add(disk_len.into())
+                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "File data extends beyond the end of the image"))?;
+            remaining -= u64::from(data_len);
         }
+        // Whatever the blocks do not hold must be in the tail-end, and the last block may only be
+        // partly full when there is no tail-end
+        let tail_len = file_size % u64::from(block_size);
+        let expected = if frag_index != u32::MAX { tail_len } else { 0 };
+        let partial = blocks.iter().rev().skip(usize::from(frag_index == u32::MAX)).any(|b| b.data_len != block_size);
+        if remaining != expected || partial {
+            return Err(inconsistent());
+        }
+        if frag_index != u32::MAX {
+            let tail_len = tail_len as u32;
+            let f = metadata::FragmentLookupTable::read_one(&mut inner.client(), mp, sb, frag_index as usize)
+                .map_err(|e| Error::in_metadata(MetadataTable::Fragment, sb.frag_table)(e))?;
+            let is_compressed = (f.size & 0x1000000) == 0;
+            blocks.push( FileBlockInfo {
+                disk_offset: f.start,
+                disk_len: f.size & 0xFFFFFF,
+                data_offset: frag_offset,
+                data_len: tail_len,
+                is_compressed,
+                // Note, block_size is not the uncompressed size; it is the maximum uncompressed size
+                reader: BlockReader::Fragment(frag_cache.get_fragment_reader(f.start, (f.size & 0xFFFFFF).into(), is_compressed, block_size.into(), frag_offset.into(), tail_len.into())?),
+            });
+        }
+        Ok(FileDataReader {
+            inner, pos, block_size, file_size, blocks,
+        })
     }
 
     pub fn into_inner(self) -> R {
         self.inner.into_inner()
     }
 
+    /// Read from the given offset in the file, without changing the position of the reader.
+    /// Returns 0 at or beyond the end of the file.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let pos = self.pos;
+        self.pos = offset;
+        let result = self.read(buf);
+        self.pos = pos;
+        result
+    }
+
+    /// Fill the buffer from the given offset in the file, without changing the position of the
+    /// reader. As with std::os::unix::fs::FileExt::read_exact_at, this fails with UnexpectedEof
+    /// if the file ends first.
+    pub fn read_exact_at(&mut self, offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+        let mut offset = offset;
+        while !buf.is_empty() {
+            match self.read_at(offset, buf) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer")),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u64;
+                },
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
     /** Get the block and (uncompressed) data offset within the block for a given file offset.
      *  Also return the amount of (uncompressed) data left in the block
      */
-    fn calc_block_and_offset(&mut self, pos: u64) -> Option<(&mut FileBlockInfo<R>, u32, u32)> {
+    fn calc_block_and_offset(&mut self, pos: u64) -> io::Result<Option<(&mut FileBlockInfo<R>, u32, u32)>> {
         if pos >= self.file_size {
-            return None;
+            return Ok(None);
         }
         let block_index = pos / (self.block_size as u64);
         let data_offset = (pos % (self.block_size as u64)) as u32;
 
-        let b = &self.blocks[block_index as usize];
-        let offset = data_offset;
+        let b = usize::try_from(block_index).ok()
+            .and_then(|i| self.blocks.get_mut(i))
+            .filter(|b| data_offset < b.data_len)
+            .ok_or_else(inconsistent)?;
         let remaining = b.data_len - data_offset;
-        let b = &mut self.blocks[block_index as usize];
-        Some((b, offset, remaining))
+        Ok(Some((b, data_offset, remaining)))
     }
 }
 
+// The error for a file whose size does not match its blocks, or whose blocks hold less data than
+// its size implies
+fn inconsistent() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "File data does not match the file size")
+}
+
 impl<R: Read + Seek> Read for FileDataReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let (b, offset, _) = match self.calc_block_and_offset(self.pos) {
+        let (b, offset, _) = match self.calc_block_and_offset(self.pos)? {
             Some(x) => x,
             None => return Ok(0),
         };
         b.reader.seek(SeekFrom::Start(offset.into()))?;
         let size = b.reader.read(buf)?;
-        self.pos += u64::try_from(size).unwrap();
+        // A block that ends early would otherwise look like the end of the file
+        if size == 0 && !buf.is_empty() {
+            return Err(inconsistent());
+        }
+        self.pos += size as u64;
         Ok(size)
     }
 }
 
 impl<R: Read + Seek> Seek for FileDataReader<R> {
     fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
-        match pos {
-            SeekFrom::Start(p) => {
-                self.pos = p;
-                Ok(self.pos)
-            },
-            SeekFrom::End(p) => {
-                assert!(self.file_size as i64 + p >= 0);
-                self.pos = (self.file_size as i64 + p) as u64;
-                Ok(self.pos)
-            },
-            SeekFrom::Current(p) => {
-                assert!(self.pos as i64 + p >= 0);
-                self.pos = (self.pos as i64 + p) as u64;
-                Ok(self.pos)
-            },
+        self.pos = seek_position(pos, self.pos, self.file_size)?;
+        Ok(self.pos)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::squashfs::test_util::open;
+    use crate::squashfs::writer::Attributes;
+
+    #[test]
+    fn test_seek_and_read_at() -> crate::Result<()> {
+        let data: Vec<u8> = (0..200_000u32).map(|n| (n % 251) as u8).collect();
+        let mut sqfs = open(128 * 1024, |w| w.add_file("/file", Attributes::new(0o644, 0, 0, 0), &data[..]))?;
+        let mut inode = sqfs.inode_from_path("/file")?;
+        let mut r = sqfs.open_file_inode(&inode)?;
+
+        for pos in [SeekFrom::End(-200_001), SeekFrom::Current(-1), SeekFrom::Current(i64::MIN)] {
+            assert_eq!(r.seek(pos).unwrap_err().kind(), io::ErrorKind::InvalidInput);
         }
+        assert_eq!(r.seek(SeekFrom::End(10))?, 200_010);
+        assert_eq!(r.read(&mut [0; 16])?, 0);
+
+        // Reads at an offset span blocks and the tail-end, and leave the position alone
+        r.seek(SeekFrom::Start(5))?;
+        let mut buf = [0; 1000];
+        r.read_exact_at(130_500, &mut buf)?;
+        assert_eq!(buf, data[130_500..131_500]);
+        r.read_exact_at(199_000, &mut buf)?;
+        assert_eq!(buf, data[199_000..]);
+        assert_eq!(r.read_exact_at(199_500, &mut buf).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(r.stream_position()?, 5);
+
+        // A file size that its blocks cannot hold
+        match &mut inode.extended_info {
+            InodeExtendedInfo::BasicFile(i) => i.file_size = 400_000,
+            _ => panic!("Expected a basic file"),
+        }
+        let err = sqfs.open_file_inode(&inode).unwrap_err();
+        assert!(matches!(&err, Error::Io(e) if e.kind() == io::ErrorKind::InvalidData), "{err}");
+        Ok(())
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,587 @@
+//! Firmware images that carry a root filesystem
+//!
+//! Embedded Linux firmware, such as that built by OpenWrt, usually stores its root filesystem as
+//! an image inside, or directly after, a boot loader container. [`detect`] recognizes:
+//! - Legacy U-Boot uImages, including multi-file uImages, which either hold the image or are
+//!   followed by it
+//! - FIT images, which are flattened device trees whose `/images` nodes hold their contents in a
+//!   `data` property, or outside of the tree at a `data-offset` or `data-position`
+//! - OpenWrt sysupgrade tar archives, which hold the image in their `root` member
+//!
+//! Containers may be nested inside one another. When none of them holds the image, as in a raw
+//! dump of a flash chip, the file is scanned for a valid superblock instead.
+//!
+//! OpenWrt's fwtool appends metadata and signatures to the end of firmware files. These are
+//! skipped when looking for the image, and can be read with [`metadata`].
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+use super::embedded::Container;
+use super::superblock::{Compressor, MAGIC, SUPERBLOCK_SIZE};
+
+const UIMAGE_MAGIC: u32 = 0x27051956;
+const UIMAGE_HEADER_SIZE: u64 = 64;
+const UIMAGE_TYPE_MULTI: u8 = 4;
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_HEADER_SIZE: usize = 40;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const MAX_FDT_STRINGS_SIZE: u64 = 0x10000;
+const MAX_FDT_NAME_LEN: u64 = 256;
+
+const TAR_BLOCK_SIZE: u64 = 512;
+const TAR_MAGIC: &[u8] = b"ustar";
+
+const FWTOOL_MAGIC: u32 = 0x46577830;
+const FWTOOL_TRAILER_SIZE: u64 = 16;
+const FWTOOL_HEADER_SIZE: usize = 8;
+
+/// How deeply containers may be nested inside one another
+const MAX_DEPTH: u32 = 4;
+const SCAN_CHUNK_SIZE: u64 = 0x10000;
+
+/// The kinds of metadata that fwtool appends to firmware
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetadataKind {
+    /// A signature over the firmware
+    Signature,
+    /// A JSON document describing the firmware, such as the boards it supports
+    Info,
+    /// A kind of metadata that is not known to this crate
+    Unknown(u8),
+}
+
+/// A block of metadata appended to a firmware file by OpenWrt's fwtool
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub kind: MetadataKind,
+    /// The contents of the block. For [`MetadataKind::Info`], this is the JSON document without
+    /// the header that precedes it.
+    pub data: Vec<u8>,
+}
+
+/// Find the image within a firmware file, returning the kind of the outermost container and the
+/// offset of the image within the file
+pub fn detect<R: Read + Seek>(r: &mut R) -> io::Result<(Container, u64)> {
+    let end = payload_size(r)?;
+    if let Some(found) = probe(r, 0, end, 0)? {
+        return Ok(found);
+    }
+    match scan(r, 0, end)? {
+        Some(offset) => Ok((Container::Flash, offset)),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "No SquashFS image found")),
+    }
+}
+
+/// Read the fwtool metadata at the end of a firmware file, in the order that it was appended
+pub fn metadata<R: Read + Seek>(r: &mut R) -> io::Result<Vec<Metadata>> {
+    Ok(trailers(r)?.1)
+}
+
+/// The size of a firmware file, excluding any fwtool metadata at its end
+pub fn payload_size<R: Read + Seek>(r: &mut R) -> io::Result<u64> {
+    Ok(trailers(r)?.0)
+}
+
+/// Walk the fwtool trailers backwards from the end of the file. Each trailer follows the block
+/// that it describes, and its size covers both.
+fn trailers<R: Read + Seek>(r: &mut R) -> io::Result<(u64, Vec<Metadata>)> {
+    let mut end = r.seek(SeekFrom::End(0))?;
+    let mut blocks = Vec::new();
+    let mut trailer = [0u8; FWTOOL_TRAILER_SIZE as usize];
+    while end >= FWTOOL_TRAILER_SIZE
+        && read_at(r, end - FWTOOL_TRAILER_SIZE, &mut trailer)?
+        && BigEndian::read_u32(&trailer) == FWTOOL_MAGIC
+    {
+        let size = u64::from(BigEndian::read_u32(&trailer[12..]));
+        if size < FWTOOL_TRAILER_SIZE || size > end {
+            break;
+        }
+        let mut data = vec![0u8; (size - FWTOOL_TRAILER_SIZE) as usize];
+        read_at(r, end - size, &mut data)?;
+        let kind = match trailer[8] {
+            0 => MetadataKind::Signature,
+            1 => {
+                data.drain(..data.len().min(FWTOOL_HEADER_SIZE));
+                MetadataKind::Info
+            },
+            k => MetadataKind::Unknown(k),
+        };
+        blocks.push(Metadata { kind, data });
+        end -= size;
+    }
+    blocks.reverse();
+    Ok((end, blocks))
+}
+
+/// Look for the image at `start`, either directly or within a container that starts there
+fn probe<R: Read + Seek>(r: &mut R, start: u64, end: u64, depth: u32) -> io::Result<Option<(Container, u64)>> {
+    let mut magic = [0u8; 4];
+    if depth > MAX_DEPTH || !read_at(r, start, &mut magic)? {
+        return Ok(None);
+    }
+    if is_superblock(r, start, end)? {
+        return Ok(Some((Container::Bare, start)));
+    }
+    let (container, offset) = match BigEndian::read_u32(&magic) {
+        UIMAGE_MAGIC => (Container::UImage, uimage(r, start, end, depth)?),
+        FDT_MAGIC => (Container::Fit, fit(r, start, end, depth)?),
+        _ if is_tar(r, start)? => (Container::Sysupgrade, sysupgrade(r, start, end, depth)?),
+        _ => return Ok(None),
+    };
+    Ok(offset.map(|o| (container, o)))
+}
+
+/// Probe each of the candidate locations in turn, returning the first image found
+fn first_image<R, I>(r: &mut R, candidates: I, end: u64, depth: u32) -> io::Result<Option<u64>>
+where R: Read + Seek,
+      I: IntoIterator<Item = u64>,
+{
+    for start in candidates {
+        if let Some((_, offset)) = probe(r, start, end, depth + 1)? {
+            return Ok(Some(offset));
+        }
+    }
+    Ok(None)
+}
+
+/// Find the image in a legacy uImage. Multi-file uImages begin their data with a zero-terminated
+/// table of the sizes of each part, and pad each part to 4 bytes. A uImage that only holds a
+/// kernel is commonly followed by the image.
+fn uimage<R: Read + Seek>(r: &mut R, start: u64, end: u64, depth: u32) -> io::Result<Option<u64>> {
+    let mut header = [0u8; UIMAGE_HEADER_SIZE as usize];
+    if !read_at(r, start, &mut header)? {
+        return Ok(None);
+    }
+    let data = start + UIMAGE_HEADER_SIZE;
+    let data_end = data.saturating_add(u64::from(BigEndian::read_u32(&header[12..])));
+
+    let mut parts = Vec::new();
+    if header[30] == UIMAGE_TYPE_MULTI {
+        let mut sizes = Vec::new();
+        let mut pos = data;
+        let mut size = [0u8; 4];
+        while pos + 4 <= data_end && read_at(r, pos, &mut size)? {
+            pos += 4;
+            match BigEndian::read_u32(&size) {
+                0 => break,
+                s => sizes.push(u64::from(s)),
+            }
+        }
+        for size in sizes {
+            parts.push(pos);
+            pos = pos.saturating_add(align(size, 4));
+        }
+    } else {
+        parts.push(data);
+    }
+    match first_image(r, parts, end, depth)? {
+        Some(offset) => Ok(Some(offset)),
+        None => scan(r, data_end, end),
+    }
+}
+
+/// An image node of a FIT
+#[derive(Default)]
+struct FitImage {
+    image_type: Vec<u8>,
+    data: Option<u64>,
+    data_offset: Option<u64>,
+    data_position: Option<u64>,
+    data_size: u64,
+}
+
+/// Find the image in a FIT. Each node under `/images` holds one part of the firmware, and the
+/// ones with a `filesystem` or `ramdisk` type are tried first. External data follows the device
+/// tree, starting at the next 4-byte boundary. A FIT that only holds a kernel is commonly
+/// followed by the image.
+fn fit<R: Read + Seek>(r: &mut R, start: u64, end: u64, depth: u32) -> io::Result<Option<u64>> {
+    let mut header = [0u8; FDT_HEADER_SIZE];
+    if !read_at(r, start, &mut header)? {
+        return Ok(None);
+    }
+    let field = |i: usize| u64::from(BigEndian::read_u32(&header[i * 4..]));
+    let (total_size, off_struct, off_strings, version) = (field(1), field(2), field(3), field(5));
+    let (size_strings, size_struct) = (field(8), field(9));
+    if version < 17 || size_strings > MAX_FDT_STRINGS_SIZE {
+        return Ok(None);
+    }
+    let mut strings = vec![0u8; size_strings as usize];
+    if !read_at(r, start + off_strings, &mut strings)? {
+        return Ok(None);
+    }
+
+    // Walk the structure block, recording the properties of each node at /images/<name>
+    let mut images: Vec<FitImage> = Vec::new();
+    let mut path: Vec<Vec<u8>> = Vec::new();
+    let mut pos = start + off_struct;
+    let struct_end = pos.saturating_add(size_struct);
+    let mut word = [0u8; 8];
+    while pos + 4 <= struct_end && read_at(r, pos, &mut word[..4])? {
+        pos += 4;
+        match BigEndian::read_u32(&word) {
+            FDT_BEGIN_NODE => {
+                let name = match read_cstr(r, pos, MAX_FDT_NAME_LEN)? {
+                    Some(name) => name,
+                    None => break,
+                };
+                pos += align(name.len() as u64 + 1, 4);
+                path.push(name);
+                if path.len() == 3 && path[1] == b"images" {
+                    images.push(FitImage::default());
+                }
+            },
+            FDT_END_NODE => { path.pop(); },
+            FDT_PROP => {
+                if !read_at(r, pos, &mut word)? {
+                    break;
+                }
+                let len = u64::from(BigEndian::read_u32(&word));
+                let name = strings.get(BigEndian::read_u32(&word[4..]) as usize..)
+                    .and_then(|s| s.split(|&b| b == 0).next())
+                    .unwrap_or_default();
+                let value = pos + 8;
+                pos = value.saturating_add(align(len, 4));
+                let image = match images.last_mut() {
+                    Some(image) if path.len() == 3 && path[1] == b"images" => image,
+                    _ => continue,
+                };
+                let mut number = || -> io::Result<Option<u64>> {
+                    let mut n = [0u8; 4];
+                    Ok((len == 4 && read_at(r, value, &mut n)?).then(|| u64::from(BigEndian::read_u32(&n))))
+                };
+                match name {
+                    b"data" => {
+                        image.data = Some(value);
+                        image.data_size = len;
+                    },
+                    b"data-offset" => image.data_offset = number()?,
+                    b"data-position" => image.data_position = number()?,
+                    b"data-size" => image.data_size = number()?.unwrap_or(0),
+                    b"type" => {
+                        image.image_type = read_cstr(r, value, len.min(MAX_FDT_NAME_LEN))?.unwrap_or_default();
+                    },
+                    _ => {},
+                }
+            },
+            FDT_NOP => {},
+            _ => break,
+        }
+    }
+
+    let external = start.saturating_add(align(total_size, 4));
+    let mut fit_end = external;
+    let mut parts = Vec::new();
+    for image in &images {
+        let location = match (image.data, image.data_position, image.data_offset) {
+            (Some(data), _, _) => data,
+            (None, Some(position), _) => start.saturating_add(position),
+            (None, None, Some(offset)) => external.saturating_add(offset),
+            _ => continue,
+        };
+        fit_end = fit_end.max(location.saturating_add(image.data_size));
+        let rootfs = image.image_type == b"filesystem" || image.image_type == b"ramdisk";
+        parts.push((!rootfs, location));
+    }
+    parts.sort_by_key(|&(rootfs, _)| rootfs);
+    match first_image(r, parts.into_iter().map(|(_, location)| location), end, depth)? {
+        Some(offset) => Ok(Some(offset)),
+        None => scan(r, align(fit_end, 4), end),
+    }
+}
+
+/// Check for the header of a ustar archive member at `start`
+fn is_tar<R: Read + Seek>(r: &mut R, start: u64) -> io::Result<bool> {
+    let mut magic = [0u8; 5];
+    Ok(read_at(r, start + 257, &mut magic)? && magic == TAR_MAGIC)
+}
+
+/// Find the image in a sysupgrade tar archive. The root filesystem is stored in a member named
+/// `sysupgrade-<board>/root`, which is tried before the others.
+fn sysupgrade<R: Read + Seek>(r: &mut R, start: u64, end: u64, depth: u32) -> io::Result<Option<u64>> {
+    let mut members = Vec::new();
+    let mut pos = start;
+    let mut header = [0u8; TAR_BLOCK_SIZE as usize];
+    while read_at(r, pos, &mut header)? && &header[257..262] == TAR_MAGIC {
+        let size = match parse_octal(&header[124..136]) {
+            Some(size) => size,
+            None => break,
+        };
+        let data = pos + TAR_BLOCK_SIZE;
+        if header[156] == b'0' || header[156] == 0 {
+            let name = header[..100].split(|&b| b == 0).next().unwrap_or_default();
+            let root = name == b"root" || name.ends_with(b"/root");
+            members.push((!root, data));
+        }
+        pos = data.saturating_add(align(size, TAR_BLOCK_SIZE));
+    }
+    members.sort_by_key(|&(root, _)| root);
+    first_image(r, members.into_iter().map(|(_, data)| data), end, depth)
+}
+
+/// Parse a tar header number, which is stored in octal digits followed by a NUL or space
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let digits = std::str::from_utf8(field).ok()?
+        .trim_matches(|c| c == '\0' || c == ' ');
+    u64::from_str_radix(digits, 8).ok()
+}
+
+/// Search for a valid superblock, which always starts on a 4-byte boundary, between `start` and
+/// `end`
+fn scan<R: Read + Seek>(r: &mut R, start: u64, end: u64) -> io::Result<Option<u64>> {
+    let mut buf = Vec::with_capacity(SCAN_CHUNK_SIZE as usize);
+    let mut pos = align(start, 4);
+    while pos + SUPERBLOCK_SIZE <= end {
+        buf.clear();
+        r.seek(SeekFrom::Start(pos))?;
+        r.by_ref().take(SCAN_CHUNK_SIZE.min(end - pos)).read_to_end(&mut buf)?;
+        if buf.len() < 4 {
+            break;
+        }
+        for i in (0..=buf.len() - 4).step_by(4) {
+            if LittleEndian::read_u32(&buf[i..]) == MAGIC && is_superblock(r, pos + i as u64, end)? {
+                return Ok(Some(pos + i as u64));
+            }
+        }
+        pos += buf.len() as u64;
+    }
+    Ok(None)
+}
+
+/// Check whether a plausible superblock, for an image that ends before `end`, starts at `offset`
+fn is_superblock<R: Read + Seek>(r: &mut R, offset: u64, end: u64) -> io::Result<bool> {
+    let mut sb = [0u8; SUPERBLOCK_SIZE as usize];
+    if !read_at(r, offset, &mut sb)? {
+        return Ok(false);
+    }
+    let u16_at = |i: usize| LittleEndian::read_u16(&sb[i..]);
+    let u64_at = |i: usize| LittleEndian::read_u64(&sb[i..]);
+    let block_log = u16_at(22);
+    let bytes_used = u64_at(40);
+    Ok(LittleEndian::read_u32(&sb) == MAGIC
+        && (u16_at(28), u16_at(30)) == (4, 0)
+        && (12..=20).contains(&block_log)
+        && LittleEndian::read_u32(&sb[12..]) == 1 << block_log
+        && Compressor::try_from(u16_at(20)).is_ok()
+        && bytes_used >= SUPERBLOCK_SIZE
+        && offset.saturating_add(bytes_used) <= end
+        && [48, 64, 72].iter().all(|&table| u64_at(table) < bytes_used))
+}
+
+/// Read a NUL-terminated string of at most `max` bytes
+fn read_cstr<R: Read + Seek>(r: &mut R, offset: u64, max: u64) -> io::Result<Option<Vec<u8>>> {
+    let mut buf = Vec::new();
+    r.seek(SeekFrom::Start(offset))?;
+    r.by_ref().take(max).read_to_end(&mut buf)?;
+    Ok(buf.iter().position(|&b| b == 0).map(|n| { buf.truncate(n); buf }))
+}
+
+/// Fill the buffer from `offset`, returning false if the reader ends first
+fn read_at<R: Read + Seek>(r: &mut R, offset: u64, buf: &mut [u8]) -> io::Result<bool> {
+    r.seek(SeekFrom::Start(offset))?;
+    match r.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn align(value: u64, to: u64) -> u64 {
+    value.saturating_add(to - 1) / to * to
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use byteorder::WriteBytesExt;
+    use crate::squashfs::embedded;
+    use crate::squashfs::test_util::build;
+    use crate::squashfs::writer::Attributes;
+    use crate::squashfs::SquashFS;
+
+    fn image() -> io::Result<Vec<u8>> {
+        build(128 * 1024, |w| w.add_file("/etc/openwrt_release", Attributes::new(0o644, 0, 0, 0), &b"DISTRIB_ID='OpenWrt'\n"[..]))
+    }
+
+    fn uimage_header(image_type: u8, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut w = Vec::new();
+        w.write_u32::<BigEndian>(UIMAGE_MAGIC)?;
+        w.resize(12, 0);
+        w.write_u32::<BigEndian>(data.len() as u32)?;
+        w.resize(30, 0);
+        w.extend_from_slice(&[image_type, 0]);
+        w.resize(UIMAGE_HEADER_SIZE as usize, 0);
+        w.extend_from_slice(data);
+        Ok(w)
+    }
+
+    type Node<'a> = (&'a str, Vec<(&'a str, Vec<u8>)>);
+
+    /// Build a FIT with the given image nodes, each a list of properties
+    fn fdt(images: &[Node]) -> io::Result<Vec<u8>> {
+        let mut strings = Vec::new();
+        let mut st = Vec::new();
+        let node = |st: &mut Vec<u8>, name: &str| -> io::Result<()> {
+            st.write_u32::<BigEndian>(FDT_BEGIN_NODE)?;
+            st.extend_from_slice(name.as_bytes());
+            st.resize(align(st.len() as u64 + 1, 4) as usize, 0);
+            Ok(())
+        };
+        node(&mut st, "")?;
+        node(&mut st, "images")?;
+        for (name, props) in images {
+            node(&mut st, name)?;
+            for (prop, value) in props {
+                st.write_u32::<BigEndian>(FDT_PROP)?;
+                st.write_u32::<BigEndian>(value.len() as u32)?;
+                st.write_u32::<BigEndian>(strings.len() as u32)?;
+                strings.extend_from_slice(prop.as_bytes());
+                strings.push(0);
+                st.extend_from_slice(value);
+                st.resize(align(st.len() as u64, 4) as usize, 0);
+            }
+            st.write_u32::<BigEndian>(FDT_END_NODE)?;
+        }
+        st.write_u32::<BigEndian>(FDT_END_NODE)?;
+        st.write_u32::<BigEndian>(FDT_END_NODE)?;
+        st.write_u32::<BigEndian>(9)?;
+
+        let off_struct = FDT_HEADER_SIZE + 16;
+        let off_strings = off_struct + st.len();
+        let total = off_strings + strings.len();
+        let mut w = Vec::new();
+        for v in [FDT_MAGIC, total as u32, off_struct as u32, off_strings as u32, FDT_HEADER_SIZE as u32, 17, 16, 0,
+                  strings.len() as u32, st.len() as u32] {
+            w.write_u32::<BigEndian>(v)?;
+        }
+        w.resize(off_struct, 0);
+        w.extend_from_slice(&st);
+        w.extend_from_slice(&strings);
+        Ok(w)
+    }
+
+    fn tar_member(w: &mut Vec<u8>, name: &str, data: &[u8]) {
+        let start = w.len();
+        w.extend_from_slice(name.as_bytes());
+        w.resize(start + 124, 0);
+        w.extend_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+        w.resize(start + 156, 0);
+        w.push(b'0');
+        w.resize(start + 257, 0);
+        w.extend_from_slice(b"ustar\x0000");
+        w.resize(start + TAR_BLOCK_SIZE as usize, 0);
+        w.extend_from_slice(data);
+        w.resize(align(w.len() as u64, TAR_BLOCK_SIZE) as usize, 0);
+    }
+
+    fn fwtool_block(w: &mut Vec<u8>, kind: u8, data: &[u8]) -> io::Result<()> {
+        let size = data.len() as u64 + FWTOOL_TRAILER_SIZE;
+        w.extend_from_slice(data);
+        w.write_u32::<BigEndian>(FWTOOL_MAGIC)?;
+        w.write_u32::<BigEndian>(0)?;
+        w.extend_from_slice(&[kind, 0, 0, 0]);
+        w.write_u32::<BigEndian>(size as u32)
+    }
+
+    fn check(firmware: Vec<u8>, container: Container, offset: u64) -> io::Result<()> {
+        assert_eq!(embedded::detect(&mut Cursor::new(&firmware))?, (container, offset));
+        let mut sqfs = SquashFS::new(embedded::locate(Cursor::new(firmware))?)?;
+        let mut data = String::new();
+        sqfs.open_file("/etc/openwrt_release")?.read_to_string(&mut data)?;
+        assert_eq!(data, "DISTRIB_ID='OpenWrt'\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_uimage() -> io::Result<()> {
+        let image = image()?;
+        check(uimage_header(7, &image)?, Container::UImage, UIMAGE_HEADER_SIZE)?;
+
+        // A kernel, followed by the image at the next erase block
+        let mut firmware = uimage_header(2, b"kernel")?;
+        firmware.resize(0x10000, 0xff);
+        firmware.extend_from_slice(&image);
+        check(firmware, Container::UImage, 0x10000)?;
+
+        let mut multi = Vec::new();
+        for size in [6, image.len() as u32, 0] {
+            multi.write_u32::<BigEndian>(size)?;
+        }
+        multi.extend_from_slice(b"kernel\0\0");
+        multi.extend_from_slice(&image);
+        check(uimage_header(UIMAGE_TYPE_MULTI, &multi)?, Container::UImage, UIMAGE_HEADER_SIZE + 20)
+    }
+
+    #[test]
+    fn test_fit() -> io::Result<()> {
+        let image = image()?;
+        let kernel = ("kernel-1", vec![("type", b"kernel\0".to_vec()), ("data", b"kernel".to_vec())]);
+
+        // External data, after a kernel that is stored in the tree
+        let mut firmware = fdt(&[kernel.clone(), ("rootfs-1", vec![
+            ("type", b"filesystem\0".to_vec()),
+            ("data-offset", 8u32.to_be_bytes().to_vec()),
+            ("data-size", (image.len() as u32).to_be_bytes().to_vec()),
+        ])])?;
+        let external = align(firmware.len() as u64, 4);
+        firmware.resize(external as usize + 8, 0);
+        firmware.extend_from_slice(&image);
+        check(firmware, Container::Fit, external + 8)?;
+
+        // Data stored in the tree
+        let firmware = fdt(&[kernel.clone(), ("rootfs-1", vec![("data", image.clone())])])?;
+        let (_, offset) = embedded::detect(&mut Cursor::new(&firmware))?;
+        assert_eq!(&firmware[offset as usize..][..image.len()], &image[..]);
+        check(firmware, Container::Fit, offset)?;
+
+        // A kernel FIT, followed by the image
+        let mut firmware = fdt(&[kernel])?;
+        firmware.resize(0x1000, 0);
+        firmware.extend_from_slice(&image);
+        check(firmware, Container::Fit, 0x1000)
+    }
+
+    #[test]
+    fn test_sysupgrade() -> io::Result<()> {
+        let image = image()?;
+        let mut firmware = Vec::new();
+        tar_member(&mut firmware, "sysupgrade-board/CONTROL", b"BOARD=board\n");
+        tar_member(&mut firmware, "sysupgrade-board/kernel", &uimage_header(2, b"kernel")?);
+        tar_member(&mut firmware, "sysupgrade-board/root", &image);
+        firmware.resize(firmware.len() + 2 * TAR_BLOCK_SIZE as usize, 0);
+        let payload = firmware.len() as u64;
+
+        let mut info = vec![0u8; FWTOOL_HEADER_SIZE];
+        info.extend_from_slice(br#"{"supported_devices":["board"]}"#);
+        fwtool_block(&mut firmware, 1, &info)?;
+        fwtool_block(&mut firmware, 0, b"signature")?;
+        assert_eq!(payload_size(&mut Cursor::new(&firmware))?, payload);
+        assert_eq!(metadata(&mut Cursor::new(&firmware))?, vec![
+            Metadata { kind: MetadataKind::Info, data: info[FWTOOL_HEADER_SIZE..].to_vec() },
+            Metadata { kind: MetadataKind::Signature, data: b"signature".to_vec() },
+        ]);
+        check(firmware, Container::Sysupgrade, 3 * TAR_BLOCK_SIZE + 2 * TAR_BLOCK_SIZE)
+    }
+
+    #[test]
+    fn test_flash() -> io::Result<()> {
+        let image = image()?;
+        // The magic alone, without a valid superblock, is not enough
+        let mut firmware = vec![0xffu8; 0x3000];
+        firmware[0x100..0x104].copy_from_slice(&MAGIC.to_le_bytes());
+        firmware.extend_from_slice(&image);
+        check(firmware.clone(), Container::Flash, 0x3000)?;
+
+        // An image that extends past the end of the file is not found
+        let bytes_used = LittleEndian::read_u64(&image[40..]);
+        firmware.truncate(0x3000 + bytes_used as usize - 1);
+        assert_eq!(embedded::detect(&mut Cursor::new(&firmware)).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+//! Bounds on the resources used to read an image
+//!
+//! A corrupt or malicious image can describe tables, directories, and files far larger than the
+//! image itself, or a directory tree that never ends. [`Limits`] caps what reading such an image
+//! can cost. Exceeding a limit fails with [`Error::LimitExceeded`] naming the [`Limit`], rather
+//! than exhausting memory or running without end.
+//!
+//! ```rust
+//! use std::io;
+//! use squinter::squashfs::{Limits, SquashFS};
+//! fn open_untrusted(image: Vec<u8>) -> squinter::Result<()> {
+//!     let limits = Limits {
+//!         max_decompressed_bytes: 64 * image.len() as u64,
+//!         ..Default::default()
+//!     };
+//!     let mut sqfs = SquashFS::new_with_limits(io::Cursor::new(image), limits)?;
+//!     let mut file = sqfs.open_file("/etc/os-release")?;
+//!     io::copy(&mut file, &mut io::sink())?;
+//!     Ok(())
+//! }
+//! ```
+
+use std::cell::Cell;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+
+use crate::Error;
+
+/// Bounds on the resources used to read an image, given to [`SquashFS::new_with_limits`]
+///
+/// The defaults allow any image that mksquashfs would reasonably produce. The exception is
+/// `max_decompressed_bytes`, which is unlimited by default because a long-lived SquashFS may
+/// legitimately read far more data than its own size. When reading an untrusted image, set it to
+/// a multiple of the image size.
+///
+/// [`SquashFS::new_with_limits`]: super::SquashFS::new_with_limits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The most memory allocated for a single table or list read from the image, such as the
+    /// fragment table, the block list of a file, or a symbolic link target
+    pub max_table_bytes: u64,
+    /// The most entries read from a single directory
+    pub max_dir_entries: u32,
+    /// The most symbolic links followed while resolving a single path
+    pub max_symlink_follows: u32,
+    /// The most directories that a path may pass through, and the deepest that a walk of the tree
+    /// may descend
+    pub max_path_depth: u32,
+    /// The most bytes read out of data and metadata blocks, after decompression, over the life
+    /// of the SquashFS
+    pub max_decompressed_bytes: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_table_bytes: 256 * 1024 * 1024,
+            max_dir_entries: 1024 * 1024,
+            // As on Linux
+            max_symlink_follows: 40,
+            max_path_depth: 256,
+            max_decompressed_bytes: u64::MAX,
+        }
+    }
+}
+
+/// The limit that was exceeded, reported by [`Error::LimitExceeded`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// [`Limits::max_table_bytes`]
+    TableBytes,
+    /// [`Limits::max_dir_entries`]
+    DirEntries,
+    /// [`Limits::max_path_depth`]
+    PathDepth,
+    /// [`Limits::max_decompressed_bytes`]
+    DecompressedBytes,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Limit::TableBytes => "the size of a table",
+            Limit::DirEntries => "the number of entries in a directory",
+            Limit::PathDepth => "the depth of a path",
+            Limit::DecompressedBytes => "the number of bytes decompressed",
+        })
+    }
+}
+
+impl Limits {
+    /// Check that a table of `count` items of `item_size` bytes each may be read
+    pub(crate) fn check_table(&self, count: u64, item_size: u64) -> io::Result<()> {
+        match count.checked_mul(item_size) {
+            Some(size) if size <= self.max_table_bytes => Ok(()),
+            _ => Err(Error::LimitExceeded(Limit::TableBytes).into()),
+        }
+    }
+
+    /// Check that a path or walk that has passed through `depth` directories may go on
+    pub(crate) fn check_depth(&self, depth: usize) -> io::Result<()> {
+        if depth > self.max_path_depth as usize {
+            return Err(Error::LimitExceeded(Limit::PathDepth).into());
+        }
+        Ok(())
+    }
+}
+
+/// The Limits of one image, along with the count of bytes decompressed so far, which is shared by
+/// all of the image's readers
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Budget {
+    limits: Limits,
+    decompressed: Rc<Cell<u64>>,
+}
+
+impl Budget {
+    pub(crate) fn new(limits: Limits) -> Self {
+        Self { limits, decompressed: Rc::new(Cell::new(0)) }
+    }
+
+    pub(crate) fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Account for bytes read out of a block, failing once the total exceeds the limit
+    pub(crate) fn decompress(&self, bytes: u64) -> io::Result<()> {
+        let total = self.decompressed.get().saturating_add(bytes);
+        self.decompressed.set(total);
+        if total > self.limits.max_decompressed_bytes {
+            return Err(Error::LimitExceeded(Limit::DecompressedBytes).into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+    use std::path::Path;
+    use crate::squashfs::path::canonicalize;
+    use crate::squashfs::test_util::build;
+    use crate::squashfs::writer::Attributes;
+    use crate::squashfs::SquashFS;
+
+    fn exceeded(e: impl Into<Error>) -> Option<Limit> {
+        match e.into() {
+            Error::LimitExceeded(limit) => Some(limit),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_limits() -> io::Result<()> {
+        let a = |mode| Attributes::new(mode, 0, 0, 0);
+        let image = build(128 * 1024, |w| {
+            w.add_file("/big", a(0o644), &vec![b'x'; 1 << 20][..])?;
+            w.add_file("/a/b/c/file", a(0o644), &b"deep"[..])?;
+            w.add_symlink("/l1", a(0o777), "l2")?;
+            w.add_symlink("/l2", a(0o777), "/a")
+        })?;
+        let open = |limits| SquashFS::new_with_limits(Cursor::new(image.clone()), limits);
+
+        let mut sqfs = open(Limits::default())?;
+        assert_eq!(sqfs.limits(), &Limits::default());
+        io::copy(&mut sqfs.open_file("/big")?, &mut io::sink())?;
+        assert_eq!(canonicalize(&mut sqfs, "/l1/b/c", "/")?, Path::new("/a/b/c"));
+
+        let mut sqfs = open(Limits { max_table_bytes: 16, ..Default::default() })?;
+        assert_eq!(exceeded(sqfs.open_file("/big").unwrap_err()), Some(Limit::TableBytes));
+
+        let mut sqfs = open(Limits { max_dir_entries: 4, ..Default::default() })?;
+        assert_eq!(sqfs.read_dir("/")?.count(), 4);
+        let mut sqfs = open(Limits { max_dir_entries: 3, ..Default::default() })?;
+        assert_eq!(exceeded(sqfs.read_dir("/").unwrap_err()), Some(Limit::DirEntries));
+
+        let mut sqfs = open(Limits { max_path_depth: 3, ..Default::default() })?;
+        assert_eq!(exceeded(sqfs.inode_from_path("/a/b/c/file").unwrap_err()), Some(Limit::PathDepth));
+        assert_eq!(exceeded(canonicalize(&mut sqfs, "/a/b/c/file", "/").unwrap_err()), Some(Limit::PathDepth));
+        assert!(sqfs.inode_from_path("/a/b/c").is_ok());
+
+        let mut sqfs = open(Limits { max_symlink_follows: 1, ..Default::default() })?;
+        assert!(matches!(canonicalize(&mut sqfs, "/l1/b", "/").unwrap_err(), Error::SymlinkLoop(_)));
+
+        let mut sqfs = open(Limits { max_decompressed_bytes: 64 * 1024, ..Default::default() })?;
+        let mut data = Vec::new();
+        let err = sqfs.open_file("/big")?.read_to_end(&mut data).unwrap_err();
+        assert_eq!(exceeded(err), Some(Limit::DecompressedBytes));
+        Ok(())
+    }
+}
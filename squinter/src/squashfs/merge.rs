@@ -0,0 +1,140 @@
+//! Merging of layered images
+//!
+//! [`merge`] combines several images into one, in the same way that overlayfs presents a stack of
+//! layers. Layers are applied in order, so entries in later layers take precedence over entries
+//! at the same paths in earlier layers. Directories that exist in several layers are merged, with
+//! the attributes of the latest layer. Any other entry replaces whatever was at its path.
+//!
+//! Later layers can also remove entries from earlier ones with whiteouts, in either the OCI image
+//! format or the overlayfs format:
+//! - A file named `.wh.NAME` removes `NAME` from the same directory
+//! - A file named `.wh..wh..opq` hides all of the earlier contents of its directory
+//! - A character device with device number 0/0 removes the entry at its path
+//! - A directory with the `trusted.overlay.opaque` xattr set to `y` hides all of the earlier
+//!   contents of the directory
+//!
+//! Whiteouts themselves are not copied. File data blocks are copied without being recompressed
+//! from any layer that uses the same compressor and block size as the merged image.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, Write};
+
+use super::walk::Visited;
+use super::writer::{Attributes, SquashFSWriter, WriterOptions};
+use super::{Inode, SquashFS};
+
+pub(crate) const WHITEOUT_PREFIX: &[u8] = b".wh.";
+pub(crate) const OPAQUE_WHITEOUT: &[u8] = b".wh..wh..opq";
+pub(crate) const OVERLAY_OPAQUE_XATTR: &[u8] = b"trusted.overlay.opaque";
+
+/// Write a single image containing the contents of all of the `layers`, where later layers take
+/// precedence over earlier ones. Returns the Writer once the new image is complete.
+pub fn merge<R: Read + Seek, W: Write + Seek>(layers: &mut [SquashFS<R>], w: W, opts: WriterOptions) -> io::Result<W> {
+    let mut writer = SquashFSWriter::new(w, opts)?;
+    for sqfs in layers.iter_mut() {
+        let raw = sqfs.compressor() == writer.options().compressor && sqfs.block_size() == writer.options().block_size;
+        let root = sqfs.root_inode()?;
+        add_dir(sqfs, &mut writer, b"/", &root)?;
+        merge_dir_contents(sqfs, &mut writer, raw, &mut HashMap::new(), &mut Visited::default(), &mut Vec::new(), &root)?;
+    }
+    writer.finish()
+}
+
+fn merge_dir_contents<R: Read + Seek, W: Write + Seek>(sqfs: &mut SquashFS<R>, writer: &mut SquashFSWriter<W>,
+    raw: bool, links: &mut HashMap<u32, Vec<u8>>, visited: &mut Visited, dir_path: &mut Vec<u8>, dir: &Inode) -> io::Result<()>
+{
+    let entries = sqfs.read_walk_dir(visited, dir_path, dir)?;
+    if entries.iter().any(|e| e.file_name_bytes() == OPAQUE_WHITEOUT) {
+        writer.clear_dir_bytes(dir_path)?;
+    }
+    for e in entries {
+        let name = e.file_name_bytes();
+        let parent_len = dir_path.len();
+        dir_path.push(b'/');
+        if name == OPAQUE_WHITEOUT {
+            // Already handled
+        } else if let Some(hidden) = name.strip_prefix(WHITEOUT_PREFIX) {
+            dir_path.extend_from_slice(hidden);
+            remove_if_exists(writer, dir_path)?;
+        } else {
+            dir_path.extend_from_slice(name);
+            let inode = sqfs.inode_from_entryref(e.inode_ref())?;
+            if is_whiteout(&inode) {
+                remove_if_exists(writer, dir_path)?;
+            } else if let Some(target) = links.get(&inode.inode_number()) {
+                writer.add_hard_link_bytes(dir_path, target)?;
+            } else if inode.is_dir() {
+                add_dir(sqfs, writer, dir_path, &inode)?;
+                merge_dir_contents(sqfs, writer, raw, links, visited, dir_path, &inode)?;
+            } else {
+                if raw && inode.is_file() {
+                    writer.add_file_raw_bytes(dir_path, sqfs, &inode)?;
+                } else {
+                    writer.add_inode_bytes(dir_path, sqfs, &inode)?;
+                }
+                if inode.link_count() > 1 {
+                    links.insert(inode.inode_number(), dir_path.clone());
+                }
+            }
+        }
+        dir_path.truncate(parent_len);
+    }
+    Ok(())
+}
+
+/// Add a directory, or update the attributes of an existing one. Directories marked as opaque
+/// with an xattr lose any existing contents, and the marker is not copied.
+fn add_dir<R: Read + Seek, W: Write + Seek>(sqfs: &mut SquashFS<R>, writer: &mut SquashFSWriter<W>,
+    path: &[u8], inode: &Inode) -> io::Result<()>
+{
+    let mut attrs = Attributes::from_inode(inode, sqfs)?;
+    let len = attrs.xattrs.len();
+    attrs.xattrs.retain(|(name, value)| name != OVERLAY_OPAQUE_XATTR || value != b"y");
+    let opaque = attrs.xattrs.len() != len;
+    writer.add_dir_bytes(path, attrs)?;
+    if opaque {
+        writer.clear_dir_bytes(path)?;
+    }
+    Ok(())
+}
+
+/// Whether an Inode is an overlayfs whiteout, which is a character device with device number 0/0
+pub(crate) fn is_whiteout(inode: &Inode) -> bool {
+    inode.is_char_dev() && inode.dev_number() == Some(0)
+}
+
+fn remove_if_exists<W: Write + Seek>(writer: &mut SquashFSWriter<W>, path: &[u8]) -> io::Result<()> {
+    match writer.remove_bytes(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        r => r,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::squashfs::test_util::{list_tree, overlay_layers};
+
+    #[test]
+    fn test_merge() -> io::Result<()> {
+        let mut layers = overlay_layers()?;
+        let opts = WriterOptions { block_size: 4096, ..Default::default() };
+        let mut sqfs = SquashFS::new(merge(&mut layers, Cursor::new(Vec::new()), opts)?)?;
+        let mut tree = Vec::new();
+        list_tree(&mut sqfs, "/", &mut tree)?;
+        assert_eq!(tree, [
+            "/bin/ 755", "/bin/alias 1 8", "/bin/tool 1 4",
+            "/etc/ 700", "/etc/passwd 1 6", "/lib 1 3",
+            "/opt/ 755", "/opt/app/ 755", "/opt/app/new 1 3",
+            "/usr 1 0", "/var/ 755", "/var/log 1 3",
+        ]);
+        for (path, expected) in [("/bin/alias", "replaced"), ("/etc/passwd", "vendor")] {
+            let mut data = String::new();
+            sqfs.open_file(path)?.read_to_string(&mut data)?;
+            assert_eq!(data, expected);
+        }
+        assert!(sqfs.inode_from_path("/var")?.xattrs(&sqfs)?.is_empty());
+        Ok(())
+    }
+}
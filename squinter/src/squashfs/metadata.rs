@@ -1,23 +1,37 @@
 use std::cmp::min;
 use std::fmt::Debug;
-use std::io::{self, Cursor, Read, Seek, SeekFrom};
-use std::ffi::CString;
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::ffi::{CStr, CString};
 use std::ops::Range;
+use std::path::PathBuf;
 
-use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
+use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
 use super::block::{MetadataBlockCache, MetadataReader};
 use super::compressed::CompressedBlockReader;
+use super::limits::{Budget, Limit, Limits};
 use super::superblock::{Compressor, Superblock};
-
-// Divide x by y, rounding up any fractional result
-macro_rules! div_ceil {
-    ($x:expr, $y:expr) => { ($x + $y - 1) / $y }
-}
+use crate::{Error, MetadataTable};
 
 // SquashFS Metadata block size is fixed by the specification
-const METADATA_BLOCK_SIZE: u16 = 8192;
+pub(crate) const METADATA_BLOCK_SIZE: u16 = 8192;
+
+// Limits imposed by the on-disk directory format and the Linux kernel
+pub(crate) const MAX_NAME_LEN: usize = 256;
+pub(crate) const MAX_DIR_HEADER_ENTRIES: usize = 256;
+
+/// Extended attribute names are stored without their namespace prefix. The index of the prefix in
+/// this list is the type recorded alongside the name.
+pub(crate) const XATTR_PREFIXES: [&[u8]; 3] = [b"user.", b"trusted.", b"security."];
+
+/// Split a full extended attribute name (ex. "user.comment") into the SquashFS namespace type and
+/// the remainder of the name. Returns None if SquashFS cannot represent the namespace.
+pub(crate) fn split_xattr_name(name: &[u8]) -> Option<(u16, &[u8])> {
+    XATTR_PREFIXES.iter().enumerate()
+        .find(|(_, p)| name.starts_with(p))
+        .map(|(n, p)| (n as u16, &name[p.len()..]))
+}
 
 /// Read and decompress a single metadata block from the provided Reader into the provided buffer.
 /// Metadata blocks are always 8KB. If a smaller buffer is provided then only part of the block is
@@ -32,8 +46,7 @@ pub(crate) fn read_metadata_block<R>(r: &mut R, c: &Compressor, buf: &mut [u8])
     //println!("Size = {}; Compressed = {}", size, compressed);
 
     if size > METADATA_BLOCK_SIZE {
-        eprintln!("Metadata block size too big -- {}", size);
-        return Err(io::Error::from(io::ErrorKind::InvalidData));
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Metadata block size too big -- {size}")));
     }
 
     if !compressed {
@@ -68,9 +81,9 @@ pub struct MetadataProvider<R: Read+Seek> {
 impl<R:Read+Seek> MetadataProvider<R> {
     
     /// Create a new MetadataProvider from a reader over the SquashFS archive and a Superblock
-    /// describing the layout of that archive.
-    pub fn new(inner: R, sb: &Superblock) -> Self {
-        let cache = MetadataBlockCache::new(inner, sb.compressor);
+    /// describing the layout of that archive. Decompressed blocks are counted against the budget.
+    pub(crate) fn new(inner: R, sb: &Superblock, budget: Budget) -> Self {
+        let cache = MetadataBlockCache::new(inner, sb.compressor, budget);
         let inode_addrs = sb.inode_table..sb.dir_table;
         let dir_addrs = if sb.frag_table != u64::MAX {
             sb.dir_table..sb.frag_table
@@ -100,7 +113,7 @@ impl<R:Read+Seek> MetadataProvider<R> {
     
     /// Retrieve a MetadataReader with access to the entire range of the inner reader. The reader
     /// is initialized to point to the location specified by entry_ref.
-    pub fn archive_reader(&self, entry_ref: EntryReference) -> io::Result<MetadataReader<R>> {
+    pub fn archive_reader(&self, entry_ref: EntryReference) -> io::Result<MetadataReader<'_, R>> {
         MetadataReader::new(
             &self.cache,
             0,
@@ -111,7 +124,7 @@ impl<R:Read+Seek> MetadataProvider<R> {
 
     /// Retrieve a MetadataReader with access to the Inode portion of the inner reader. The reader
     /// is initialized to point to the location specified by entry_ref.
-    pub fn inode_reader(&self, entry_ref: EntryReference) -> io::Result<MetadataReader<R>> {
+    pub fn inode_reader(&self, entry_ref: EntryReference) -> io::Result<MetadataReader<'_, R>> {
         MetadataReader::new(
             &self.cache,
             self.inode_addrs.start,
@@ -120,9 +133,21 @@ impl<R:Read+Seek> MetadataProvider<R> {
         )
     }
 
+    /// Retrieve a MetadataReader with access to the extended attribute key/value portion of the
+    /// inner reader, which begins at kv_start. The reader is initialized to point to the location
+    /// specified by entry_ref.
+    pub fn xattr_reader(&self, kv_start: u64, entry_ref: EntryReference) -> io::Result<MetadataReader<'_, R>> {
+        MetadataReader::new(
+            &self.cache,
+            kv_start,
+            None,
+            entry_ref,
+        )
+    }
+
     /// Retrieve a MetadataReader with access to the Directory portion of the inner reader. The reader
     /// is initialized to point to the location specified by entry_ref.
-    pub fn dir_reader(&self, entry_ref: EntryReference) -> io::Result<MetadataReader<R>> {
+    pub fn dir_reader(&self, entry_ref: EntryReference) -> io::Result<MetadataReader<'_, R>> {
         MetadataReader::new(
             &self.cache,
             self.dir_addrs.start,
@@ -143,6 +168,9 @@ pub struct EntryReference {
 
 #[allow(dead_code)]
 impl EntryReference {
+    /// The largest location that fits in a reference, alongside the 16-bit offset
+    pub(crate) const MAX_LOCATION: u64 = u64::MAX >> 16;
+
     pub(crate) fn new(location: u64, offset: u16) -> Self {
         Self {
             val: (location << 16) | u64::from(offset),
@@ -170,6 +198,12 @@ impl EntryReference {
             val: r.read_u64::<LittleEndian>()?
         })
     }
+
+    pub(crate) fn write<W>(&self, w: &mut W) -> io::Result<()>
+    where W: Write
+    {
+        w.write_u64::<LittleEndian>(self.val)
+    }
 }
 
 impl std::fmt::Debug for EntryReference {
@@ -178,23 +212,21 @@ impl std::fmt::Debug for EntryReference {
     }
 }
 
-#[allow(dead_code)]
 #[derive(Debug)]
 struct ExtendedAttribute {
     pub prefix: u16,
-    pub name: String,
+    pub name: Vec<u8>,
     pub value: AttributeValue,
 }
 
 #[allow(dead_code)]
 #[derive(Debug)]
-struct ExtendedAttributeLookupEntry {
+pub(crate) struct ExtendedAttributeLookupEntry {
     pub xattr_ref: u64,
     pub count: u32,
     pub size: u32,
 }
 
-#[allow(dead_code)]
 #[derive(Debug)]
 enum AttributeValue {
     Value(Vec<u8>),
@@ -251,13 +283,16 @@ pub(crate) struct LookupTable<I: FromBytes> {
 impl<I: FromBytes> LookupTable<I> {
     
     /// Read and store all of the entries in a lookup table and return them in a LookupTable struct.
-    fn read<R>(r: &mut R, table_offset: u64, item_count: u32, compressor: &Compressor) -> io::Result<Self>
+    fn read<R>(r: &mut R, table_offset: u64, item_count: u32, compressor: &Compressor, limits: &Limits) -> io::Result<Self>
     where R: Read + Seek
     {
-        let block_count = div_ceil!(item_count, (METADATA_BLOCK_SIZE / I::BYTE_SIZE) as u32);
+        limits.check_table(item_count.into(), I::BYTE_SIZE.into())?;
+        let entries_per_block = (METADATA_BLOCK_SIZE / I::BYTE_SIZE) as u32;
+        let block_count = item_count.div_ceil(entries_per_block);
+        // The counts come from the image, so only trust them as far as the first block
         let mut me = LookupTable {
-            block_offsets: Vec::with_capacity(usize::try_from(block_count).unwrap()),
-            entries: Vec::with_capacity(usize::try_from(item_count).unwrap()),
+            block_offsets: Vec::with_capacity(min(block_count, entries_per_block) as usize),
+            entries: Vec::with_capacity(min(item_count, entries_per_block) as usize),
         };
 
         // Read the metadata block locations
@@ -272,15 +307,17 @@ impl<I: FromBytes> LookupTable<I> {
         let mut data = &buf[0..size];
         for n in 0..item_count {
             // Read the next block, if needed
-            if n % (METADATA_BLOCK_SIZE / I::BYTE_SIZE) as u32 == 0 {
-                let block_num = n / (METADATA_BLOCK_SIZE/I::BYTE_SIZE) as u32;
+            if n % entries_per_block == 0 {
+                let block_num = n / entries_per_block;
                 r.seek(SeekFrom::Start(me.block_offsets[block_num as usize]))?;
                 size = read_metadata_block(r, compressor, &mut buf)?.1;
                 data = &buf[0..size];
             }
 
-            me.entries.push(I::from_bytes(&data[..I::BYTE_SIZE as usize]));
-            data = &data[I::BYTE_SIZE as usize..];
+            let (entry, rest) = data.split_at_checked(I::BYTE_SIZE as usize)
+                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Lookup table block is too short"))?;
+            me.entries.push(I::from_bytes(entry));
+            data = rest;
         }
         Ok(me)
     }
@@ -289,12 +326,18 @@ impl<I: FromBytes> LookupTable<I> {
     where R: Read + Seek,
           P: Read + Seek,
     {
-        let meta_index = index * I::BYTE_SIZE as usize / METADATA_BLOCK_SIZE as usize;
-        let block_offset: u16 = ((index * I::BYTE_SIZE as usize) % METADATA_BLOCK_SIZE as usize).try_into().unwrap();
+        let pos = index as u64 * u64::from(I::BYTE_SIZE);
+        let meta_index = pos / u64::from(METADATA_BLOCK_SIZE);
+        let block_offset = (pos % u64::from(METADATA_BLOCK_SIZE)) as u16;
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid lookup table location");
 
         // Read the metadata block location
-        r.seek(SeekFrom::Start(table_offset + (meta_index as u64 * 8)))?;
+        let location_addr = table_offset.checked_add(meta_index * 8).ok_or_else(invalid)?;
+        r.seek(SeekFrom::Start(location_addr))?;
         let block_addr = r.read_u64::<LittleEndian>()?;
+        if block_addr > EntryReference::MAX_LOCATION {
+            return Err(invalid());
+        }
 
         let mut reader = mp.archive_reader(EntryReference::new(block_addr, block_offset))?;
         let mut buf = vec![0; I::BYTE_SIZE as usize];
@@ -319,6 +362,16 @@ impl FromBytes for FragmentEntry {
     }
 }
 
+impl FragmentEntry {
+    pub(crate) fn write<W>(&self, w: &mut W) -> io::Result<()>
+    where W: Write
+    {
+        w.write_u64::<LittleEndian>(self.start)?;
+        w.write_u32::<LittleEndian>(self.size)?;
+        w.write_u32::<LittleEndian>(0)
+    }
+}
+
 #[derive(Debug)]
 #[allow(dead_code)]
 pub(crate) struct FragmentLookupTable {
@@ -327,11 +380,11 @@ pub(crate) struct FragmentLookupTable {
 
 #[allow(dead_code)]
 impl FragmentLookupTable {
-    pub fn read<R>(r: &mut R, sb: &Superblock) -> io::Result<Self>
+    pub fn read<R>(r: &mut R, sb: &Superblock, limits: &Limits) -> io::Result<Self>
     where R: Read + Seek
     {
         Ok(Self {
-            lu_table: LookupTable::read(r, sb.frag_table, sb.frag_count as u32, &sb.compressor)?,
+            lu_table: LookupTable::read(r, sb.frag_table, sb.frag_count, &sb.compressor, limits)?,
         })
     }
     
@@ -339,6 +392,9 @@ impl FragmentLookupTable {
     where R: Read + Seek,
           P: Read + Seek,
     {
+        if index >= sb.frag_count as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid fragment index"));
+        }
         LookupTable::read_one(r, mp, sb.frag_table, index)
     }
 }
@@ -349,46 +405,63 @@ pub(crate) struct IdLookupTable {
 }
 
 impl IdLookupTable {
-    pub fn read<R>(r: &mut R, sb: &Superblock) -> io::Result<Self>
+    pub fn read<R>(r: &mut R, sb: &Superblock, limits: &Limits) -> io::Result<Self>
     where R: Read + Seek
     {
         Ok(Self {
-            lu_table: LookupTable::read(r, sb.id_table, sb.id_count as u32, &sb.compressor)?,
+            lu_table: LookupTable::read(r, sb.id_table, sb.id_count.into(), &sb.compressor, limits)?,
         })
     }
 }
 
 #[allow(dead_code)]
 #[derive(Debug)]
-struct ExportLookupTable {
+pub(crate) struct ExportLookupTable {
     pub lu_table: LookupTable<EntryReference>,
 }
 
 impl ExportLookupTable {
     #[allow(dead_code)]
-    fn read<R>(r: &mut R, sb: &Superblock) -> io::Result<Option<Self>>
+    fn read<R>(r: &mut R, sb: &Superblock, limits: &Limits) -> io::Result<Option<Self>>
     where R: Read + Seek
     {
         if sb.export_table == u64::MAX {
             return Ok(None)
         }
         Ok(Some(Self {
-            lu_table: LookupTable::read(r, sb.export_table, sb.inode_count, &sb.compressor)?,
+            lu_table: LookupTable::read(r, sb.export_table, sb.inode_count, &sb.compressor, limits)?,
         }))
     }
+
+    /// Look up the reference to the inode with the given inode number
+    pub(crate) fn read_one<R,P>(r: &mut R, mp: &MetadataProvider<P>, sb: &Superblock, inode_number: u32) -> io::Result<EntryReference>
+    where R: Read + Seek,
+          P: Read + Seek,
+    {
+        if sb.export_table == u64::MAX {
+            return Err(Error::NoExportTable.into());
+        }
+        if inode_number == 0 || inode_number > sb.inode_count {
+            return Err(Error::InvalidInodeNumber(inode_number).into());
+        }
+        LookupTable::read_one(r, mp, sb.export_table, (inode_number - 1) as usize)
+    }
 }
 
+// Flag set in an xattr key's type to indicate that the value is stored elsewhere, and only a
+// reference to it is stored with the key
+const XATTR_VALUE_OOL: u16 = 0x100;
+
 #[allow(dead_code)]
 #[derive(Debug)]
-struct ExtendedAttributeLookupTable {
+pub(crate) struct ExtendedAttributeLookupTable {
     kv_start: u64,
     count: u32,
     lu_table: LookupTable<ExtendedAttributeLookupEntry>,
 }
 
 impl ExtendedAttributeLookupTable {
-    #[allow(dead_code)]
-    fn read<R>(r: &mut R, sb: &Superblock) -> io::Result<Option<Self>>
+    pub fn read<R>(r: &mut R, sb: &Superblock, limits: &Limits) -> io::Result<Option<Self>>
     where R: Read + Seek
     {
         if sb.xattr_table == u64::MAX {
@@ -398,28 +471,90 @@ impl ExtendedAttributeLookupTable {
         r.seek(SeekFrom::Start(sb.xattr_table))?;
         let kv_start = r.read_u64::<LittleEndian>()?;
         let count = r.read_u32::<LittleEndian>()?;
+        let lu_table_offset = sb.xattr_table.checked_add(16)
+            .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Invalid xattr table location"))?;
         Ok(Some(Self {
             kv_start, count,
-            lu_table: LookupTable::read(r, sb.xattr_table+16, count as u32, &sb.compressor)?,
+            lu_table: LookupTable::read(r, lu_table_offset, count, &sb.compressor, limits)?,
         }))
     }
+
+    /// Read the set of extended attributes with the given index, as (full name, value) pairs
+    pub fn read_xattrs<R>(&self, mp: &MetadataProvider<R>, index: u32, limits: &Limits) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>>
+    where R: Read + Seek
+    {
+        let entry = self.lu_table.entries.get(index as usize)
+            .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Invalid xattr index"))?;
+        let mut reader = mp.xattr_reader(self.kv_start, EntryReference { val: entry.xattr_ref })?;
+        let mut attrs = Vec::new();
+        for _ in 0..entry.count {
+            attrs.push(ExtendedAttribute::read(&mut reader, limits)?);
+        }
+
+        let mut xattrs = Vec::with_capacity(attrs.len());
+        for a in attrs {
+            let prefix = XATTR_PREFIXES.get(usize::from(a.prefix))
+                .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Unknown xattr prefix"))?;
+            let mut name = prefix.to_vec();
+            name.extend_from_slice(&a.name);
+            let value = match a.value {
+                AttributeValue::Value(v) => v,
+                AttributeValue::Ref(r) => {
+                    let mut reader = mp.xattr_reader(self.kv_start, r)?;
+                    read_xattr_value(&mut reader, limits)?
+                },
+            };
+            xattrs.push((name, value));
+        }
+        Ok(xattrs)
+    }
+}
+
+impl ExtendedAttribute {
+    fn read<R: Read>(r: &mut R, limits: &Limits) -> io::Result<Self> {
+        let key_type = r.read_u16::<LittleEndian>()?;
+        let name_size = r.read_u16::<LittleEndian>()?;
+        let mut name = vec![0; name_size.into()];
+        r.read_exact(&mut name)?;
+        let value = if key_type & XATTR_VALUE_OOL != 0 {
+            let size = r.read_u32::<LittleEndian>()?;
+            if size != 8 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid xattr value reference"));
+            }
+            AttributeValue::Ref(EntryReference::read(r)?)
+        } else {
+            AttributeValue::Value(read_xattr_value(r, limits)?)
+        };
+        Ok(Self { prefix: key_type & !XATTR_VALUE_OOL, name, value })
+    }
+}
+
+fn read_xattr_value<R: Read>(r: &mut R, limits: &Limits) -> io::Result<Vec<u8>> {
+    let size = r.read_u32::<LittleEndian>()?;
+    limits.check_table(size.into(), 1)?;
+    let mut value = Vec::new();
+    r.take(size.into()).read_to_end(&mut value)?;
+    if value.len() != size as usize {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    Ok(value)
 }
 
 /// Information about an object in the filesystem (ex. file, directory, device node)
 #[derive(Debug)]
 pub struct Inode {
-    inode_type: InodeType,
-    permissions: u16,
-    uid_index: u16,
-    gid_index: u16,
-    mtime: u32,
-    inode_number: u32,
+    pub(crate) inode_type: InodeType,
+    pub(crate) permissions: u16,
+    pub(crate) uid_index: u16,
+    pub(crate) gid_index: u16,
+    pub(crate) mtime: u32,
+    pub(crate) inode_number: u32,
     pub extended_info: InodeExtendedInfo,
 }
 
-#[derive(Debug, IntoPrimitive, TryFromPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
 #[repr(u16)]
-enum InodeType {
+pub(crate) enum InodeType {
     BasicDir = 1,
     BasicFile = 2,
     BasicSymlink = 3,
@@ -438,6 +573,36 @@ enum InodeType {
     Unknown = 0xFFFF,
 }
 
+impl InodeType {
+    /// The extended variant of an inode's type, which is able to carry an xattr index
+    pub(crate) fn to_ext(self) -> Self {
+        match self {
+            InodeType::BasicDir => InodeType::ExtDir,
+            InodeType::BasicFile => InodeType::ExtFile,
+            InodeType::BasicSymlink => InodeType::ExtSymlink,
+            InodeType::BasicBlockDev => InodeType::ExtBlockDev,
+            InodeType::BasicCharDev => InodeType::ExtCharDev,
+            InodeType::BasicNamedPipe => InodeType::ExtNamedPipe,
+            InodeType::BasicSocked => InodeType::ExtSocked,
+            t => t,
+        }
+    }
+
+    /// The basic variant of an inode's type, which is the type recorded in directory entries
+    pub(crate) fn to_basic(self) -> Self {
+        match self {
+            InodeType::ExtDir => InodeType::BasicDir,
+            InodeType::ExtFile => InodeType::BasicFile,
+            InodeType::ExtSymlink => InodeType::BasicSymlink,
+            InodeType::ExtBlockDev => InodeType::BasicBlockDev,
+            InodeType::ExtCharDev => InodeType::BasicCharDev,
+            InodeType::ExtNamedPipe => InodeType::BasicNamedPipe,
+            InodeType::ExtSocked => InodeType::BasicSocked,
+            t => t,
+        }
+    }
+}
+
 /// Type-specific information about a filesystem object
 #[derive(Debug)]
 pub enum InodeExtendedInfo {
@@ -445,19 +610,23 @@ pub enum InodeExtendedInfo {
     BasicDir(BasicDirInfo),
     ExtDir(ExtDirInfo),
     BasicFile(BasicFileInfo),
+    ExtFile(ExtFileInfo),
     BasicSymlink(BasicSymlinkInfo),
+    ExtSymlink(ExtSymlinkInfo),
     BasicDev(BasicDevInfo),
+    ExtDev(ExtDevInfo),
     BasicIpc(BasicIpcInfo),
+    ExtIpc(ExtIpcInfo),
 }
 
 /// Information about a directory object in the filesystem
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct BasicDirInfo {
-    block_index: u32,
-    link_count: u32,
-    file_size: u16,
-    block_offset: u16,
+    pub(crate) block_index: u32,
+    pub(crate) link_count: u32,
+    pub(crate) file_size: u16,
+    pub(crate) block_offset: u16,
     pub parent_inode: u32,
 }
 
@@ -465,13 +634,13 @@ pub struct BasicDirInfo {
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct ExtDirInfo {
-    link_count: u32,
+    pub(crate) link_count: u32,
     pub file_size: u32,
-    block_index: u32,
+    pub(crate) block_index: u32,
     pub parent_inode: u32,
-    index_count: u16,
-    block_offset: u16,
-    xattr_index: u32,
+    pub(crate) index_count: u16,
+    pub(crate) block_offset: u16,
+    pub(crate) xattr_index: u32,
 }
 
 /// Information about a file object in the filesystem
@@ -485,36 +654,115 @@ pub struct BasicFileInfo {
     pub(crate) block_sizes: Vec<u32>,
 }
 
+/// Information about a file object with extended attributes, hard links, or a large size in the
+/// filesystem
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct ExtFileInfo {
+    pub(crate) blocks_start: u64,
+    pub file_size: u64,
+    pub(crate) sparse: u64,
+    pub(crate) link_count: u32,
+    pub(crate) frag_index: u32,
+    pub(crate) block_offset: u32,
+    pub(crate) xattr_index: u32,
+    pub(crate) block_sizes: Vec<u32>,
+}
 
 /// Information about a symbolic link object in the filesystem
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct BasicSymlinkInfo {
-    link_count: u32,
+    pub(crate) link_count: u32,
     pub target_path: CString,
 }
 
+/// Information about a symbolic link object with extended attributes in the filesystem
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct ExtSymlinkInfo {
+    pub(crate) link_count: u32,
+    pub target_path: CString,
+    pub(crate) xattr_index: u32,
+}
+
 /// Information about a device object in the filesystem
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct BasicDevInfo {
-    link_count: u32,
+    pub(crate) link_count: u32,
+    pub dev_number: u32,
+}
+
+/// Information about a device object with extended attributes in the filesystem
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct ExtDevInfo {
+    pub(crate) link_count: u32,
     pub dev_number: u32,
+    pub(crate) xattr_index: u32,
 }
 
 /// Information about an IPC object in the filesystem
 #[allow(dead_code)]
 #[derive(Debug)]
 pub struct BasicIpcInfo {
-    link_count: u32,
+    pub(crate) link_count: u32,
+}
+
+/// Information about an IPC object with extended attributes in the filesystem
+#[allow(dead_code)]
+#[derive(Debug)]
+pub struct ExtIpcInfo {
+    pub(crate) link_count: u32,
+    pub(crate) xattr_index: u32,
+}
+
+/// Combine a device major and minor number into the encoding used by SquashFS device inodes
+pub fn encode_dev(major: u32, minor: u32) -> u32 {
+    (minor & 0xFF) | ((major & 0xFFF) << 8) | ((minor & !0xFF) << 12)
+}
+
+/// Split a SquashFS device inode number into its major and minor numbers
+pub fn decode_dev(dev: u32) -> (u32, u32) {
+    ((dev >> 8) & 0xFFF, (dev & 0xFF) | ((dev >> 12) & 0xFFF00))
+}
+
+// Read a symlink target of the given size. Targets are not NUL-terminated on disk.
+fn read_symlink_target<R: Read>(r: &mut R, limits: &Limits) -> io::Result<CString> {
+    let target_size = r.read_u32::<LittleEndian>()?;
+    limits.check_table(target_size.into(), 1)?;
+    let mut path_buf = Vec::new();
+    let read_size = r.take(target_size.into()).read_to_end(&mut path_buf)?;
+    if read_size as u64 != u64::from(target_size) {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+    CString::new(path_buf).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Symlink target contains a NUL byte"))
+}
+
+// Read the list of data block sizes that follows a file inode
+fn read_block_sizes<R: Read>(r: &mut R, file_size: u64, frag_index: u32, block_size: u32, limits: &Limits) -> io::Result<Vec<u32>> {
+    let num_blocks = if frag_index == u32::MAX {
+        file_size.div_ceil(block_size.into())
+    } else {
+        file_size / u64::from(block_size)
+    };
+    limits.check_table(num_blocks, 4)?;
+    // The file size comes from the image, so only trust it as far as a metadata block's worth
+    let max_prealloc = u64::from(METADATA_BLOCK_SIZE) / 4;
+    let mut block_sizes = Vec::with_capacity(min(num_blocks, max_prealloc) as usize);
+    for _ in 0..num_blocks {
+        block_sizes.push(r.read_u32::<LittleEndian>()?);
+    }
+    Ok(block_sizes)
 }
 
 impl Inode {
     #[allow(dead_code)]
-    pub(crate) fn read<R>(r: &mut R, block_size: u32) -> io::Result<Self>
+    pub(crate) fn read<R>(r: &mut R, block_size: u32, limits: &Limits) -> io::Result<Self>
     where R: Read
     {
-        let inode_type = InodeType::try_from(r.read_u16::<LittleEndian>()?).unwrap();
+        let inode_type = InodeType::try_from(r.read_u16::<LittleEndian>()?).unwrap_or(InodeType::Unknown);
         let permissions = r.read_u16::<LittleEndian>()?;
         let uid_index = r.read_u16::<LittleEndian>()?;
         let gid_index = r.read_u16::<LittleEndian>()?;
@@ -542,49 +790,68 @@ impl Inode {
                 let frag_index = r.read_u32::<LittleEndian>()?;
                 let block_offset = r.read_u32::<LittleEndian>()?;
                 let file_size = r.read_u32::<LittleEndian>()?;
-                let num_blocks = if frag_index == u32::MAX {
-                    div_ceil!(file_size, block_size)
-                } else {
-                    file_size / block_size
-                };
-                let mut block_sizes = Vec::with_capacity(usize::try_from(num_blocks).unwrap());
-                for _ in 0..num_blocks {
-                    block_sizes.push(r.read_u32::<LittleEndian>()?);
-                }
+                let block_sizes = read_block_sizes(r, file_size.into(), frag_index, block_size, limits)?;
 
                 InodeExtendedInfo::BasicFile( BasicFileInfo {
                     blocks_start, frag_index, block_offset, file_size, block_sizes
                 })
             },
-            InodeType::BasicSymlink => {
+            InodeType::ExtFile => {
+                let blocks_start = r.read_u64::<LittleEndian>()?;
+                let file_size = r.read_u64::<LittleEndian>()?;
+                let sparse = r.read_u64::<LittleEndian>()?;
                 let link_count = r.read_u32::<LittleEndian>()?;
-                let target_size = r.read_u32::<LittleEndian>()?;
+                let frag_index = r.read_u32::<LittleEndian>()?;
+                let block_offset = r.read_u32::<LittleEndian>()?;
+                let xattr_index = r.read_u32::<LittleEndian>()?;
+                let block_sizes = read_block_sizes(r, file_size, frag_index, block_size, limits)?;
 
-                let mut path_buf = Vec::with_capacity(usize::try_from(target_size).unwrap());
-                let read_size = r.take(target_size.into()).read_to_end(&mut path_buf)?;
-                if read_size != target_size.try_into().unwrap() {
-                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
-                }
+                InodeExtendedInfo::ExtFile( ExtFileInfo {
+                    blocks_start, file_size, sparse, link_count, frag_index, block_offset, xattr_index, block_sizes
+                })
+            },
+            InodeType::BasicSymlink => {
                 InodeExtendedInfo::BasicSymlink( BasicSymlinkInfo {
-                    link_count,
-                    target_path: CString::new(path_buf).unwrap(),
+                    link_count: r.read_u32::<LittleEndian>()?,
+                    target_path: read_symlink_target(r, limits)?,
                 })
             },
+            InodeType::ExtSymlink => {
+                InodeExtendedInfo::ExtSymlink( ExtSymlinkInfo {
+                    link_count: r.read_u32::<LittleEndian>()?,
+                    target_path: read_symlink_target(r, limits)?,
+                    xattr_index: r.read_u32::<LittleEndian>()?,
+                })
+            },
+            InodeType::BasicBlockDev |
             InodeType::BasicCharDev => {
                 InodeExtendedInfo::BasicDev( BasicDevInfo {
                     link_count : r.read_u32::<LittleEndian>()?,
                     dev_number : r.read_u32::<LittleEndian>()?,
                 })
-
             },
-            InodeType::BasicNamedPipe => {
+            InodeType::ExtBlockDev |
+            InodeType::ExtCharDev => {
+                InodeExtendedInfo::ExtDev( ExtDevInfo {
+                    link_count : r.read_u32::<LittleEndian>()?,
+                    dev_number : r.read_u32::<LittleEndian>()?,
+                    xattr_index : r.read_u32::<LittleEndian>()?,
+                })
+            },
+            InodeType::BasicNamedPipe |
+            InodeType::BasicSocked => {
                 InodeExtendedInfo::BasicIpc( BasicIpcInfo {
                     link_count : r.read_u32::<LittleEndian>()?,
                 })
-
             },
-            _ => InodeExtendedInfo::None,
-
+            InodeType::ExtNamedPipe |
+            InodeType::ExtSocked => {
+                InodeExtendedInfo::ExtIpc( ExtIpcInfo {
+                    link_count : r.read_u32::<LittleEndian>()?,
+                    xattr_index : r.read_u32::<LittleEndian>()?,
+                })
+            },
+            InodeType::Unknown => InodeExtendedInfo::None,
         };
 
         Ok(Self {
@@ -592,20 +859,103 @@ impl Inode {
         })
     }
 
+    /// Serialize this Inode in its on-disk format. The inode type must agree with the variant of
+    /// the extended info.
+    pub(crate) fn write<W>(&self, w: &mut W) -> io::Result<()>
+    where W: Write
+    {
+        w.write_u16::<LittleEndian>(self.inode_type.into())?;
+        w.write_u16::<LittleEndian>(self.permissions)?;
+        w.write_u16::<LittleEndian>(self.uid_index)?;
+        w.write_u16::<LittleEndian>(self.gid_index)?;
+        w.write_u32::<LittleEndian>(self.mtime)?;
+        w.write_u32::<LittleEndian>(self.inode_number)?;
+        match &self.extended_info {
+            InodeExtendedInfo::None => {},
+            InodeExtendedInfo::BasicDir(i) => {
+                w.write_u32::<LittleEndian>(i.block_index)?;
+                w.write_u32::<LittleEndian>(i.link_count)?;
+                w.write_u16::<LittleEndian>(i.file_size)?;
+                w.write_u16::<LittleEndian>(i.block_offset)?;
+                w.write_u32::<LittleEndian>(i.parent_inode)?;
+            },
+            InodeExtendedInfo::ExtDir(i) => {
+                w.write_u32::<LittleEndian>(i.link_count)?;
+                w.write_u32::<LittleEndian>(i.file_size)?;
+                w.write_u32::<LittleEndian>(i.block_index)?;
+                w.write_u32::<LittleEndian>(i.parent_inode)?;
+                w.write_u16::<LittleEndian>(i.index_count)?;
+                w.write_u16::<LittleEndian>(i.block_offset)?;
+                w.write_u32::<LittleEndian>(i.xattr_index)?;
+            },
+            InodeExtendedInfo::BasicFile(i) => {
+                w.write_u32::<LittleEndian>(i.blocks_start)?;
+                w.write_u32::<LittleEndian>(i.frag_index)?;
+                w.write_u32::<LittleEndian>(i.block_offset)?;
+                w.write_u32::<LittleEndian>(i.file_size)?;
+                for b in &i.block_sizes {
+                    w.write_u32::<LittleEndian>(*b)?;
+                }
+            },
+            InodeExtendedInfo::ExtFile(i) => {
+                w.write_u64::<LittleEndian>(i.blocks_start)?;
+                w.write_u64::<LittleEndian>(i.file_size)?;
+                w.write_u64::<LittleEndian>(i.sparse)?;
+                w.write_u32::<LittleEndian>(i.link_count)?;
+                w.write_u32::<LittleEndian>(i.frag_index)?;
+                w.write_u32::<LittleEndian>(i.block_offset)?;
+                w.write_u32::<LittleEndian>(i.xattr_index)?;
+                for b in &i.block_sizes {
+                    w.write_u32::<LittleEndian>(*b)?;
+                }
+            },
+            InodeExtendedInfo::BasicSymlink(i) => {
+                w.write_u32::<LittleEndian>(i.link_count)?;
+                w.write_u32::<LittleEndian>(i.target_path.as_bytes().len() as u32)?;
+                w.write_all(i.target_path.as_bytes())?;
+            },
+            InodeExtendedInfo::ExtSymlink(i) => {
+                w.write_u32::<LittleEndian>(i.link_count)?;
+                w.write_u32::<LittleEndian>(i.target_path.as_bytes().len() as u32)?;
+                w.write_all(i.target_path.as_bytes())?;
+                w.write_u32::<LittleEndian>(i.xattr_index)?;
+            },
+            InodeExtendedInfo::BasicDev(i) => {
+                w.write_u32::<LittleEndian>(i.link_count)?;
+                w.write_u32::<LittleEndian>(i.dev_number)?;
+            },
+            InodeExtendedInfo::ExtDev(i) => {
+                w.write_u32::<LittleEndian>(i.link_count)?;
+                w.write_u32::<LittleEndian>(i.dev_number)?;
+                w.write_u32::<LittleEndian>(i.xattr_index)?;
+            },
+            InodeExtendedInfo::BasicIpc(i) => {
+                w.write_u32::<LittleEndian>(i.link_count)?;
+            },
+            InodeExtendedInfo::ExtIpc(i) => {
+                w.write_u32::<LittleEndian>(i.link_count)?;
+                w.write_u32::<LittleEndian>(i.xattr_index)?;
+            },
+        }
+        Ok(())
+    }
+
     #[allow(dead_code)]
     pub(crate) fn read_at_ref<R>(r: &mut R, sb: &Superblock, inode_ref: EntryReference) -> io::Result<Self>
     where R: Read + Seek
     {
-        r.seek(SeekFrom::Start(sb.inode_table + inode_ref.location()))?;
+        let addr = sb.inode_table.checked_add(inode_ref.location())
+            .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Inode reference is out of range"))?;
+        r.seek(SeekFrom::Start(addr))?;
         //let mut reader = MetadataReader::new(r, sb.compressor);
         io::copy(&mut r.by_ref().take(inode_ref.offset().into()), &mut io::sink())?;
 
-        Self::read(r, sb.block_size)
+        Self::read(r, sb.block_size, &Limits::default())
     }
 
     #[allow(dead_code)]
-    pub(crate) fn from_bytes(buf: &mut [u8], block_size: u32) -> Self {
-        Self::read(&mut &buf[..], block_size).unwrap()
+    pub(crate) fn from_bytes(buf: &mut [u8], block_size: u32) -> io::Result<Self> {
+        Self::read(&mut &buf[..], block_size, &Limits::default())
     }
 
     pub fn is_file(&self) -> bool {
@@ -623,6 +973,26 @@ impl Inode {
         matches!(self.inode_type, InodeType::ExtSymlink)
     }
 
+    pub fn is_block_dev(&self) -> bool {
+        matches!(self.inode_type, InodeType::BasicBlockDev) ||
+        matches!(self.inode_type, InodeType::ExtBlockDev)
+    }
+
+    pub fn is_char_dev(&self) -> bool {
+        matches!(self.inode_type, InodeType::BasicCharDev) ||
+        matches!(self.inode_type, InodeType::ExtCharDev)
+    }
+
+    pub fn is_fifo(&self) -> bool {
+        matches!(self.inode_type, InodeType::BasicNamedPipe) ||
+        matches!(self.inode_type, InodeType::ExtNamedPipe)
+    }
+
+    pub fn is_socket(&self) -> bool {
+        matches!(self.inode_type, InodeType::BasicSocked) ||
+        matches!(self.inode_type, InodeType::ExtSocked)
+    }
+
     pub fn inode_number(&self) -> u32 {
         self.inode_number
     }
@@ -653,23 +1023,70 @@ impl Inode {
         mode
     }
 
-    pub fn uid<R:Read+Seek>(&self, sqfs: &super::SquashFS<R>) -> io::Result<u32> {
-        let id_table = &sqfs.id_table;
-        id_table.lu_table.entries.get(self.uid_index as usize).cloned()
-        .ok_or(io::Error::from(io::ErrorKind::NotFound))
+    pub fn uid<R:Read+Seek>(&self, sqfs: &super::SquashFS<R>) -> crate::Result<u32> {
+        Self::lookup_id(sqfs, self.uid_index)
+    }
+
+    pub fn gid<R:Read+Seek>(&self, sqfs: &super::SquashFS<R>) -> crate::Result<u32> {
+        Self::lookup_id(sqfs, self.gid_index)
+    }
+
+    fn lookup_id<R:Read+Seek>(sqfs: &super::SquashFS<R>, index: u16) -> crate::Result<u32> {
+        sqfs.id_table.lu_table.entries.get(usize::from(index)).cloned()
+            .ok_or(Error::CorruptMetadata { table: MetadataTable::Id, offset: sqfs.superblock().id_table })
+    }
+
+    /// The extended attributes of this Inode as (full name, value) pairs, such as
+    /// ("user.comment", "hello")
+    pub fn xattrs<R:Read+Seek>(&self, sqfs: &super::SquashFS<R>) -> crate::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let offset = sqfs.superblock().xattr_table;
+        match (self.xattr_index(), &sqfs.xattr_table) {
+            (Some(index), Some(table)) => table.read_xattrs(&sqfs.md_provider, index, sqfs.limits())
+                .map_err(Error::in_metadata(MetadataTable::Xattr, offset)),
+            (Some(_), None) => Err(Error::CorruptMetadata { table: MetadataTable::Inode, offset: sqfs.superblock().inode_table }),
+            (None, _) => Ok(Vec::new()),
+        }
+    }
+
+    /// The index of this Inode's set of extended attributes in the xattr table, if it has any
+    pub(crate) fn xattr_index(&self) -> Option<u32> {
+        let index = match &self.extended_info {
+            InodeExtendedInfo::ExtDir(i) => i.xattr_index,
+            InodeExtendedInfo::ExtFile(i) => i.xattr_index,
+            InodeExtendedInfo::ExtSymlink(i) => i.xattr_index,
+            InodeExtendedInfo::ExtDev(i) => i.xattr_index,
+            InodeExtendedInfo::ExtIpc(i) => i.xattr_index,
+            _ => u32::MAX,
+        };
+        (index != u32::MAX).then_some(index)
+    }
+
+    /// The disk location of a regular file's first data block and the sizes of all of its data
+    /// blocks, not including any tail-end stored in a fragment
+    pub(crate) fn data_blocks(&self) -> Option<(u64, &[u32])> {
+        match &self.extended_info {
+            InodeExtendedInfo::BasicFile(i) => Some((i.blocks_start.into(), &i.block_sizes)),
+            InodeExtendedInfo::ExtFile(i) => Some((i.blocks_start, &i.block_sizes)),
+            _ => None,
+        }
     }
 
-    pub fn gid<R:Read+Seek>(&self, sqfs: &super::SquashFS<R>) -> io::Result<u32> {
-        let id_table = &sqfs.id_table;
-        id_table.lu_table.entries.get(self.gid_index as usize).cloned()
-        .ok_or(io::Error::from(io::ErrorKind::NotFound))
+    /// The fragment index and the offset within the fragment block of a regular file's tail-end
+    pub(crate) fn fragment(&self) -> Option<(u32, u32)> {
+        let (frag_index, block_offset) = match &self.extended_info {
+            InodeExtendedInfo::BasicFile(i) => (i.frag_index, i.block_offset),
+            InodeExtendedInfo::ExtFile(i) => (i.frag_index, i.block_offset),
+            _ => return None,
+        };
+        (frag_index != u32::MAX).then_some((frag_index, block_offset))
     }
 
-    pub fn file_size(&self) -> Option<u32> {
+    pub fn file_size(&self) -> Option<u64> {
         match &self.extended_info {
-            InodeExtendedInfo::BasicFile(i) => Some(i.file_size),
-            InodeExtendedInfo::BasicDir(i) => Some(i.file_size as u32),
-            InodeExtendedInfo::ExtDir(i) => Some(i.file_size),
+            InodeExtendedInfo::BasicFile(i) => Some(i.file_size.into()),
+            InodeExtendedInfo::ExtFile(i) => Some(i.file_size),
+            InodeExtendedInfo::BasicDir(i) => Some(i.file_size.into()),
+            InodeExtendedInfo::ExtDir(i) => Some(i.file_size.into()),
             _ => None,
         }
     }
@@ -677,6 +1094,43 @@ impl Inode {
     pub fn mtime(&self) -> u32 {
         self.mtime
     }
+
+    /// The number of directory entries that refer to this Inode. For directories, this also
+    /// counts the '.' entry and the '..' entry of each subdirectory.
+    pub fn link_count(&self) -> u32 {
+        match &self.extended_info {
+            InodeExtendedInfo::None => 1,
+            InodeExtendedInfo::BasicDir(i) => i.link_count,
+            InodeExtendedInfo::ExtDir(i) => i.link_count,
+            InodeExtendedInfo::BasicFile(_) => 1,
+            InodeExtendedInfo::ExtFile(i) => i.link_count,
+            InodeExtendedInfo::BasicSymlink(i) => i.link_count,
+            InodeExtendedInfo::ExtSymlink(i) => i.link_count,
+            InodeExtendedInfo::BasicDev(i) => i.link_count,
+            InodeExtendedInfo::ExtDev(i) => i.link_count,
+            InodeExtendedInfo::BasicIpc(i) => i.link_count,
+            InodeExtendedInfo::ExtIpc(i) => i.link_count,
+        }
+    }
+
+    /// The target of a symbolic link, or None if this Inode is not a symbolic link
+    pub fn symlink_target(&self) -> Option<&CStr> {
+        match &self.extended_info {
+            InodeExtendedInfo::BasicSymlink(i) => Some(&i.target_path),
+            InodeExtendedInfo::ExtSymlink(i) => Some(&i.target_path),
+            _ => None,
+        }
+    }
+
+    /// The raw device number of a block or character device, or None for other Inode types.
+    /// See [`decode_dev`] to split it into major and minor numbers.
+    pub fn dev_number(&self) -> Option<u32> {
+        match &self.extended_info {
+            InodeExtendedInfo::BasicDev(i) => Some(i.dev_number),
+            InodeExtendedInfo::ExtDev(i) => Some(i.dev_number),
+            _ => None,
+        }
+    }
 }
 
 #[doc(hidden)]
@@ -686,6 +1140,9 @@ pub struct DirTable {
     pub start: u32,
     pub inode_number: u32,
     pub entries: Vec<DirEntry>,
+    /// The names of entries that were left out because they could name something outside of the
+    /// directory
+    pub invalid_names: Vec<Vec<u8>>,
 }
 
 #[doc(hidden)]
@@ -694,7 +1151,7 @@ pub struct DirTable {
 pub struct DirEntry {
     pub(crate) offset: u16,
     pub(crate) inode_offset: i16,
-    inode_type: InodeType,
+    pub(crate) inode_type: InodeType,
     pub(crate) name: CString,
 }
 
@@ -702,55 +1159,143 @@ impl DirTable {
     pub(crate) fn load<R>(r: &mut R) -> io::Result<Self>
     where R: Read
     {
-        let count = r.read_u32::<LittleEndian>()? + 1;
+        let count = r.read_u32::<LittleEndian>()?;
+        if count as usize >= MAX_DIR_HEADER_ENTRIES {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Too many entries in a directory header"));
+        }
+        let count = count + 1;
         let start = r.read_u32::<LittleEndian>()?;
         let inode_number = r.read_u32::<LittleEndian>()?;
-        let mut entries = Vec::with_capacity(count.try_into().unwrap());
+        let mut entries = Vec::with_capacity(count as usize);
+        let mut invalid_names = Vec::new();
         for _ in 0..count {
             let offset = r.read_u16::<LittleEndian>()?;
             let inode_offset = r.read_i16::<LittleEndian>()?;
-            let inode_type = InodeType::try_from(r.read_u16::<LittleEndian>()?).unwrap();
-            let name_size = r.read_u16::<LittleEndian>()? + 1;
-            let mut name_buf = Vec::with_capacity(name_size.into());
-            let read_size = r.take(name_size.into()).read_to_end(&mut name_buf)?;
-            if read_size != name_size.into() {
+            let inode_type = InodeType::try_from(r.read_u16::<LittleEndian>()?).unwrap_or(InodeType::Unknown);
+            let name_size = usize::from(r.read_u16::<LittleEndian>()?) + 1;
+            if name_size > MAX_NAME_LEN {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Directory entry name is too long"));
+            }
+            let mut name_buf = Vec::with_capacity(name_size);
+            let read_size = r.take(name_size as u64).read_to_end(&mut name_buf)?;
+            if read_size != name_size {
                 return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
             }
-            entries.push(DirEntry {
-                offset, inode_offset, inode_type,
-                name: CString::new(name_buf).unwrap(),
-            });
+            match check_name(name_buf) {
+                Ok(name) => entries.push(DirEntry { offset, inode_offset, inode_type, name }),
+                Err(name) => invalid_names.push(name),
+            }
         }
         Ok(DirTable {
-            count, start, inode_number, entries
+            count, start, inode_number, entries, invalid_names
         })
     }
 
+    /// Serialize this directory header and its entries in their on-disk format
+    pub(crate) fn write<W>(&self, w: &mut W) -> io::Result<()>
+    where W: Write
+    {
+        w.write_u32::<LittleEndian>(self.count - 1)?;
+        w.write_u32::<LittleEndian>(self.start)?;
+        w.write_u32::<LittleEndian>(self.inode_number)?;
+        for e in &self.entries {
+            w.write_u16::<LittleEndian>(e.offset)?;
+            w.write_i16::<LittleEndian>(e.inode_offset)?;
+            w.write_u16::<LittleEndian>(e.inode_type.into())?;
+            w.write_u16::<LittleEndian>((e.name.as_bytes().len() - 1) as u16)?;
+            w.write_all(e.name.as_bytes())?;
+        }
+        Ok(())
+    }
+
     pub(crate) fn entryref_from_inode(inode: &Inode) -> io::Result<EntryReference> {
         let (block_addr, block_offset) = match &inode.extended_info {
             InodeExtendedInfo::BasicDir(d) => (d.block_index, d.block_offset),
             InodeExtendedInfo::ExtDir(d) => (d.block_index, d.block_offset),
-            _ => {return Err(io::Error::new(io::ErrorKind::InvalidInput, "Inode is not a directory"))},
+            _ => {return Err(Error::NotADirectory(PathBuf::new()).into())},
         };
         Ok(EntryReference::new(block_addr.into(), block_offset))
     }
 
-    pub(crate) fn read_for_inode<R>(r: &mut MetadataReader<R>, inode: &Inode) -> io::Result<Vec<Self>>
+    pub(crate) fn read_for_inode<R>(r: &mut MetadataReader<R>, inode: &Inode, limits: &Limits) -> io::Result<Vec<Self>>
     where R: Read + Seek
     {
         let (block_index, block_offset, file_size) = match &inode.extended_info {
             InodeExtendedInfo::BasicDir(d) => (d.block_index, d.block_offset, d.file_size.into()),
             InodeExtendedInfo::ExtDir(d) => (d.block_index, d.block_offset, d.file_size),
-            _ => {return Err(io::Error::new(io::ErrorKind::InvalidInput, "Inode is not a directory"))},
+            _ => {return Err(Error::NotADirectory(PathBuf::new()).into())},
         };
 
+        // The size includes 3 bytes for the '.' and '..' entries, which are not stored
+        let size = file_size.checked_sub(3)
+            .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Invalid directory size"))?;
         r.seek_ref(EntryReference::new(block_index.into(), block_offset))?;
-        let mut reader = r.take((file_size-3).into());
+        let mut reader = r.take(size.into());
 
         let mut tables = Vec::new();
+        let mut entry_count = 0;
         while reader.limit() > 0 {
-            tables.push(Self::load(&mut reader)?);
+            let table = Self::load(&mut reader)?;
+            entry_count += table.entries.len() + table.invalid_names.len();
+            if entry_count > limits.max_dir_entries as usize {
+                return Err(Error::LimitExceeded(Limit::DirEntries).into());
+            }
+            tables.push(table);
         }
         Ok(tables)
     }
 }
+
+/// Check that a directory entry's name can only name an entry within its directory, so that paths
+/// built from names can never reach outside of the image. Returns the name back if it is invalid.
+fn check_name(name: Vec<u8>) -> std::result::Result<CString, Vec<u8>> {
+    if name == b"." || name == b".." || name.contains(&b'/') {
+        return Err(name);
+    }
+    CString::new(name).map_err(|e| e.into_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A directory header with one entry, whose count is stored as given (one less than the number
+    // of entries)
+    fn dir_header(count: u32, name: &[u8]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for v in [count, 0, 1] {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        buf.extend_from_slice(&[0, 0, 0, 0, 2, 0]);
+        buf.extend_from_slice(&(name.len() as u16 - 1).to_le_bytes());
+        buf.extend_from_slice(name);
+        buf
+    }
+
+    #[test]
+    fn test_dir_table_limits() -> io::Result<()> {
+        let table = DirTable::load(&mut &dir_header(0, b"file")[..])?;
+        assert_eq!(table.entries[0].name.as_bytes(), b"file");
+
+        let err = DirTable::load(&mut &dir_header(256, b"file")[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = DirTable::load(&mut &dir_header(u32::MAX, b"file")[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = DirTable::load(&mut &dir_header(0, &[b'x'; MAX_NAME_LEN + 1])[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dir_entry_names() -> io::Result<()> {
+        for name in [&b"..."[..], b".hidden", b"a..b", b"\\", b"caf\xe9"] {
+            assert_eq!(DirTable::load(&mut &dir_header(0, name)[..])?.entries[0].name.as_bytes(), name);
+        }
+        for name in [&b"."[..], b"..", b"/", b"../etc", b"a/b", b"nul\0"] {
+            let table = DirTable::load(&mut &dir_header(0, name)[..])?;
+            assert!(table.entries.is_empty());
+            assert_eq!(table.invalid_names, [name]);
+        }
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
 mod block;
 mod compressed;
+#[allow(clippy::module_inception)]
 mod squashfs;
 mod filedata;
 mod readermux;
@@ -7,5 +8,30 @@ mod superblock;
 
 pub mod metadata;
 pub mod path;
+pub mod writer;
+#[cfg(feature = "tar")]
+pub mod tar;
+pub mod cpio;
+pub mod transcode;
+pub mod subtree;
+pub mod patch;
+pub mod pseudo;
+pub mod merge;
+pub mod union;
+pub mod nested;
+pub mod embedded;
+pub mod firmware;
+pub mod partition;
+pub mod limits;
+pub mod check;
+pub mod salvage;
+pub mod walk;
+#[cfg(test)]
+pub(crate) mod test_util;
+#[cfg(feature = "verity")]
+pub mod verity;
 pub use squashfs::*;
-pub use metadata::Inode;
\ No newline at end of file
+pub use metadata::Inode;
+pub use superblock::Compressor;
+pub use union::UnionFS;
+pub use limits::{Limit, Limits};
\ No newline at end of file
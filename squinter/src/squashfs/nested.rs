@@ -0,0 +1,106 @@
+//! Images stored as files inside other images
+//!
+//! Any file in an image can be opened as an image itself with [`SquashFS::open_nested`]. The
+//! nested image reads through the outer one, and remains usable after the outer SquashFS is
+//! dropped.
+//!
+//! Each level of nesting wraps the reader in another layer, which changes the type of the nested
+//! SquashFS. [`open`] erases the reader type, so that images nested to any depth can be handled by
+//! the same code. It accepts paths in which [`SEPARATOR`] separates an image from the path of an
+//! image stored inside it, such as `outer.sqfs::/images/app.sqfs`. At every level, the image is
+//! found with [`embedded::locate`], so any of them may be an AppImage. The outermost image may
+//! also be a partition of a disk image, given with [`partition::SEPARATOR`] as in
+//! `disk.img@rootfs::/images/app.sqfs`.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek};
+use std::path::Path;
+
+use super::embedded::{self, OffsetReader};
+use super::{partition, SquashFS};
+
+/// Separates an image from the path of an image stored within it
+pub const SEPARATOR: &str = "::";
+
+/// A reader that can be used for an image at any depth of nesting
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Open an image from the filesystem, descending into nested images with the `::` syntax. For
+/// example, `outer.sqfs::/images/app.sqfs` opens `/images/app.sqfs` from within `outer.sqfs`. A
+/// path that names an existing file is always opened as-is.
+pub fn open<P>(path: P) -> crate::Result<SquashFS<Box<dyn ReadSeek>>>
+where P: AsRef<Path>
+{
+    let path = path.as_ref();
+    let (file, nested) = match path.to_str() {
+        Some(s) if !path.exists() => {
+            let mut parts = s.split(SEPARATOR);
+            (Path::new(parts.next().unwrap()), parts.collect())
+        },
+        _ => (path, Vec::new()),
+    };
+    let mut sqfs = SquashFS::new(open_file(file)?)?;
+    for p in nested {
+        sqfs = open_nested(&mut sqfs, p)?;
+    }
+    Ok(sqfs)
+}
+
+/// Open the outermost image file, which may be a partition of a disk image given as
+/// `disk.img@p3` or `disk.img@rootfs`
+fn open_file(file: &Path) -> io::Result<Box<dyn ReadSeek>> {
+    let disk = file.to_str()
+        .filter(|_| !file.exists())
+        .and_then(|s| s.rsplit_once(partition::SEPARATOR));
+    if let Some((disk, spec)) = disk {
+        let mut r = BufReader::new(File::open(disk)?);
+        let offset = partition::find(&mut r, spec)?.offset;
+        return Ok(Box::new(embedded::locate(OffsetReader::new(r, offset)?)?));
+    }
+    Ok(Box::new(embedded::locate(BufReader::new(File::open(file)?))?))
+}
+
+/// Open an image that is stored as a file within another, erasing the type of its reader. It is
+/// read with the same Limits as the other image.
+pub fn open_nested<R, P>(sqfs: &mut SquashFS<R>, path: P) -> crate::Result<SquashFS<Box<dyn ReadSeek>>>
+where R: Read + Seek + 'static,
+      P: AsRef<Path>,
+{
+    SquashFS::new_with_limits(Box::new(embedded::locate(sqfs.open_file(path)?)?), *sqfs.limits())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::squashfs::Limits;
+    use crate::squashfs::test_util::build;
+    use crate::squashfs::writer::Attributes;
+
+    #[test]
+    fn test_nested() -> io::Result<()> {
+        let a = |mode| Attributes::new(mode, 0, 0, 0);
+        let config: Vec<u8> = (0..20000u32).flat_map(|i| i.to_le_bytes()).collect();
+        let app = build(4096, |w| w.add_file("/etc/config", a(0o644), &config[..]))?;
+        let images = build(8192, |w| w.add_file("/images/app.sqfs", a(0o644), &app[..]))?;
+        let outer = build(4096, |w| {
+            w.add_file("/images.sqfs", a(0o644), &images[..])?;
+            w.add_dir("/dir", a(0o755))
+        })?;
+
+        let limits = Limits { max_path_depth: 8, ..Default::default() };
+        let mut sqfs = SquashFS::new_with_limits(Cursor::new(outer), limits)?;
+        let mut middle = sqfs.open_nested("/images.sqfs")?;
+        assert_eq!(middle.open_nested("/images").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        let mut inner = open_nested(&mut middle, "/images/app.sqfs")?;
+        assert_eq!(inner.limits().max_path_depth, 8);
+        drop(middle);
+        drop(sqfs);
+        let mut data = Vec::new();
+        inner.open_file("/etc/config")?.read_to_end(&mut data)?;
+        assert_eq!(data, config);
+        Ok(())
+    }
+}
@@ -0,0 +1,329 @@
+//! Partitions of disk images
+//!
+//! [`list`] reads the partition table of a disk image, which may be either GPT or MBR, and checks
+//! each partition for a SquashFS superblock. Partitions are numbered in the same way as Linux
+//! numbers them, so that partition 3 of `disk.img` is the one that Linux would call `p3`:
+//! - GPT partitions are numbered by their position in the partition entry array, starting at 1
+//! - MBR primary partitions are numbered 1 to 4, and logical partitions within an extended
+//!   partition are numbered from 5
+//!
+//! [`open`] opens the image in a partition chosen by number, label or GUID.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::embedded::OffsetReader;
+use super::superblock::MAGIC;
+use super::SquashFS;
+
+/// Separates a disk image from the partition within it, as in `disk.img@p3` or `disk.img@rootfs`
+pub const SEPARATOR: char = '@';
+
+const SECTOR_SIZE: u64 = 512;
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+const MBR_SIGNATURE: u16 = 0xAA55;
+const MBR_PROTECTIVE: u8 = 0xEE;
+const MBR_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+// Bounds for corrupt tables. GPT disks normally have 128 entries, and Linux supports up to 256
+// partitions on a disk.
+const MAX_GPT_ENTRIES: u32 = 1024;
+const MAX_GPT_ENTRY_SIZE: u32 = 4096;
+const MAX_LOGICAL_PARTITIONS: u32 = 256;
+
+/// The kind of partition table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TableKind {
+    Gpt,
+    Mbr,
+}
+
+/// A partition in a disk image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Partition {
+    /// The partition number, starting at 1
+    pub number: u32,
+    /// The offset of the partition within the disk image, in bytes
+    pub offset: u64,
+    /// The size of the partition, in bytes
+    pub size: u64,
+    /// The partition label. MBR partitions do not have labels.
+    pub label: Option<String>,
+    /// The unique partition GUID, in its usual lowercase text form. MBR partitions do not have
+    /// GUIDs.
+    pub guid: Option<String>,
+    /// The partition type, as a GUID for GPT partitions or a type byte for MBR partitions
+    pub partition_type: String,
+    /// Whether the partition starts with a SquashFS superblock
+    pub squashfs: bool,
+}
+
+impl Partition {
+    /// Whether the partition is chosen by `spec`, which is either its number as `pN`, its GUID,
+    /// or its label
+    pub fn matches(&self, spec: &str) -> bool {
+        if let Some(n) = spec.strip_prefix('p').and_then(|n| n.parse::<u32>().ok()) {
+            return n == self.number;
+        }
+        self.guid.as_deref().is_some_and(|g| g.eq_ignore_ascii_case(spec)) ||
+        self.label.as_deref() == Some(spec)
+    }
+}
+
+/// Read the partition table of a disk image
+pub fn list<R: Read + Seek>(r: &mut R) -> io::Result<(TableKind, Vec<Partition>)> {
+    let mut mbr = [0u8; 512];
+    r.seek(SeekFrom::Start(0))?;
+    r.read_exact(&mut mbr)?;
+    if LittleEndian::read_u16(&mbr[510..]) != MBR_SIGNATURE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "No partition table found"));
+    }
+
+    let (kind, mut partitions) = if mbr_entries(&mbr).any(|(_, t, _, _)| t == MBR_PROTECTIVE) {
+        (TableKind::Gpt, read_gpt(r)?)
+    } else {
+        (TableKind::Mbr, read_mbr(r, &mbr)?)
+    };
+    for p in &mut partitions {
+        let mut magic = [0u8; 4];
+        r.seek(SeekFrom::Start(p.offset))?;
+        p.squashfs = match r.read_exact(&mut magic) {
+            Ok(()) => LittleEndian::read_u32(&magic) == MAGIC,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => false,
+            Err(e) => return Err(e),
+        };
+    }
+    Ok((kind, partitions))
+}
+
+/// Find the partition chosen by `spec`, as described in [`Partition::matches`]
+pub fn find<R: Read + Seek>(r: &mut R, spec: &str) -> io::Result<Partition> {
+    let (_, partitions) = list(r)?;
+    partitions.into_iter().find(|p| p.matches(spec))
+        .ok_or(io::Error::new(io::ErrorKind::NotFound, format!("No partition matches '{spec}'")))
+}
+
+/// Open the image in the partition chosen by `spec`, as described in [`Partition::matches`]
+pub fn open<R: Read + Seek>(mut r: R, spec: &str) -> crate::Result<SquashFS<OffsetReader<R>>> {
+    let offset = find(&mut r, spec)?.offset;
+    SquashFS::new_at_offset(r, offset)
+}
+
+/// The position, type, first sector, and sector count of each used entry in an MBR or EBR
+fn mbr_entries(sector: &[u8]) -> impl Iterator<Item = (u32, u8, u64, u64)> + '_ {
+    sector[446..510].chunks(16).zip(1..)
+        .map(|(e, i)| (i, e[4], LittleEndian::read_u32(&e[8..]).into(), LittleEndian::read_u32(&e[12..]).into()))
+        .filter(|&(_, t, _, count)| t != 0 && count != 0)
+}
+
+fn read_mbr<R: Read + Seek>(r: &mut R, mbr: &[u8]) -> io::Result<Vec<Partition>> {
+    let mut partitions = Vec::new();
+    let mut extended = None;
+    for (number, t, start, count) in mbr_entries(mbr) {
+        if MBR_EXTENDED.contains(&t) {
+            extended = Some(start);
+        }
+        partitions.push(mbr_partition(number, t, start, count));
+    }
+
+    // Logical partitions are described by a chain of EBRs. The partition in each EBR starts
+    // relative to that EBR, and the link to the next EBR is relative to the extended partition.
+    if let Some(extended_start) = extended {
+        let mut ebr_start = extended_start;
+        let mut ebr = [0u8; 512];
+        for number in 5..5 + MAX_LOGICAL_PARTITIONS {
+            r.seek(SeekFrom::Start(ebr_start * SECTOR_SIZE))?;
+            r.read_exact(&mut ebr)?;
+            if LittleEndian::read_u16(&ebr[510..]) != MBR_SIGNATURE {
+                break;
+            }
+            let mut next = None;
+            for (_, t, start, count) in mbr_entries(&ebr) {
+                if MBR_EXTENDED.contains(&t) {
+                    next.get_or_insert(extended_start + start);
+                } else {
+                    partitions.push(mbr_partition(number, t, ebr_start + start, count));
+                }
+            }
+            match next {
+                Some(n) if n > ebr_start => ebr_start = n,
+                _ => break,
+            }
+        }
+    }
+    Ok(partitions)
+}
+
+fn mbr_partition(number: u32, partition_type: u8, start: u64, count: u64) -> Partition {
+    Partition {
+        number,
+        offset: start * SECTOR_SIZE,
+        size: count * SECTOR_SIZE,
+        label: None,
+        guid: None,
+        partition_type: format!("{partition_type:#04x}"),
+        squashfs: false,
+    }
+}
+
+fn read_gpt<R: Read + Seek>(r: &mut R) -> io::Result<Vec<Partition>> {
+    // The GPT header is in the second logical block, which is usually 512 bytes long, but may
+    // also be 4096 bytes
+    let mut header = [0u8; 92];
+    let mut block_size = None;
+    for size in [SECTOR_SIZE, 4096] {
+        r.seek(SeekFrom::Start(size))?;
+        if r.read_exact(&mut header).is_ok() && &header[..8] == GPT_SIGNATURE {
+            block_size = Some(size);
+            break;
+        }
+    }
+    let block_size = block_size
+        .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Missing GPT header"))?;
+
+    let entries_start = LittleEndian::read_u64(&header[72..]);
+    let entry_count = LittleEndian::read_u32(&header[80..]);
+    let entry_size = LittleEndian::read_u32(&header[84..]);
+    if entry_count > MAX_GPT_ENTRIES || !(128..=MAX_GPT_ENTRY_SIZE).contains(&entry_size) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid GPT partition entry array"));
+    }
+
+    let mut partitions = Vec::new();
+    let mut entry = vec![0u8; entry_size as usize];
+    r.seek(SeekFrom::Start(entries_start.saturating_mul(block_size)))?;
+    for i in 0..entry_count {
+        r.read_exact(&mut entry)?;
+        if entry[..16].iter().all(|b| *b == 0) {
+            continue;
+        }
+        let first = LittleEndian::read_u64(&entry[32..]);
+        let last = LittleEndian::read_u64(&entry[40..]);
+        if last < first {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid GPT partition bounds"));
+        }
+        let name: Vec<u16> = entry[56..128].chunks(2)
+            .map(LittleEndian::read_u16)
+            .take_while(|c| *c != 0)
+            .collect();
+        partitions.push(Partition {
+            number: i + 1,
+            offset: first.saturating_mul(block_size),
+            size: (last - first + 1).saturating_mul(block_size),
+            label: Some(String::from_utf16_lossy(&name)),
+            guid: Some(format_guid(&entry[16..32])),
+            partition_type: format_guid(&entry[..16]),
+            squashfs: false,
+        });
+    }
+    Ok(partitions)
+}
+
+/// Format a GUID as text. The first three fields are stored little-endian.
+fn format_guid(b: &[u8]) -> String {
+    format!("{:08x}-{:04x}-{:04x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+        LittleEndian::read_u32(b), LittleEndian::read_u16(&b[4..]), LittleEndian::read_u16(&b[6..]),
+        b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::squashfs::test_util::build;
+    use crate::squashfs::writer::Attributes;
+
+    fn image(contents: &str) -> io::Result<Vec<u8>> {
+        build(128 * 1024, |w| w.add_file("/etc/version", Attributes::new(0o644, 0, 0, 0), contents.as_bytes()))
+    }
+
+    fn version<R: Read + Seek>(sqfs: &mut SquashFS<R>) -> io::Result<String> {
+        let mut data = String::new();
+        sqfs.open_file("/etc/version")?.read_to_string(&mut data)?;
+        Ok(data)
+    }
+
+    fn mbr_entry(sector: &mut [u8], index: usize, partition_type: u8, start: u32, count: u32) {
+        let e = &mut sector[446 + 16 * index..];
+        e[4] = partition_type;
+        LittleEndian::write_u32(&mut e[8..], start);
+        LittleEndian::write_u32(&mut e[12..], count);
+        LittleEndian::write_u16(&mut sector[510..], MBR_SIGNATURE);
+    }
+
+    fn place(disk: &mut Vec<u8>, sector: u64, data: &[u8]) {
+        let offset = (sector * SECTOR_SIZE) as usize;
+        if disk.len() < offset + data.len() {
+            disk.resize(offset + data.len(), 0);
+        }
+        disk[offset..offset + data.len()].copy_from_slice(data);
+    }
+
+    #[test]
+    fn test_gpt() -> io::Result<()> {
+        let mut disk = vec![0u8; 34 * 512];
+        mbr_entry(&mut disk, 0, MBR_PROTECTIVE, 1, u32::MAX);
+        let header = &mut disk[512..];
+        header[..8].copy_from_slice(GPT_SIGNATURE);
+        LittleEndian::write_u64(&mut header[72..], 2);
+        LittleEndian::write_u32(&mut header[80..], 128);
+        LittleEndian::write_u32(&mut header[84..], 128);
+
+        let guid = [0x78, 0x56, 0x34, 0x12, 0xbc, 0x9a, 0xf0, 0xde, 1, 2, 3, 4, 5, 6, 7, 8];
+        // Entries 1 and 3 are used, and the image is in partition 3
+        for (index, first, last, name) in [(0, 40, 47, "boot"), (2, 48, 200, "rootfs")] {
+            let entry = &mut disk[1024 + 128 * index..];
+            entry[..16].copy_from_slice(&[0xaf; 16]);
+            entry[16..32].copy_from_slice(&guid);
+            entry[16] = index as u8;
+            LittleEndian::write_u64(&mut entry[32..], first);
+            LittleEndian::write_u64(&mut entry[40..], last);
+            for (i, c) in name.encode_utf16().enumerate() {
+                LittleEndian::write_u16(&mut entry[56 + 2 * i..], c);
+            }
+        }
+        place(&mut disk, 48, &image("gpt")?);
+        disk.resize(201 * 512, 0);
+
+        let (kind, partitions) = list(&mut Cursor::new(&disk))?;
+        assert_eq!(kind, TableKind::Gpt);
+        assert_eq!(partitions.iter().map(|p| (p.number, p.offset, p.size, p.squashfs)).collect::<Vec<_>>(),
+            [(1, 40 * 512, 8 * 512, false), (3, 48 * 512, 153 * 512, true)]);
+        assert_eq!(partitions[1].label.as_deref(), Some("rootfs"));
+        assert_eq!(partitions[1].guid.as_deref(), Some("12345602-9abc-def0-0102-030405060708"));
+
+        for spec in ["p3", "rootfs", "12345602-9ABC-DEF0-0102-030405060708"] {
+            assert_eq!(version(&mut open(Cursor::new(&disk), spec)?)?, "gpt");
+        }
+        assert_eq!(open(Cursor::new(&disk), "p2").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(open(Cursor::new(&disk), "boot").unwrap_err().kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+
+    #[test]
+    fn test_mbr() -> io::Result<()> {
+        // A primary partition, and an extended partition holding two logical partitions
+        let mut disk = vec![0u8; 512];
+        mbr_entry(&mut disk, 0, 0x83, 8, 8);
+        mbr_entry(&mut disk, 1, 0x0f, 16, 400);
+        let mut ebr = [0u8; 512];
+        mbr_entry(&mut ebr, 0, 0x83, 4, 8);
+        mbr_entry(&mut ebr, 1, 0x05, 100, 300);
+        place(&mut disk, 16, &ebr);
+        let mut ebr = [0u8; 512];
+        mbr_entry(&mut ebr, 0, 0x83, 8, 200);
+        place(&mut disk, 116, &ebr);
+        place(&mut disk, 124, &image("logical")?);
+        place(&mut disk, 8, &image("primary")?[..512]);
+        disk.resize(416 * 512, 0);
+
+        let (kind, partitions) = list(&mut Cursor::new(&disk))?;
+        assert_eq!(kind, TableKind::Mbr);
+        assert_eq!(partitions.iter().map(|p| (p.number, p.offset / 512, p.size / 512, p.squashfs)).collect::<Vec<_>>(),
+            [(1, 8, 8, true), (2, 16, 400, false), (5, 20, 8, false), (6, 124, 200, true)]);
+        assert_eq!(partitions[0].partition_type, "0x83");
+        assert_eq!(version(&mut open(Cursor::new(&disk), "p6")?)?, "logical");
+
+        assert_eq!(list(&mut Cursor::new(vec![0u8; 1024])).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        Ok(())
+    }
+}
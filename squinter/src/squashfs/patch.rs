@@ -0,0 +1,152 @@
+//! Modification of attributes in existing images
+//!
+//! An [`ImagePatcher`] changes the ownership, permissions, modification times, and extended
+//! attributes of entries in an existing image. Every data block and fragment block is left
+//! untouched, but since changing an inode can change its size and move every inode and directory
+//! entry after it, all of the metadata tables are written anew: the inode, directory, fragment,
+//! export, id, and xattr tables go after the end of the image through
+//! [`SquashFSWriter::append`], and the superblock is replaced last. The old tables are left behind
+//! as unused space, so the image grows by the size of its metadata with every patch.
+//!
+//! ```no_run
+//! use std::fs::OpenOptions;
+//! use std::io;
+//! use squinter::squashfs::patch::ImagePatcher;
+//!
+//! fn fix_ownership() -> io::Result<()> {
+//!     let image = OpenOptions::new().read(true).write(true).open("rootfs.squashfs")?;
+//!     let mut patcher = ImagePatcher::open(image)?;
+//!     patcher.chown("/home/user", 1000, 1000)?;
+//!     patcher.chmod("/etc/shadow", 0o600)?;
+//!     patcher.finish()?;
+//!     Ok(())
+//! }
+//! ```
+
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+
+use super::metadata;
+use super::writer::{Attributes, SquashFSWriter};
+
+/// Modifies the attributes of entries in an existing image. Paths refer to the entries
+/// themselves, so symbolic links are not followed.
+#[derive(Debug)]
+pub struct ImagePatcher<W: Read + Write + Seek> {
+    writer: SquashFSWriter<W>,
+}
+
+impl<W: Read + Write + Seek> ImagePatcher<W> {
+    /// Open an existing image for patching. Nothing is written until [`finish`](Self::finish) is
+    /// called.
+    pub fn open(w: W) -> io::Result<Self> {
+        Ok(Self { writer: SquashFSWriter::append(w)? })
+    }
+
+    /// Set the owning user and group of the entry at `path`
+    pub fn chown<P: AsRef<Path>>(&mut self, path: P, uid: u32, gid: u32) -> io::Result<()> {
+        let attrs = self.attributes_mut(path.as_ref())?;
+        attrs.uid = uid;
+        attrs.gid = gid;
+        Ok(())
+    }
+
+    /// Set the permission bits of the entry at `path`, including the setuid, setgid, and sticky
+    /// bits. File type bits are ignored.
+    pub fn chmod<P: AsRef<Path>>(&mut self, path: P, mode: u16) -> io::Result<()> {
+        self.attributes_mut(path.as_ref())?.mode = mode & 0o7777;
+        Ok(())
+    }
+
+    /// Set the modification time of the entry at `path`, in seconds since the UNIX epoch
+    pub fn set_mtime<P: AsRef<Path>>(&mut self, path: P, mtime: u32) -> io::Result<()> {
+        self.attributes_mut(path.as_ref())?.mtime = mtime;
+        Ok(())
+    }
+
+    /// Set an extended attribute of the entry at `path`, replacing any existing value. The name
+    /// must include its namespace, such as "user.comment".
+    pub fn set_xattr<P, N, V>(&mut self, path: P, name: N, value: V) -> io::Result<()>
+    where P: AsRef<Path>, N: AsRef<[u8]>, V: AsRef<[u8]>
+    {
+        let (name, value) = (name.as_ref(), value.as_ref());
+        if metadata::split_xattr_name(name).is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("Unsupported xattr namespace: {}", String::from_utf8_lossy(name))));
+        }
+        let attrs = self.attributes_mut(path.as_ref())?;
+        match attrs.xattrs.iter_mut().find(|(n, _)| n == name) {
+            Some((_, v)) => *v = value.to_vec(),
+            None => attrs.xattrs.push((name.to_vec(), value.to_vec())),
+        }
+        Ok(())
+    }
+
+    /// Remove an extended attribute from the entry at `path`. Returns an error if the entry does
+    /// not have the attribute.
+    pub fn remove_xattr<P: AsRef<Path>, N: AsRef<[u8]>>(&mut self, path: P, name: N) -> io::Result<()> {
+        let attrs = self.attributes_mut(path.as_ref())?;
+        let index = attrs.xattrs.iter().position(|(n, _)| n == name.as_ref())
+            .ok_or(io::Error::new(io::ErrorKind::NotFound, "Extended attribute does not exist"))?;
+        attrs.xattrs.remove(index);
+        Ok(())
+    }
+
+    /// Rewrite the metadata tables and superblock. Returns the inner Writer, positioned at the
+    /// end of the image.
+    pub fn finish(self) -> io::Result<W> {
+        self.writer.finish()
+    }
+
+    fn attributes_mut(&mut self, path: &Path) -> io::Result<&mut Attributes> {
+        self.writer.attributes_mut_bytes(path.as_os_str().as_encoded_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::squashfs::SquashFS;
+    use crate::squashfs::test_util::build;
+
+    #[test]
+    fn test_patch() -> io::Result<()> {
+        let data: Vec<u8> = (0..10000u32).map(|x| (x * 31 % 256) as u8).collect();
+        let base = build(4096, |w| {
+            let mut attrs = Attributes::new(0o644, 0, 0, 100);
+            attrs.xattrs.push((b"user.a".to_vec(), b"1".to_vec()));
+            w.add_file("/bin/tool", attrs, &data[..])?;
+            w.add_file("/etc/shadow", Attributes::new(0o644, 0, 0, 100), &b"secret"[..])?;
+            w.add_symlink("/bin/alias", Attributes::new(0o777, 0, 0, 100), "tool")
+        })?;
+        let data_end = SquashFS::new(Cursor::new(&base))?.superblock().inode_table as usize;
+
+        let mut patcher = ImagePatcher::open(Cursor::new(base.clone()))?;
+        patcher.chown("/bin/tool", 1000, 1001)?;
+        patcher.chmod("/etc/shadow", 0o100600)?;
+        patcher.set_mtime("/bin/alias", 200)?;
+        patcher.set_xattr("/bin/tool", "user.a", "2")?;
+        patcher.set_xattr("/etc/shadow", "security.selinux", "shadow_t")?;
+        patcher.remove_xattr("/bin/tool", "user.a")?;
+        patcher.set_xattr("/bin/tool", "trusted.b", "3")?;
+        assert!(patcher.set_xattr("/bin/tool", "system.x", "y").is_err());
+        assert!(patcher.remove_xattr("/bin/tool", "user.missing").is_err());
+        assert!(patcher.chmod("/missing", 0o644).is_err());
+        let image = patcher.finish()?;
+        assert_eq!(image.get_ref()[..data_end][96..], base[..data_end][96..]);
+
+        let mut sqfs = SquashFS::new(image)?;
+        let tool = sqfs.inode_from_path("/bin/tool")?;
+        assert_eq!((tool.uid(&sqfs)?, tool.gid(&sqfs)?), (1000, 1001));
+        assert_eq!(tool.xattrs(&sqfs)?, [(b"trusted.b".to_vec(), b"3".to_vec())]);
+        let mut read = Vec::new();
+        sqfs.open_file_inode(&tool)?.read_to_end(&mut read)?;
+        assert_eq!(read, data);
+        let shadow = sqfs.inode_from_path("/etc/shadow")?;
+        assert_eq!(shadow.permissions(), 0o600);
+        assert_eq!(shadow.xattrs(&sqfs)?, [(b"security.selinux".to_vec(), b"shadow_t".to_vec())]);
+        assert_eq!(sqfs.inode_from_path("/bin/alias")?.mtime(), 200);
+        Ok(())
+    }
+}
@@ -2,25 +2,68 @@
 // Section 4.11 Pathname Resolution, found here:
 // https://pubs.opengroup.org/onlinepubs/009696699/basedefs/xbd_chap04.html
 
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
 use std::io::{self, ErrorKind, Read, Seek};
 use std::path::{Component, Path, PathBuf};
 
-use crate::squashfs::metadata::InodeExtendedInfo;
 
-use super::squashfs::{DirEntry, SquashFS};
+use super::limits::Limits;
+use super::metadata::{EntryReference, Inode};
+use super::squashfs::SquashFS;
+use super::union::UnionFS;
+use crate::{Error, Result};
+
+/// The directory lookups needed to resolve a path, so that paths can be resolved in both a single
+/// SquashFS and a [`UnionFS`]
+pub(crate) trait Resolve {
+    /// Whatever is needed to search a directory for its children
+    type Dir;
+
+    /// The Limits on resolving a path, such as the number of symbolic links that may be followed
+    fn limits(&self) -> Limits;
+
+    fn root_dir(&mut self) -> Result<Self::Dir>;
+
+    /// Find the child called `name` in a directory, returning both its Inode and, in case it is a
+    /// directory, the means to search it in turn. Returns None if there is no such child.
+    fn child(&mut self, dir: &Self::Dir, name: &OsStr) -> Result<Option<(Self::Dir, Inode)>>;
+}
+
+impl<R: Read + Seek> Resolve for SquashFS<R> {
+    type Dir = EntryReference;
+
+    fn limits(&self) -> Limits {
+        *SquashFS::limits(self)
+    }
+
+    fn root_dir(&mut self) -> Result<Self::Dir> {
+        Ok(self.superblock().root_inode)
+    }
+
+    fn child(&mut self, dir: &Self::Dir, name: &OsStr) -> Result<Option<(Self::Dir, Inode)>> {
+        let dir_inode = self.inode_from_entryref(*dir)?;
+        let dirent = match self.read_dir_inode(&dir_inode)?.find(|de| de.file_name_bytes() == name.as_encoded_bytes()) {
+            Some(dirent) => dirent,
+            None => return Ok(None),
+        };
+        let inode = self.inode_from_entryref(dirent.inode_ref())?;
+        Ok(Some((dirent.inode_ref(), inode)))
+    }
+}
 
 /// Return the canonical, absolute form of the provided path with all intermediate components
 /// normalized and all symbolic links resolved.
 ///  - If the path is relative, the CWD is prepended
 ///  - If the path contains symbolic links, they are replaced with their targets
-pub fn canonicalize<R,P,Q>(sqfs: &mut SquashFS<R>, path: P, cwd: Q) -> io::Result<PathBuf>
+pub fn canonicalize<R,P,Q>(sqfs: &mut SquashFS<R>, path: P, cwd: Q) -> Result<PathBuf>
 where P: AsRef<Path>,
       Q: AsRef<Path>,
       R: Read + Seek,
 {
     // "A null pathname shall not be successfully resolved"
     if path.as_ref().as_os_str().is_empty() {
-        return Err(io::Error::new(io::ErrorKind::NotFound, "Path is empty"));
+        return Err(Error::NotFound(PathBuf::new()));
     }
 
     // "If the pathname does not begin with a '/', the predecessor of the first filename of the pathname shall
@@ -34,11 +77,30 @@ where P: AsRef<Path>,
     resolve_absolute_path(sqfs, target_path)
 }
 
+/// Return the canonical, absolute form of the provided path within a [`UnionFS`], in the same way
+/// as [`canonicalize`]. Symbolic links are read from whichever layer provides them.
+pub fn canonicalize_union<R,P,Q>(union: &mut UnionFS<R>, path: P, cwd: Q) -> Result<PathBuf>
+where P: AsRef<Path>,
+      Q: AsRef<Path>,
+      R: Read + Seek,
+{
+    if path.as_ref().as_os_str().is_empty() {
+        return Err(Error::NotFound(PathBuf::new()));
+    }
+    let target_path = if path.as_ref().is_absolute() {
+        path.as_ref().to_path_buf()
+    } else {
+        cwd.as_ref().join(path)
+    };
+
+    resolve_absolute_path(union, target_path)
+}
+
 /// Walk the components of the path and resolve all symbolic links according to the open group
 /// rules.
-fn resolve_absolute_path<R,P>(sqfs: &mut SquashFS<R>, path: P) -> io::Result<PathBuf>
+fn resolve_absolute_path<T,P>(fs: &mut T, path: P) -> Result<PathBuf>
 where P: AsRef<Path>,
-      R: Read + Seek,
+      T: Resolve,
 {
     assert!(path.as_ref().is_absolute());
     assert!(!path.as_ref().as_os_str().is_empty());
@@ -46,11 +108,13 @@ where P: AsRef<Path>,
     // "A pathname the contains at least one non-'/' character and that ends with one or more trailing '/'
     // characters shall not be resolved successfully unless the last pathname component before the trailing '/'
     // characters names an existing directory..."
-    let trailing_slash = path.as_ref().to_str().unwrap().ends_with("/");
+    let trailing_slash = path.as_ref().as_os_str().as_encoded_bytes().ends_with(b"/");
 
     // So that we can navigate to a parent component without having to re-resolve the entire path, as each component
-    // is resolved, add it to a vector of components with their corresponding Inodes.
-    let mut resolved_components: Vec<DirEntry> = Vec::new();
+    // is resolved, add it to a vector of components with their corresponding directories.
+    let mut resolved_components: Vec<(OsString, T::Dir)> = Vec::new();
+    let root = fs.root_dir()?;
+    let limits = fs.limits();
 
     // working_path is initially the entire absolute path. However, when symbolic links are traversed,
     // working_path may be replaced by a concatenation of the symbolic link target with the remaining
@@ -58,6 +122,7 @@ where P: AsRef<Path>,
     // path components.
     let mut working_path = path.as_ref().to_path_buf();
     let mut path_components = working_path.components();
+    let mut symlinks_followed = 0;
     while let Some(comp) = path_components.next() {
         let last_comp = path_components.as_path().as_os_str().is_empty();
         match comp {
@@ -73,19 +138,12 @@ where P: AsRef<Path>,
                 }
             },
             Component::Normal(c) => {
-                // Get the directory inode that should contain this component
-                let parent_inode = if let Some(de) = resolved_components.last() {
-                    sqfs.inode_from_entryref(de.inode_ref())?
-                } else {
-                    sqfs.root_inode()?
-                };
-
-                // Search the directory for a dirent with the correct name
-                let dirent = sqfs.read_dir_inode(&parent_inode)?.find(|de| {
-                    de.file_name().as_str() == c
-                }).ok_or(io::Error::from(ErrorKind::NotFound))?;
+                // Get the directory that should contain this component
+                let parent = resolved_components.last().map_or(&root, |(_, dir)| dir);
 
-                let inode = sqfs.inode_from_entryref(dirent.inode_ref())?;
+                // Search the directory for an entry with the correct name
+                let (dir, inode) = fs.child(parent, c)?
+                    .ok_or_else(|| Error::NotFound(path.as_ref().to_path_buf()))?;
                 if inode.is_symlink() {
                     // "If all of the following are true, then pathname resolution is complete:
                     //  1. This is the last pathname component of the pathname.
@@ -93,23 +151,22 @@ where P: AsRef<Path>,
                     //  3. The function is required to act on the symbolic link itself..."
                     if last_comp && !trailing_slash {
                         // The symbolic link itself is the final path component
-                        resolved_components.push(dirent);
+                        resolved_components.push((c.to_os_string(), dir));
                     } else {
+                        symlinks_followed += 1;
+                        if symlinks_followed > limits.max_symlink_follows {
+                            return Err(Error::SymlinkLoop(path.as_ref().to_path_buf()));
+                        }
                         // If this dirent is a symbolic link then substitute its contents:
                         //   - Empty, return error
                         //   - relative path, insert the contents at the current position
                         //   - absolute path, restart resolution with the symbolic link contents as the first component
-                        let target = match &inode.extended_info {
-                            InodeExtendedInfo::BasicSymlink(i) => {
-                                let target_path = i.target_path.to_str().map_err(
-                                    |e| io::Error::new(ErrorKind::InvalidData, e))?;
-                                PathBuf::from(target_path)
-                            }
-                            // TODO: ExtSymlink
-                            _ => return Err(io::Error::from(ErrorKind::Unsupported)),
+                        let target = match inode.symlink_target() {
+                            Some(t) => PathBuf::from(os_str_from_bytes(t.to_bytes()).into_owned()),
+                            None => return Err(io::Error::from(ErrorKind::Unsupported).into()),
                         };
                         if target.as_os_str().is_empty() {
-                            return Err(io::Error::from(ErrorKind::InvalidData));
+                            return Err(Error::NotFound(path.as_ref().to_path_buf()));
                         }
                         if target.is_absolute() {
                             resolved_components.clear();
@@ -118,19 +175,93 @@ where P: AsRef<Path>,
                         path_components = working_path.components();
                     }
                 } else {
-                    resolved_components.push(dirent);
+                    resolved_components.push((c.to_os_string(), dir));
+                    limits.check_depth(resolved_components.len())?;
                 }
             },
             Component::Prefix(_) => {
-                return Err(io::Error::from(ErrorKind::InvalidData));
+                return Err(Error::InvalidPath(path.as_ref().to_path_buf()));
             }
         }
     }
 
     let mut resolved = PathBuf::from("/");
-    for de in resolved_components {
-        resolved.push(de.file_name());
+    for (name, _) in resolved_components {
+        resolved.push(name);
     }
     // TODO: What about the trailing slash???
     Ok(resolved)
-}
\ No newline at end of file
+}
+
+/// View a name or path stored in an image as an OsStr. On Unix this is exact, whatever the
+/// encoding of the name. Elsewhere, bytes that are not valid UTF-8 are replaced with U+FFFD.
+pub(crate) fn os_str_from_bytes(bytes: &[u8]) -> Cow<'_, OsStr> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        Cow::Borrowed(OsStr::from_bytes(bytes))
+    }
+    #[cfg(not(unix))]
+    {
+        match String::from_utf8_lossy(bytes) {
+            Cow::Borrowed(s) => Cow::Borrowed(OsStr::new(s)),
+            Cow::Owned(s) => Cow::Owned(OsString::from(s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::squashfs::test_util::open;
+    use crate::squashfs::writer::Attributes;
+
+    #[test]
+    fn test_canonicalize_stays_in_root() -> Result<()> {
+        let attrs = Attributes::new(0o755, 0, 0, 0);
+        let mut sqfs = open(128 * 1024, |w| {
+            w.add_file("/etc/passwd", attrs.clone(), &b"root"[..])?;
+            w.add_symlink("/a/b/up", attrs.clone(), "../../../../../etc")?;
+            w.add_symlink("/a/abs", attrs.clone(), "/../../etc/passwd")
+        })?;
+
+        // '..' in the root refers to the root itself, however it is reached
+        assert_eq!(canonicalize(&mut sqfs, "/../../..", "/")?, Path::new("/"));
+        assert_eq!(canonicalize(&mut sqfs, "../../../etc/passwd", "/a/b")?, Path::new("/etc/passwd"));
+        assert_eq!(canonicalize(&mut sqfs, "/a/b/up/passwd", "/")?, Path::new("/etc/passwd"));
+        assert_eq!(canonicalize(&mut sqfs, "/a/abs/..", "/")?, Path::new("/etc"));
+        assert_eq!(canonicalize(&mut sqfs, "abs", "/a")?, Path::new("/a/abs"));
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_non_utf8_names() -> Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+        // Latin-1 names, as old vendor firmware often has
+        let attrs = Attributes::new(0o644, 0, 0, 0);
+        let mut sqfs = open(128 * 1024, |w| {
+            w.add_file(OsStr::from_bytes(b"/caf\xe9/men\xfa"), attrs.clone(), &b"soup"[..])?;
+            w.add_file("/cafe", attrs.clone(), &b"tea"[..])?;
+            w.add_symlink("/link", attrs.clone(), OsStr::from_bytes(b"caf\xe9"))
+        })?;
+
+        let entry = sqfs.read_dir("/")?.find(|e| e.file_name_bytes() == b"caf\xe9").unwrap();
+        assert_eq!(entry.file_name(), "caf\u{fffd}");
+        assert_eq!(entry.file_name_os(), OsStr::from_bytes(b"caf\xe9"));
+
+        // Lookups match the exact bytes of each name, rather than their lossy forms
+        let path = Path::new(OsStr::from_bytes(b"/caf\xe9/men\xfa"));
+        let mut data = Vec::new();
+        sqfs.open_file(path)?.read_to_end(&mut data)?;
+        assert_eq!(data, b"soup");
+        assert!(matches!(sqfs.inode_from_path("/caf\u{fffd}/men\u{fffd}"), Err(Error::NotFound(_))));
+        assert_eq!(canonicalize(&mut sqfs, "/link/men\u{fffd}", "/").ok(), None);
+        assert_eq!(canonicalize(&mut sqfs, Path::new(OsStr::from_bytes(b"/link/men\xfa")), "/")?, path);
+
+        let mut union = UnionFS::new(vec![sqfs])?;
+        assert!(union.inode_from_path(path)?.is_file());
+        assert_eq!(canonicalize_union(&mut union, Path::new(OsStr::from_bytes(b"link/men\xfa")), "/")?, path);
+        Ok(())
+    }
+}
@@ -0,0 +1,427 @@
+//! mksquashfs pseudo file definitions
+//!
+//! Pseudo file definitions describe entries to add to an image, or changes to make to existing
+//! entries, without those entries needing to exist in the source tree. They are most often used
+//! to create device nodes and set ownership without root privileges. Each definition is a single
+//! line in the format accepted by the mksquashfs `-p` and `-pf` options:
+//!
+//! | Definition                                   | Effect                                        |
+//! |----------------------------------------------|-----------------------------------------------|
+//! | `name d mode uid gid`                        | Create a directory                            |
+//! | `name m mode uid gid`                        | Modify an existing entry                      |
+//! | `name b mode uid gid major minor`            | Create a block device                         |
+//! | `name c mode uid gid major minor`            | Create a character device                     |
+//! | `name f mode uid gid command`                | Create a file from the output of a command    |
+//! | `name s mode uid gid target`                 | Create a symbolic link                        |
+//! | `name i mode uid gid s` or `... f`           | Create a socket or named pipe                 |
+//! | `name l target` or `name L target`           | Create a hard link to an existing entry       |
+//! | `name x name=value`                          | Set an extended attribute of an existing entry|
+//!
+//! The uppercase types `D`, `M`, `B`, `C`, `F`, `S`, and `I` take an additional modification time,
+//! in seconds since the UNIX epoch, before the mode. Otherwise, new entries are given the
+//! modification time of the image. Modes are octal, and uids and gids are numeric. Names and
+//! symlink targets may be quoted with `"` or contain characters escaped with `\`. Commands are
+//! run with `/bin/sh -c`.
+//!
+//! ```no_run
+//! use std::fs::File;
+//! use std::io;
+//! use squinter::squashfs::pseudo;
+//! use squinter::squashfs::writer::{SquashFSWriter, WriterOptions};
+//!
+//! fn build_rootfs() -> io::Result<()> {
+//!     let mut writer = SquashFSWriter::new(File::create("rootfs.squashfs")?, WriterOptions::default())?;
+//!     // ... populate the writer ...
+//!     let defs = pseudo::load(["rootfs.pseudo"], ["/dev/console c 600 0 0 5 1"])?;
+//!     for def in &defs {
+//!         def.apply(&mut writer)?;
+//!     }
+//!     writer.finish()?;
+//!     Ok(())
+//! }
+//! ```
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Seek, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+
+use super::metadata::{self, encode_dev};
+use super::writer::{Attributes, SquashFSWriter};
+
+/// A single pseudo file definition. See the [module documentation](self) for the format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PseudoDefinition {
+    /// The path of the entry to create or modify
+    pub path: String,
+    pub entry: PseudoEntry,
+}
+
+/// The entry created, or the change made, by a pseudo file definition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PseudoEntry {
+    Dir(PseudoAttributes),
+    /// Change the attributes of an existing entry
+    Modify(PseudoAttributes),
+    BlockDevice { attrs: PseudoAttributes, major: u32, minor: u32 },
+    CharDevice { attrs: PseudoAttributes, major: u32, minor: u32 },
+    /// A regular file containing the output of a shell command
+    File { attrs: PseudoAttributes, command: String },
+    Symlink { attrs: PseudoAttributes, target: String },
+    Fifo(PseudoAttributes),
+    Socket(PseudoAttributes),
+    HardLink { target: String },
+    /// Set an extended attribute of an existing entry
+    Xattr { name: Vec<u8>, value: Vec<u8> },
+}
+
+/// The attributes given in a pseudo file definition
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PseudoAttributes {
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    /// The modification time, if the definition has one
+    pub mtime: Option<u32>,
+}
+
+impl PseudoAttributes {
+    fn to_attributes(&self, default_mtime: u32) -> Attributes {
+        Attributes::new(self.mode, self.uid, self.gid, self.mtime.unwrap_or(default_mtime))
+    }
+}
+
+/// Parse a pseudo file, as given to the mksquashfs `-pf` option. Blank lines and lines starting
+/// with `#` are ignored.
+pub fn parse_pseudo_file<R: BufRead>(r: R) -> io::Result<Vec<PseudoDefinition>> {
+    let mut defs = Vec::new();
+    for (n, line) in r.lines().enumerate() {
+        let line = line?;
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let def = line.parse()
+            .map_err(|e: io::Error| io::Error::new(e.kind(), format!("Line {}: {e}", n + 1)))?;
+        defs.push(def);
+    }
+    Ok(defs)
+}
+
+/// Load the definitions in each of the given pseudo files, followed by each of the given
+/// definitions, in the order that mksquashfs applies its `-pf` and `-p` options
+pub fn load<P, S>(files: impl IntoIterator<Item = P>, inline: impl IntoIterator<Item = S>) -> io::Result<Vec<PseudoDefinition>>
+where P: AsRef<Path>, S: AsRef<str>
+{
+    let mut defs = Vec::new();
+    for path in files {
+        let path = path.as_ref();
+        let file_defs = File::open(path)
+            .map(BufReader::new)
+            .and_then(parse_pseudo_file)
+            .map_err(|e| io::Error::new(e.kind(), format!("Pseudo file '{}': {e}", path.display())))?;
+        defs.extend(file_defs);
+    }
+    for def in inline {
+        let def = def.as_ref();
+        defs.push(def.parse()
+            .map_err(|e: io::Error| io::Error::new(e.kind(), format!("Pseudo definition '{def}': {e}")))?);
+    }
+    Ok(defs)
+}
+
+impl FromStr for PseudoDefinition {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = Fields(s);
+        let path = fields.token("file name")?;
+        let entry_type = fields.token("type")?;
+        let (lower, timed) = match entry_type.as_str() {
+            "D" | "M" | "B" | "C" | "F" | "S" | "I" => (entry_type.to_ascii_lowercase(), true),
+            _ => (entry_type, false),
+        };
+        let entry = match lower.as_str() {
+            "l" | "L" => PseudoEntry::HardLink { target: fields.token("link target")? },
+            "x" => {
+                let xattr = fields.token("xattr")?;
+                let (name, value) = xattr.split_once('=')
+                    .ok_or(invalid(format!("Invalid xattr '{xattr}'")))?;
+                PseudoEntry::Xattr { name: name.as_bytes().to_vec(), value: parse_xattr_value(value)? }
+            },
+            t => {
+                let attrs = fields.attributes(timed)?;
+                match t {
+                    "d" => PseudoEntry::Dir(attrs),
+                    "m" => PseudoEntry::Modify(attrs),
+                    "b" => PseudoEntry::BlockDevice { attrs, major: fields.number("major")?, minor: fields.number("minor")? },
+                    "c" => PseudoEntry::CharDevice { attrs, major: fields.number("major")?, minor: fields.number("minor")? },
+                    "f" => PseudoEntry::File { attrs, command: fields.rest("command")? },
+                    "s" => PseudoEntry::Symlink { attrs, target: fields.token("symlink target")? },
+                    "i" => match fields.token("IPC type")?.as_str() {
+                        "f" => PseudoEntry::Fifo(attrs),
+                        "s" => PseudoEntry::Socket(attrs),
+                        i => return Err(invalid(format!("Unknown IPC type '{i}'"))),
+                    },
+                    _ => return Err(invalid(format!("Unknown type '{t}'"))),
+                }
+            },
+        };
+        if !matches!(entry, PseudoEntry::File { .. }) && !fields.0.trim().is_empty() {
+            return Err(invalid(format!("Unexpected '{}'", fields.0.trim())));
+        }
+        Ok(PseudoDefinition { path, entry })
+    }
+}
+
+impl PseudoDefinition {
+    /// Apply this definition to an image being built. New entries replace any existing entry at
+    /// the same path, except that an existing directory keeps its contents.
+    pub fn apply<W: Write + Seek>(&self, writer: &mut SquashFSWriter<W>) -> io::Result<()> {
+        let path = self.path.as_bytes();
+        let mtime = writer.options().mod_time;
+        match &self.entry {
+            PseudoEntry::Dir(a) => writer.add_dir_bytes(path, a.to_attributes(mtime)),
+            PseudoEntry::Modify(a) => {
+                let attrs = writer.attributes_mut_bytes(path)?;
+                attrs.mode = a.mode & 0o7777;
+                attrs.uid = a.uid;
+                attrs.gid = a.gid;
+                if let Some(t) = a.mtime {
+                    attrs.mtime = t;
+                }
+                Ok(())
+            },
+            PseudoEntry::BlockDevice { attrs, major, minor } => {
+                writer.add_block_device_bytes(path, attrs.to_attributes(mtime), encode_dev(*major, *minor))
+            },
+            PseudoEntry::CharDevice { attrs, major, minor } => {
+                writer.add_char_device_bytes(path, attrs.to_attributes(mtime), encode_dev(*major, *minor))
+            },
+            PseudoEntry::File { attrs, command } => {
+                let mut child = Command::new("/bin/sh")
+                    .arg("-c")
+                    .arg(command)
+                    .stdin(Stdio::null())
+                    .stdout(Stdio::piped())
+                    .spawn()?;
+                let result = writer.add_file_bytes(path, attrs.to_attributes(mtime), child.stdout.take().unwrap());
+                let status = child.wait()?;
+                result?;
+                if !status.success() {
+                    return Err(io::Error::other(format!("Command '{command}' failed: {status}")));
+                }
+                Ok(())
+            },
+            PseudoEntry::Symlink { attrs, target } => {
+                writer.add_symlink_bytes(path, attrs.to_attributes(mtime), target.as_bytes())
+            },
+            PseudoEntry::Fifo(a) => writer.add_fifo_bytes(path, a.to_attributes(mtime)),
+            PseudoEntry::Socket(a) => writer.add_socket_bytes(path, a.to_attributes(mtime)),
+            PseudoEntry::HardLink { target } => writer.add_hard_link_bytes(path, target.as_bytes()),
+            PseudoEntry::Xattr { name, value } => {
+                if metadata::split_xattr_name(name).is_none() {
+                    return Err(invalid(format!("Unsupported xattr namespace: {}", String::from_utf8_lossy(name))));
+                }
+                let attrs = writer.attributes_mut_bytes(path)?;
+                match attrs.xattrs.iter_mut().find(|(n, _)| n == name) {
+                    Some((_, v)) => *v = value.clone(),
+                    None => attrs.xattrs.push((name.clone(), value.clone())),
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+/// The unparsed remainder of a definition
+struct Fields<'a>(&'a str);
+
+impl Fields<'_> {
+    /// The next whitespace-separated field, with quotes and escapes removed
+    fn token(&mut self, what: &str) -> io::Result<String> {
+        let s = self.0.trim_start();
+        let mut token = String::new();
+        let mut quoted = false;
+        let mut chars = s.char_indices();
+        let mut end = s.len();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, e)) => token.push(e),
+                    None => return Err(invalid("Trailing '\\'")),
+                },
+                '"' => quoted = !quoted,
+                c if c.is_whitespace() && !quoted => {
+                    end = i;
+                    break;
+                },
+                c => token.push(c),
+            }
+        }
+        if quoted {
+            return Err(invalid("Unterminated quote"));
+        }
+        if end == 0 {
+            return Err(invalid(format!("Missing {what}")));
+        }
+        self.0 = &s[end..];
+        Ok(token)
+    }
+
+    /// The rest of the definition, as-is
+    fn rest(&mut self, what: &str) -> io::Result<String> {
+        let rest = self.0.trim();
+        if rest.is_empty() {
+            return Err(invalid(format!("Missing {what}")));
+        }
+        self.0 = "";
+        Ok(rest.to_string())
+    }
+
+    fn number(&mut self, what: &str) -> io::Result<u32> {
+        let token = self.token(what)?;
+        token.parse().map_err(|_| invalid(format!("Invalid {what} '{token}'")))
+    }
+
+    fn attributes(&mut self, timed: bool) -> io::Result<PseudoAttributes> {
+        let mtime = if timed { Some(self.number("time")?) } else { None };
+        let mode = self.token("mode")?;
+        let mode = u16::from_str_radix(&mode, 8).ok()
+            .filter(|m| *m <= 0o7777)
+            .ok_or(invalid(format!("Invalid mode '{mode}'")))?;
+        Ok(PseudoAttributes { mode, uid: self.number("uid")?, gid: self.number("gid")?, mtime })
+    }
+}
+
+/// Parse an xattr value. As with setfattr, values starting with "0x" are hexadecimal.
+fn parse_xattr_value(value: &str) -> io::Result<Vec<u8>> {
+    let Some(hex) = value.strip_prefix("0x").or(value.strip_prefix("0X")) else {
+        return Ok(value.as_bytes().to_vec());
+    };
+    if hex.len() % 2 != 0 {
+        return Err(invalid(format!("Invalid hexadecimal xattr value '{value}'")));
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2).unwrap_or_default(), 16)
+            .map_err(|_| invalid(format!("Invalid hexadecimal xattr value '{value}'"))))
+        .collect()
+}
+
+fn invalid<S: Into<String>>(msg: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+    use crate::squashfs::SquashFS;
+    use crate::squashfs::test_util::open;
+    use crate::squashfs::writer::WriterOptions;
+
+    #[test]
+    fn test_parse() -> io::Result<()> {
+        let attrs = |mode, uid, gid, mtime| PseudoAttributes { mode, uid, gid, mtime };
+        let def: PseudoDefinition = "/dev/console c 600 0 0 5 1".parse()?;
+        assert_eq!(def, PseudoDefinition {
+            path: "/dev/console".into(),
+            entry: PseudoEntry::CharDevice { attrs: attrs(0o600, 0, 0, None), major: 5, minor: 1 },
+        });
+        let def: PseudoDefinition = r#""/my dir"/a\ b D 1000 755 1 2"#.parse()?;
+        assert_eq!(def.path, "/my dir/a b");
+        assert_eq!(def.entry, PseudoEntry::Dir(attrs(0o755, 1, 2, Some(1000))));
+        let def: PseudoDefinition = "date f 644 0 0 echo  hello | tr a-z A-Z ".parse()?;
+        assert_eq!(def.entry, PseudoEntry::File { attrs: attrs(0o644, 0, 0, None), command: "echo  hello | tr a-z A-Z".into() });
+        let def: PseudoDefinition = "/bin x security.capability=0x0100".parse()?;
+        assert_eq!(def.entry, PseudoEntry::Xattr { name: b"security.capability".to_vec(), value: vec![1, 0] });
+
+        assert!("/a".parse::<PseudoDefinition>().is_err());
+        assert!("/a q 755 0 0".parse::<PseudoDefinition>().is_err());
+        assert!("/a d 789 0 0".parse::<PseudoDefinition>().is_err());
+        assert!("/a d 755 root 0".parse::<PseudoDefinition>().is_err());
+        assert!("/a c 600 0 0 5".parse::<PseudoDefinition>().is_err());
+        assert!("/a d 755 0 0 extra".parse::<PseudoDefinition>().is_err());
+        assert!("\"/a d 755 0 0".parse::<PseudoDefinition>().is_err());
+
+        let file = "# comment\n\n/dev d 755 0 0\n/dev/null c 666 0 0 1 3\n/bad\n";
+        let err = parse_pseudo_file(file.as_bytes()).unwrap_err();
+        assert!(err.to_string().starts_with("Line 5:"));
+        assert_eq!(parse_pseudo_file(&file.as_bytes()[..file.len() - 5])?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply() -> io::Result<()> {
+        let opts = WriterOptions { mod_time: 77, ..Default::default() };
+        let mut writer = SquashFSWriter::new(Cursor::new(Vec::new()), opts)?;
+        writer.add_file("/etc/passwd", Attributes::new(0o644, 1000, 1000, 5), &b"root"[..])?;
+        let defs = parse_pseudo_file(&b"\
+            / D 10 755 0 0
+            /dev d 755 0 0
+            /dev/console c 600 0 0 5 1
+            /dev/sda B 20 660 0 6 8 0
+            /etc/passwd m 600 0 0
+            /etc/passwd x user.origin=pseudo
+            /etc/pw l /etc/passwd
+            /etc/localtime S 30 777 0 0 /usr/share/zoneinfo/UTC
+            /run/initctl i 600 0 0 f
+            /run/sock I 40 600 0 0 s
+        "[..])?;
+        for def in &defs {
+            def.apply(&mut writer)?;
+        }
+        assert!("/missing m 644 0 0".parse::<PseudoDefinition>()?.apply(&mut writer).is_err());
+        assert!("/etc/passwd x system.a=b".parse::<PseudoDefinition>()?.apply(&mut writer).is_err());
+        let mut sqfs = SquashFS::new(writer.finish()?)?;
+
+        let root = sqfs.root_inode()?;
+        assert_eq!(root.mtime(), 10);
+        let console = sqfs.inode_from_path("/dev/console")?;
+        assert!(console.is_char_dev());
+        assert_eq!(console.dev_number(), Some(encode_dev(5, 1)));
+        assert_eq!((console.permissions(), console.mtime()), (0o600, 77));
+        let sda = sqfs.inode_from_path("/dev/sda")?;
+        assert!(sda.is_block_dev());
+        assert_eq!((sda.dev_number(), sda.mtime()), (Some(encode_dev(8, 0)), 20));
+        let passwd = sqfs.inode_from_path("/etc/passwd")?;
+        assert_eq!((passwd.permissions(), passwd.uid(&sqfs)?, passwd.mtime()), (0o600, 0, 5));
+        assert_eq!(passwd.xattrs(&sqfs)?, [(b"user.origin".to_vec(), b"pseudo".to_vec())]);
+        assert_eq!(passwd.link_count(), 2);
+        let localtime = sqfs.inode_from_path("/etc/localtime")?;
+        assert_eq!(localtime.symlink_target().unwrap().to_bytes(), b"/usr/share/zoneinfo/UTC");
+        assert!(sqfs.inode_from_path("/run/initctl")?.is_fifo());
+        assert!(sqfs.inode_from_path("/run/sock")?.is_socket());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_file_from_command() -> io::Result<()> {
+        let mut sqfs = open(128 * 1024, |w| {
+            assert!("/fail f 444 0 0 exit 3".parse::<PseudoDefinition>()?.apply(w).is_err());
+            "/version f 444 0 0 echo hello | tr a-z A-Z".parse::<PseudoDefinition>()?.apply(w)
+        })?;
+        let mut data = Vec::new();
+        sqfs.open_file("/version")?.read_to_end(&mut data)?;
+        assert_eq!(data, b"HELLO\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_load() -> io::Result<()> {
+        let path = std::env::temp_dir().join(format!("squinter-pseudo-{}", std::process::id()));
+        std::fs::write(&path, "# devices\n/dev/console c 600 0 0 5 1\n")?;
+        let defs = load([&path], ["/tmp d 1777 0 0"]);
+        std::fs::remove_file(&path)?;
+        let paths: Vec<_> = defs?.into_iter().map(|d| d.path).collect();
+        assert_eq!(paths, ["/dev/console", "/tmp"]);
+
+        let err = load([&path], None::<&str>).unwrap_err();
+        assert!(err.to_string().contains("squinter-pseudo"), "{err}");
+        let err = load(None::<&Path>, ["/tmp q"]).unwrap_err();
+        assert!(err.to_string().starts_with("Pseudo definition '/tmp q'"), "{err}");
+        Ok(())
+    }
+}
@@ -0,0 +1,657 @@
+//! Recovery of whatever can still be read from a damaged image
+//!
+//! [`salvage`] copies what survives of a damaged image into a new one, as fsck does for a damaged
+//! filesystem. Rather than walking the directory tree, which loses everything beneath a directory
+//! that cannot be read, it scans the inode table block by block and decodes every inode that it
+//! can find, stepping over blocks that cannot be decompressed. The directory tree is then rebuilt
+//! from the directories that can still be read, and the inodes that cannot be reached from the
+//! root are put in a `lost+found` directory, named by their inode numbers as in `#1234`. Blocks of
+//! file data that cannot be read are replaced by zeros.
+//!
+//! Everything that could not be recovered is recorded in the [`Report`], and the salvage carries
+//! on past it. Only failures to write the new image end the salvage early.
+//!
+//! ```rust
+//! use std::fs::File;
+//! use std::io::BufWriter;
+//! use squinter::squashfs::{salvage, SquashFS};
+//! use squinter::squashfs::writer::WriterOptions;
+//! fn recover_dump() -> std::io::Result<()> {
+//!     let mut sqfs = SquashFS::open("flash-dump.squashfs")?;
+//!     let output = BufWriter::new(File::create("recovered.squashfs")?);
+//!     let (_, report) = salvage::salvage(&mut sqfs, output, WriterOptions::default())?;
+//!     for loss in &report.losses {
+//!         eprintln!("{loss}");
+//!     }
+//!     Ok(())
+//! }
+//! ```
+
+use std::cmp::min;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use super::metadata::{self, EntryReference, Inode, InodeExtendedInfo, METADATA_BLOCK_SIZE};
+use super::writer::{Attributes, SquashFSWriter, WriterOptions};
+use super::SquashFS;
+use crate::{Error, MetadataTable};
+
+/// The number of consecutive inodes that must decode from a position in the inode table before
+/// the scan trusts that it has found the start of an inode again
+const RESYNC_INODES: usize = 3;
+
+/// The directory in the root that inodes which cannot be reached from the root are put in
+const LOST_AND_FOUND: &str = "/lost+found";
+
+// Flag set in a data block size to indicate that the block is stored uncompressed
+const DATA_BLOCK_UNCOMPRESSED: u32 = 0x1000000;
+
+/// The outcome of salvaging an image
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Everything that could not be recovered, in the order in which it was found
+    pub losses: Vec<Loss>,
+    /// The number of inodes copied to the new image
+    pub inodes: u32,
+    /// The number of those inodes that were put in lost+found because they could not be reached
+    /// from the root
+    pub orphans: u32,
+}
+
+/// Something that could not be recovered, along with the path of the entry that it concerns, if
+/// any. Paths in lost+found are those in the new image.
+#[derive(Debug)]
+pub struct Loss {
+    pub path: Option<PathBuf>,
+    pub cause: Cause,
+}
+
+impl fmt::Display for Loss {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(p) => write!(f, "{}: {}", p.display(), self.cause),
+            None => write!(f, "{}", self.cause),
+        }
+    }
+}
+
+/// The reasons that part of an image could not be recovered
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Cause {
+    /// A block of the inode table could not be read, so the inodes in it were lost. When even the
+    /// size of the block is unreadable, the rest of the table is lost too.
+    UnreadableInodeBlock { offset: u64, error: Error },
+    /// No inode could be decoded at a position in the inode table, so the inodes up to the next
+    /// one that could be found were lost
+    UndecodableInode { offset: u64, position: u16 },
+    /// A directory's entries could not be read. Any of its contents that were found in the inode
+    /// table are in lost+found.
+    UnreadableDirectory(Error),
+    /// A directory entry refers to an inode that could not be read
+    UnreadableInode(Error),
+    /// A directory entry refers to a directory that is already in the new image
+    RepeatedDirectory,
+    /// A directory entry's name cannot be used in the new image
+    InvalidName,
+    /// The ownership or extended attributes of an inode could not be read. Whichever of them could
+    /// not be read were replaced by root ownership and no extended attributes.
+    UnreadableAttributes(Error),
+    /// A block of a file's data could not be read, and was replaced by zeros
+    UnreadableData { offset: u64, error: Error },
+    /// The inode could not be stored in the new image
+    Rejected(Error),
+}
+
+impl fmt::Display for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cause::UnreadableInodeBlock { offset, error } =>
+                write!(f, "The inode table block at offset {offset} could not be read: {error}"),
+            Cause::UndecodableInode { offset, position } =>
+                write!(f, "No inode could be decoded at position {position} of the inode table block at offset {offset}"),
+            Cause::UnreadableDirectory(e) => write!(f, "The directory could not be read: {e}"),
+            Cause::UnreadableInode(e) => write!(f, "The inode could not be read: {e}"),
+            Cause::RepeatedDirectory => f.write_str("The directory is already in the tree"),
+            Cause::InvalidName => f.write_str("The entry's name is not valid"),
+            Cause::UnreadableAttributes(e) => write!(f, "The ownership or extended attributes could not be read: {e}"),
+            Cause::UnreadableData { offset, error } =>
+                write!(f, "The data at offset {offset} could not be read and was replaced by zeros: {error}"),
+            Cause::Rejected(e) => write!(f, "The entry could not be stored: {e}"),
+        }
+    }
+}
+
+/// Copy whatever can be recovered from a damaged image into a new image written to `w` with the
+/// settings in `opts`. Returns the Writer once the new image is complete, along with a Report of
+/// what was lost.
+pub fn salvage<R: Read + Seek, W: Write + Seek>(sqfs: &mut SquashFS<R>, w: W, opts: WriterOptions) -> io::Result<(W, Report)> {
+    let mut salvager = Salvager {
+        writer: SquashFSWriter::new(w, opts)?,
+        sqfs,
+        report: Report::default(),
+        inodes: BTreeMap::new(),
+        placed: HashMap::new(),
+        numbers: HashSet::new(),
+    };
+    salvager.scan_inode_table();
+    salvager.rebuild_tree()?;
+    salvager.add_orphans()?;
+    let Salvager { writer, mut report, placed, .. } = salvager;
+    report.inodes = placed.len() as u32;
+    Ok((writer.finish()?, report))
+}
+
+/// The decompressed contents of consecutive readable blocks of the inode table
+#[derive(Default)]
+struct Run {
+    data: Vec<u8>,
+    // The location of each block relative to the start of the table, and where it starts in data
+    blocks: Vec<(u64, usize)>,
+}
+
+impl Run {
+    /// The location of the block holding a position in the run, and the offset within that block
+    fn reference(&self, pos: usize) -> (u64, u16) {
+        let i = self.blocks.partition_point(|(_, start)| *start <= pos) - 1;
+        let (location, start) = self.blocks[i];
+        (location, (pos - start) as u16)
+    }
+
+    /// The position in the run of an inode reference, if it refers to a block of the run
+    fn position(&self, inode_ref: EntryReference) -> Option<usize> {
+        let i = self.blocks.iter().position(|(location, _)| *location == inode_ref.location())?;
+        let pos = self.blocks[i].1 + usize::from(inode_ref.offset());
+        let end = self.blocks.get(i + 1).map_or(self.data.len(), |(_, start)| *start);
+        (pos < end).then_some(pos)
+    }
+}
+
+struct Salvager<'a, R: Read + Seek, W: Write + Seek> {
+    sqfs: &'a mut SquashFS<R>,
+    writer: SquashFSWriter<W>,
+    report: Report,
+    // The inodes decoded from the inode table that have not been copied yet, by the location and
+    // offset of their references
+    inodes: BTreeMap<(u64, u16), Inode>,
+    // The path in the new image of each inode that has been copied, and whether it is a directory,
+    // by its reference
+    placed: HashMap<(u64, u16), (Vec<u8>, bool)>,
+    // The inode numbers already requested for the new image
+    numbers: HashSet<u32>,
+}
+
+impl<R: Read + Seek, W: Write + Seek> Salvager<'_, R, W> {
+    fn lose(&mut self, path: Option<&[u8]>, cause: Cause) {
+        let path = path.map(|p| PathBuf::from(String::from_utf8_lossy(p).into_owned()));
+        self.report.losses.push(Loss { path, cause });
+    }
+
+    /// Decode every inode that can be found in the inode table
+    fn scan_inode_table(&mut self) {
+        let sb = self.sqfs.superblock();
+        let (start, end, compressor, root) = (sb.inode_table, sb.dir_table, sb.compressor, sb.root_inode);
+        let mut runs = Vec::new();
+        let mut run = Run::default();
+        let mut buf = vec![0; METADATA_BLOCK_SIZE.into()];
+        let mut offset = start;
+        while offset < end {
+            // The size of a block is known from its header even if the block cannot be decompressed
+            let mut header = [0; 2];
+            let size = self.sqfs.read_raw(offset, &mut header)
+                .map(|_| u16::from_le_bytes(header) & 0x7FFF);
+            let size = match size {
+                Ok(s) if s > 0 && s <= METADATA_BLOCK_SIZE => s,
+                Ok(_) => {
+                    let error = Error::CorruptMetadata { table: MetadataTable::Inode, offset };
+                    self.lose(None, Cause::UnreadableInodeBlock { offset, error });
+                    break;
+                },
+                Err(e) => {
+                    self.lose(None, Cause::UnreadableInodeBlock { offset, error: e.into() });
+                    break;
+                },
+            };
+            let mut r = self.sqfs.raw_reader();
+            let block = r.seek(SeekFrom::Start(offset))
+                .and_then(|_| metadata::read_metadata_block(&mut r, &compressor, &mut buf));
+            match block {
+                Ok((_, len)) => {
+                    run.blocks.push((offset - start, run.data.len()));
+                    run.data.extend_from_slice(&buf[..len]);
+                },
+                Err(e) => {
+                    let error = Error::in_metadata(MetadataTable::Inode, offset)(e);
+                    self.lose(None, Cause::UnreadableInodeBlock { offset, error });
+                    runs.push(std::mem::take(&mut run));
+                },
+            }
+            offset += u64::from(size) + 2;
+        }
+        runs.push(run);
+
+        for (i, run) in runs.iter().enumerate().filter(|(_, r)| !r.data.is_empty()) {
+            let known = run.position(root);
+            // Only the first run is known to start with an inode. The others start part of the way
+            // through whichever inode spans the block that could not be read.
+            let mut pos = if i == 0 { Some(0) } else { self.resync(run, 0, known) };
+            while let Some(p) = pos.filter(|p| *p < run.data.len()) {
+                match self.decode(run, p) {
+                    Some((inode, len)) => {
+                        self.inodes.insert(run.reference(p), inode);
+                        pos = Some(p + len);
+                    },
+                    None => {
+                        let (location, position) = run.reference(p);
+                        self.lose(None, Cause::UndecodableInode { offset: start + location, position });
+                        pos = self.resync(run, p + 1, known.filter(|k| *k > p));
+                    },
+                }
+            }
+        }
+    }
+
+    /// Find the next position in a run from which inodes can be decoded again, stopping at the
+    /// position of an inode that is known to be there
+    fn resync(&self, run: &Run, from: usize, known: Option<usize>) -> Option<usize> {
+        let limit = known.unwrap_or(run.data.len());
+        (from..limit).find(|&p| {
+            let mut pos = p;
+            for _ in 0..RESYNC_INODES {
+                match self.decode(run, pos) {
+                    Some((_, len)) => pos += len,
+                    None => return false,
+                }
+                if pos == run.data.len() {
+                    break;
+                }
+            }
+            true
+        }).or(known)
+    }
+
+    /// Decode the inode at a position in a run, along with its length, if it is plausibly an inode
+    fn decode(&self, run: &Run, pos: usize) -> Option<(Inode, usize)> {
+        let mut r = &run.data[pos..];
+        let inode = Inode::read(&mut r, self.sqfs.block_size(), self.sqfs.limits()).ok()?;
+        let len = run.data.len() - pos - r.len();
+        self.is_plausible(&inode).then_some((inode, len))
+    }
+
+    /// Whether an inode decoded from an unknown position looks like a real one
+    fn is_plausible(&self, inode: &Inode) -> bool {
+        let sb = self.sqfs.superblock();
+        let data_in_image = match inode.data_blocks() {
+            Some((start, sizes)) => sizes.iter()
+                .try_fold(start, |end, size| end.checked_add(u64::from(size & !DATA_BLOCK_UNCOMPRESSED)))
+                .is_some_and(|end| end <= sb.bytes_used),
+            None => true,
+        };
+        inode.mode() & 0o170000 != 0 &&
+            (1..=sb.inode_count).contains(&inode.inode_number()) &&
+            inode.uid(self.sqfs).is_ok() &&
+            inode.gid(self.sqfs).is_ok() &&
+            data_in_image
+    }
+
+    /// Take the inode with the given reference from those found by the scan of the inode table,
+    /// or read it if the scan did not find it
+    fn take_inode(&mut self, inode_ref: EntryReference) -> crate::Result<Inode> {
+        match self.inodes.remove(&(inode_ref.location(), inode_ref.offset())) {
+            Some(inode) => Ok(inode),
+            None => self.sqfs.inode_from_entryref(inode_ref),
+        }
+    }
+
+    /// Copy the tree that can still be reached from the root
+    fn rebuild_tree(&mut self) -> io::Result<()> {
+        let root_ref = self.sqfs.superblock().root_inode;
+        let key = (root_ref.location(), root_ref.offset());
+        match self.take_inode(root_ref) {
+            Ok(root) if root.is_dir() => self.place(&mut Vec::new(), key, root)?,
+            Ok(root) => {
+                self.lose(Some(b"/"), Cause::UnreadableInode(Error::NotADirectory("/".into())));
+                self.inodes.insert(key, root);
+            },
+            Err(e) => self.lose(Some(b"/"), Cause::UnreadableInode(e)),
+        }
+        Ok(())
+    }
+
+    /// Put every inode that was found in the inode table but not reached from the root in
+    /// lost+found. Directories whose parents were not found go first, so that as much of each
+    /// lost subtree as possible keeps its structure.
+    fn add_orphans(&mut self) -> io::Result<()> {
+        let unplaced: Vec<_> = self.inodes.keys().copied().collect();
+        if unplaced.is_empty() {
+            return Ok(());
+        }
+        let mut lost_and_found = LOST_AND_FOUND.to_string();
+        for n in 1.. {
+            if !self.writer.exists(&lost_and_found) {
+                break;
+            }
+            lost_and_found = format!("{LOST_AND_FOUND}.{n}");
+        }
+        self.writer.add_dir(&lost_and_found, Attributes::new(0o700, 0, 0, self.sqfs.mod_time()))?;
+
+        let unplaced_dirs: HashSet<u32> = unplaced.iter()
+            .map(|r| &self.inodes[r])
+            .filter(|i| i.is_dir())
+            .map(Inode::inode_number)
+            .collect();
+        let parent = |i: &Inode| match &i.extended_info {
+            InodeExtendedInfo::BasicDir(d) => Some(d.parent_inode),
+            InodeExtendedInfo::ExtDir(d) => Some(d.parent_inode),
+            _ => None,
+        };
+        let (top, rest): (Vec<_>, Vec<_>) = unplaced.into_iter()
+            .partition(|r| parent(&self.inodes[r]).is_some_and(|p| !unplaced_dirs.contains(&p)));
+        let (dirs, others): (Vec<_>, Vec<_>) = rest.into_iter().partition(|r| self.inodes[r].is_dir());
+        for inode_ref in top.into_iter().chain(dirs).chain(others) {
+            // Inodes in the subtree of a directory in lost+found have already been taken
+            let Some(inode) = self.inodes.remove(&inode_ref) else {
+                continue;
+            };
+            let mut path = format!("{lost_and_found}/#{}", inode.inode_number());
+            for n in 1.. {
+                if !self.writer.exists(&path) {
+                    break;
+                }
+                path = format!("{lost_and_found}/#{}.{n}", inode.inode_number());
+            }
+            let placed = self.placed.len();
+            self.place(&mut path.into_bytes(), inode_ref, inode)?;
+            if self.placed.len() > placed {
+                self.report.orphans += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy the inode that a directory entry refers to the new image at `path`, or link to it if
+    /// it has already been copied
+    fn place_ref(&mut self, path: &mut Vec<u8>, inode_ref: EntryReference) -> io::Result<()> {
+        let key = (inode_ref.location(), inode_ref.offset());
+        if let Some((target, is_dir)) = self.placed.get(&key) {
+            if *is_dir {
+                self.lose(Some(path), Cause::RepeatedDirectory);
+                return Ok(());
+            }
+            let target = target.clone();
+            return self.writer.add_hard_link_bytes(path, &target);
+        }
+        match self.take_inode(inode_ref) {
+            Ok(inode) => self.place(path, key, inode),
+            Err(e) => {
+                self.lose(Some(path), Cause::UnreadableInode(e));
+                Ok(())
+            },
+        }
+    }
+
+    /// Copy an inode to the new image at `path`, along with the contents of a directory
+    fn place(&mut self, path: &mut Vec<u8>, key: (u64, u16), inode: Inode) -> io::Result<()> {
+        let attrs = match Attributes::from_inode(&inode, self.sqfs) {
+            Ok(a) => a,
+            Err(e) => {
+                self.lose(Some(path), Cause::UnreadableAttributes(e.into()));
+                Attributes {
+                    uid: inode.uid(self.sqfs).unwrap_or(0),
+                    gid: inode.gid(self.sqfs).unwrap_or(0),
+                    xattrs: inode.xattrs(self.sqfs).unwrap_or_default(),
+                    ..Attributes::new(inode.permissions(), 0, 0, inode.mtime())
+                }
+            },
+        };
+        let added = if inode.is_file() {
+            self.add_file(path, attrs, &inode)
+        } else {
+            self.writer.add_special_inode_bytes(path, attrs, &inode).map(|_| true)
+        };
+        match added {
+            Ok(true) => {},
+            Ok(false) => return Ok(()),
+            // The writer rejects what it cannot store before writing anything
+            Err(e) if matches!(e.kind(), io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData) => {
+                self.lose(Some(path), Cause::Rejected(e.into()));
+                return Ok(());
+            },
+            Err(e) => return Err(e),
+        }
+        let path_str = if path.is_empty() { b"/".to_vec() } else { path.clone() };
+        self.placed.insert(key, (path_str.clone(), inode.is_dir()));
+        let number = inode.inode_number();
+        if number != 0 && self.numbers.insert(number) {
+            self.writer.set_inode_number_bytes(&path_str, number)?;
+        }
+        if inode.is_dir() {
+            self.copy_dir(path, &inode)?;
+        }
+        Ok(())
+    }
+
+    /// Copy a regular file, replacing any blocks of its data that cannot be read with zeros.
+    /// Returns whether the file could be copied at all.
+    fn add_file(&mut self, path: &[u8], attrs: Attributes, inode: &Inode) -> io::Result<bool> {
+        let reader = match self.sqfs.open_file_inode(inode) {
+            Ok(r) => r,
+            Err(e) => {
+                self.lose(Some(path), Cause::UnreadableData { offset: 0, error: e });
+                return Ok(false);
+            },
+        };
+        let mut data = SalvagedData {
+            reader,
+            file_size: inode.file_size().unwrap_or(0),
+            block_size: self.sqfs.block_size().into(),
+            pos: 0,
+            block: Vec::new(),
+            block_pos: 0,
+            losses: Vec::new(),
+        };
+        let added = self.writer.add_file_bytes(path, attrs, &mut data);
+        for (offset, error) in data.losses {
+            self.lose(Some(path), Cause::UnreadableData { offset, error });
+        }
+        added.map(|_| true)
+    }
+
+    /// Copy the contents of a directory that has been added at `dir_path`
+    fn copy_dir(&mut self, dir_path: &mut Vec<u8>, dir: &Inode) -> io::Result<()> {
+        let entries = self.sqfs.check_walk_depth(dir_path)
+            .map_err(Error::from)
+            .and_then(|_| self.sqfs.read_dir_inode_lenient(dir));
+        let entries = match entries {
+            Ok(entries) => entries,
+            Err(e) => {
+                let path: &[u8] = if dir_path.is_empty() { b"/" } else { dir_path };
+                self.lose(Some(path), Cause::UnreadableDirectory(e));
+                return Ok(());
+            },
+        };
+        for name in entries.invalid_names() {
+            let parent_len = dir_path.len();
+            dir_path.push(b'/');
+            dir_path.extend_from_slice(name);
+            self.lose(Some(dir_path), Cause::InvalidName);
+            dir_path.truncate(parent_len);
+        }
+        for e in entries.collect::<Vec<_>>() {
+            let parent_len = dir_path.len();
+            dir_path.push(b'/');
+            dir_path.extend_from_slice(e.file_name_bytes());
+            self.place_ref(dir_path, e.inode_ref())?;
+            dir_path.truncate(parent_len);
+        }
+        Ok(())
+    }
+}
+
+/// A reader over a file's data that replaces each block that cannot be read with zeros
+struct SalvagedData<F: Read + Seek> {
+    reader: F,
+    file_size: u64,
+    block_size: u64,
+    pos: u64,
+    block: Vec<u8>,
+    block_pos: usize,
+    // The offset of each block that could not be read, and why
+    losses: Vec<(u64, Error)>,
+}
+
+impl<F: Read + Seek> Read for SalvagedData<F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.block_pos == self.block.len() {
+            if self.pos >= self.file_size {
+                return Ok(0);
+            }
+            let len = min(self.block_size, self.file_size - self.pos);
+            self.block.resize(len as usize, 0);
+            let read = self.reader.seek(SeekFrom::Start(self.pos))
+                .and_then(|_| self.reader.read_exact(&mut self.block));
+            if let Err(e) = read {
+                self.block.fill(0);
+                self.losses.push((self.pos, e.into()));
+            }
+            self.pos += len;
+            self.block_pos = 0;
+        }
+        let len = min(buf.len(), self.block.len() - self.block_pos);
+        buf[..len].copy_from_slice(&self.block[self.block_pos..self.block_pos + len]);
+        self.block_pos += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::squashfs::test_util::build;
+
+    /// The contents of every regular file in the image, by path
+    fn collect_files<R: Read + Seek>(sqfs: &mut SquashFS<R>, path: &str, out: &mut BTreeMap<String, Vec<u8>>) -> crate::Result<()> {
+        let inode = sqfs.inode_from_path(path)?;
+        if inode.is_dir() {
+            let names: Vec<String> = sqfs.read_dir_inode(&inode)?.map(|e| e.file_name()).collect();
+            for n in names {
+                collect_files(sqfs, &format!("{}/{n}", path.trim_end_matches('/')), out)?;
+            }
+        } else if inode.is_file() {
+            let mut data = Vec::new();
+            sqfs.open_file_inode(&inode)?.read_to_end(&mut data)?;
+            out.insert(path.to_string(), data);
+        }
+        Ok(())
+    }
+
+    /// Damage the metadata block at `offset` so that it cannot be decompressed
+    fn damage_block(image: &mut [u8], offset: u64) {
+        let offset = offset as usize;
+        let size = u16::from_le_bytes([image[offset], image[offset + 1]]) & 0x7FFF;
+        image[offset..offset + 2].copy_from_slice(&size.to_le_bytes());
+        image[offset + 2..offset + 2 + usize::from(size)].fill(0xFF);
+    }
+
+    fn salvage_image(image: Vec<u8>) -> crate::Result<(Report, BTreeMap<String, Vec<u8>>)> {
+        let mut sqfs = SquashFS::new(Cursor::new(image))?;
+        let (w, report) = salvage(&mut sqfs, Cursor::new(Vec::new()), WriterOptions::default())?;
+        let mut files = BTreeMap::new();
+        collect_files(&mut SquashFS::new(Cursor::new(w.into_inner()))?, "/", &mut files)?;
+        Ok((report, files))
+    }
+
+    #[test]
+    fn test_salvage() -> crate::Result<()> {
+        let attrs = Attributes::new(0o644, 0, 0, 0);
+        let big: Vec<u8> = (0..300_000u32).map(|n| (n % 251) as u8).collect();
+        let image = build(128 * 1024, |w| {
+            // Enough files for an inode table of more than one block
+            for i in 0..400 {
+                w.add_file(format!("/d/f{i}"), attrs.clone(), format!("file {i}").as_bytes())?;
+            }
+            w.add_file("/big", attrs.clone(), &big[..])
+        })?;
+
+        let mut sqfs = SquashFS::new(Cursor::new(image.clone()))?;
+        let sb = sqfs.superblock();
+        let (inode_table, dir_table) = (sb.inode_table, sb.dir_table);
+        let mut original = BTreeMap::new();
+        collect_files(&mut sqfs, "/", &mut original)?;
+        let mut by_number = HashMap::new();
+        for (path, data) in &original {
+            by_number.insert(format!("#{}", sqfs.inode_from_path(path)?.inode_number()), data.clone());
+        }
+        let big_block = sqfs.inode_from_path("/big")?.data_blocks().unwrap().0 as usize;
+        // Every file that was recovered has its original contents
+        let check_contents = |files: &BTreeMap<String, Vec<u8>>| {
+            for (path, data) in files {
+                let name = path.rsplit('/').next().unwrap();
+                let expected = if name.starts_with('#') { &by_number[name] } else { &original[path] };
+                assert_eq!(data, expected, "{path}");
+            }
+        };
+
+        // An undamaged image is copied as it is
+        let (report, files) = salvage_image(image.clone())?;
+        assert!(report.losses.is_empty());
+        assert_eq!((report.inodes, report.orphans), (403, 0));
+        assert_eq!(files, original);
+
+        // Without the directory table, everything is found in the inode table and put in
+        // lost+found, and a damaged data block is replaced by zeros
+        let mut damaged = image.clone();
+        damage_block(&mut damaged, dir_table);
+        damaged[big_block + 10] ^= 0xFF;
+        let (report, files) = salvage_image(damaged)?;
+        assert_eq!(report.inodes, 403);
+        assert_eq!(files.len(), 401);
+        assert!(files.keys().all(|p| p.starts_with("/lost+found/#")));
+        assert!(report.losses.iter().any(|l| matches!(l.cause, Cause::UnreadableDirectory(_))));
+        let big_path = files.iter().find(|(_, d)| d.len() == big.len()).unwrap().0.clone();
+        let zeroed = &files[&big_path];
+        assert!(zeroed[..131072].iter().all(|b| *b == 0));
+        assert_eq!(zeroed[131072..], big[131072..]);
+        assert!(report.losses.iter().any(|l| matches!(l.cause, Cause::UnreadableData { offset: 0, .. })));
+        let mut files = files;
+        files.remove(&big_path);
+        check_contents(&files);
+
+        // Without the first block of the inode table, the tree is kept for the inodes that remain
+        let mut damaged = image.clone();
+        damage_block(&mut damaged, inode_table);
+        let (report, files) = salvage_image(damaged)?;
+        assert!(matches!(report.losses[0].cause, Cause::UnreadableInodeBlock { .. }));
+        assert!(report.losses.iter().any(|l| matches!(l.cause, Cause::UnreadableInode(_))));
+        assert_eq!(report.orphans, 0);
+        assert!(files.len() > 100 && files.len() < 401);
+        check_contents(&files);
+
+        // Without either, the scan finds the inodes that follow the damaged block on its own
+        let mut damaged = image;
+        damage_block(&mut damaged, inode_table);
+        damage_block(&mut damaged, dir_table);
+        let (report, files) = salvage_image(damaged)?;
+        assert!(report.orphans > 100);
+        assert!(files.len() > 100 && files.keys().all(|p| p.starts_with("/lost+found/#")));
+        check_contents(&files);
+        Ok(())
+    }
+
+    #[test]
+    fn test_salvage_invalid_name() -> crate::Result<()> {
+        // Only the entry with the invalid name is lost, and its file is found in lost+found
+        let (report, files) = salvage_image(crate::squashfs::test_util::invalid_name_image()?)?;
+        assert_eq!(report.losses.len(), 1, "{:?}", report.losses);
+        assert!(matches!(report.losses[0].cause, Cause::InvalidName));
+        assert_eq!(report.losses[0].path.as_deref(), Some(std::path::Path::new("/d/b/c")));
+        assert_eq!((report.inodes, report.orphans), (5, 1));
+        assert_eq!(files["/d/a"], b"a");
+        assert_eq!(files["/d/e"], b"e");
+        assert_eq!(files.values().filter(|d| d.as_slice() == b"b-c").count(), 1);
+        Ok(())
+    }
+}
@@ -3,14 +3,19 @@
 
 use std::fs::File;
 use std::io::{self, BufReader, Read, Seek, SeekFrom};
-use std::path::{Component, Path};
+use std::path::{Component, Path, PathBuf};
 use std::boxed::Box;
+use std::ffi::OsString;
 
 use super::filedata::FileDataReader;
 use super::metadata::{self, MetadataProvider};
 use super::block::FragmentBlockCache;
+use super::limits::{Budget, Limits};
+use super::path;
 use super::readermux::{ReaderMux, ReaderClient};
-use super::superblock::Superblock;
+use super::superblock::{Compressor, Superblock};
+use super::walk::{Visited, Walk};
+use crate::{Error, MetadataTable, Result};
 
 /// The top-level interface to a SquashFS filesystem. This struct can be used to look up Inodes,
 /// list directory contents, and open file data readers.
@@ -18,14 +23,16 @@ use super::superblock::Superblock;
 pub struct SquashFS<R:Read+Seek> {
     reader_mux: Box<ReaderMux<R>>,
     frag_cache: FragmentBlockCache<ReaderClient<R>>,
-    md_provider: MetadataProvider<ReaderClient<R>>,
+    pub(crate) md_provider: MetadataProvider<ReaderClient<R>>,
     sb: Superblock,
+    budget: Budget,
     pub(crate) id_table: metadata::IdLookupTable,
+    pub(crate) xattr_table: Option<metadata::ExtendedAttributeLookupTable>,
 }
 
 impl SquashFS<BufReader<File>> {
     /// Open the contents of a filepath as a SquashFS, using a BufReader
-    pub fn open<P>(path: P) -> io::Result<Self>
+    pub fn open<P>(path: P) -> Result<Self>
     where P: AsRef<Path>
     {
         Self::new(BufReader::new(File::open(path)?))
@@ -34,93 +41,211 @@ impl SquashFS<BufReader<File>> {
 
 impl<R: Read + Seek> SquashFS<R> {
     /// Create a new SquashFS instance from the provided Reader
-    pub fn new(mut r: R) -> io::Result<Self>
+    pub fn new(r: R) -> Result<Self>
+    {
+        Self::new_with_limits(r, Limits::default())
+    }
+
+    /// Create a new SquashFS instance from the provided Reader, bounding the resources used to
+    /// read it by the given Limits
+    pub fn new_with_limits(mut r: R, limits: Limits) -> Result<Self>
     {
         r.seek(SeekFrom::Start(0))?;
         let sb = Superblock::read(&mut r)?;
-        let id_table = metadata::IdLookupTable::read(&mut r, &sb)?;
+        let id_table = metadata::IdLookupTable::read(&mut r, &sb, &limits)
+            .map_err(Error::in_metadata(MetadataTable::Id, sb.id_table))?;
+        let xattr_table = metadata::ExtendedAttributeLookupTable::read(&mut r, &sb, &limits)
+            .map_err(Error::in_metadata(MetadataTable::Xattr, sb.xattr_table))?;
+        let budget = Budget::new(limits);
         let mut reader_mux = Box::new(ReaderMux::new(r));
-        let frag_cache = FragmentBlockCache::new(reader_mux.client(), sb.compressor);
-        let md_provider = MetadataProvider::new(reader_mux.client(), &sb);
-        Ok(SquashFS { reader_mux, frag_cache, md_provider, sb, id_table })
+        let frag_cache = FragmentBlockCache::new(reader_mux.client(), sb.compressor, budget.clone());
+        let md_provider = MetadataProvider::new(reader_mux.client(), &sb, budget.clone());
+        Ok(SquashFS { reader_mux, frag_cache, md_provider, sb, budget, id_table, xattr_table })
+    }
+
+    /// The Limits that bound the resources used to read this SquashFS
+    pub fn limits(&self) -> &Limits {
+        self.budget.limits()
+    }
+
+    /// The compressor used for the data and metadata blocks of this SquashFS
+    pub fn compressor(&self) -> Compressor {
+        self.sb.compressor
+    }
+
+    /// The size of the data blocks that files are divided into
+    pub fn block_size(&self) -> u32 {
+        self.sb.block_size
+    }
+
+    /// The modification time recorded in the superblock, in seconds since the UNIX epoch
+    pub fn mod_time(&self) -> u32 {
+        self.sb.mod_time
     }
 
     /// Retrieve an iterator that walks the dirents within a directory specified by the given
     /// path. path must refer to an existing directory or this function returns an error.
-    pub fn read_dir<P>(&mut self, path: P) -> io::Result<ReadDir<std::vec::IntoIter<metadata::DirTable>>>
+    pub fn read_dir<P>(&mut self, path: P) -> Result<ReadDir<std::vec::IntoIter<metadata::DirTable>>>
     where P: AsRef<Path>
     {
-        let inode = self.inode_from_path(path)?;
-        self.read_dir_inode(&inode)
+        let inode = self.inode_from_path(&path)?;
+        self.read_dir_inode(&inode).map_err(|e| e.at_path(path.as_ref()))
+    }
+
+    /// Retrieve an iterator that walks every entry beneath the directory at the given path, with
+    /// the contents of each subdirectory right after the subdirectory itself. A directory that
+    /// appears more than once in the tree is returned as an error rather than walked again.
+    pub fn walk<P>(&mut self, path: P) -> Result<Walk<'_, R>>
+    where P: AsRef<Path>
+    {
+        Walk::new(self, path.as_ref())
     }
 
     /// Retrieve an iterator that walks the dirents within a directory specified by the given
     /// DirEntry.
-    pub fn read_dir_dirent(&mut self, dir_entry: &DirEntry) -> io::Result<ReadDir<std::vec::IntoIter<metadata::DirTable>>>
+    pub fn read_dir_dirent(&mut self, dir_entry: &DirEntry) -> Result<ReadDir<std::vec::IntoIter<metadata::DirTable>>>
     {
         let inode = self.inode_from_entryref(dir_entry.inode_ref)?;
         self.read_dir_inode(&inode)
     }
 
     /// Retrieve an iterator that walks the dirents within a directory specified by the given
-    /// Inode.
-    pub fn read_dir_inode(&mut self, inode: &metadata::Inode) -> io::Result<ReadDir<std::vec::IntoIter<metadata::DirTable>>>
+    /// Inode. A directory with an entry named `.` or `..`, or whose name contains a `/` or a NUL
+    /// byte, is returned as [`Error::InvalidName`].
+    pub fn read_dir_inode(&mut self, inode: &metadata::Inode) -> Result<ReadDir<std::vec::IntoIter<metadata::DirTable>>>
+    {
+        let dir = self.read_dir_inode_lenient(inode)?;
+        match dir.invalid_names().first() {
+            Some(name) => Err(Error::InvalidName(name.clone())),
+            None => Ok(dir),
+        }
+    }
+
+    /// Like [`read_dir_inode`](Self::read_dir_inode), but an entry with an invalid name is left
+    /// out rather than failing the whole directory. The names that were left out are available
+    /// from [`ReadDir::invalid_names`].
+    pub fn read_dir_inode_lenient(&mut self, inode: &metadata::Inode) -> Result<ReadDir<std::vec::IntoIter<metadata::DirTable>>>
     {
         // TODO: This method has some redundancy. Look at refactoring read_for_inode
         let entry_ref = metadata::DirTable::entryref_from_inode(inode)?;
-        let mut reader = self.md_provider.dir_reader(entry_ref)?;
-        let dir_tables = metadata::DirTable::read_for_inode(&mut reader, &inode)?;
-        Ok(ReadDir::new(dir_tables.into_iter()))
+        let corrupt = Error::in_metadata(MetadataTable::Directory, self.sb.dir_table.saturating_add(entry_ref.location()));
+        let mut dir_tables = self.md_provider.dir_reader(entry_ref)
+            .and_then(|mut reader| metadata::DirTable::read_for_inode(&mut reader, inode, self.budget.limits()))
+            .map_err(corrupt)?;
+        let invalid_names = dir_tables.iter_mut().flat_map(|t| std::mem::take(&mut t.invalid_names)).collect();
+        Ok(ReadDir::new(dir_tables.into_iter(), invalid_names))
     }
 
     /// Create an IO reader for the contents of the file specified by the given path
-    pub fn open_file<P>(&mut self, path: P) -> io::Result<FileDataReader<ReaderClient<R>>>
+    pub fn open_file<P>(&mut self, path: P) -> Result<FileDataReader<ReaderClient<R>>>
     where P: AsRef<Path>
     {
-        let inode = self.inode_from_path(path)?;
-        self.open_file_inode(&inode)
+        let inode = self.inode_from_path(&path)?;
+        self.open_file_inode(&inode).map_err(|e| e.at_path(path.as_ref()))
     }
 
     /// Create an IO reader for the contents of the file specified by the given DirEntry
-    pub fn open_file_dirent<P>(&mut self, dir_entry: &DirEntry) -> io::Result<FileDataReader<ReaderClient<R>>>
+    pub fn open_file_dirent<P>(&mut self, dir_entry: &DirEntry) -> Result<FileDataReader<ReaderClient<R>>>
     {
         let inode = self.inode_from_entryref(dir_entry.inode_ref)?;
         self.open_file_inode(&inode)
     }
 
     /// Create an IO reader for the contents of the file specified by the given Inode
-    pub fn open_file_inode(&mut self, inode: &metadata::Inode) -> io::Result<FileDataReader<ReaderClient<R>>> {
+    pub fn open_file_inode(&mut self, inode: &metadata::Inode) -> Result<FileDataReader<ReaderClient<R>>> {
         let reader = self.reader_mux.client();
-        Ok(FileDataReader::from_inode(reader, &self.md_provider, &self.sb, &mut self.frag_cache, inode)?.unwrap())
+        FileDataReader::from_inode(reader, &self.md_provider, &self.sb, &mut self.frag_cache, &self.budget, inode)?
+            .ok_or(Error::InodeNotAFile(inode.inode_number()))
+    }
+
+    /// Open an image that is stored as a file within this one. The nested image reads through
+    /// this SquashFS's reader, but remains usable after this SquashFS is dropped. It is read with
+    /// the same Limits as this one.
+    pub fn open_nested<P>(&mut self, path: P) -> Result<SquashFS<FileDataReader<ReaderClient<R>>>>
+    where P: AsRef<Path>
+    {
+        SquashFS::new_with_limits(self.open_file(path)?, *self.limits())
+    }
+
+    /// Check that a walk of the tree may descend into the directory at `dir_path`, so that a
+    /// corrupt image cannot make a walk recurse without end
+    pub(crate) fn check_walk_depth(&self, dir_path: &[u8]) -> io::Result<()> {
+        self.limits().check_depth(dir_path.iter().filter(|&&b| b == b'/').count())
+    }
+
+    /// Read the entries of the directory at `dir_path` for a recursive walk of the tree. Fails
+    /// beyond the depth limit, or if the walk has already entered the directory's listing, so that
+    /// a corrupt image cannot make the walk recurse without end.
+    pub(crate) fn read_walk_dir(&mut self, visited: &mut Visited, dir_path: &[u8], dir: &metadata::Inode) -> io::Result<Vec<DirEntry>> {
+        self.check_walk_depth(dir_path)?;
+        let entries: Vec<_> = self.read_dir_inode(dir)?.collect();
+        if !visited.enter(dir, &entries)? {
+            return Err(Error::DirectoryLoop(PathBuf::from(path::os_str_from_bytes(dir_path).into_owned())).into());
+        }
+        Ok(entries)
+    }
+
+    pub(crate) fn superblock(&self) -> &Superblock {
+        &self.sb
+    }
+
+    /// Read every entry of the fragment table
+    pub(crate) fn fragment_entries(&mut self) -> io::Result<Vec<metadata::FragmentEntry>> {
+        let mut reader = self.reader_mux.client();
+        Ok(metadata::FragmentLookupTable::read(&mut reader, &self.sb, self.budget.limits())?.lu_table.entries)
+    }
+
+    /// A reader over the underlying image, without decompression
+    pub(crate) fn raw_reader(&mut self) -> ReaderClient<R> {
+        self.reader_mux.client()
+    }
+
+    /// Read bytes directly from the underlying image, without decompression
+    pub(crate) fn read_raw(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let mut reader = self.reader_mux.client();
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read_exact(buf)
     }
 
     /// Retrieve the root Inode of the SquashFS. This corresponds to the '/' directory
-    pub fn root_inode(&mut self) -> io::Result<metadata::Inode> {
-        let mut reader = self.md_provider.inode_reader(self.sb.root_inode)?;
-        metadata::Inode::read(&mut reader, self.sb.block_size)
+    pub fn root_inode(&mut self) -> Result<metadata::Inode> {
+        self.inode_from_entryref(self.sb.root_inode)
     }
 
     /// Retrieve the Inode specified by SquashFS metadata Entry Reference
-    pub fn inode_from_entryref(&mut self, inode_ref: metadata::EntryReference) -> io::Result<metadata::Inode> {
-        let mut reader = self.md_provider.inode_reader(inode_ref)?;
-        metadata::Inode::read(&mut reader, self.sb.block_size)
+    pub fn inode_from_entryref(&mut self, inode_ref: metadata::EntryReference) -> Result<metadata::Inode> {
+        self.md_provider.inode_reader(inode_ref)
+            .and_then(|mut reader| metadata::Inode::read(&mut reader, self.sb.block_size, self.budget.limits()))
+            .map_err(Error::in_metadata(MetadataTable::Inode, self.sb.inode_table.saturating_add(inode_ref.location())))
+    }
+
+    /// Retrieve the Inode with the given inode number. This requires the SquashFS to have an NFS
+    /// export table.
+    pub fn inode_from_number(&mut self, inode_number: u32) -> Result<metadata::Inode> {
+        let mut reader = self.reader_mux.client();
+        let inode_ref = metadata::ExportLookupTable::read_one(&mut reader, &self.md_provider, &self.sb, inode_number)
+            .map_err(Error::in_metadata(MetadataTable::Export, self.sb.export_table))?;
+        self.inode_from_entryref(inode_ref)
     }
 
     /// Retreive the Inode specified by the given path
-    pub fn inode_from_path<P>(&mut self, path: P) -> io::Result<metadata::Inode>
+    pub fn inode_from_path<P>(&mut self, path: P) -> Result<metadata::Inode>
     where P: AsRef<Path>
     {
+        let path = path.as_ref();
+        self.limits().check_depth(path.components().filter(|c| matches!(c, Component::Normal(_))).count())?;
         let mut inode = self.root_inode()?;
-        for comp in path.as_ref().components() {
+        for comp in path.components() {
             inode = match comp {
                 Component::RootDir => self.root_inode()?,
                 Component::Normal(n) => {
-                    self.read_dir_inode(&inode)?.find(|e| n == e.file_name().as_str())
-                        .map(|e| self.inode_from_entryref(e.inode_ref()).ok())
-                        .flatten()
-                        .ok_or(io::Error::from(io::ErrorKind::NotFound))?
+                    let entry = self.read_dir_inode(&inode)
+                        .map_err(|e| e.at_path(path))?
+                        .find(|e| e.file_name_bytes() == n.as_encoded_bytes())
+                        .ok_or_else(|| Error::NotFound(path.to_path_buf()))?;
+                    self.inode_from_entryref(entry.inode_ref())?
                 },
-                _ => { return Err(io::Error::new(io::ErrorKind::InvalidData, "Error parsing filepath"))}
+                _ => { return Err(Error::InvalidPath(path.to_path_buf())) }
             };
         }
         Ok(inode)
@@ -134,25 +259,61 @@ pub struct DirEntry {
     inner: metadata::DirEntry,
     inode_ref: metadata::EntryReference,
     inode_num: u32,
+    layer: usize,
 }
 
 impl DirEntry {
     fn new(dt_start: u64, dt_inode_num: u32, inner: metadata::DirEntry) -> Self {
         let inode_ref = metadata::EntryReference::new(dt_start, inner.offset);
         let inode_num = dt_inode_num.wrapping_add_signed(inner.inode_offset as i32);
-        Self { inner, inode_ref, inode_num }
+        Self { inner, inode_ref, inode_num, layer: 0 }
+    }
+
+    pub(crate) fn with_layer(mut self, layer: usize) -> Self {
+        self.layer = layer;
+        self
     }
 
+    /// The name of this entry. Any bytes of the name that are not valid UTF-8 are replaced with
+    /// U+FFFD, so use [`file_name_os`](Self::file_name_os) or
+    /// [`file_name_bytes`](Self::file_name_bytes) where the exact name matters.
     pub fn file_name(&self) -> String {
-        self.inner.name.to_str().unwrap().to_string()
+        self.inner.name.to_string_lossy().into_owned()
+    }
+
+    /// The name of this entry as an OsString. On Unix, this is exactly the name stored in the
+    /// image, whatever its encoding. Elsewhere, bytes that are not valid UTF-8 are replaced with
+    /// U+FFFD.
+    pub fn file_name_os(&self) -> OsString {
+        path::os_str_from_bytes(self.file_name_bytes()).into_owned()
+    }
+
+    /// The name of this entry exactly as it is stored in the image
+    pub fn file_name_bytes(&self) -> &[u8] {
+        self.inner.name.as_bytes()
     }
 
     pub fn inode_ref(&self) -> metadata::EntryReference {
         self.inode_ref
     }
 
-    #[allow(dead_code)]
-    fn inode_num(&self) -> u32 {
+    /// The index of the layer that this entry was found in, when listing a
+    /// [`UnionFS`](super::union::UnionFS). Entries listed from a single SquashFS are always in layer 0.
+    pub fn layer(&self) -> usize {
+        self.layer
+    }
+
+    pub(crate) fn is_char_dev(&self) -> bool {
+        matches!(self.inner.inode_type, metadata::InodeType::BasicCharDev | metadata::InodeType::ExtCharDev)
+    }
+
+    /// The type of the inode, as recorded in the directory entry
+    pub(crate) fn inode_type(&self) -> metadata::InodeType {
+        self.inner.inode_type
+    }
+
+    /// The number of the inode, as recorded in the directory entry
+    pub(crate) fn inode_num(&self) -> u32 {
         self.inode_num
     }
 }
@@ -167,14 +328,22 @@ pub struct ReadDir<TI> {
     cur_iter: Option<std::vec::IntoIter<metadata::DirEntry>>,
     cur_start: u64,
     cur_inode_num: u32,
+    invalid_names: Vec<Vec<u8>>,
 }
 
 impl<TI> ReadDir<TI>
 where TI: Iterator<Item = metadata::DirTable>
 {
-    fn new(table_iter: TI) -> Self
+    fn new(table_iter: TI, invalid_names: Vec<Vec<u8>>) -> Self
     {
-        ReadDir { table_iter, cur_iter: None, cur_start: 0, cur_inode_num: 0 }
+        ReadDir { table_iter, cur_iter: None, cur_start: 0, cur_inode_num: 0, invalid_names }
+    }
+
+    /// The names of entries that were left out of a directory read with
+    /// [`SquashFS::read_dir_inode_lenient`] because they are `.` or `..`, or contain a `/` or a NUL
+    /// byte, so they could name something outside of the directory
+    pub fn invalid_names(&self) -> &[Vec<u8>] {
+        &self.invalid_names
     }
 }
 
@@ -185,28 +354,13 @@ where TI: Iterator<Item = metadata::DirTable>
 
     fn next(&mut self) -> Option<Self::Item> {
         loop {
-            if self.cur_iter.is_none() {
-                match self.table_iter.next() {
-                    None => return None,
-                    Some(t) => {
-                        self.cur_start = t.start.into();
-                        self.cur_inode_num = t.inode_number.into();
-                        self.cur_iter = Some(t.entries.into_iter());
-                    }
-                }
-            }
-            match &mut self.cur_iter {
-                Some(i) => {
-                    match i.next() {
-                        Some(e) => return Some(DirEntry::new(self.cur_start, self.cur_inode_num, e)),
-                        None => {
-                            self.cur_iter = None;
-                            self.cur_start = 0;
-                        }
-                    }
-                },
-                None => panic!("cur_iter cannot be None!"),
+            if let Some(e) = self.cur_iter.as_mut().and_then(|i| i.next()) {
+                return Some(DirEntry::new(self.cur_start, self.cur_inode_num, e));
             }
+            let t = self.table_iter.next()?;
+            self.cur_start = t.start.into();
+            self.cur_inode_num = t.inode_number;
+            self.cur_iter = Some(t.entries.into_iter());
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,268 @@
+//! Creation of new images from part of an existing image
+//!
+//! [`export_subtree`] builds a new image whose root is a directory in an existing image. Data
+//! blocks are copied without being decompressed, so only the metadata tables are rebuilt and the
+//! tail-ends of files are re-packed into new fragment blocks. The new image uses the compressor
+//! and block size of the source image.
+//!
+//! Include and exclude patterns select which entries are copied. Patterns are paths relative to
+//! the root of the subtree, and each component may contain the wildcards `*` and `?`, which do not
+//! match `/`. A pattern matches an entry at that path and everything beneath it.
+//!
+//! ```no_run
+//! use std::fs::File;
+//! use std::io::{self, BufWriter};
+//! use squinter::squashfs::SquashFS;
+//! use squinter::squashfs::subtree::{export_subtree, SubtreeOptions};
+//!
+//! fn extract_firmware() -> io::Result<()> {
+//!     let mut sqfs = SquashFS::open("rootfs.squashfs")?;
+//!     let opts = SubtreeOptions {
+//!         exclude: vec!["*.txt".into()],
+//!         ..Default::default()
+//!     };
+//!     let out = BufWriter::new(File::create("firmware.squashfs")?);
+//!     export_subtree(&mut sqfs, "/usr/lib/firmware", out, &opts)?;
+//!     Ok(())
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+
+use super::walk::Visited;
+use super::writer::{split_path, SquashFSWriter, WriterOptions};
+use super::{Inode, SquashFS};
+
+/// Options that control which entries [`export_subtree`] copies, and how the new image is built
+#[derive(Debug, Clone, Default)]
+pub struct SubtreeOptions {
+    /// If not empty, only entries matching one of these patterns (and the directories leading to
+    /// them) are copied
+    pub include: Vec<String>,
+    /// Entries matching any of these patterns are not copied
+    pub exclude: Vec<String>,
+    /// The modification time recorded in the superblock. The source image's time is used if None.
+    pub mod_time: Option<u32>,
+    /// Store file tail-ends in their own data blocks instead of packing them into fragment blocks
+    pub no_fragments: bool,
+}
+
+/// Write a new image containing the directory at `path` and the entries beneath it, which become
+/// the root of the new image. Returns the Writer once the new image is complete.
+pub fn export_subtree<R, W, P>(sqfs: &mut SquashFS<R>, path: P, w: W, opts: &SubtreeOptions) -> io::Result<W>
+where R: Read + Seek, W: Write + Seek, P: AsRef<Path>
+{
+    let root = sqfs.inode_from_path(path)?;
+    if !root.is_dir() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Subtree root is not a directory"));
+    }
+    let filter = PathFilter::new(opts)?;
+    let writer_opts = WriterOptions {
+        compressor: sqfs.compressor(),
+        block_size: sqfs.block_size(),
+        mod_time: opts.mod_time.unwrap_or(sqfs.mod_time()),
+        no_fragments: opts.no_fragments,
+        export_table: sqfs.superblock().export_table != u64::MAX,
+        ..Default::default()
+    };
+    let mut writer = SquashFSWriter::new(w, writer_opts)?;
+    writer.add_inode_bytes(b"/", sqfs, &root)?;
+    copy_dir_contents(sqfs, &mut writer, &filter, &mut HashMap::new(), &mut Visited::default(), &mut Vec::new(), &root)?;
+    writer.finish()
+}
+
+fn copy_dir_contents<R: Read + Seek, W: Write + Seek>(sqfs: &mut SquashFS<R>, writer: &mut SquashFSWriter<W>,
+    filter: &PathFilter, links: &mut HashMap<u32, Vec<u8>>, visited: &mut Visited, dir_path: &mut Vec<u8>, dir: &Inode) -> io::Result<()>
+{
+    let entries = sqfs.read_walk_dir(visited, dir_path, dir)?;
+    for e in entries {
+        let parent_len = dir_path.len();
+        dir_path.push(b'/');
+        dir_path.extend_from_slice(e.file_name_bytes());
+        let inode = sqfs.inode_from_entryref(e.inode_ref())?;
+        let names = split_path(dir_path)?;
+        match filter.select(&names) {
+            Selection::Excluded => {},
+            Selection::Ancestor if inode.is_dir() => {
+                // Only copy this directory if something beneath it is selected
+                writer.add_inode_bytes(dir_path, sqfs, &inode)?;
+                copy_dir_contents(sqfs, writer, filter, links, visited, dir_path, &inode)?;
+                if writer.is_empty_dir_bytes(dir_path) {
+                    writer.remove_bytes(dir_path)?;
+                }
+            },
+            Selection::Ancestor => {},
+            Selection::Included => match links.get(&inode.inode_number()) {
+                Some(target) => writer.add_hard_link_bytes(dir_path, target)?,
+                None => {
+                    if inode.is_file() {
+                        writer.add_file_raw_bytes(dir_path, sqfs, &inode)?;
+                    } else {
+                        writer.add_inode_bytes(dir_path, sqfs, &inode)?;
+                    }
+                    if inode.is_dir() {
+                        copy_dir_contents(sqfs, writer, filter, links, visited, dir_path, &inode)?;
+                    } else if inode.link_count() > 1 {
+                        links.insert(inode.inode_number(), dir_path.clone());
+                    }
+                },
+            },
+        }
+        dir_path.truncate(parent_len);
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum Selection {
+    /// The entry and everything beneath it is copied
+    Included,
+    /// The entry is not selected, but entries beneath it may be
+    Ancestor,
+    /// Neither the entry nor anything beneath it is copied
+    Excluded,
+}
+
+#[derive(Debug)]
+struct PathFilter {
+    include: Vec<Vec<Vec<u8>>>,
+    exclude: Vec<Vec<Vec<u8>>>,
+}
+
+impl PathFilter {
+    fn new(opts: &SubtreeOptions) -> io::Result<Self> {
+        let parse = |patterns: &[String]| patterns.iter()
+            .map(|p| split_path(p.as_bytes()))
+            .collect::<io::Result<Vec<_>>>();
+        Ok(PathFilter { include: parse(&opts.include)?, exclude: parse(&opts.exclude)? })
+    }
+
+    fn select(&self, names: &[Vec<u8>]) -> Selection {
+        if self.exclude.iter().any(|p| pattern_matches(p, names)) {
+            return Selection::Excluded;
+        }
+        if self.include.is_empty() || self.include.iter().any(|p| pattern_matches(p, names)) {
+            return Selection::Included;
+        }
+        // Entries that lead to an include pattern must be visited to find what they contain
+        if self.include.iter().any(|p| p.len() > names.len() && pattern_matches(&p[..names.len()], names)) {
+            Selection::Ancestor
+        } else {
+            Selection::Excluded
+        }
+    }
+}
+
+/// Returns true if `names` is the path matched by `pattern`, or lies beneath it
+fn pattern_matches(pattern: &[Vec<u8>], names: &[Vec<u8>]) -> bool {
+    pattern.len() <= names.len() && pattern.iter().zip(names).all(|(p, n)| wildcard_matches(p, n))
+}
+
+/// Match a single path component against a pattern containing `*` and `?` wildcards
+fn wildcard_matches(pattern: &[u8], name: &[u8]) -> bool {
+    // Position to resume from when a mismatch follows a '*': (pattern index, name index)
+    let mut backtrack = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, n));
+                p += 1;
+            },
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            },
+            _ => match backtrack {
+                Some((bp, bn)) => {
+                    // Let the '*' consume one more byte of the name
+                    backtrack = Some((bp, bn + 1));
+                    p = bp + 1;
+                    n = bn + 1;
+                },
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::squashfs::test_util::{list_tree, open};
+    use crate::squashfs::writer::Attributes;
+
+    fn source() -> io::Result<SquashFS<Cursor<Vec<u8>>>> {
+        let attrs = Attributes::new(0o644, 0, 0, 0);
+        let big: Vec<u8> = (0..10000u32).map(|x| (x * 7 % 251) as u8).collect();
+        open(4096, |w| {
+            w.add_file("/etc/passwd", attrs.clone(), &b"root"[..])?;
+            w.add_file("/lib/firmware/a.bin", attrs.clone(), &big[..])?;
+            w.add_file("/lib/firmware/b.bin", attrs.clone(), &big[..5000])?;
+            w.add_file("/lib/firmware/README.txt", attrs.clone(), &b"docs"[..])?;
+            w.add_file("/lib/firmware/vendor/c.bin", attrs.clone(), &big[..8192])?;
+            w.add_hard_link("/lib/firmware/vendor/d.bin", "/lib/firmware/b.bin")?;
+            w.add_dir("/lib/firmware/empty", Attributes::new(0o755, 0, 0, 0))
+        })
+    }
+
+    #[test]
+    fn test_export_subtree() -> io::Result<()> {
+        let mut src = source()?;
+        let out = export_subtree(&mut src, "/lib/firmware", Cursor::new(Vec::new()), &SubtreeOptions::default())?;
+        let mut sqfs = SquashFS::new(out)?;
+        let mut tree = Vec::new();
+        list_tree(&mut sqfs, "/", &mut tree)?;
+        assert_eq!(tree, [
+            "/README.txt 1 4", "/a.bin 1 10000", "/b.bin 2 5000", "/empty/ 755",
+            "/vendor/ 755", "/vendor/c.bin 1 8192", "/vendor/d.bin 2 5000",
+        ]);
+        for path in ["/a.bin", "/vendor/c.bin", "/vendor/d.bin"] {
+            let mut expected = Vec::new();
+            src.open_file(format!("/lib/firmware{path}"))?.read_to_end(&mut expected)?;
+            let mut actual = Vec::new();
+            sqfs.open_file(path)?.read_to_end(&mut actual)?;
+            assert_eq!(actual, expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_filters() -> io::Result<()> {
+        let mut src = source()?;
+        let opts = SubtreeOptions {
+            include: vec!["lib/firmware/*.bin".into(), "lib/firmware/vendor/c.*".into(), "etc".into()],
+            exclude: vec!["/lib/firmware/a*".into()],
+            no_fragments: true,
+            ..Default::default()
+        };
+        let mut sqfs = SquashFS::new(export_subtree(&mut src, "/", Cursor::new(Vec::new()), &opts)?)?;
+        let mut tree = Vec::new();
+        list_tree(&mut sqfs, "/", &mut tree)?;
+        assert_eq!(tree, [
+            "/etc/ 755", "/etc/passwd 1 4", "/lib/ 755", "/lib/firmware/ 755", "/lib/firmware/b.bin 1 5000",
+            "/lib/firmware/vendor/ 755", "/lib/firmware/vendor/c.bin 1 8192",
+        ]);
+        let mut data = Vec::new();
+        sqfs.open_file("/lib/firmware/b.bin")?.read_to_end(&mut data)?;
+        let mut expected = Vec::new();
+        src.open_file("/lib/firmware/b.bin")?.read_to_end(&mut expected)?;
+        assert_eq!(data, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_wildcard_matches() {
+        assert!(wildcard_matches(b"*.bin", b"a.bin"));
+        assert!(wildcard_matches(b"*", b""));
+        assert!(wildcard_matches(b"a*b*c", b"aXbYbZc"));
+        assert!(wildcard_matches(b"?.txt", b"a.txt"));
+        assert!(!wildcard_matches(b"?.txt", b"ab.txt"));
+        assert!(!wildcard_matches(b"*.bin", b"a.binx"));
+        assert!(!wildcard_matches(b"abc", b"ab"));
+    }
+}
@@ -1,15 +1,19 @@
 use std::io;
-use std::io::Read;
+use std::io::{Read, Write};
 
 use bitflags::bitflags;
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
 use super::metadata::EntryReference;
+use crate::Error;
 
 #[allow(dead_code)]
 pub const MAGIC: u32 = 0x73717368;
 
+/// The on-disk size of the Superblock
+pub const SUPERBLOCK_SIZE: u64 = 96;
+
 #[derive(Debug)]
 pub struct Superblock {
     pub magic: u32,
@@ -49,7 +53,7 @@ bitflags! {
     }
 }
 
-#[derive(Debug, IntoPrimitive, TryFromPrimitive, Clone, Copy)]
+#[derive(Debug, IntoPrimitive, TryFromPrimitive, Clone, Copy, PartialEq, Eq)]
 #[repr(u16)]
 pub enum Compressor {
     Gzip = 1,
@@ -63,6 +67,38 @@ pub enum Compressor {
     Unknown = 0xFFFF,
 }
 
+impl std::str::FromStr for Compressor {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(Compressor::Gzip),
+            "lzo" => Ok(Compressor::Lzo),
+            "lzma" => Ok(Compressor::Lzma),
+            "xz" => Ok(Compressor::Xz),
+            "lz4" => Ok(Compressor::Lz4),
+            "zstd" => Ok(Compressor::Zstd),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown compressor '{s}'"))),
+        }
+    }
+}
+
+impl std::fmt::Display for Compressor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Compressor::Gzip => "gzip",
+            Compressor::Lzo => "lzo",
+            Compressor::Lzma => "lzma",
+            Compressor::Xz => "xz",
+            Compressor::Lz4 => "lz4",
+            Compressor::Zstd => "zstd",
+            Compressor::None => "none",
+            Compressor::Unknown => "unknown",
+        };
+        f.write_str(name)
+    }
+}
+
 #[derive(Debug)]
 #[allow(dead_code)]
 pub struct GzipOptions {
@@ -71,6 +107,28 @@ pub struct GzipOptions {
     pub strategies: GzipStrategies,
 }
 
+impl GzipOptions {
+    /// Deserialize the options from their on-disk format, excluding the metadata block header
+    pub fn read<R>(r: &mut R) -> io::Result<Self>
+    where R: Read
+    {
+        Ok(GzipOptions {
+            compression_level: r.read_u32::<LittleEndian>()?,
+            window_size: r.read_u16::<LittleEndian>()?,
+            strategies: GzipStrategies::from_bits_truncate(r.read_u16::<LittleEndian>()?),
+        })
+    }
+
+    /// Serialize the options in their on-disk format, excluding the metadata block header
+    pub fn write<W>(&self, w: &mut W) -> io::Result<()>
+    where W: Write
+    {
+        w.write_u32::<LittleEndian>(self.compression_level)?;
+        w.write_u16::<LittleEndian>(self.window_size)?;
+        w.write_u16::<LittleEndian>(self.strategies.bits())
+    }
+}
+
 bitflags! {
     pub struct GzipStrategies: u16 {
         const DEFAULT  = 0x0001;
@@ -86,13 +144,17 @@ impl Superblock {
     pub fn read<R>(r: &mut R) -> io::Result<Superblock>
     where R: Read
     {
+        let compressor_id;
         let sb = Superblock {
             magic: r.read_u32::<LittleEndian>()?,
             inode_count: r.read_u32::<LittleEndian>()?,
             mod_time: r.read_u32::<LittleEndian>()?,
             block_size: r.read_u32::<LittleEndian>()?,
             frag_count: r.read_u32::<LittleEndian>()?,
-            compressor: Compressor::try_from(r.read_u16::<LittleEndian>()?).unwrap(),
+            compressor: {
+                compressor_id = r.read_u16::<LittleEndian>()?;
+                Compressor::try_from(compressor_id).unwrap_or(Compressor::Unknown)
+            },
             block_log: r.read_u16::<LittleEndian>()?,
             flags: SuperblockFlags::from_bits_truncate(r.read_u16::<LittleEndian>()?),
             id_count: r.read_u16::<LittleEndian>()?,
@@ -108,11 +170,49 @@ impl Superblock {
             export_table: r.read_u64::<LittleEndian>()?,
         };
         if sb.magic != MAGIC {
-            return Err(io::Error::new(io::ErrorKind::InvalidData, "Incorrect SquashFS magic"));
+            return Err(Error::InvalidSuperblock("Incorrect SquashFS magic").into());
+        }
+        if matches!(sb.compressor, Compressor::Unknown | Compressor::None) {
+            return Err(Error::UnknownCompressor(compressor_id).into());
+        }
+        if sb.version_major != 4 || sb.version_minor != 0 {
+            return Err(Error::InvalidSuperblock("Unsupported SquashFS version").into());
+        }
+        // Everything that divides by the block size or sizes a buffer by it relies on this
+        if !sb.block_size.is_power_of_two() || !(4096..=1024 * 1024).contains(&sb.block_size)
+            || u32::from(sb.block_log) != sb.block_size.trailing_zeros()
+        {
+            return Err(Error::InvalidSuperblock("Invalid block size").into());
         }
         Ok(sb)
     }
 
+    /// Serialize the Superblock in its on-disk format
+    pub fn write<W>(&self, w: &mut W) -> io::Result<()>
+    where W: Write
+    {
+        w.write_u32::<LittleEndian>(self.magic)?;
+        w.write_u32::<LittleEndian>(self.inode_count)?;
+        w.write_u32::<LittleEndian>(self.mod_time)?;
+        w.write_u32::<LittleEndian>(self.block_size)?;
+        w.write_u32::<LittleEndian>(self.frag_count)?;
+        w.write_u16::<LittleEndian>(self.compressor.into())?;
+        w.write_u16::<LittleEndian>(self.block_log)?;
+        w.write_u16::<LittleEndian>(self.flags.bits())?;
+        w.write_u16::<LittleEndian>(self.id_count)?;
+        w.write_u16::<LittleEndian>(self.version_major)?;
+        w.write_u16::<LittleEndian>(self.version_minor)?;
+        self.root_inode.write(w)?;
+        w.write_u64::<LittleEndian>(self.bytes_used)?;
+        w.write_u64::<LittleEndian>(self.id_table)?;
+        w.write_u64::<LittleEndian>(self.xattr_table)?;
+        w.write_u64::<LittleEndian>(self.inode_table)?;
+        w.write_u64::<LittleEndian>(self.dir_table)?;
+        w.write_u64::<LittleEndian>(self.frag_table)?;
+        w.write_u64::<LittleEndian>(self.export_table)
+    }
+
+    #[allow(dead_code)]
     pub fn from_bytes(b: &[u8]) -> io::Result<Superblock>
     {
         Superblock::read(&mut &b[..])
@@ -135,4 +235,21 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_invalid_superblock() -> io::Result<()> {
+        let image = crate::squashfs::test_util::build(128 * 1024, |_| Ok(()))?;
+        assert!(Superblock::from_bytes(&image).is_ok());
+
+        let corrupt = |offset: usize, bytes: &[u8]| {
+            let mut image = image.clone();
+            image[offset..][..bytes.len()].copy_from_slice(bytes);
+            Error::from(Superblock::from_bytes(&image).unwrap_err())
+        };
+        assert!(matches!(corrupt(12, &0u32.to_le_bytes()), Error::InvalidSuperblock(_)));
+        assert!(matches!(corrupt(12, &3000u32.to_le_bytes()), Error::InvalidSuperblock(_)));
+        assert!(matches!(corrupt(22, &12u16.to_le_bytes()), Error::InvalidSuperblock(_)));
+        assert!(matches!(corrupt(28, &3u16.to_le_bytes()), Error::InvalidSuperblock(_)));
+        Ok(())
+    }
+
 }
\ No newline at end of file
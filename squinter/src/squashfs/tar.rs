@@ -0,0 +1,394 @@
+//! Conversion between tar archives and SquashFS images
+//!
+//! When importing, entries are added to a [`SquashFSWriter`] in the order that they appear in the
+//! archive, so later entries replace earlier entries with the same path, as they would when
+//! extracting the archive. POSIX (PAX) extended headers are honored for long paths, ownership,
+//! high-precision modification times, and extended attributes stored as `SCHILY.xattr.*` records.
+//!
+//! When exporting, the archive is written as a POSIX (PAX) tar. PAX records are only emitted
+//! where the plain ustar header cannot hold an entry's path, link target, ownership, or size, and
+//! for extended attributes.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, Write};
+use std::path::Path;
+
+use super::metadata::{decode_dev, encode_dev, split_xattr_name};
+use super::walk::Visited;
+use super::writer::{Attributes, SquashFSWriter, WriterOptions};
+use super::{Inode, SquashFS};
+
+const PAX_MTIME: &[u8] = b"mtime";
+const PAX_XATTR_PREFIX: &[u8] = b"SCHILY.xattr.";
+
+// The largest values that fit in the octal numeric fields of a ustar header
+const USTAR_MAX_ID: u64 = 0o7777777;
+const USTAR_MAX_SIZE: u64 = 0o77777777777;
+const USTAR_NAME_LEN: usize = 100;
+
+/// Convert a tar archive into a new SquashFS image. Returns the Writer once the image is complete.
+pub fn tar_to_squashfs<R: Read, W: Write + Seek>(r: R, w: W, opts: WriterOptions) -> io::Result<W> {
+    let mut writer = SquashFSWriter::new(w, opts)?;
+    append_tar(&mut writer, r)?;
+    writer.finish()
+}
+
+/// Add every entry in a tar archive to an image that is being built
+pub fn append_tar<R: Read, W: Write + Seek>(writer: &mut SquashFSWriter<W>, r: R) -> io::Result<()> {
+    let mut archive = ::tar::Archive::new(r);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path_bytes().into_owned();
+        let header = entry.header();
+        let entry_type = header.entry_type();
+        let mut attrs = Attributes::new(
+            (header.mode()? & 0o7777) as u16,
+            id_from_header(header.uid()?)?,
+            id_from_header(header.gid()?)?,
+            header.mtime()?.try_into().unwrap_or(u32::MAX),
+        );
+        // Device numbers are only meaningful (and only reliably parseable) for device entries
+        let dev = if entry_type.is_character_special() || entry_type.is_block_special() {
+            match (header.device_major()?, header.device_minor()?) {
+                (Some(major), Some(minor)) => encode_dev(major, minor),
+                _ => return Err(invalid_entry(&path, "device has no device number")),
+            }
+        } else {
+            0
+        };
+        let link_name = entry.link_name_bytes().map(|l| l.into_owned());
+
+        if let Some(extensions) = entry.pax_extensions()? {
+            for ext in extensions {
+                let ext = ext?;
+                let key = ext.key_bytes();
+                if key == PAX_MTIME {
+                    attrs.mtime = parse_pax_time(ext.value_bytes())?;
+                } else if let Some(name) = key.strip_prefix(PAX_XATTR_PREFIX) {
+                    // Namespaces that SquashFS cannot store (ex. system.posix_acl_access) are
+                    // dropped, as mksquashfs does
+                    if split_xattr_name(name).is_some() {
+                        attrs.xattrs.push((name.to_vec(), ext.value_bytes().to_vec()));
+                    }
+                }
+            }
+        }
+
+        match entry_type {
+            ::tar::EntryType::Directory => writer.add_dir_bytes(&path, attrs)?,
+            // Pre-POSIX archives mark directories with a trailing slash on a regular entry
+            ::tar::EntryType::Regular if path.ends_with(b"/") => writer.add_dir_bytes(&path, attrs)?,
+            ::tar::EntryType::Regular |
+            ::tar::EntryType::Continuous => writer.add_file_bytes(&path, attrs, &mut entry)?,
+            ::tar::EntryType::Symlink => {
+                let target = link_name.ok_or(invalid_entry(&path, "symlink has no target"))?;
+                writer.add_symlink_bytes(&path, attrs, &target)?;
+            },
+            ::tar::EntryType::Link => {
+                let target = link_name.ok_or(invalid_entry(&path, "hard link has no target"))?;
+                writer.add_hard_link_bytes(&path, &target)?;
+            },
+            ::tar::EntryType::Char => writer.add_char_device_bytes(&path, attrs, dev)?,
+            ::tar::EntryType::Block => writer.add_block_device_bytes(&path, attrs, dev)?,
+            ::tar::EntryType::Fifo => writer.add_fifo_bytes(&path, attrs)?,
+            // Global PAX headers only carry defaults for following entries, which are not needed
+            ::tar::EntryType::XGlobalHeader => {},
+            ::tar::EntryType::GNUSparse => {
+                return Err(io::Error::new(io::ErrorKind::Unsupported,
+                    format!("{}: GNU sparse files are not supported", String::from_utf8_lossy(&path))));
+            },
+            t => return Err(invalid_entry(&path, &format!("unsupported entry type {:?}", t))),
+        }
+    }
+    Ok(())
+}
+
+/// Write the entry at `path` in a SquashFS image, and everything beneath it if it is a directory,
+/// to a tar archive. Paths in the archive are relative to `path`, which itself appears as "./".
+/// Entries that share an inode are written as hard links to the first path at which the inode
+/// was found. Returns the Writer once the archive is complete.
+pub fn squashfs_to_tar<R, P, W>(sqfs: &mut SquashFS<R>, path: P, w: W) -> io::Result<W>
+where R: Read + Seek,
+      P: AsRef<Path>,
+      W: Write,
+{
+    let mut builder = ::tar::Builder::new(w);
+    let inode = sqfs.inode_from_path(&path)?;
+    let mut links = HashMap::new();
+    if inode.is_dir() {
+        append_inode(sqfs, &mut builder, &mut links, b"./", &inode)?;
+        append_dir_contents(sqfs, &mut builder, &mut links, &mut Visited::default(), &mut Vec::new(), &inode)?;
+    } else {
+        let name = path.as_ref().file_name()
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        append_inode(sqfs, &mut builder, &mut links, name.as_encoded_bytes(), &inode)?;
+    }
+    builder.into_inner()
+}
+
+/// Append every entry beneath a directory, in the same depth-first order that tar uses
+fn append_dir_contents<R: Read + Seek, W: Write>(sqfs: &mut SquashFS<R>, builder: &mut ::tar::Builder<W>,
+    links: &mut HashMap<u32, Vec<u8>>, visited: &mut Visited, dir_path: &mut Vec<u8>, dir: &Inode) -> io::Result<()>
+{
+    let entries = sqfs.read_walk_dir(visited, dir_path, dir)?;
+    for e in entries {
+        let parent_len = dir_path.len();
+        dir_path.extend_from_slice(e.file_name_bytes());
+        let inode = sqfs.inode_from_entryref(e.inode_ref())?;
+        if inode.is_dir() {
+            dir_path.push(b'/');
+            append_inode(sqfs, builder, links, dir_path, &inode)?;
+            append_dir_contents(sqfs, builder, links, visited, dir_path, &inode)?;
+        } else {
+            append_inode(sqfs, builder, links, dir_path, &inode)?;
+        }
+        dir_path.truncate(parent_len);
+    }
+    Ok(())
+}
+
+fn append_inode<R: Read + Seek, W: Write>(sqfs: &mut SquashFS<R>, builder: &mut ::tar::Builder<W>,
+    links: &mut HashMap<u32, Vec<u8>>, path: &[u8], inode: &Inode) -> io::Result<()>
+{
+    let mut header = ::tar::Header::new_ustar();
+    let mut pax: Vec<(String, Vec<u8>)> = Vec::new();
+    header.set_mode((inode.mode() & 0o7777).into());
+    header.set_mtime(inode.mtime().into());
+    header.set_size(0);
+    let uid = u64::from(inode.uid(sqfs)?);
+    let gid = u64::from(inode.gid(sqfs)?);
+    if uid > USTAR_MAX_ID {
+        pax.push(("uid".to_string(), uid.to_string().into_bytes()));
+    }
+    if gid > USTAR_MAX_ID {
+        pax.push(("gid".to_string(), gid.to_string().into_bytes()));
+    }
+    header.set_uid(uid.min(USTAR_MAX_ID));
+    header.set_gid(gid.min(USTAR_MAX_ID));
+    for (name, value) in inode.xattrs(sqfs)? {
+        let key = [PAX_XATTR_PREFIX, &name].concat();
+        let key = String::from_utf8(key)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Xattr name is not valid UTF-8"))?;
+        pax.push((key, value));
+    }
+    set_name_field(&mut header.as_ustar_mut().unwrap().name, path, "path", &mut pax);
+
+    // Hard links to an inode that was already written carry no data of their own
+    if !inode.is_dir() && inode.link_count() > 1 {
+        if let Some(target) = links.get(&inode.inode_number()) {
+            header.set_entry_type(::tar::EntryType::Link);
+            set_name_field(&mut header.as_ustar_mut().unwrap().linkname, target, "linkpath", &mut pax);
+            return append_entry(builder, header, pax, io::empty());
+        }
+        links.insert(inode.inode_number(), path.to_vec());
+    }
+
+    if inode.is_file() {
+        let size = inode.file_size().unwrap_or(0);
+        if size > USTAR_MAX_SIZE {
+            pax.push(("size".to_string(), size.to_string().into_bytes()));
+        } else {
+            header.set_size(size);
+        }
+        header.set_entry_type(::tar::EntryType::Regular);
+        let reader = sqfs.open_file_inode(inode)?;
+        return append_entry(builder, header, pax, reader);
+    }
+
+    if inode.is_dir() {
+        header.set_entry_type(::tar::EntryType::Directory);
+    } else if let Some(target) = inode.symlink_target() {
+        header.set_entry_type(::tar::EntryType::Symlink);
+        set_name_field(&mut header.as_ustar_mut().unwrap().linkname, target.to_bytes(), "linkpath", &mut pax);
+    } else if let Some(dev) = inode.dev_number() {
+        let (major, minor) = decode_dev(dev);
+        header.set_entry_type(if inode.is_block_dev() { ::tar::EntryType::Block } else { ::tar::EntryType::Char });
+        header.set_device_major(major)?;
+        header.set_device_minor(minor)?;
+    } else if inode.is_fifo() {
+        header.set_entry_type(::tar::EntryType::Fifo);
+    } else {
+        // Sockets cannot be represented in a tar archive, and tar itself skips them
+        return Ok(());
+    }
+    append_entry(builder, header, pax, io::empty())
+}
+
+/// Store a path or link target in a ustar header field. Names that are too long are truncated in
+/// the header and stored in full as a PAX record.
+fn set_name_field(field: &mut [u8; USTAR_NAME_LEN], name: &[u8], pax_key: &str, pax: &mut Vec<(String, Vec<u8>)>) {
+    let len = name.len().min(USTAR_NAME_LEN);
+    field.fill(0);
+    field[..len].copy_from_slice(&name[..len]);
+    if name.len() > USTAR_NAME_LEN {
+        pax.push((pax_key.to_string(), name.to_vec()));
+    }
+}
+
+fn append_entry<W: Write, D: Read>(builder: &mut ::tar::Builder<W>, mut header: ::tar::Header,
+    pax: Vec<(String, Vec<u8>)>, data: D) -> io::Result<()>
+{
+    if !pax.is_empty() {
+        builder.append_pax_extensions(pax.iter().map(|(k, v)| (k.as_str(), v.as_slice())))?;
+    }
+    header.set_cksum();
+    builder.append(&header, data)
+}
+
+fn invalid_entry(path: &[u8], msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", String::from_utf8_lossy(path), msg))
+}
+
+fn id_from_header(id: u64) -> io::Result<u32> {
+    u32::try_from(id).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "uid or gid is too large"))
+}
+
+/// Parse a PAX timestamp, which may carry a fractional part and may be negative. SquashFS only
+/// stores whole, unsigned, 32-bit seconds, so values are truncated and clamped to fit.
+fn parse_pax_time(value: &[u8]) -> io::Result<u32> {
+    let value = std::str::from_utf8(value)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid PAX mtime"))?;
+    let whole = value.split('.').next().unwrap_or_default();
+    let secs: i64 = whole.parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid PAX mtime '{value}'")))?;
+    Ok(secs.clamp(0, u32::MAX.into()) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::squashfs::SquashFS;
+    use crate::squashfs::test_util::open;
+
+    fn header(entry_type: ::tar::EntryType, mode: u32, size: u64) -> ::tar::Header {
+        let mut h = ::tar::Header::new_ustar();
+        h.set_entry_type(entry_type);
+        h.set_mode(mode);
+        h.set_uid(1000);
+        h.set_gid(1000);
+        h.set_mtime(1600000000);
+        h.set_size(size);
+        h
+    }
+
+    #[test]
+    fn test_tar_to_squashfs() -> io::Result<()> {
+        let mut b = ::tar::Builder::new(Vec::new());
+        b.append_data(&mut header(::tar::EntryType::Directory, 0o750, 0), "etc/", io::empty())?;
+        b.append_pax_extensions([
+            ("mtime", &b"1700000000.5"[..]),
+            ("SCHILY.xattr.user.comment", b"hello"),
+            ("SCHILY.xattr.system.posix_acl_access", b"ignored"),
+        ])?;
+        b.append_data(&mut header(::tar::EntryType::Regular, 0o644, 6), "etc/motd", &b"hello\n"[..])?;
+        b.append_link(&mut header(::tar::EntryType::Symlink, 0o777, 0), "etc/motd.sym", "motd")?;
+        b.append_link(&mut header(::tar::EntryType::Link, 0o644, 0), "etc/motd.hard", "etc/motd")?;
+        let mut dev = header(::tar::EntryType::Char, 0o666, 0);
+        dev.set_device_major(1)?;
+        dev.set_device_minor(3)?;
+        b.append_data(&mut dev, "dev/null", io::empty())?;
+        let tar = b.into_inner()?;
+
+        let image = tar_to_squashfs(Cursor::new(tar), Cursor::new(Vec::new()), WriterOptions::default())?;
+        let mut sqfs = SquashFS::new(image)?;
+
+        let etc = sqfs.inode_from_path("/etc")?;
+        assert_eq!(etc.permissions(), 0o750);
+        assert_eq!(etc.mtime(), 1600000000);
+
+        let motd = sqfs.inode_from_path("/etc/motd")?;
+        assert_eq!(motd.mtime(), 1700000000);
+        assert_eq!(motd.uid(&sqfs)?, 1000);
+        assert_eq!(motd.link_count(), 2);
+        assert_eq!(motd.inode_number(), sqfs.inode_from_path("/etc/motd.hard")?.inode_number());
+        let mut content = String::new();
+        sqfs.open_file("/etc/motd")?.read_to_string(&mut content)?;
+        assert_eq!(content, "hello\n");
+
+        let sym = sqfs.inode_from_path("/etc/motd.sym")?;
+        assert_eq!(sym.symlink_target().unwrap().to_bytes(), b"motd");
+
+        let null = sqfs.inode_from_path("/dev/null")?;
+        assert!(null.is_char_dev());
+        assert_eq!(crate::squashfs::metadata::decode_dev(null.dev_number().unwrap()), (1, 3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_squashfs_to_tar() -> io::Result<()> {
+        let long_name = "d".repeat(80) + "/" + &"f".repeat(80);
+        let mut attrs = Attributes::new(0o640, 3000000, 5, 1700000000);
+        attrs.xattrs.push((b"user.comment".to_vec(), b"hello".to_vec()));
+        let mut sqfs = open(128 * 1024, |w| {
+            w.add_file("/usr/data", attrs.clone(), &b"data"[..])?;
+            w.add_hard_link("/usr/data.link", "/usr/data")?;
+            w.add_file(format!("/usr/{long_name}"), Attributes::new(0o644, 0, 0, 0), &b"long"[..])?;
+            w.add_symlink("/usr/sym", Attributes::new(0o777, 0, 0, 0), "data")?;
+            w.add_block_device("/usr/dev", Attributes::new(0o600, 0, 0, 0), encode_dev(8, 1))?;
+            w.add_fifo("/usr/fifo", Attributes::new(0o600, 0, 0, 0))?;
+            w.add_socket("/usr/socket", Attributes::new(0o600, 0, 0, 0))?;
+            w.add_file("/other", Attributes::new(0o644, 0, 0, 0), &b"not exported"[..])
+        })?;
+
+        let tar = squashfs_to_tar(&mut sqfs, "/usr", Vec::new())?;
+        let mut archive = ::tar::Archive::new(&tar[..]);
+        let entries: Vec<(String, ::tar::EntryType)> = archive.entries()?
+            .map(|e| e.map(|e| (String::from_utf8_lossy(&e.path_bytes()).into_owned(), e.header().entry_type())))
+            .collect::<io::Result<_>>()?;
+        assert_eq!(entries, [
+            ("./".to_string(), ::tar::EntryType::Directory),
+            ("data".to_string(), ::tar::EntryType::Regular),
+            ("data.link".to_string(), ::tar::EntryType::Link),
+            (format!("{}/", "d".repeat(80)), ::tar::EntryType::Directory),
+            (long_name.clone(), ::tar::EntryType::Regular),
+            ("dev".to_string(), ::tar::EntryType::Block),
+            ("fifo".to_string(), ::tar::EntryType::Fifo),
+            ("sym".to_string(), ::tar::EntryType::Symlink),
+        ]);
+
+        // Convert back and check that everything survived the trip
+        let image = tar_to_squashfs(&tar[..], Cursor::new(Vec::new()), WriterOptions::default())?;
+        let mut sqfs = SquashFS::new(image)?;
+        let data = sqfs.inode_from_path("/data")?;
+        assert_eq!(data.uid(&sqfs)?, 3000000);
+        assert_eq!(data.gid(&sqfs)?, 5);
+        assert_eq!(data.permissions(), 0o640);
+        assert_eq!(data.mtime(), 1700000000);
+        assert_eq!(data.link_count(), 2);
+        assert_eq!(data.xattrs(&sqfs)?, attrs.xattrs);
+        let mut content = String::new();
+        sqfs.open_file(format!("/{long_name}"))?.read_to_string(&mut content)?;
+        assert_eq!(content, "long");
+        assert_eq!(sqfs.inode_from_path("/sym")?.symlink_target().unwrap().to_bytes(), b"data");
+        assert_eq!(decode_dev(sqfs.inode_from_path("/dev")?.dev_number().unwrap()), (8, 1));
+
+        // A single file is exported under its own name
+        let tar = squashfs_to_tar(&mut sqfs, "/data", Vec::new())?;
+        let mut archive = ::tar::Archive::new(&tar[..]);
+        let mut entry = archive.entries()?.next().unwrap()?;
+        assert_eq!(&entry.path_bytes()[..], b"data");
+        let mut content = String::new();
+        entry.read_to_string(&mut content)?;
+        assert_eq!(content, "data");
+        Ok(())
+    }
+
+    #[test]
+    fn test_squashfs_to_tar_loop() -> io::Result<()> {
+        let mut sqfs = SquashFS::new(Cursor::new(crate::squashfs::test_util::loop_image()?))?;
+        let err = squashfs_to_tar(&mut sqfs, "/", Vec::new()).unwrap_err();
+        assert!(matches!(crate::Error::from(err), crate::Error::DirectoryLoop(p) if p == Path::new("a/c/")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_pax_time() -> io::Result<()> {
+        assert_eq!(parse_pax_time(b"1700000000")?, 1700000000);
+        assert_eq!(parse_pax_time(b"1700000000.999999999")?, 1700000000);
+        assert_eq!(parse_pax_time(b"-5.5")?, 0);
+        assert_eq!(parse_pax_time(b"99999999999")?, u32::MAX);
+        assert!(parse_pax_time(b"soon").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+//! Fixtures shared by the unit tests
+
+use std::io::{self, Cursor, Read, Seek};
+
+use super::metadata::{read_metadata_block, EntryReference};
+use super::superblock::Superblock;
+use super::writer::{Attributes, SquashFSWriter, WriterOptions};
+use super::SquashFS;
+use crate::Result;
+
+pub(crate) type Writer = SquashFSWriter<Cursor<Vec<u8>>>;
+
+/// Build an image with the given block size
+pub(crate) fn build(block_size: u32, f: impl FnOnce(&mut Writer) -> io::Result<()>) -> io::Result<Vec<u8>> {
+    let opts = WriterOptions { block_size, ..Default::default() };
+    let mut writer = SquashFSWriter::new(Cursor::new(Vec::new()), opts)?;
+    f(&mut writer)?;
+    Ok(writer.finish()?.into_inner())
+}
+
+/// Build an image with the given block size, and open it
+pub(crate) fn open(block_size: u32, f: impl FnOnce(&mut Writer) -> io::Result<()>) -> io::Result<SquashFS<Cursor<Vec<u8>>>> {
+    Ok(SquashFS::new(Cursor::new(build(block_size, f)?))?)
+}
+
+/// Base, vendor, and product layers of an overlay, with a different block size in the middle.
+/// Vendor overrides, whites out, and adds files in base, and makes /opt/app opaque. Product adds an
+/// opaque /var with the overlay xattr, and whites out a file that does not exist.
+pub(crate) fn overlay_layers() -> io::Result<[SquashFS<Cursor<Vec<u8>>>; 3]> {
+    let a = |mode| Attributes::new(mode, 0, 0, 0);
+    let base = open(4096, |w| {
+        w.add_file("/etc/passwd", a(0o644), &b"base"[..])?;
+        w.add_file("/etc/shadow", a(0o600), &b"secret"[..])?;
+        w.add_file("/etc/group", a(0o644), &b"group"[..])?;
+        w.add_file("/opt/app/old", a(0o644), &b"old"[..])?;
+        w.add_file("/var/cache/x", a(0o644), &b"x"[..])?;
+        w.add_file("/lib/file", a(0o644), &b"file"[..])?;
+        w.add_symlink("/usr", a(0o777), "/opt")?;
+        w.add_file("/bin/tool", a(0o755), &b"tool"[..])?;
+        w.add_hard_link("/bin/alias", "/bin/tool")
+    })?;
+    let vendor = open(8192, |w| {
+        w.add_dir("/etc", a(0o700))?;
+        w.add_file("/etc/passwd", a(0o644), &b"vendor"[..])?;
+        w.add_file("/etc/.wh.shadow", a(0o644), io::empty())?;
+        w.add_char_device("/etc/group", a(0o644), 0)?;
+        w.add_file("/opt/app/.wh..wh..opq", a(0o644), io::empty())?;
+        w.add_file("/opt/app/new", a(0o644), &b"new"[..])?;
+        w.add_file("/lib", a(0o644), &b"lib"[..])?;
+        w.add_file("/bin/alias", a(0o755), &b"replaced"[..])
+    })?;
+    let product = open(4096, |w| {
+        let mut opaque = a(0o755);
+        opaque.xattrs.push((b"trusted.overlay.opaque".to_vec(), b"y".to_vec()));
+        w.add_dir("/var", opaque)?;
+        w.add_file("/var/log", a(0o644), &b"log"[..])?;
+        w.add_file("/.wh.missing", a(0o644), io::empty())
+    })?;
+    Ok([base, vendor, product])
+}
+
+/// List every entry beneath a directory: each directory with its permissions, and everything
+/// else with its link count and the size of its data
+pub(crate) fn list_tree<R: Read + Seek>(sqfs: &mut SquashFS<R>, path: &str, out: &mut Vec<String>) -> io::Result<()> {
+    let names: Vec<String> = sqfs.read_dir(path)?.map(|e| e.file_name()).collect();
+    for n in names {
+        let child = format!("{}/{n}", path.trim_end_matches('/'));
+        let inode = sqfs.inode_from_path(&child)?;
+        if inode.is_dir() {
+            out.push(format!("{child}/ {:o}", inode.permissions()));
+            list_tree(sqfs, &child, out)?;
+        } else {
+            let mut data = Vec::new();
+            if inode.is_file() {
+                sqfs.open_file_inode(&inode)?.read_to_end(&mut data)?;
+            }
+            out.push(format!("{child} {} {}", inode.link_count(), data.len()));
+        }
+    }
+    Ok(())
+}
+
+/// An image holding /a/b/file, the empty directory /a/c, /a/d/file, and the symlink /z
+pub(crate) fn tree_image() -> io::Result<Vec<u8>> {
+    let attrs = Attributes::new(0o755, 0, 0, 0);
+    build(4096, |w| {
+        w.add_file("/a/b/file", attrs.clone(), &b"data"[..])?;
+        w.add_dir("/a/c", attrs.clone())?;
+        w.add_file("/a/d/file", attrs.clone(), &b"data"[..])?;
+        w.add_symlink("/z", attrs, "a")
+    })
+}
+
+/// An image in which the directory /a/c has the listing of /a, so that /a contains itself. The
+/// inode table is rewritten uncompressed at the end of the image.
+pub(crate) fn loop_image() -> io::Result<Vec<u8>> {
+    let mut image = tree_image()?;
+    let mut sqfs = SquashFS::new(Cursor::new(image.clone()))?;
+    let mut inode_ref = |dir, name: &[u8]| -> Result<EntryReference> {
+        Ok(sqfs.read_dir(dir)?.find(|e| e.file_name_bytes() == name).unwrap().inode_ref())
+    };
+    let (a, c) = (inode_ref("/", b"a")?, inode_ref("/a", b"c")?);
+
+    let mut sb = Superblock::read(&mut &image[..])?;
+    let mut table = vec![0; 8192];
+    let mut r = Cursor::new(&image);
+    r.set_position(sb.inode_table);
+    let (block_len, len) = read_metadata_block(&mut r, &sb.compressor, &mut table)?;
+    assert_eq!(sb.inode_table + block_len as u64, sb.dir_table, "The inode table is a single block");
+    table.truncate(len);
+
+    // Copy the directory table location and size from /a's basic directory inode
+    let (a, c) = (a.offset() as usize, c.offset() as usize);
+    assert_eq!(table[a..a + 2], [1, 0]);
+    let listing = [16..20, 24..28];
+    for range in listing {
+        let src = table[a + range.start..a + range.end].to_vec();
+        table[c + range.start..c + range.end].copy_from_slice(&src);
+    }
+
+    image.truncate(sb.bytes_used as usize);
+    sb.inode_table = sb.bytes_used;
+    image.extend_from_slice(&(len as u16 | 0x8000).to_le_bytes());
+    image.extend_from_slice(&table);
+    sb.bytes_used = image.len() as u64;
+    sb.write(&mut &mut image[..])?;
+    Ok(image)
+}
+
+/// An image in which /d holds the files a, b/c, and e, the second of which has a name that
+/// no directory entry may have. The directory table is rewritten uncompressed, and the tables
+/// that follow it are moved along to make room.
+pub(crate) fn invalid_name_image() -> io::Result<Vec<u8>> {
+    let attrs = Attributes::new(0o644, 0, 0, 0);
+    let image = build(4096, |w| {
+        for name in ["a", "b-c", "e"] {
+            w.add_file(format!("/d/{name}"), attrs.clone(), name.as_bytes())?;
+        }
+        Ok(())
+    })?;
+
+    let mut sb = Superblock::read(&mut &image[..])?;
+    assert_eq!((sb.export_table, sb.xattr_table), (u64::MAX, u64::MAX));
+    let mut table = vec![0; 8192];
+    let mut r = Cursor::new(&image);
+    r.set_position(sb.dir_table);
+    let (block_len, len) = read_metadata_block(&mut r, &sb.compressor, &mut table)?;
+    table.truncate(len);
+    let name = table.windows(3).position(|w| w == b"b-c").unwrap();
+    table[name + 1] = b'/';
+
+    // The fragment and id tables follow the single directory block. Move them, and the block
+    // locations in their indexes, along.
+    let rest = sb.dir_table as usize + block_len;
+    let mut new = image[..sb.dir_table as usize].to_vec();
+    new.extend_from_slice(&(len as u16 | 0x8000).to_le_bytes());
+    new.extend_from_slice(&table);
+    let shift = new.len() as u64 - rest as u64;
+    new.extend_from_slice(&image[rest..sb.bytes_used as usize]);
+    let indexes = [(&mut sb.frag_table, sb.frag_count as usize * 16), (&mut sb.id_table, usize::from(sb.id_count) * 4)];
+    for (index, size) in indexes {
+        *index += shift;
+        for n in 0..size.div_ceil(8192) {
+            let entry = &mut new[*index as usize + n * 8..][..8];
+            let block = u64::from_le_bytes(entry.try_into().unwrap()) + shift;
+            entry.copy_from_slice(&block.to_le_bytes());
+        }
+    }
+    sb.bytes_used = new.len() as u64;
+    sb.write(&mut &mut new[..])?;
+    Ok(new)
+}
@@ -0,0 +1,126 @@
+//! Recompression of existing images
+//!
+//! Transcoding rebuilds an image with different compression settings without extracting it.
+//! Every file is read through the source image's decompressor and written again with the new
+//! compressor, and all of the metadata tables are rebuilt. The directory structure, inode numbers,
+//! ownership, permissions, timestamps, hard links, and extended attributes are all preserved.
+//!
+//! Files are rewritten from their contents rather than block by block, so the layout of their
+//! data is the writer's rather than the source image's. Sparse blocks are written as compressed
+//! blocks of zeros, and tail-ends are packed into new fragment blocks, or given blocks of their
+//! own with [`WriterOptions::no_fragments`], however the source image stored them.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, Write};
+
+use super::walk::Visited;
+use super::writer::{SquashFSWriter, WriterOptions};
+use super::{Inode, SquashFS};
+
+/// Write a copy of an existing image using the compressor and other settings in `opts`. Returns
+/// the Writer once the new image is complete.
+pub fn transcode<R: Read + Seek, W: Write + Seek>(sqfs: &mut SquashFS<R>, w: W, opts: WriterOptions) -> io::Result<W> {
+    let mut writer = SquashFSWriter::new(w, opts)?;
+    let root = sqfs.root_inode()?;
+    writer.add_inode_bytes(b"/", sqfs, &root)?;
+    writer.set_inode_number_bytes(b"/", root.inode_number())?;
+    copy_dir_contents(sqfs, &mut writer, &mut HashMap::new(), &mut Visited::default(), &mut Vec::new(), &root)?;
+    writer.finish()
+}
+
+fn copy_dir_contents<R: Read + Seek, W: Write + Seek>(sqfs: &mut SquashFS<R>, writer: &mut SquashFSWriter<W>,
+    links: &mut HashMap<u32, Vec<u8>>, visited: &mut Visited, dir_path: &mut Vec<u8>, dir: &Inode) -> io::Result<()>
+{
+    let entries = sqfs.read_walk_dir(visited, dir_path, dir)?;
+    for e in entries {
+        let parent_len = dir_path.len();
+        dir_path.push(b'/');
+        dir_path.extend_from_slice(e.file_name_bytes());
+        let inode = sqfs.inode_from_entryref(e.inode_ref())?;
+        match links.get(&inode.inode_number()) {
+            Some(target) => writer.add_hard_link_bytes(dir_path, target)?,
+            None => {
+                writer.add_inode_bytes(dir_path, sqfs, &inode)?;
+                writer.set_inode_number_bytes(dir_path, inode.inode_number())?;
+                if inode.is_dir() {
+                    copy_dir_contents(sqfs, writer, links, visited, dir_path, &inode)?;
+                } else if inode.link_count() > 1 {
+                    links.insert(inode.inode_number(), dir_path.clone());
+                }
+            },
+        }
+        dir_path.truncate(parent_len);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::squashfs::metadata::encode_dev;
+    use crate::squashfs::test_util::list_tree;
+    use crate::squashfs::writer::Attributes;
+    use crate::squashfs::Compressor;
+
+    #[test]
+    #[cfg(feature = "flate2")]
+    fn test_transcode() -> io::Result<()> {
+        let opts = WriterOptions { compressor: Compressor::Gzip, compression_level: Some(1), block_size: 4096, ..Default::default() };
+        let mut writer = SquashFSWriter::new(Cursor::new(Vec::new()), opts)?;
+        let mut attrs = Attributes::new(0o640, 1000, 1000, 1234);
+        attrs.xattrs.push((b"user.x".to_vec(), b"y".to_vec()));
+        let data: Vec<u8> = (0..10000u32).map(|x| (x % 7) as u8).collect();
+        writer.add_file("/a/file", attrs.clone(), &data[..])?;
+        writer.add_hard_link("/b/link", "/a/file")?;
+        writer.add_symlink("/a/sym", attrs.clone(), "file")?;
+        writer.add_block_device("/dev/sda", attrs.clone(), encode_dev(8, 0))?;
+        writer.add_socket("/run/sock", attrs)?;
+        let mut source = SquashFS::new(writer.finish()?)?;
+        let mut expected = Vec::new();
+        list_tree(&mut source, "/", &mut expected)?;
+
+        let compressors = [
+            #[cfg(feature = "lzma-rs")]
+            Compressor::Xz,
+            #[cfg(feature = "ruzstd")]
+            Compressor::Zstd,
+        ];
+        for compressor in compressors {
+            let opts = WriterOptions { compressor, block_size: source.block_size(), ..Default::default() };
+            let mut sqfs = SquashFS::new(transcode(&mut source, Cursor::new(Vec::new()), opts)?)?;
+            assert_eq!(sqfs.compressor(), compressor);
+            let mut actual = Vec::new();
+            list_tree(&mut sqfs, "/", &mut actual)?;
+            assert_eq!(actual, expected);
+            for path in ["/", "/a", "/a/file", "/a/sym", "/b/link", "/dev/sda", "/run/sock"] {
+                let (old, new) = (source.inode_from_path(path)?, sqfs.inode_from_path(path)?);
+                assert_eq!(new.inode_number(), old.inode_number());
+                assert_eq!(Attributes::from_inode(&new, &sqfs)?, Attributes::from_inode(&old, &source)?);
+                assert_eq!(new.symlink_target(), old.symlink_target());
+                assert_eq!(new.dev_number(), old.dev_number());
+            }
+            let mut read = Vec::new();
+            sqfs.open_file("/b/link")?.read_to_end(&mut read)?;
+            assert_eq!(read, data);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_transcode_loop() -> io::Result<()> {
+        let mut sqfs = SquashFS::new(Cursor::new(crate::squashfs::test_util::loop_image()?))?;
+        let err = transcode(&mut sqfs, Cursor::new(Vec::new()), WriterOptions::default()).unwrap_err();
+        assert!(matches!(crate::Error::from(err), crate::Error::DirectoryLoop(p) if p == std::path::Path::new("/a/c")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_compression_level() -> io::Result<()> {
+        let opts = WriterOptions { compressor: Compressor::Gzip, compression_level: Some(10), ..Default::default() };
+        assert!(SquashFSWriter::new(Cursor::new(Vec::new()), opts).is_err());
+        let opts = WriterOptions { compressor: Compressor::Zstd, compression_level: Some(3), ..Default::default() };
+        assert!(SquashFSWriter::new(Cursor::new(Vec::new()), opts).is_err());
+        Ok(())
+    }
+}
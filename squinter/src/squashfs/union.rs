@@ -0,0 +1,294 @@
+//! Read-only union of several images
+//!
+//! A [`UnionFS`] presents a stack of images as a single tree at runtime, in the same way that
+//! overlayfs presents its layers, without writing a merged image. Layers are given from the bottom
+//! up, so an entry in a later layer hides any entry at the same path in earlier layers.
+//! Directories that exist in several layers have their contents merged, and the whiteouts and
+//! opaque directories described in [`merge`](super::merge) hide the contents of lower layers.
+//!
+//! Lookups return the same [`Inode`] and [`DirEntry`] types as a single [`SquashFS`]. Each
+//! [`DirEntry`] records the [layer](DirEntry::layer) that it was found in, which is needed to look
+//! up the owner or xattrs of its Inode.
+
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek};
+use std::path::{Component, Path};
+
+use super::filedata::FileDataReader;
+use super::limits::Limits;
+use super::merge::{is_whiteout, OPAQUE_WHITEOUT, OVERLAY_OPAQUE_XATTR, WHITEOUT_PREFIX};
+use super::metadata::EntryReference;
+use super::path::Resolve;
+use super::readermux::ReaderClient;
+use super::{DirEntry, Inode, SquashFS};
+use crate::{Error, Result};
+
+/// A directory in the union, as the directory at the same path in each layer whose contents are
+/// visible, from the top down
+type UnionDir = Vec<(usize, EntryReference)>;
+
+/// A read-only view of several SquashFS images stacked on top of each other
+#[derive(Debug)]
+pub struct UnionFS<R: Read + Seek> {
+    layers: Vec<SquashFS<R>>,
+}
+
+impl UnionFS<BufReader<File>> {
+    /// Open each of the filepaths as a SquashFS, and stack them from the bottom layer up
+    pub fn open<P>(paths: &[P]) -> Result<Self>
+    where P: AsRef<Path>
+    {
+        Self::new(paths.iter().map(SquashFS::open).collect::<Result<_>>()?)
+    }
+}
+
+impl<R: Read + Seek> UnionFS<R> {
+    /// Create a new UnionFS from the provided images, from the bottom layer up
+    pub fn new(layers: Vec<SquashFS<R>>) -> Result<Self> {
+        if layers.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "A union requires at least one layer").into());
+        }
+        Ok(UnionFS { layers })
+    }
+
+    /// The images that make up the union, from the bottom layer up
+    pub fn layers(&self) -> &[SquashFS<R>] {
+        &self.layers
+    }
+
+    /// Consume the UnionFS, returning its images
+    pub fn into_layers(self) -> Vec<SquashFS<R>> {
+        self.layers
+    }
+
+    /// Retrieve the root Inode of the union, which is the root Inode of the top layer
+    pub fn root_inode(&mut self) -> Result<Inode> {
+        self.layers.last_mut().unwrap().root_inode()
+    }
+
+    /// Get an iterator over the merged entries of the directory at the given path, sorted by name
+    pub fn read_dir<P>(&mut self, path: P) -> Result<std::vec::IntoIter<DirEntry>>
+    where P: AsRef<Path>
+    {
+        let (_, inode, dir) = self.walk(path.as_ref())?;
+        if !inode.is_dir() {
+            return Err(Error::NotADirectory(path.as_ref().to_path_buf()));
+        }
+
+        let mut entries = BTreeMap::new();
+        let mut hidden = HashSet::new();
+        for (layer, dir_ref) in dir {
+            let sqfs = &mut self.layers[layer];
+            let dir_inode = sqfs.inode_from_entryref(dir_ref)?;
+            // Whiteouts only hide the entries of lower layers
+            let mut whiteouts = Vec::new();
+            for e in sqfs.read_dir_inode(&dir_inode)? {
+                let name = e.file_name_bytes().to_vec();
+                if name == OPAQUE_WHITEOUT {
+                    continue;
+                }
+                if let Some(target) = name.strip_prefix(WHITEOUT_PREFIX) {
+                    whiteouts.push(target.to_vec());
+                } else if !entries.contains_key(&name) && !hidden.contains(&name) {
+                    if e.is_char_dev() && is_whiteout(&sqfs.inode_from_entryref(e.inode_ref())?) {
+                        hidden.insert(name);
+                    } else {
+                        entries.insert(name, e.with_layer(layer));
+                    }
+                }
+            }
+            hidden.extend(whiteouts);
+        }
+        Ok(entries.into_values().collect::<Vec<_>>().into_iter())
+    }
+
+    /// Open a file for reading, by path
+    pub fn open_file<P>(&mut self, path: P) -> Result<FileDataReader<ReaderClient<R>>>
+    where P: AsRef<Path>
+    {
+        let (layer, inode) = self.lookup(&path)?;
+        self.layers[layer].open_file_inode(&inode).map_err(|e| e.at_path(path.as_ref()))
+    }
+
+    /// Open a file for reading, by a DirEntry listed from this union
+    pub fn open_file_dirent(&mut self, dir_entry: &DirEntry) -> Result<FileDataReader<ReaderClient<R>>> {
+        let inode = self.inode_from_dirent(dir_entry)?;
+        self.layers[dir_entry.layer()].open_file_inode(&inode)
+    }
+
+    /// Retrieve the Inode specified by a DirEntry listed from this union
+    pub fn inode_from_dirent(&mut self, dir_entry: &DirEntry) -> Result<Inode> {
+        let sqfs = self.layers.get_mut(dir_entry.layer())
+            .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "DirEntry is not from this union"))?;
+        sqfs.inode_from_entryref(dir_entry.inode_ref())
+    }
+
+    /// Retrieve the Inode specified by the given path
+    pub fn inode_from_path<P>(&mut self, path: P) -> Result<Inode>
+    where P: AsRef<Path>
+    {
+        self.lookup(path).map(|(_, inode)| inode)
+    }
+
+    /// Retrieve the Inode specified by the given path, together with the index of the layer that
+    /// it was found in
+    pub fn lookup<P>(&mut self, path: P) -> Result<(usize, Inode)>
+    where P: AsRef<Path>
+    {
+        self.walk(path.as_ref()).map(|(layer, inode, _)| (layer, inode))
+    }
+
+    fn walk(&mut self, path: &Path) -> Result<(usize, Inode, UnionDir)> {
+        let mut dir = self.root_dir()?;
+        let mut layer = dir[0].0;
+        let mut inode = self.layers[layer].root_inode()?;
+        for comp in path.components() {
+            match comp {
+                Component::RootDir => {},
+                Component::Normal(n) => {
+                    let (child_dir, child_layer, child_inode) = self.find_child(&dir, n)?
+                        .ok_or_else(|| Error::NotFound(path.to_path_buf()))?;
+                    (dir, layer, inode) = (child_dir, child_layer, child_inode);
+                },
+                _ => { return Err(Error::InvalidPath(path.to_path_buf())) }
+            }
+        }
+        Ok((layer, inode, dir))
+    }
+
+    fn root_dir(&mut self) -> Result<UnionDir> {
+        let mut dir = Vec::new();
+        for (layer, sqfs) in self.layers.iter_mut().enumerate().rev() {
+            dir.push((layer, sqfs.superblock().root_inode));
+            let root = sqfs.root_inode()?;
+            if is_opaque(sqfs, &root)? {
+                break;
+            }
+        }
+        Ok(dir)
+    }
+
+    /// Find the topmost entry called `name` in a directory, along with the layer it was found in.
+    /// If the entry is a directory, the directories in lower layers that it is merged with are
+    /// found as well.
+    fn find_child(&mut self, dir: &UnionDir, name: &OsStr) -> Result<Option<(UnionDir, usize, Inode)>> {
+        // Whiteouts are never visible themselves
+        let name = name.as_encoded_bytes();
+        if name.starts_with(WHITEOUT_PREFIX) {
+            return Ok(None);
+        }
+        let mut found: Option<(UnionDir, usize, Inode)> = None;
+        for &(layer, dir_ref) in dir {
+            let sqfs = &mut self.layers[layer];
+            let dir_inode = sqfs.inode_from_entryref(dir_ref)?;
+            let mut entry = None;
+            let mut whited_out = false;
+            for e in sqfs.read_dir_inode(&dir_inode)? {
+                if e.file_name_bytes() == name {
+                    entry = Some(e);
+                } else if e.file_name_bytes().strip_prefix(WHITEOUT_PREFIX) == Some(name) {
+                    whited_out = true;
+                }
+            }
+            let Some(e) = entry else {
+                if whited_out {
+                    break;
+                }
+                continue;
+            };
+            let inode = sqfs.inode_from_entryref(e.inode_ref())?;
+            if is_whiteout(&inode) {
+                break;
+            }
+            // Anything other than a directory hides all lower layers, and so does an opaque
+            // directory. A directory also hides anything other than a directory beneath it.
+            if !inode.is_dir() {
+                if found.is_none() {
+                    found = Some((Vec::new(), layer, inode));
+                }
+                break;
+            }
+            let opaque = is_opaque(sqfs, &inode)?;
+            match &mut found {
+                Some((child_dir, _, _)) => child_dir.push((layer, e.inode_ref())),
+                None => found = Some((vec![(layer, e.inode_ref())], layer, inode)),
+            }
+            if opaque {
+                break;
+            }
+        }
+        Ok(found)
+    }
+}
+
+impl<R: Read + Seek> Resolve for UnionFS<R> {
+    type Dir = UnionDir;
+
+    /// The Limits of the top layer
+    fn limits(&self) -> Limits {
+        *self.layers.last().unwrap().limits()
+    }
+
+    fn root_dir(&mut self) -> Result<Self::Dir> {
+        UnionFS::root_dir(self)
+    }
+
+    fn child(&mut self, dir: &Self::Dir, name: &OsStr) -> Result<Option<(Self::Dir, Inode)>> {
+        Ok(self.find_child(dir, name)?.map(|(child_dir, _, inode)| (child_dir, inode)))
+    }
+}
+
+/// Whether a directory hides the contents of the same directory in lower layers
+fn is_opaque<R: Read + Seek>(sqfs: &mut SquashFS<R>, inode: &Inode) -> Result<bool> {
+    if inode.xattrs(sqfs)?.iter().any(|(name, value)| name == OVERLAY_OPAQUE_XATTR && value == b"y") {
+        return Ok(true);
+    }
+    Ok(sqfs.read_dir_inode(inode)?.any(|e| e.file_name_bytes() == OPAQUE_WHITEOUT))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::squashfs::test_util::overlay_layers;
+
+    fn names<R: Read + Seek>(union: &mut UnionFS<R>, path: &str) -> io::Result<Vec<(String, usize)>> {
+        Ok(union.read_dir(path)?.map(|e| (e.file_name(), e.layer())).collect())
+    }
+
+    fn contents<R: Read + Seek>(union: &mut UnionFS<R>, path: &str) -> io::Result<String> {
+        let mut data = String::new();
+        union.open_file(path)?.read_to_string(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn test_union() -> io::Result<()> {
+        let mut union = UnionFS::new(Vec::from(overlay_layers()?))?;
+        let n = |s: &str, l| (s.to_string(), l);
+        assert_eq!(names(&mut union, "/")?, [n("bin", 1), n("etc", 1), n("lib", 1), n("opt", 1), n("usr", 0), n("var", 2)]);
+        assert_eq!(names(&mut union, "/etc")?, [n("passwd", 1)]);
+        assert_eq!(names(&mut union, "/opt/app")?, [n("new", 1)]);
+        assert_eq!(names(&mut union, "/var")?, [n("log", 2)]);
+
+        assert_eq!(union.inode_from_path("/etc")?.permissions(), 0o700);
+        assert_eq!(contents(&mut union, "/etc/passwd")?, "vendor");
+        assert_eq!(contents(&mut union, "/lib")?, "lib");
+        for hidden in ["/etc/shadow", "/etc/group", "/opt/app/old", "/var/cache", "/lib/file", "/.wh.missing"] {
+            assert_eq!(union.inode_from_path(hidden).unwrap_err().kind(), io::ErrorKind::NotFound, "{hidden}");
+        }
+
+        let entry = union.read_dir("/opt/app")?.next().unwrap();
+        let mut data = String::new();
+        union.open_file_dirent(&entry)?.read_to_string(&mut data)?;
+        assert_eq!(data, "new");
+
+        let (layer, inode) = union.lookup("/var")?;
+        assert_eq!(inode.xattrs(&union.layers()[layer])?.len(), 1);
+
+        let resolved = crate::squashfs::path::canonicalize_union(&mut union, "usr/app/new", "/")?;
+        assert_eq!(resolved, Path::new("/opt/app/new"));
+        Ok(())
+    }
+}
@@ -0,0 +1,439 @@
+//! dm-verity verification of an image as it is read
+//!
+//! Images are often distributed with a dm-verity hash tree, either appended to the image or in a
+//! separate file, along with the root hash that the tree must lead to. [`VerityReader`] wraps the
+//! reader of such an image and checks every block read through it against the tree, in the same
+//! way as a kernel verity mount. A block that has been tampered with fails to read with
+//! [`Error::VerityMismatch`], and [`VerityReader::verify_all`] checks the whole image up front.
+//!
+//! Only the normal hash format (type 1) is supported, as created by `veritysetup format`.
+//!
+//! ```rust
+//! use std::fs::File;
+//! use squinter::squashfs::SquashFS;
+//! use squinter::squashfs::verity::{VerityParams, VerityReader};
+//! fn open_verified(root_hash: Vec<u8>) -> squinter::Result<()> {
+//!     // The hash tree was written to a separate file by veritysetup, starting with its superblock
+//!     let mut hash_file = File::open("rootfs.squashfs.verity")?;
+//!     let params = VerityParams::from_superblock(&mut hash_file, 0, root_hash)?;
+//!     let reader = VerityReader::with_hash_reader(File::open("rootfs.squashfs")?, hash_file, params)?;
+//!     let mut sqfs = SquashFS::new(reader)?;
+//!     let mut file = sqfs.open_file("/etc/os-release")?;
+//!     std::io::copy(&mut file, &mut std::io::sink())?;
+//!     Ok(())
+//! }
+//! ```
+
+use std::cmp::min;
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use sha2::{Digest, Sha256, Sha512};
+
+use super::block::seek_position;
+use crate::Error;
+
+/// The signature at the start of a veritysetup superblock
+const SUPERBLOCK_SIGNATURE: &[u8; 8] = b"verity\0\0";
+
+/// The on-disk size of a veritysetup superblock
+const SUPERBLOCK_SIZE: u64 = 512;
+
+/// The hash algorithms that a tree may be built with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerityAlgorithm {
+    Sha256,
+    Sha512,
+}
+
+impl VerityAlgorithm {
+    /// The size of a digest, in bytes
+    pub fn digest_size(&self) -> usize {
+        match self {
+            VerityAlgorithm::Sha256 => 32,
+            VerityAlgorithm::Sha512 => 64,
+        }
+    }
+
+    /// Hash the salt followed by the block, as the normal verity format does
+    fn hash(&self, salt: &[u8], block: &[u8]) -> Vec<u8> {
+        match self {
+            VerityAlgorithm::Sha256 => Sha256::new().chain_update(salt).chain_update(block).finalize().to_vec(),
+            VerityAlgorithm::Sha512 => Sha512::new().chain_update(salt).chain_update(block).finalize().to_vec(),
+        }
+    }
+}
+
+impl std::str::FromStr for VerityAlgorithm {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(VerityAlgorithm::Sha256),
+            "sha512" => Ok(VerityAlgorithm::Sha512),
+            _ => Err(io::Error::new(io::ErrorKind::Unsupported, format!("Unsupported verity hash algorithm '{s}'"))),
+        }
+    }
+}
+
+/// The parameters of a dm-verity hash tree, as given to `veritysetup open` or recorded in its
+/// superblock
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VerityParams {
+    /// The hash at the top of the tree, which everything else is checked against
+    pub root_hash: Vec<u8>,
+    pub salt: Vec<u8>,
+    pub algorithm: VerityAlgorithm,
+    pub data_block_size: u32,
+    pub hash_block_size: u32,
+    /// The number of data blocks that the tree covers. Nothing after them can be read.
+    pub data_blocks: u64,
+    /// The offset of the hash tree itself, in bytes from the start of the hash reader. When the
+    /// hash area starts with a veritysetup superblock, this is past the superblock, as the kernel's
+    /// hash start block is.
+    pub hash_offset: u64,
+}
+
+impl VerityParams {
+    /// Read the parameters from the veritysetup superblock at `offset` in the hash reader, which
+    /// is the `--hash-offset` given to `veritysetup format`. The root hash is not recorded in the
+    /// superblock, so it must be given.
+    pub fn from_superblock<R>(r: &mut R, offset: u64, root_hash: Vec<u8>) -> io::Result<Self>
+    where R: Read + Seek
+    {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        r.seek(SeekFrom::Start(offset))?;
+        let mut signature = [0; 8];
+        r.read_exact(&mut signature)?;
+        if &signature != SUPERBLOCK_SIGNATURE {
+            return Err(invalid("Incorrect verity superblock signature"));
+        }
+        let version = r.read_u32::<LittleEndian>()?;
+        let hash_type = r.read_u32::<LittleEndian>()?;
+        if version != 1 || hash_type != 1 {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "Unsupported verity format"));
+        }
+        let mut uuid = [0; 16];
+        r.read_exact(&mut uuid)?;
+        let mut algorithm = [0; 32];
+        r.read_exact(&mut algorithm)?;
+        let name_len = algorithm.iter().position(|b| *b == 0).unwrap_or(algorithm.len());
+        let algorithm = std::str::from_utf8(&algorithm[..name_len])
+            .map_err(|_| invalid("Invalid verity hash algorithm"))?
+            .parse()?;
+        let data_block_size = r.read_u32::<LittleEndian>()?;
+        let hash_block_size = r.read_u32::<LittleEndian>()?;
+        let data_blocks = r.read_u64::<LittleEndian>()?;
+        let salt_size = r.read_u16::<LittleEndian>()?;
+        let mut pad = [0; 6];
+        r.read_exact(&mut pad)?;
+        let mut salt = [0; 256];
+        r.read_exact(&mut salt)?;
+        let salt = salt.get(..usize::from(salt_size)).ok_or_else(|| invalid("Invalid verity salt size"))?.to_vec();
+        if !hash_block_size.is_power_of_two() {
+            return Err(invalid("Invalid verity hash block size"));
+        }
+        // The tree starts at the first hash block after the superblock
+        let hash_offset = offset.checked_add(SUPERBLOCK_SIZE)
+            .and_then(|end| end.checked_next_multiple_of(hash_block_size.into()))
+            .ok_or_else(|| invalid("Invalid verity hash offset"))?;
+        Ok(Self { root_hash, salt, algorithm, data_block_size, hash_block_size, data_blocks, hash_offset })
+    }
+}
+
+/// A reader that verifies every block read from the inner reader against a dm-verity hash tree.
+///
+/// The hash tree is read either from the inner reader, when it is appended to the image, or from
+/// a separate hash reader. Hash blocks are only verified once, but data blocks are verified every
+/// time that they are read from the inner reader, so a block cannot change after it is checked.
+#[derive(Debug)]
+pub struct VerityReader<R, H = R> {
+    inner: R,
+    hash: Option<H>,
+    params: VerityParams,
+    digest_size: usize,
+    // The hashes in a hash block are each this many bits of a data block's index
+    hash_per_block_bits: u32,
+    // The first hash block of each level of the tree, counting from the bottom level
+    level_starts: Vec<u64>,
+    verified_hashes: HashMap<u64, Vec<u8>>,
+    block: Option<(u64, Vec<u8>)>,
+    pos: u64,
+}
+
+impl<R: Read + Seek> VerityReader<R> {
+    /// Create a reader over an image whose hash tree is stored in the same reader, after the data,
+    /// at the hash offset of the params
+    pub fn new(inner: R, params: VerityParams) -> io::Result<Self> {
+        Self::create(inner, None, params)
+    }
+}
+
+impl<R: Read + Seek, H: Read + Seek> VerityReader<R, H> {
+    /// Create a reader over an image whose hash tree is stored in a separate reader, at the hash
+    /// offset of the params
+    pub fn with_hash_reader(inner: R, hash: H, params: VerityParams) -> io::Result<Self> {
+        Self::create(inner, Some(hash), params)
+    }
+
+    fn create(inner: R, hash: Option<H>, params: VerityParams) -> io::Result<Self> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidInput, msg);
+        let digest_size = params.algorithm.digest_size();
+        if params.root_hash.len() != digest_size {
+            return Err(invalid("The verity root hash is not the size of a digest"));
+        }
+        for size in [params.data_block_size, params.hash_block_size] {
+            if !size.is_power_of_two() || !(512..=1024 * 1024).contains(&size) {
+                return Err(invalid("Invalid verity block size"));
+            }
+        }
+        if params.data_blocks == 0 || params.data_blocks.checked_mul(params.data_block_size.into()).is_none() {
+            return Err(invalid("Invalid verity data block count"));
+        }
+        // Digests are padded to a power of two, so that each hash block holds a power of two
+        let hash_per_block_bits = (params.hash_block_size / digest_size.next_power_of_two() as u32).ilog2();
+
+        // As the kernel lays them out, the top level of the tree comes first, and each level has
+        // one hash for each block of the level below it
+        let mut levels = 0;
+        while hash_per_block_bits * levels < 64 && (params.data_blocks - 1) >> (hash_per_block_bits * levels) != 0 {
+            levels += 1;
+        }
+        let mut level_starts = vec![0; levels as usize];
+        let mut position = 0u64;
+        for level in (0..levels).rev() {
+            level_starts[level as usize] = position;
+            let covered = 1u64.checked_shl(hash_per_block_bits * (level + 1)).unwrap_or(0);
+            position += if covered == 0 { 1 } else { params.data_blocks.div_ceil(covered) };
+        }
+
+        Ok(Self {
+            inner, hash, params, digest_size, hash_per_block_bits, level_starts,
+            verified_hashes: HashMap::new(),
+            block: None,
+            pos: 0,
+        })
+    }
+
+    /// The parameters of the hash tree
+    pub fn params(&self) -> &VerityParams {
+        &self.params
+    }
+
+    /// Verify every data block against the tree
+    pub fn verify_all(&mut self) -> io::Result<()> {
+        for index in 0..self.params.data_blocks {
+            self.load_block(index)?;
+        }
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// The size of the verified data
+    fn data_size(&self) -> u64 {
+        self.params.data_blocks * u64::from(self.params.data_block_size)
+    }
+
+    /// Read the data block with the given index from the inner reader and verify it
+    fn load_block(&mut self, index: u64) -> io::Result<()> {
+        let offset = index * u64::from(self.params.data_block_size);
+        let mut block = self.block.take().map(|(_, b)| b)
+            .unwrap_or_else(|| vec![0; self.params.data_block_size as usize]);
+        self.inner.seek(SeekFrom::Start(offset))?;
+        self.inner.read_exact(&mut block)?;
+        let expected = self.expected_digest(index)?;
+        if self.params.algorithm.hash(&self.params.salt, &block) != expected {
+            return Err(Error::VerityMismatch { offset }.into());
+        }
+        self.block = Some((index, block));
+        Ok(())
+    }
+
+    /// Walk down the tree from the root hash to the hash of the data block with the given index,
+    /// verifying each hash block on the way
+    fn expected_digest(&mut self, index: u64) -> io::Result<Vec<u8>> {
+        let digest_size = self.digest_size;
+        let mut digest = self.params.root_hash.clone();
+        for level in (0..self.level_starts.len()).rev() {
+            let bits = self.hash_per_block_bits * level as u32;
+            let hash_block = self.level_starts[level] + index.checked_shr(bits + self.hash_per_block_bits).unwrap_or(0);
+            let entry = (index >> bits) as usize & ((1 << self.hash_per_block_bits) - 1);
+            let block = self.hash_block(hash_block, &digest, index)?;
+            let start = entry * digest_size.next_power_of_two();
+            digest = block[start..start + digest_size].to_vec();
+        }
+        Ok(digest)
+    }
+
+    /// Read the hash block with the given index and verify it against its digest. The index of the
+    /// data block being verified is given for the error.
+    fn hash_block(&mut self, index: u64, digest: &[u8], data_index: u64) -> io::Result<&[u8]> {
+        if !self.verified_hashes.contains_key(&index) {
+            let size = self.params.hash_block_size;
+            let offset = index.checked_mul(size.into())
+                .and_then(|o| o.checked_add(self.params.hash_offset))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid verity hash offset"))?;
+            let mut block = vec![0; size as usize];
+            match &mut self.hash {
+                Some(h) => {
+                    h.seek(SeekFrom::Start(offset))?;
+                    h.read_exact(&mut block)?;
+                },
+                None => {
+                    self.inner.seek(SeekFrom::Start(offset))?;
+                    self.inner.read_exact(&mut block)?;
+                },
+            }
+            if self.params.algorithm.hash(&self.params.salt, &block) != digest {
+                let offset = data_index * u64::from(self.params.data_block_size);
+                return Err(Error::VerityMismatch { offset }.into());
+            }
+            self.verified_hashes.insert(index, block);
+        }
+        Ok(&self.verified_hashes[&index])
+    }
+}
+
+impl<R: Read + Seek, H: Read + Seek> Read for VerityReader<R, H> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.data_size() {
+            return Ok(0);
+        }
+        let block_size = u64::from(self.params.data_block_size);
+        let index = self.pos / block_size;
+        if !matches!(self.block, Some((i, _)) if i == index) {
+            self.load_block(index)?;
+        }
+        let Some((_, block)) = &self.block else { unreachable!() };
+        let start = (self.pos % block_size) as usize;
+        let len = min(buf.len(), block.len() - start);
+        buf[..len].copy_from_slice(&block[start..start + len]);
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek, H: Read + Seek> Seek for VerityReader<R, H> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = seek_position(pos, self.pos, self.data_size())?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::squashfs::test_util::build;
+    use crate::squashfs::writer::Attributes;
+    use crate::squashfs::SquashFS;
+
+    const BLOCK_SIZE: usize = 4096;
+
+    /// Build a hash tree over the data in the same way as veritysetup, returning the root hash and
+    /// the tree
+    fn hash_tree(data: &[u8], salt: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let algorithm = VerityAlgorithm::Sha256;
+        let mut levels = Vec::new();
+        let mut hashes: Vec<Vec<u8>> = data.chunks(BLOCK_SIZE).map(|b| algorithm.hash(salt, b)).collect();
+        while hashes.len() > 1 {
+            let level: Vec<u8> = hashes.chunks(BLOCK_SIZE / 32)
+                .flat_map(|c| {
+                    let mut block = c.concat();
+                    block.resize(BLOCK_SIZE, 0);
+                    block
+                })
+                .collect();
+            hashes = level.chunks(BLOCK_SIZE).map(|b| algorithm.hash(salt, b)).collect();
+            levels.push(level);
+        }
+        levels.reverse();
+        (hashes.remove(0), levels.concat())
+    }
+
+    fn image() -> io::Result<Vec<u8>> {
+        // Enough incompressible data for a tree of more than one level
+        let mut state = 0x2545f491u32;
+        let data: Vec<u8> = (0..1_000_000).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        }).collect();
+        let mut image = build(128 * 1024, |w| {
+            w.add_file("/data", Attributes::new(0o644, 0, 0, 0), &data[..])?;
+            w.add_file("/small", Attributes::new(0o644, 0, 0, 0), &b"small"[..])
+        })?;
+        image.resize(image.len().next_multiple_of(BLOCK_SIZE), 0);
+        Ok(image)
+    }
+
+    fn read_all<R: Read + Seek>(sqfs: &mut SquashFS<R>) -> crate::Result<()> {
+        for path in ["/data", "/small"] {
+            io::copy(&mut sqfs.open_file(path)?, &mut io::sink())?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_verity() -> crate::Result<()> {
+        let image = image()?;
+        let salt = b"salt".to_vec();
+        let (root_hash, tree) = hash_tree(&image, &salt);
+        assert!(tree.len() > 2 * BLOCK_SIZE);
+
+        // Appended to the image, with a superblock in front of the tree
+        let mut sb = Vec::new();
+        sb.extend_from_slice(SUPERBLOCK_SIGNATURE);
+        sb.extend_from_slice(&1u32.to_le_bytes());
+        sb.extend_from_slice(&1u32.to_le_bytes());
+        sb.extend_from_slice(&[0; 16]);
+        sb.extend_from_slice(b"sha256");
+        sb.resize(64, 0);
+        sb.extend_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+        sb.extend_from_slice(&(BLOCK_SIZE as u32).to_le_bytes());
+        sb.extend_from_slice(&((image.len() / BLOCK_SIZE) as u64).to_le_bytes());
+        sb.extend_from_slice(&(salt.len() as u16).to_le_bytes());
+        sb.extend_from_slice(&[0; 6]);
+        sb.extend_from_slice(&salt);
+        sb.resize(BLOCK_SIZE, 0);
+        let appended = [&image[..], &sb, &tree].concat();
+        let mut r = Cursor::new(appended.clone());
+        let params = VerityParams::from_superblock(&mut r, image.len() as u64, root_hash.clone())?;
+        assert_eq!(params.hash_offset, (image.len() + BLOCK_SIZE) as u64);
+        let mut reader = VerityReader::new(r, params.clone())?;
+        reader.verify_all()?;
+        read_all(&mut SquashFS::new(reader)?)?;
+
+        // In a separate file
+        let reader = VerityReader::with_hash_reader(Cursor::new(image.clone()), Cursor::new(tree.clone()),
+            VerityParams { hash_offset: 0, ..params.clone() })?;
+        read_all(&mut SquashFS::new(reader)?)?;
+
+        // A damaged data block fails to read, but only when it is read
+        let mut sqfs = SquashFS::new(VerityReader::new(Cursor::new(appended.clone()), params.clone())?)?;
+        let (blocks_start, _) = sqfs.inode_from_path("/data")?.data_blocks().map(|(s, b)| (s, b.to_vec())).unwrap();
+        let mut damaged = appended.clone();
+        damaged[blocks_start as usize + 5000] ^= 1;
+        let mut sqfs = SquashFS::new(VerityReader::new(Cursor::new(damaged.clone()), params.clone())?)?;
+        io::copy(&mut sqfs.open_file("/small")?, &mut io::sink())?;
+        let err = io::copy(&mut sqfs.open_file("/data")?, &mut io::sink()).unwrap_err();
+        let block_offset = (blocks_start + 5000) / BLOCK_SIZE as u64 * BLOCK_SIZE as u64;
+        assert!(matches!(Error::from(err), Error::VerityMismatch { offset } if offset == block_offset));
+        let mut reader = VerityReader::new(Cursor::new(damaged), params.clone())?;
+        assert!(matches!(Error::from(reader.verify_all().unwrap_err()), Error::VerityMismatch { .. }));
+
+        // So does every block beneath a damaged hash block, and a wrong root hash fails everything
+        let mut damaged = appended.clone();
+        damaged[params.hash_offset as usize + BLOCK_SIZE + 10] ^= 1;
+        let mut reader = VerityReader::new(Cursor::new(damaged), params.clone())?;
+        assert!(matches!(Error::from(reader.verify_all().unwrap_err()), Error::VerityMismatch { offset: 0 }));
+        let wrong_root = VerityParams { root_hash: vec![0; 32], ..params.clone() };
+        assert!(SquashFS::new(VerityReader::new(Cursor::new(appended), wrong_root)?).is_err());
+        Ok(())
+    }
+}
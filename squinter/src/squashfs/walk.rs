@@ -0,0 +1,189 @@
+//! Walking of a whole directory tree
+//!
+//! [`Walk`], created by [`SquashFS::walk`], visits every entry beneath a directory, with the
+//! contents of each subdirectory right after the subdirectory itself. A corrupt image cannot make
+//! it walk without end: a directory that is linked from inside itself, or from more than one
+//! place, is returned as [`Error::DirectoryLoop`] rather than walked again, and a directory deeper
+//! than the image's [`Limits`](super::Limits) allow is returned as [`Error::LimitExceeded`]. A
+//! directory with an entry whose name could escape it is returned as [`Error::InvalidName`] rather
+//! than partly walked. The walk carries on with the next entry after any of them.
+//!
+//! ```rust
+//! use squinter::squashfs::SquashFS;
+//! fn largest_file() -> squinter::Result<Option<(std::path::PathBuf, u64)>> {
+//!     let mut sqfs = SquashFS::open("rootfs.squashfs")?;
+//!     let mut largest = None;
+//!     for entry in sqfs.walk("/")? {
+//!         let entry = entry?;
+//!         if let Some(size) = entry.inode().file_size().filter(|_| entry.inode().is_file()) {
+//!             if largest.as_ref().is_none_or(|(_, s)| size > *s) {
+//!                 largest = Some((entry.path().to_path_buf(), size));
+//!             }
+//!         }
+//!     }
+//!     Ok(largest)
+//! }
+//! ```
+
+use std::collections::HashSet;
+use std::io::{self, Read, Seek};
+use std::path::{Path, PathBuf};
+
+use super::metadata::{DirTable, EntryReference, Inode};
+use super::{DirEntry, SquashFS};
+use crate::{Error, Result};
+
+/// An entry found by a [`Walk`], along with its path and Inode
+#[derive(Debug)]
+pub struct WalkEntry {
+    path: PathBuf,
+    depth: usize,
+    entry: DirEntry,
+    inode: Inode,
+}
+
+impl WalkEntry {
+    /// The path of the entry, beginning with the path that the walk started from
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// How deep the entry is beneath the directory that the walk started from. The entries of
+    /// that directory have a depth of 1.
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn dir_entry(&self) -> &DirEntry {
+        &self.entry
+    }
+
+    pub fn inode(&self) -> &Inode {
+        &self.inode
+    }
+}
+
+/// An iterator over every entry beneath a directory. See the [module documentation](self) for an
+/// overview.
+#[derive(Debug)]
+pub struct Walk<'a, R: Read + Seek> {
+    sqfs: &'a mut SquashFS<R>,
+    // The path of each directory being walked, and its entries that have not been visited yet
+    stack: Vec<(PathBuf, std::vec::IntoIter<DirEntry>)>,
+    // The directory just visited, whose contents come next
+    pending: Option<(PathBuf, EntryReference)>,
+    visited: Visited,
+}
+
+/// The directory listings that a walk has entered, so that a directory linked from inside itself
+/// or from more than one place is not walked again. Walkers that need the SquashFS between entries,
+/// and so cannot use [`Walk`], use this through [`SquashFS::read_walk_dir`].
+#[derive(Debug, Default)]
+pub(crate) struct Visited(HashSet<(u64, u16)>);
+
+impl Visited {
+    /// Record that the walk is entering a directory with the given entries. Returns false if it
+    /// has entered the directory's listing before.
+    pub(crate) fn enter(&mut self, dir: &Inode, entries: &[DirEntry]) -> io::Result<bool> {
+        // Empty directories may share the location of their listing, but cannot lead anywhere
+        if entries.is_empty() {
+            return Ok(true);
+        }
+        let listing = DirTable::entryref_from_inode(dir)?;
+        Ok(self.0.insert((listing.location(), listing.offset())))
+    }
+}
+
+impl<'a, R: Read + Seek> Walk<'a, R> {
+    pub(crate) fn new(sqfs: &'a mut SquashFS<R>, path: &Path) -> Result<Self> {
+        let dir = sqfs.inode_from_path(path)?;
+        let mut walk = Self { sqfs, stack: Vec::new(), pending: None, visited: Visited::default() };
+        walk.descend(path.to_path_buf(), &dir)?;
+        Ok(walk)
+    }
+
+    /// Leave out the contents of the directory most recently returned by the walk
+    pub fn skip_dir(&mut self) {
+        self.pending = None;
+    }
+
+    /// Start walking the contents of a directory, unless it has already been walked
+    fn descend(&mut self, path: PathBuf, dir: &Inode) -> Result<()> {
+        self.sqfs.limits().check_depth(self.stack.len())?;
+        let entries: Vec<_> = self.sqfs.read_dir_inode(dir).map_err(|e| e.at_path(&path))?.collect();
+        if !self.visited.enter(dir, &entries)? {
+            return Err(Error::DirectoryLoop(path));
+        }
+        self.stack.push((path, entries.into_iter()));
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Iterator for Walk<'_, R> {
+    type Item = Result<WalkEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some((path, inode_ref)) = self.pending.take() {
+            let descended = self.sqfs.inode_from_entryref(inode_ref)
+                .and_then(|dir| self.descend(path, &dir));
+            if let Err(e) = descended {
+                return Some(Err(e));
+            }
+        }
+        loop {
+            let (dir_path, entries) = self.stack.last_mut()?;
+            let Some(entry) = entries.next() else {
+                self.stack.pop();
+                continue;
+            };
+            let path = dir_path.join(entry.file_name_os());
+            let depth = self.stack.len();
+            let inode = match self.sqfs.inode_from_entryref(entry.inode_ref()) {
+                Ok(inode) => inode,
+                Err(e) => return Some(Err(e)),
+            };
+            if inode.is_dir() {
+                self.pending = Some((path.clone(), entry.inode_ref()));
+            }
+            return Some(Ok(WalkEntry { path, depth, entry, inode }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::squashfs::test_util::{invalid_name_image, loop_image, tree_image};
+
+    fn walk_paths<R: Read + Seek>(sqfs: &mut SquashFS<R>, path: &str) -> Vec<std::result::Result<(String, usize), String>> {
+        sqfs.walk(path).unwrap()
+            .map(|e| e.map(|e| (e.path().display().to_string(), e.depth())).map_err(|e| e.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_walk() -> Result<()> {
+        let ok = |p: &str, d| Ok((p.to_string(), d));
+        let mut sqfs = SquashFS::new(Cursor::new(tree_image()?))?;
+        assert_eq!(walk_paths(&mut sqfs, "/"), [ok("/a", 1), ok("/a/b", 2), ok("/a/b/file", 3),
+            ok("/a/c", 2), ok("/a/d", 2), ok("/a/d/file", 3), ok("/z", 1)]);
+        assert_eq!(walk_paths(&mut sqfs, "/a/b"), [ok("/a/b/file", 1)]);
+        let mut walk = sqfs.walk("/a")?;
+        assert_eq!(walk.next().unwrap()?.path(), Path::new("/a/b"));
+        walk.skip_dir();
+        assert_eq!(walk.next().unwrap()?.path(), Path::new("/a/c"));
+        assert!(matches!(sqfs.walk("/a/b/file").unwrap_err(), Error::NotADirectory(_)));
+
+        // The loop is reported once, and the rest of the tree is still walked
+        let mut sqfs = SquashFS::new(Cursor::new(loop_image()?))?;
+        assert_eq!(walk_paths(&mut sqfs, "/"), [ok("/a", 1), ok("/a/b", 2), ok("/a/b/file", 3),
+            ok("/a/c", 2), Err(Error::DirectoryLoop("/a/c".into()).to_string()),
+            ok("/a/d", 2), ok("/a/d/file", 3), ok("/z", 1)]);
+
+        // So is a directory with an invalid entry name, rather than a partial listing of it
+        let mut sqfs = SquashFS::new(Cursor::new(invalid_name_image()?))?;
+        assert_eq!(walk_paths(&mut sqfs, "/"), [ok("/d", 1), Err(Error::InvalidName(b"b/c".to_vec()).to_string())]);
+        Ok(())
+    }
+}
@@ -0,0 +1,1610 @@
+//! Creation of new SquashFS images
+//!
+//! A [`SquashFSWriter`] builds an image from a sequence of calls that add directories, files,
+//! symbolic links, and special files by path. File contents are compressed and written to the
+//! output as soon as each file is added, so large trees can be streamed into an image (for
+//! example, from a tar archive) without holding file data in memory. The directory tree and all
+//! other metadata are kept in memory until [`SquashFSWriter::finish`] writes the metadata tables
+//! and the superblock.
+//!
+//! ```rust
+//! use std::io::{self, Cursor};
+//! use squinter::squashfs::writer::{Attributes, SquashFSWriter, WriterOptions};
+//! fn build_image() -> io::Result<Vec<u8>> {
+//!     let mut writer = SquashFSWriter::new(Cursor::new(Vec::new()), WriterOptions::default())?;
+//!     writer.add_dir("/etc", Attributes::new(0o755, 0, 0, 0))?;
+//!     writer.add_file("/etc/hostname", Attributes::new(0o644, 0, 0, 0), &b"squinter\n"[..])?;
+//!     writer.add_symlink("/etc/localtime", Attributes::new(0o777, 0, 0, 0), "/usr/share/zoneinfo/UTC")?;
+//!     Ok(writer.finish()?.into_inner())
+//! }
+//! ```
+
+use std::cmp::min;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::CString;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use super::compressed::compress_block;
+use super::metadata::{self, EntryReference, FragmentEntry, Inode, InodeExtendedInfo, InodeType,
+    MAX_DIR_HEADER_ENTRIES, MAX_NAME_LEN, METADATA_BLOCK_SIZE};
+use super::squashfs::SquashFS;
+use super::superblock::{Compressor, GzipOptions, GzipStrategies, Superblock, SuperblockFlags, MAGIC, SUPERBLOCK_SIZE};
+use super::walk::Visited;
+
+// Flag set in a data block or fragment size to indicate that the block is stored uncompressed
+const DATA_BLOCK_UNCOMPRESSED: u32 = 0x1000000;
+
+// Flag set in a metadata block header to indicate that the block is stored uncompressed
+const METADATA_BLOCK_UNCOMPRESSED: u16 = 0x8000;
+
+// Marker for "no fragment" and "no xattrs" in inode fields
+const NO_INDEX: u32 = u32::MAX;
+
+// The root directory is always the first node created
+const ROOT: usize = 0;
+
+/// Options controlling the layout and compression of a new SquashFS image
+#[derive(Debug, Clone)]
+pub struct WriterOptions {
+    /// The compressor used for all data and metadata blocks
+    pub compressor: Compressor,
+    /// The compression level, or None for the compressor's default. Only gzip supports levels
+    /// (1 to 9, default 9).
+    pub compression_level: Option<u32>,
+    /// The size of each data block. Must be a power of two between 4KiB and 1MiB.
+    pub block_size: u32,
+    /// The modification time recorded in the superblock, and the mtime given to directories that
+    /// are created implicitly because a path beneath them was added
+    pub mod_time: u32,
+    /// Store the tail-end of each file in its own short data block rather than packing tail-ends
+    /// together into fragment blocks
+    pub no_fragments: bool,
+    /// Pad the image with zeros to a multiple of 4KiB, as mksquashfs does
+    pub pad: bool,
+    /// Write the NFS export table, which maps inode numbers to inodes
+    pub export_table: bool,
+}
+
+impl Default for WriterOptions {
+    fn default() -> Self {
+        Self {
+            compressor: default_compressor(),
+            compression_level: None,
+            block_size: 128 * 1024,
+            mod_time: 0,
+            no_fragments: false,
+            pad: true,
+            export_table: false,
+        }
+    }
+}
+
+// The default compressor is the first one that is compiled in, preferring the mksquashfs default
+#[allow(unreachable_code)]
+fn default_compressor() -> Compressor {
+    #[cfg(feature = "flate2")]
+    return Compressor::Gzip;
+    #[cfg(feature = "ruzstd")]
+    return Compressor::Zstd;
+    #[cfg(feature = "lzma-rs")]
+    return Compressor::Xz;
+    Compressor::None
+}
+
+/// Ownership, permissions, and other attributes shared by every type of filesystem entry
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Attributes {
+    /// Permission bits, including the setuid, setgid, and sticky bits. File type bits are ignored.
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    /// Modification time in seconds since the UNIX epoch
+    pub mtime: u32,
+    /// Extended attributes as (full name, value) pairs, such as ("user.comment", "hello"). Only the
+    /// "user.", "trusted.", and "security." namespaces can be stored in SquashFS.
+    pub xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl Attributes {
+    pub fn new(mode: u16, uid: u32, gid: u32, mtime: u32) -> Self {
+        Self { mode: mode & 0o7777, uid, gid, mtime, xattrs: Vec::new() }
+    }
+
+    /// Read the attributes of an Inode in an existing image
+    pub fn from_inode<R: Read + Seek>(inode: &Inode, sqfs: &SquashFS<R>) -> io::Result<Self> {
+        Ok(Self {
+            mode: inode.permissions(),
+            uid: inode.uid(sqfs)?,
+            gid: inode.gid(sqfs)?,
+            mtime: inode.mtime(),
+            xattrs: inode.xattrs(sqfs)?,
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Node {
+    attrs: Attributes,
+    kind: NodeKind,
+    /// An inode number requested for this node, rather than one assigned by the writer
+    inode_number: Option<u32>,
+}
+
+#[derive(Debug)]
+enum NodeKind {
+    Dir(BTreeMap<Vec<u8>, usize>),
+    File(FileData),
+    Symlink(CString),
+    BlockDev(u32),
+    CharDev(u32),
+    Fifo,
+    Socket,
+}
+
+impl NodeKind {
+    fn inode_type(&self) -> InodeType {
+        match self {
+            NodeKind::Dir(_) => InodeType::BasicDir,
+            NodeKind::File(_) => InodeType::BasicFile,
+            NodeKind::Symlink(_) => InodeType::BasicSymlink,
+            NodeKind::BlockDev(_) => InodeType::BasicBlockDev,
+            NodeKind::CharDev(_) => InodeType::BasicCharDev,
+            NodeKind::Fifo => InodeType::BasicNamedPipe,
+            NodeKind::Socket => InodeType::BasicSocked,
+        }
+    }
+}
+
+/// The location of a file's contents within the image
+#[derive(Debug)]
+struct FileData {
+    file_size: u64,
+    blocks_start: u64,
+    block_sizes: Vec<u32>,
+    /// Fragment index and offset within the fragment block of the file's tail-end, if any
+    fragment: Option<(u32, u32)>,
+}
+
+/// Builder for a new SquashFS image. See the [module documentation](self) for an overview.
+#[derive(Debug)]
+pub struct SquashFSWriter<W: Write + Seek> {
+    w: W,
+    opts: WriterOptions,
+    pos: u64,
+    nodes: Vec<Node>,
+    frag_buf: Vec<u8>,
+    frag_table: Vec<FragmentEntry>,
+    block_buf: Vec<u8>,
+    compress_buf: Vec<u8>,
+    /// Whether a compressor options block follows the superblock
+    compressor_options: bool,
+}
+
+impl<W: Write + Seek> SquashFSWriter<W> {
+    /// Create a new writer that will build an image at the start of the provided Writer
+    pub fn new(mut w: W, opts: WriterOptions) -> io::Result<Self> {
+        if !opts.block_size.is_power_of_two() || !(4096..=1024 * 1024).contains(&opts.block_size) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Block size must be a power of two between 4KiB and 1MiB"));
+        }
+        // Fail early, rather than after all of the file data has been written
+        compress_block(opts.compressor, opts.compression_level, &[0; 64], &mut Vec::new())?;
+
+        // The superblock is written last, once all of the table locations are known. Compressor
+        // options immediately follow it, but are only needed when they differ from the defaults.
+        w.seek(SeekFrom::Start(SUPERBLOCK_SIZE))?;
+        let mut pos = SUPERBLOCK_SIZE;
+        let options = compressor_options(&opts)?;
+        if let Some(options) = &options {
+            w.write_all(options)?;
+            pos += options.len() as u64;
+        }
+        Ok(Self::from_parts(w, opts, pos, options.is_some(), Vec::new()))
+    }
+
+    fn from_parts(w: W, opts: WriterOptions, pos: u64, compressor_options: bool, frag_table: Vec<FragmentEntry>) -> Self {
+        let root = Node {
+            attrs: Attributes::new(0o755, 0, 0, opts.mod_time),
+            kind: NodeKind::Dir(BTreeMap::new()),
+            inode_number: None,
+        };
+        Self {
+            w,
+            pos,
+            nodes: vec![root],
+            frag_buf: Vec::with_capacity(opts.block_size as usize),
+            frag_table,
+            block_buf: vec![0; opts.block_size as usize],
+            compress_buf: Vec::new(),
+            compressor_options,
+            opts,
+        }
+    }
+
+    /// The options that this writer was created with
+    pub fn options(&self) -> &WriterOptions {
+        &self.opts
+    }
+
+    /// Add a directory. Adding a directory that already exists replaces its attributes but keeps
+    /// its contents. Adding "/" sets the attributes of the root directory.
+    pub fn add_dir<P: AsRef<Path>>(&mut self, path: P, attrs: Attributes) -> io::Result<()> {
+        self.add_dir_bytes(path_bytes(path.as_ref()), attrs)
+    }
+
+    /// Add a regular file, reading its contents from `data` until EOF. Any existing entry at the
+    /// same path is replaced.
+    pub fn add_file<P: AsRef<Path>, F: Read>(&mut self, path: P, attrs: Attributes, data: F) -> io::Result<()> {
+        self.add_file_bytes(path_bytes(path.as_ref()), attrs, data)
+    }
+
+    /// Add a symbolic link pointing at `target`. Any existing entry at the same path is replaced.
+    pub fn add_symlink<P: AsRef<Path>, T: AsRef<Path>>(&mut self, path: P, attrs: Attributes, target: T) -> io::Result<()> {
+        self.add_symlink_bytes(path_bytes(path.as_ref()), attrs, path_bytes(target.as_ref()))
+    }
+
+    /// Add a block device node. See [`metadata::encode_dev`] to build `dev` from major and minor
+    /// numbers.
+    pub fn add_block_device<P: AsRef<Path>>(&mut self, path: P, attrs: Attributes, dev: u32) -> io::Result<()> {
+        self.add_block_device_bytes(path_bytes(path.as_ref()), attrs, dev)
+    }
+
+    /// Add a character device node. See [`metadata::encode_dev`] to build `dev` from major and
+    /// minor numbers.
+    pub fn add_char_device<P: AsRef<Path>>(&mut self, path: P, attrs: Attributes, dev: u32) -> io::Result<()> {
+        self.add_char_device_bytes(path_bytes(path.as_ref()), attrs, dev)
+    }
+
+    /// Add a named pipe
+    pub fn add_fifo<P: AsRef<Path>>(&mut self, path: P, attrs: Attributes) -> io::Result<()> {
+        self.add_fifo_bytes(path_bytes(path.as_ref()), attrs)
+    }
+
+    /// Add a UNIX domain socket
+    pub fn add_socket<P: AsRef<Path>>(&mut self, path: P, attrs: Attributes) -> io::Result<()> {
+        self.add_socket_bytes(path_bytes(path.as_ref()), attrs)
+    }
+
+    /// Add a hard link at `path` to the existing non-directory entry at `target`. Both entries will
+    /// share a single inode.
+    pub fn add_hard_link<P: AsRef<Path>, T: AsRef<Path>>(&mut self, path: P, target: T) -> io::Result<()> {
+        self.add_hard_link_bytes(path_bytes(path.as_ref()), path_bytes(target.as_ref()))
+    }
+
+    /// Remove the entry at `path`, including all of its contents if it is a directory. Data that
+    /// was already written for removed files remains in the image but is unreferenced.
+    pub fn remove<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.remove_bytes(path_bytes(path.as_ref()))
+    }
+
+    /// Returns true if an entry exists at `path`
+    pub fn exists<P: AsRef<Path>>(&self, path: P) -> bool {
+        split_path(path_bytes(path.as_ref())).is_ok_and(|names| self.lookup(&names).is_some())
+    }
+
+    /// Returns true if `path` is a directory with no entries
+    pub(crate) fn is_empty_dir_bytes(&self, path: &[u8]) -> bool {
+        split_path(path).ok()
+            .and_then(|names| self.lookup(&names))
+            .and_then(|node| self.children(node))
+            .is_some_and(|children| children.is_empty())
+    }
+
+    /// The attributes of the entry at `path`, for modification
+    pub(crate) fn attributes_mut_bytes(&mut self, path: &[u8]) -> io::Result<&mut Attributes> {
+        let node = self.lookup(&split_path(path)?)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        Ok(&mut self.nodes[node].attrs)
+    }
+
+    pub(crate) fn add_dir_bytes(&mut self, path: &[u8], attrs: Attributes) -> io::Result<()> {
+        self.insert(path, attrs, NodeKind::Dir(BTreeMap::new()))
+    }
+
+    pub(crate) fn add_file_bytes<F: Read>(&mut self, path: &[u8], attrs: Attributes, data: F) -> io::Result<()> {
+        // Check the path before writing any data
+        split_path(path)?;
+        let file_data = self.write_file_data(data)?;
+        self.insert(path, attrs, NodeKind::File(file_data))
+    }
+
+    pub(crate) fn add_symlink_bytes(&mut self, path: &[u8], attrs: Attributes, target: &[u8]) -> io::Result<()> {
+        let target = CString::new(target)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Symlink target contains a NUL byte"))?;
+        self.insert(path, attrs, NodeKind::Symlink(target))
+    }
+
+    pub(crate) fn add_block_device_bytes(&mut self, path: &[u8], attrs: Attributes, dev: u32) -> io::Result<()> {
+        self.insert(path, attrs, NodeKind::BlockDev(dev))
+    }
+
+    pub(crate) fn add_char_device_bytes(&mut self, path: &[u8], attrs: Attributes, dev: u32) -> io::Result<()> {
+        self.insert(path, attrs, NodeKind::CharDev(dev))
+    }
+
+    pub(crate) fn add_fifo_bytes(&mut self, path: &[u8], attrs: Attributes) -> io::Result<()> {
+        self.insert(path, attrs, NodeKind::Fifo)
+    }
+
+    pub(crate) fn add_socket_bytes(&mut self, path: &[u8], attrs: Attributes) -> io::Result<()> {
+        self.insert(path, attrs, NodeKind::Socket)
+    }
+
+    pub(crate) fn add_hard_link_bytes(&mut self, path: &[u8], target: &[u8]) -> io::Result<()> {
+        let target = self.lookup(&split_path(target)?)
+            .ok_or(io::Error::new(io::ErrorKind::NotFound, "Hard link target does not exist"))?;
+        if matches!(self.nodes[target].kind, NodeKind::Dir(_)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Hard links to directories are not allowed"));
+        }
+        let names = split_path(path)?;
+        let (name, parents) = names.split_last()
+            .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "Cannot replace the root directory"))?;
+        let parent = self.make_parents(parents)?;
+        self.children_mut(parent).insert(name.clone(), target);
+        Ok(())
+    }
+
+    pub(crate) fn remove_bytes(&mut self, path: &[u8]) -> io::Result<()> {
+        let names = split_path(path)?;
+        let (name, parents) = names.split_last()
+            .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "Cannot remove the root directory"))?;
+        let parent = self.lookup(parents)
+            .filter(|p| matches!(self.nodes[*p].kind, NodeKind::Dir(_)))
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        self.children_mut(parent).remove(name)
+            .map(|_| ())
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    /// Remove all of the entries in the directory at `path`
+    pub(crate) fn clear_dir_bytes(&mut self, path: &[u8]) -> io::Result<()> {
+        let dir = self.lookup(&split_path(path)?)
+            .filter(|d| matches!(self.nodes[*d].kind, NodeKind::Dir(_)))
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        self.children_mut(dir).clear();
+        Ok(())
+    }
+
+    /// Add a copy of an Inode from an existing image at `path`. Directory contents are not copied.
+    pub(crate) fn add_inode_bytes<R: Read + Seek>(&mut self, path: &[u8], sqfs: &mut SquashFS<R>, inode: &Inode) -> io::Result<()> {
+        let attrs = Attributes::from_inode(inode, sqfs)?;
+        if inode.is_file() {
+            let reader = sqfs.open_file_inode(inode)?;
+            self.add_file_bytes(path, attrs, reader)
+        } else {
+            self.insert(path, attrs, node_kind_from_inode(inode)?)
+        }
+    }
+
+    /// Add a copy of an Inode from an existing image that is not a regular file at `path`, with
+    /// the given attributes. Directory contents are not copied.
+    pub(crate) fn add_special_inode_bytes(&mut self, path: &[u8], attrs: Attributes, inode: &Inode) -> io::Result<()> {
+        if inode.is_file() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Regular files must be added with their data"));
+        }
+        self.insert(path, attrs, node_kind_from_inode(inode)?)
+    }
+
+    /// Add a copy of a regular file from an existing image at `path`. The file's data blocks are
+    /// copied without being decompressed, so the image must use the same compressor and block
+    /// size as this writer. Any tail-end is decompressed and packed into this writer's fragments.
+    pub(crate) fn add_file_raw_bytes<R: Read + Seek>(&mut self, path: &[u8], sqfs: &mut SquashFS<R>, inode: &Inode) -> io::Result<()> {
+        if sqfs.compressor() != self.opts.compressor || sqfs.block_size() != self.opts.block_size {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Copying data blocks requires the same compressor and block size"));
+        }
+        let (src_start, src_sizes) = inode.data_blocks()
+            .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "Not a regular file"))?;
+        // Check the path before writing any data
+        split_path(path)?;
+        let attrs = Attributes::from_inode(inode, sqfs)?;
+        let file_size = inode.file_size().unwrap_or(0);
+
+        let blocks_start = self.pos;
+        let mut block_sizes = Vec::with_capacity(src_sizes.len());
+        let mut buf = std::mem::take(&mut self.compress_buf);
+        let mut src_pos = src_start;
+        for &size in src_sizes {
+            let len = size & !DATA_BLOCK_UNCOMPRESSED;
+            buf.resize(len as usize, 0);
+            sqfs.read_raw(src_pos, &mut buf)?;
+            self.write_raw(&buf)?;
+            block_sizes.push(size);
+            src_pos += u64::from(len);
+        }
+        self.compress_buf = buf;
+
+        let block_data_len = min(file_size, src_sizes.len() as u64 * u64::from(self.opts.block_size));
+        let mut fragment = None;
+        if block_data_len < file_size {
+            let mut tail = Vec::with_capacity((file_size - block_data_len) as usize);
+            let mut reader = sqfs.open_file_inode(inode)?;
+            reader.seek(SeekFrom::Start(block_data_len))?;
+            reader.read_to_end(&mut tail)?;
+            if self.opts.no_fragments {
+                block_sizes.push(self.write_data_block(&tail)?);
+            } else {
+                fragment = Some(self.add_fragment(&tail)?);
+            }
+        }
+        let file_data = FileData { file_size, blocks_start, block_sizes, fragment };
+        self.insert(path, attrs, NodeKind::File(file_data))
+    }
+
+    /// Request a specific inode number for the entry at `path`. Entries without a requested number
+    /// are given the lowest numbers that are not already requested.
+    pub(crate) fn set_inode_number_bytes(&mut self, path: &[u8], inode_number: u32) -> io::Result<()> {
+        if inode_number == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Inode numbers must be non-zero"));
+        }
+        let node = self.lookup(&split_path(path)?)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        self.nodes[node].inode_number = Some(inode_number);
+        Ok(())
+    }
+
+    /// Write the metadata tables and superblock, completing the image. Returns the inner Writer,
+    /// positioned at the end of the image.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.flush_fragment()?;
+        let compressor = self.opts.compressor;
+
+        // Number the inodes so that every directory's contents come before the directory itself,
+        // leaving the root directory with the highest number, as mksquashfs does. Entries that are
+        // hard links to the same node share a single inode. Requested numbers are kept, and the
+        // rest are numbered around them. Numbers must not exceed the inode count, so requested
+        // numbers that would leave a gap are not kept.
+        let (order, parents) = self.postorder();
+        let inode_count = u32::try_from(order.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Too many inodes"))?;
+        let mut inode_numbers = vec![0u32; self.nodes.len()];
+        let mut used = HashSet::new();
+        for idx in &order {
+            if let Some(n) = self.nodes[*idx].inode_number.filter(|n| *n <= inode_count) {
+                if !used.insert(n) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Inode number {n} was requested more than once")));
+                }
+                inode_numbers[*idx] = n;
+            }
+        }
+        let mut next = 1;
+        for idx in &order {
+            if inode_numbers[*idx] == 0 {
+                while used.contains(&next) {
+                    next += 1;
+                }
+                inode_numbers[*idx] = next;
+                next += 1;
+            }
+        }
+
+        // Directories are linked from their parent, from '.', and from the '..' of each
+        // subdirectory. Everything else is linked once per directory entry.
+        let mut link_counts = vec![0u32; self.nodes.len()];
+        for idx in &order {
+            if let NodeKind::Dir(children) = &self.nodes[*idx].kind {
+                link_counts[*idx] += 2;
+                for c in children.values() {
+                    if matches!(self.nodes[*c].kind, NodeKind::Dir(_)) {
+                        link_counts[*idx] += 1;
+                    } else {
+                        link_counts[*c] += 1;
+                    }
+                }
+            }
+        }
+
+        let mut ids = IdTableBuilder::default();
+        let mut xattrs = XattrTableBuilder::new(compressor, self.opts.compression_level);
+        let mut xattr_indices = vec![NO_INDEX; self.nodes.len()];
+        for idx in &order {
+            let attrs = &self.nodes[*idx].attrs;
+            ids.index(attrs.uid)?;
+            ids.index(attrs.gid)?;
+            xattr_indices[*idx] = xattrs.index(&attrs.xattrs)?;
+        }
+
+        // Write the inodes in the same order that they were numbered, so that every directory's
+        // contents already have inode references by the time its listing is written
+        let mut inode_md = MetadataWriter::new(compressor, self.opts.compression_level);
+        let mut dir_md = MetadataWriter::new(compressor, self.opts.compression_level);
+        let mut inode_refs = vec![EntryReference::new(0, 0); self.nodes.len()];
+        for idx in &order {
+            let node = &self.nodes[*idx];
+            let link_count = link_counts[*idx];
+            let xattr_index = xattr_indices[*idx];
+            let has_xattrs = xattr_index != NO_INDEX;
+            let inode_type = node.kind.inode_type();
+            let (inode_type, extended_info) = match &node.kind {
+                NodeKind::Dir(children) => {
+                    let dir_ref = dir_md.position();
+                    let entries: Vec<DirListingEntry> = children.iter()
+                        .map(|(name, c)| DirListingEntry {
+                            name,
+                            inode_ref: inode_refs[*c],
+                            inode_number: inode_numbers[*c],
+                            inode_type: self.nodes[*c].kind.inode_type(),
+                        })
+                        .collect();
+                    let listing_size = write_dir_listing(&mut dir_md, &entries)?;
+                    // The listing size is recorded with 3 extra bytes for the implicit '.' and '..'
+                    let file_size = listing_size + 3;
+                    let block_index = u32::try_from(dir_ref.location())
+                        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Directory table is too large"))?;
+                    let parent_inode = if *idx == ROOT {
+                        inode_count + 1
+                    } else {
+                        inode_numbers[parents[*idx]]
+                    };
+                    if !has_xattrs && file_size <= u16::MAX.into() {
+                        (inode_type, InodeExtendedInfo::BasicDir(metadata::BasicDirInfo {
+                            block_index,
+                            link_count,
+                            file_size: file_size as u16,
+                            block_offset: dir_ref.offset(),
+                            parent_inode,
+                        }))
+                    } else {
+                        (inode_type.to_ext(), InodeExtendedInfo::ExtDir(metadata::ExtDirInfo {
+                            link_count,
+                            file_size,
+                            block_index,
+                            parent_inode,
+                            index_count: 0,
+                            block_offset: dir_ref.offset(),
+                            xattr_index,
+                        }))
+                    }
+                },
+                NodeKind::File(f) => {
+                    let (frag_index, block_offset) = f.fragment.unwrap_or((NO_INDEX, 0));
+                    let basic_blocks_start = u32::try_from(f.blocks_start).ok();
+                    let basic_file_size = u32::try_from(f.file_size).ok();
+                    match (basic_blocks_start, basic_file_size) {
+                        (Some(blocks_start), Some(file_size)) if !has_xattrs && link_count == 1 => {
+                            (inode_type, InodeExtendedInfo::BasicFile(metadata::BasicFileInfo {
+                                blocks_start,
+                                frag_index,
+                                block_offset,
+                                file_size,
+                                block_sizes: f.block_sizes.clone(),
+                            }))
+                        },
+                        _ => {
+                            (inode_type.to_ext(), InodeExtendedInfo::ExtFile(metadata::ExtFileInfo {
+                                blocks_start: f.blocks_start,
+                                file_size: f.file_size,
+                                sparse: 0,
+                                link_count,
+                                frag_index,
+                                block_offset,
+                                xattr_index,
+                                block_sizes: f.block_sizes.clone(),
+                            }))
+                        },
+                    }
+                },
+                NodeKind::Symlink(target) => {
+                    let target_path = target.clone();
+                    if has_xattrs {
+                        (inode_type.to_ext(), InodeExtendedInfo::ExtSymlink(metadata::ExtSymlinkInfo { link_count, target_path, xattr_index }))
+                    } else {
+                        (inode_type, InodeExtendedInfo::BasicSymlink(metadata::BasicSymlinkInfo { link_count, target_path }))
+                    }
+                },
+                NodeKind::BlockDev(dev_number) |
+                NodeKind::CharDev(dev_number) => {
+                    let dev_number = *dev_number;
+                    if has_xattrs {
+                        (inode_type.to_ext(), InodeExtendedInfo::ExtDev(metadata::ExtDevInfo { link_count, dev_number, xattr_index }))
+                    } else {
+                        (inode_type, InodeExtendedInfo::BasicDev(metadata::BasicDevInfo { link_count, dev_number }))
+                    }
+                },
+                NodeKind::Fifo |
+                NodeKind::Socket => {
+                    if has_xattrs {
+                        (inode_type.to_ext(), InodeExtendedInfo::ExtIpc(metadata::ExtIpcInfo { link_count, xattr_index }))
+                    } else {
+                        (inode_type, InodeExtendedInfo::BasicIpc(metadata::BasicIpcInfo { link_count }))
+                    }
+                },
+            };
+            let inode = Inode {
+                inode_type,
+                permissions: node.attrs.mode & 0o7777,
+                uid_index: ids.index(node.attrs.uid)?,
+                gid_index: ids.index(node.attrs.gid)?,
+                mtime: node.attrs.mtime,
+                inode_number: inode_numbers[*idx],
+                extended_info,
+            };
+            inode_refs[*idx] = inode_md.position();
+            inode.write(&mut inode_md)?;
+        }
+
+        // Lay out the tables in the same order as mksquashfs
+        let inode_table = self.pos;
+        self.write_raw(&inode_md.finish()?.0)?;
+        let dir_table = self.pos;
+        self.write_raw(&dir_md.finish()?.0)?;
+
+        let mut frag_entries = Vec::with_capacity(self.frag_table.len() * 16);
+        for f in &self.frag_table {
+            f.write(&mut frag_entries)?;
+        }
+        let frag_table = self.write_lookup_table(&frag_entries)?;
+
+        let export_table = if self.opts.export_table {
+            let mut refs = vec![EntryReference::new(0, 0); order.len()];
+            for idx in &order {
+                refs[(inode_numbers[*idx] - 1) as usize] = inode_refs[*idx];
+            }
+            let mut export_entries = Vec::with_capacity(refs.len() * 8);
+            for r in refs {
+                r.write(&mut export_entries)?;
+            }
+            self.write_lookup_table(&export_entries)?
+        } else {
+            u64::MAX
+        };
+
+        let mut id_entries = Vec::with_capacity(ids.ids.len() * 4);
+        for id in &ids.ids {
+            id_entries.write_u32::<LittleEndian>(*id)?;
+        }
+        let id_table = self.write_lookup_table(&id_entries)?;
+
+        let xattr_table = if xattrs.count > 0 {
+            let (kv, _) = xattrs.kv.finish()?;
+            let kv_start = self.pos;
+            self.write_raw(&kv)?;
+            let (blocks, block_starts) = xattrs.ids.finish()?;
+            let ids_start = self.pos;
+            self.write_raw(&blocks)?;
+            let xattr_table = self.pos;
+            let mut header = Vec::with_capacity(16 + block_starts.len() * 8);
+            header.write_u64::<LittleEndian>(kv_start)?;
+            header.write_u32::<LittleEndian>(xattrs.count)?;
+            header.write_u32::<LittleEndian>(0)?;
+            for s in block_starts {
+                header.write_u64::<LittleEndian>(ids_start + s)?;
+            }
+            self.write_raw(&header)?;
+            xattr_table
+        } else {
+            u64::MAX
+        };
+
+        let mut flags = SuperblockFlags::empty();
+        if self.compressor_options {
+            flags |= SuperblockFlags::COMPRESSOR_OPTIONS_PRESENT;
+        }
+        if xattr_table == u64::MAX {
+            flags |= SuperblockFlags::NO_XATTRS;
+        }
+        if self.opts.no_fragments {
+            flags |= SuperblockFlags::FRAGMENTS_NOT_USED;
+        }
+        if self.opts.export_table {
+            flags |= SuperblockFlags::NFS_EXPORT_EXISTS;
+        }
+        let sb = Superblock {
+            magic: MAGIC,
+            inode_count,
+            mod_time: self.opts.mod_time,
+            block_size: self.opts.block_size,
+            frag_count: self.frag_table.len() as u32,
+            compressor,
+            block_log: self.opts.block_size.trailing_zeros() as u16,
+            flags,
+            id_count: ids.ids.len() as u16,
+            version_major: 4,
+            version_minor: 0,
+            root_inode: inode_refs[ROOT],
+            bytes_used: self.pos,
+            id_table,
+            xattr_table,
+            inode_table,
+            dir_table,
+            frag_table,
+            export_table,
+        };
+
+        let mut end = self.pos;
+        if self.opts.pad {
+            end = self.pos.next_multiple_of(4096);
+            io::copy(&mut io::repeat(0).take(end - self.pos), &mut self.w)?;
+        }
+        // Everything that the superblock refers to is written before it, so that an image being
+        // appended to stays valid until the superblock is replaced
+        self.w.flush()?;
+        self.w.seek(SeekFrom::Start(0))?;
+        sb.write(&mut self.w)?;
+        self.w.seek(SeekFrom::Start(end))?;
+        self.w.flush()?;
+        Ok(self.w)
+    }
+
+    fn write_raw(&mut self, data: &[u8]) -> io::Result<()> {
+        self.w.write_all(data)?;
+        self.pos += data.len() as u64;
+        Ok(())
+    }
+
+    /// Write fixed-size table entries as metadata blocks, followed by the uncompressed list of
+    /// block locations that SquashFS lookup tables use to find them. Returns the location of the
+    /// list, which is the location recorded in the superblock.
+    fn write_lookup_table(&mut self, entries: &[u8]) -> io::Result<u64> {
+        let mut md = MetadataWriter::new(self.opts.compressor, self.opts.compression_level);
+        md.write_all(entries)?;
+        let (blocks, block_starts) = md.finish()?;
+        let table_start = self.pos;
+        self.write_raw(&blocks)?;
+        let list_start = self.pos;
+        let mut list = Vec::with_capacity(block_starts.len() * 8);
+        for s in block_starts {
+            list.write_u64::<LittleEndian>(table_start + s)?;
+        }
+        self.write_raw(&list)?;
+        Ok(list_start)
+    }
+
+    /// Compress and write one data block, returning its size as recorded in a file inode
+    fn write_data_block(&mut self, data: &[u8]) -> io::Result<u32> {
+        let mut out = std::mem::take(&mut self.compress_buf);
+        let result = if compress_block(self.opts.compressor, self.opts.compression_level, data, &mut out)? {
+            self.write_raw(&out).map(|_| out.len() as u32)
+        } else {
+            self.write_raw(data).map(|_| data.len() as u32 | DATA_BLOCK_UNCOMPRESSED)
+        };
+        self.compress_buf = out;
+        result
+    }
+
+    /// Write a file's contents as a series of data blocks, with any tail-end packed into a
+    /// fragment block
+    fn write_file_data<F: Read>(&mut self, mut data: F) -> io::Result<FileData> {
+        let block_size = self.opts.block_size as usize;
+        let mut buf = std::mem::take(&mut self.block_buf);
+        let blocks_start = self.pos;
+        let mut block_sizes = Vec::new();
+        let mut file_size = 0;
+        let mut fragment = None;
+        let result = loop {
+            let n = match read_full(&mut data, &mut buf) {
+                Ok(n) => n,
+                Err(e) => break Err(e),
+            };
+            file_size += n as u64;
+            if n == block_size {
+                match self.write_data_block(&buf) {
+                    Ok(b) => block_sizes.push(b),
+                    Err(e) => break Err(e),
+                }
+                continue;
+            }
+            if n > 0 {
+                if self.opts.no_fragments {
+                    match self.write_data_block(&buf[..n]) {
+                        Ok(b) => block_sizes.push(b),
+                        Err(e) => break Err(e),
+                    }
+                } else {
+                    match self.add_fragment(&buf[..n]) {
+                        Ok(f) => fragment = Some(f),
+                        Err(e) => break Err(e),
+                    }
+                }
+            }
+            break Ok(());
+        };
+        self.block_buf = buf;
+        result.map(|_| FileData { file_size, blocks_start, block_sizes, fragment })
+    }
+
+    /// Append a tail-end to the current fragment block, returning the fragment index and offset
+    fn add_fragment(&mut self, data: &[u8]) -> io::Result<(u32, u32)> {
+        if self.frag_buf.len() + data.len() > self.opts.block_size as usize {
+            self.flush_fragment()?;
+        }
+        let index = self.frag_table.len() as u32;
+        let offset = self.frag_buf.len() as u32;
+        self.frag_buf.extend_from_slice(data);
+        Ok((index, offset))
+    }
+
+    fn flush_fragment(&mut self) -> io::Result<()> {
+        if self.frag_buf.is_empty() {
+            return Ok(());
+        }
+        let data = std::mem::take(&mut self.frag_buf);
+        let start = self.pos;
+        let size = self.write_data_block(&data)?;
+        self.frag_table.push(FragmentEntry { start, size });
+        self.frag_buf = data;
+        self.frag_buf.clear();
+        Ok(())
+    }
+
+    fn children(&self, dir: usize) -> Option<&BTreeMap<Vec<u8>, usize>> {
+        match &self.nodes[dir].kind {
+            NodeKind::Dir(children) => Some(children),
+            _ => None,
+        }
+    }
+
+    fn children_mut(&mut self, dir: usize) -> &mut BTreeMap<Vec<u8>, usize> {
+        match &mut self.nodes[dir].kind {
+            NodeKind::Dir(children) => children,
+            _ => unreachable!("Node {dir} is not a directory"),
+        }
+    }
+
+    /// Find the node at the given path without following symbolic links
+    fn lookup(&self, names: &[Vec<u8>]) -> Option<usize> {
+        let mut cur = ROOT;
+        for name in names {
+            cur = *self.children(cur)?.get(name)?;
+        }
+        Some(cur)
+    }
+
+    /// Find the directory at the given path, creating any directories that do not exist
+    fn make_parents(&mut self, names: &[Vec<u8>]) -> io::Result<usize> {
+        let mut cur = ROOT;
+        for name in names {
+            let existing = self.children(cur)
+                .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "Parent path is not a directory"))?
+                .get(name)
+                .copied();
+            cur = match existing {
+                Some(c) => c,
+                None => {
+                    let c = self.nodes.len();
+                    self.nodes.push(Node {
+                        attrs: Attributes::new(0o755, 0, 0, self.opts.mod_time),
+                        kind: NodeKind::Dir(BTreeMap::new()),
+                        inode_number: None,
+                    });
+                    self.children_mut(cur).insert(name.clone(), c);
+                    c
+                }
+            };
+        }
+        if self.children(cur).is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Parent path is not a directory"));
+        }
+        Ok(cur)
+    }
+
+    fn insert(&mut self, path: &[u8], attrs: Attributes, kind: NodeKind) -> io::Result<()> {
+        for (name, _) in &attrs.xattrs {
+            if metadata::split_xattr_name(name).is_none() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("Unsupported xattr namespace: {}", String::from_utf8_lossy(name))));
+            }
+        }
+        let names = split_path(path)?;
+        let Some((name, parents)) = names.split_last() else {
+            // The root directory can only have its attributes replaced
+            if !matches!(kind, NodeKind::Dir(_)) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "The root must be a directory"));
+            }
+            self.nodes[ROOT].attrs = attrs;
+            return Ok(());
+        };
+        let parent = self.make_parents(parents)?;
+        let existing = self.children(parent).and_then(|c| c.get(name)).copied();
+        if let Some(e) = existing {
+            if matches!(kind, NodeKind::Dir(_)) && matches!(self.nodes[e].kind, NodeKind::Dir(_)) {
+                self.nodes[e].attrs = attrs;
+                return Ok(());
+            }
+        }
+        let idx = self.nodes.len();
+        self.nodes.push(Node { attrs, kind, inode_number: None });
+        self.children_mut(parent).insert(name.clone(), idx);
+        Ok(())
+    }
+
+    /// List every node reachable from the root exactly once, with each directory's contents ahead
+    /// of the directory. Also returns the parent of each directory.
+    fn postorder(&self) -> (Vec<usize>, Vec<usize>) {
+        let child_list = |idx: usize| -> Vec<usize> {
+            self.children(idx).map(|c| c.values().rev().copied().collect()).unwrap_or_default()
+        };
+        let mut order = Vec::new();
+        let mut visited = vec![false; self.nodes.len()];
+        let mut parents = vec![ROOT; self.nodes.len()];
+        let mut stack = vec![(ROOT, child_list(ROOT))];
+        visited[ROOT] = true;
+        while let Some(top) = stack.last_mut() {
+            match top.1.pop() {
+                Some(c) => {
+                    let p = top.0;
+                    if !visited[c] {
+                        visited[c] = true;
+                        if matches!(self.nodes[c].kind, NodeKind::Dir(_)) {
+                            parents[c] = p;
+                            stack.push((c, child_list(c)));
+                        } else {
+                            order.push(c);
+                        }
+                    }
+                },
+                None => {
+                    order.push(top.0);
+                    stack.pop();
+                },
+            }
+        }
+        (order, parents)
+    }
+}
+
+impl<W: Read + Write + Seek> SquashFSWriter<W> {
+    /// Open an existing image for appending. The image's contents are loaded into the writer with
+    /// their data blocks left in place, so entries can be added, replaced, and removed as usual.
+    /// New data, and the metadata tables rebuilt by [`finish`](Self::finish), are written after
+    /// the end of the existing image. The compressor, the block size, and the modification time
+    /// are taken from the existing image. Its compressor options are kept as they are, but only
+    /// the gzip compression level is applied to new blocks: they are compressed with the default
+    /// gzip window size and strategy, and with the default xz and zstd settings, whatever the
+    /// options say. The options are not needed to decompress a block, so the image stays readable.
+    ///
+    /// Nothing that the existing image uses is overwritten, and the superblock is written last,
+    /// so the image stays as it was if an error occurs or the writer is dropped before `finish`
+    /// returns. The old metadata tables are left behind as unused space.
+    pub fn append(mut w: W) -> io::Result<Self> {
+        let mut sqfs = SquashFS::new(&mut w)?;
+        let sb = sqfs.superblock();
+        let mut opts = WriterOptions {
+            compressor: sb.compressor,
+            block_size: sb.block_size,
+            mod_time: sb.mod_time,
+            export_table: sb.export_table != u64::MAX,
+            ..Default::default()
+        };
+        let has_options = sb.flags.contains(SuperblockFlags::COMPRESSOR_OPTIONS_PRESENT);
+        let image_end = sb.bytes_used;
+        if has_options && opts.compressor == Compressor::Gzip {
+            let mut buf = [0; 8];
+            let mut r = sqfs.raw_reader();
+            r.seek(SeekFrom::Start(SUPERBLOCK_SIZE))?;
+            metadata::read_metadata_block(&mut r, &opts.compressor, &mut buf)?;
+            let level = GzipOptions::read(&mut &buf[..])?.compression_level;
+            if !(1..=9).contains(&level) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Invalid gzip compression level in the compressor options"));
+            }
+            opts.compression_level = Some(level);
+        }
+        compress_block(opts.compressor, opts.compression_level, &[0; 64], &mut Vec::new())?;
+
+        let frag_table = sqfs.fragment_entries()?;
+        let root = sqfs.root_inode()?;
+        let mut entries = vec![ExistingEntry::Node {
+            path: b"/".to_vec(),
+            attrs: Attributes::from_inode(&root, &sqfs)?,
+            kind: NodeKind::Dir(BTreeMap::new()),
+            inode_number: root.inode_number(),
+        }];
+        collect_existing(&mut sqfs, &root, &mut Vec::new(), &mut HashMap::new(), &mut Visited::default(), &mut entries)?;
+        drop(sqfs);
+
+        w.seek(SeekFrom::Start(image_end))?;
+        let mut writer = Self::from_parts(w, opts, image_end, has_options, frag_table);
+        for e in entries {
+            match e {
+                ExistingEntry::Node { path, attrs, kind, inode_number } => {
+                    writer.insert(&path, attrs, kind)?;
+                    writer.set_inode_number_bytes(&path, inode_number)?;
+                },
+                ExistingEntry::HardLink { path, target } => writer.add_hard_link_bytes(&path, &target)?,
+            }
+        }
+        Ok(writer)
+    }
+}
+
+/// An entry of an image being appended to, collected before the writer takes over the image
+enum ExistingEntry {
+    Node { path: Vec<u8>, attrs: Attributes, kind: NodeKind, inode_number: u32 },
+    HardLink { path: Vec<u8>, target: Vec<u8> },
+}
+
+fn collect_existing<R: Read + Seek>(sqfs: &mut SquashFS<R>, dir: &Inode, dir_path: &mut Vec<u8>,
+    links: &mut HashMap<u32, Vec<u8>>, visited: &mut Visited, out: &mut Vec<ExistingEntry>) -> io::Result<()>
+{
+    let entries = sqfs.read_walk_dir(visited, dir_path, dir)?;
+    for e in entries {
+        let parent_len = dir_path.len();
+        dir_path.push(b'/');
+        dir_path.extend_from_slice(e.file_name_bytes());
+        let inode = sqfs.inode_from_entryref(e.inode_ref())?;
+        if let Some(target) = links.get(&inode.inode_number()) {
+            out.push(ExistingEntry::HardLink { path: dir_path.clone(), target: target.clone() });
+        } else {
+            let kind = node_kind_from_inode(&inode)?;
+            out.push(ExistingEntry::Node {
+                path: dir_path.clone(),
+                attrs: Attributes::from_inode(&inode, sqfs)?,
+                kind,
+                inode_number: inode.inode_number(),
+            });
+            if inode.is_dir() {
+                collect_existing(sqfs, &inode, dir_path, links, visited, out)?;
+            } else if inode.link_count() > 1 {
+                links.insert(inode.inode_number(), dir_path.clone());
+            }
+        }
+        dir_path.truncate(parent_len);
+    }
+    Ok(())
+}
+
+/// Describe an Inode from an existing image as a new node. Regular files refer to their existing
+/// data blocks and fragment, and directories are created empty.
+fn node_kind_from_inode(inode: &Inode) -> io::Result<NodeKind> {
+    if inode.is_dir() {
+        Ok(NodeKind::Dir(BTreeMap::new()))
+    } else if let Some((blocks_start, block_sizes)) = inode.data_blocks() {
+        Ok(NodeKind::File(FileData {
+            file_size: inode.file_size().unwrap_or(0),
+            blocks_start,
+            block_sizes: block_sizes.to_vec(),
+            fragment: inode.fragment(),
+        }))
+    } else if let Some(target) = inode.symlink_target() {
+        Ok(NodeKind::Symlink(target.to_owned()))
+    } else if inode.is_block_dev() {
+        Ok(NodeKind::BlockDev(inode.dev_number().unwrap_or(0)))
+    } else if inode.is_char_dev() {
+        Ok(NodeKind::CharDev(inode.dev_number().unwrap_or(0)))
+    } else if inode.is_fifo() {
+        Ok(NodeKind::Fifo)
+    } else if inode.is_socket() {
+        Ok(NodeKind::Socket)
+    } else {
+        Err(io::Error::new(io::ErrorKind::InvalidData, "Unknown inode type"))
+    }
+}
+
+/// The compressor options block that follows the superblock, including its metadata block header,
+/// or None if the options are all defaults
+fn compressor_options(opts: &WriterOptions) -> io::Result<Option<Vec<u8>>> {
+    let mut buf = Vec::new();
+    match (opts.compressor, opts.compression_level) {
+        (Compressor::Gzip, Some(level)) if level != 9 => {
+            let options = GzipOptions { compression_level: level, window_size: 15, strategies: GzipStrategies::DEFAULT };
+            buf.write_u16::<LittleEndian>(8 | METADATA_BLOCK_UNCOMPRESSED)?;
+            options.write(&mut buf)?;
+            Ok(Some(buf))
+        },
+        _ => Ok(None),
+    }
+}
+
+/// Convert a Path to the raw bytes that will be stored in the image
+fn path_bytes(path: &Path) -> &[u8] {
+    path.as_os_str().as_encoded_bytes()
+}
+
+/// Split a path into its component names. Paths are always interpreted relative to the root of
+/// the image, and '.' components are ignored.
+pub(crate) fn split_path(path: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    let mut names = Vec::new();
+    for name in path.split(|c| *c == b'/') {
+        match name {
+            b"" | b"." => {},
+            b".." => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Paths must not contain '..'")),
+            n if n.len() > MAX_NAME_LEN => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "File name is too long"));
+            },
+            n if n.contains(&0) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "File name contains a NUL byte"));
+            },
+            n => names.push(n.to_vec()),
+        }
+    }
+    Ok(names)
+}
+
+/// Fill the buffer from the reader, stopping early only at EOF
+fn read_full<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match r.read(&mut buf[total..]) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(total)
+}
+
+struct DirListingEntry<'a> {
+    name: &'a [u8],
+    inode_ref: EntryReference,
+    inode_number: u32,
+    inode_type: InodeType,
+}
+
+/// Write the listing of a single directory as a series of headers and entries, returning the
+/// number of bytes written. Entries must already be sorted by name. A new header is started
+/// whenever the next entry's inode is in a different metadata block, its inode number is too far
+/// from the header's to be stored as an offset, or the header is full.
+fn write_dir_listing(md: &mut MetadataWriter, entries: &[DirListingEntry]) -> io::Result<u32> {
+    let start_len = md.uncompressed_len();
+    let mut rest = entries;
+    while let Some(first) = rest.first() {
+        let start = first.inode_ref.location();
+        let base = first.inode_number;
+        let run = rest.iter()
+            .take(MAX_DIR_HEADER_ENTRIES)
+            .take_while(|e| {
+                e.inode_ref.location() == start &&
+                i16::try_from(i64::from(e.inode_number) - i64::from(base)).is_ok()
+            })
+            .count();
+        let (run_entries, remaining) = rest.split_at(run);
+        let table = metadata::DirTable {
+            count: run as u32,
+            start: u32::try_from(start)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Inode table is too large"))?,
+            inode_number: base,
+            entries: run_entries.iter().map(|e| metadata::DirEntry {
+                offset: e.inode_ref.offset(),
+                inode_offset: (i64::from(e.inode_number) - i64::from(base)) as i16,
+                inode_type: e.inode_type,
+                name: CString::new(e.name).expect("names are checked when added"),
+            }).collect(),
+            invalid_names: Vec::new(),
+        };
+        table.write(md)?;
+        rest = remaining;
+    }
+    u32::try_from(md.uncompressed_len() - start_len)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Directory is too large"))
+}
+
+/// Assigns each distinct uid/gid an index in the id table
+#[derive(Debug, Default)]
+struct IdTableBuilder {
+    ids: Vec<u32>,
+    indices: HashMap<u32, u16>,
+}
+
+impl IdTableBuilder {
+    fn index(&mut self, id: u32) -> io::Result<u16> {
+        if let Some(i) = self.indices.get(&id) {
+            return Ok(*i);
+        }
+        let i = u16::try_from(self.ids.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Too many distinct uids and gids"))?;
+        self.ids.push(id);
+        self.indices.insert(id, i);
+        Ok(i)
+    }
+}
+
+// A sorted list of (full name, value) pairs
+type XattrSet = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// Builds the extended attribute key/value region and the xattr id table that indexes it. Each
+/// distinct set of xattrs is stored once and shared by every inode that carries it.
+#[derive(Debug)]
+struct XattrTableBuilder {
+    kv: MetadataWriter,
+    ids: MetadataWriter,
+    count: u32,
+    indices: HashMap<XattrSet, u32>,
+}
+
+impl XattrTableBuilder {
+    fn new(compressor: Compressor, level: Option<u32>) -> Self {
+        Self {
+            kv: MetadataWriter::new(compressor, level),
+            ids: MetadataWriter::new(compressor, level),
+            count: 0,
+            indices: HashMap::new(),
+        }
+    }
+
+    fn index(&mut self, xattrs: &[(Vec<u8>, Vec<u8>)]) -> io::Result<u32> {
+        if xattrs.is_empty() {
+            return Ok(NO_INDEX);
+        }
+        let mut set = xattrs.to_vec();
+        set.sort();
+        if let Some(i) = self.indices.get(&set) {
+            return Ok(*i);
+        }
+
+        let kv_ref = self.kv.position();
+        let mut size = 0u32;
+        for (name, value) in &set {
+            let (prefix, suffix) = metadata::split_xattr_name(name)
+                .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "Unsupported xattr namespace"))?;
+            let name_len = u16::try_from(suffix.len())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Xattr name is too long"))?;
+            let value_len = u32::try_from(value.len())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Xattr value is too large"))?;
+            self.kv.write_u16::<LittleEndian>(prefix)?;
+            self.kv.write_u16::<LittleEndian>(name_len)?;
+            self.kv.write_all(suffix)?;
+            self.kv.write_u32::<LittleEndian>(value_len)?;
+            self.kv.write_all(value)?;
+            // This matches the size that mksquashfs records: each full name with a NUL terminator,
+            // plus each value
+            size = size.saturating_add((name.len() + 1) as u32).saturating_add(value_len);
+        }
+        kv_ref.write(&mut self.ids)?;
+        self.ids.write_u32::<LittleEndian>(set.len() as u32)?;
+        self.ids.write_u32::<LittleEndian>(size)?;
+
+        let i = self.count;
+        self.count += 1;
+        self.indices.insert(set, i);
+        Ok(i)
+    }
+}
+
+/// Accumulates a metadata section (ex. the inode or directory table) in memory, compressing it
+/// into metadata blocks as each 8KiB block fills. References to positions within the section are
+/// relative to the start of the section.
+#[derive(Debug)]
+pub(crate) struct MetadataWriter {
+    compressor: Compressor,
+    level: Option<u32>,
+    pending: Vec<u8>,
+    output: Vec<u8>,
+    block_starts: Vec<u64>,
+    uncompressed_len: u64,
+    compress_buf: Vec<u8>,
+}
+
+impl MetadataWriter {
+    pub(crate) fn new(compressor: Compressor, level: Option<u32>) -> Self {
+        Self {
+            compressor,
+            level,
+            pending: Vec::with_capacity(METADATA_BLOCK_SIZE.into()),
+            output: Vec::new(),
+            block_starts: Vec::new(),
+            uncompressed_len: 0,
+            compress_buf: Vec::new(),
+        }
+    }
+
+    /// The reference at which the next byte written will be found
+    pub(crate) fn position(&self) -> EntryReference {
+        EntryReference::new(self.output.len() as u64, self.pending.len() as u16)
+    }
+
+    /// The total number of bytes written so far, before compression
+    pub(crate) fn uncompressed_len(&self) -> u64 {
+        self.uncompressed_len
+    }
+
+    fn flush_block(&mut self) -> io::Result<()> {
+        self.block_starts.push(self.output.len() as u64);
+        if compress_block(self.compressor, self.level, &self.pending, &mut self.compress_buf)? {
+            self.output.write_u16::<LittleEndian>(self.compress_buf.len() as u16)?;
+            self.output.extend_from_slice(&self.compress_buf);
+        } else {
+            self.output.write_u16::<LittleEndian>(self.pending.len() as u16 | METADATA_BLOCK_UNCOMPRESSED)?;
+            self.output.extend_from_slice(&self.pending);
+        }
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Compress any partial final block and return the compressed section together with the
+    /// offset of each metadata block within it
+    pub(crate) fn finish(mut self) -> io::Result<(Vec<u8>, Vec<u64>)> {
+        if !self.pending.is_empty() {
+            self.flush_block()?;
+        }
+        Ok((self.output, self.block_starts))
+    }
+}
+
+impl Write for MetadataWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = min(buf.len(), usize::from(METADATA_BLOCK_SIZE) - self.pending.len());
+        self.pending.extend_from_slice(&buf[..n]);
+        self.uncompressed_len += n as u64;
+        if self.pending.len() == METADATA_BLOCK_SIZE.into() {
+            self.flush_block()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use crate::squashfs::SquashFS;
+
+    fn attrs(mode: u16) -> Attributes {
+        Attributes::new(mode, 1000, 100, 1700000000)
+    }
+
+    fn read_all<R: Read + Seek>(sqfs: &mut SquashFS<R>, path: &str) -> io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        sqfs.open_file(path)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn test_write_and_read_back() -> io::Result<()> {
+        let opts = WriterOptions { block_size: 4096, ..Default::default() };
+        let mut writer = SquashFSWriter::new(Cursor::new(Vec::new()), opts)?;
+        let big: Vec<u8> = (0..20000u32).map(|x| (x % 251) as u8).collect();
+        writer.add_dir("/etc", attrs(0o755))?;
+        writer.add_file("/etc/hostname", attrs(0o644), &b"squinter\n"[..])?;
+        writer.add_file("/usr/lib/big.bin", attrs(0o600), &big[..])?;
+        writer.add_file("/empty", attrs(0o644), io::empty())?;
+        writer.add_symlink("/etc/localtime", attrs(0o777), "/usr/share/zoneinfo/UTC")?;
+        writer.add_char_device("/dev/console", attrs(0o600), metadata::encode_dev(5, 1))?;
+        writer.add_block_device("/dev/sda", attrs(0o660), metadata::encode_dev(8, 0))?;
+        writer.add_fifo("/run/fifo", attrs(0o644))?;
+        writer.add_hard_link("/etc/hostname.link", "/etc/hostname")?;
+        let image = writer.finish()?;
+
+        let mut sqfs = SquashFS::new(image)?;
+        let names: Vec<String> = sqfs.read_dir("/")?.map(|d| d.file_name()).collect();
+        assert_eq!(names, ["dev", "empty", "etc", "run", "usr"]);
+        assert_eq!(read_all(&mut sqfs, "/etc/hostname")?, b"squinter\n");
+        assert_eq!(read_all(&mut sqfs, "/usr/lib/big.bin")?, big);
+        assert_eq!(read_all(&mut sqfs, "/empty")?, b"");
+
+        let hostname = sqfs.inode_from_path("/etc/hostname")?;
+        let link = sqfs.inode_from_path("/etc/hostname.link")?;
+        assert_eq!(hostname.inode_number(), link.inode_number());
+        assert_eq!(hostname.link_count(), 2);
+        assert_eq!(hostname.mode(), 0o100644);
+        assert_eq!(hostname.uid(&sqfs)?, 1000);
+        assert_eq!(hostname.gid(&sqfs)?, 100);
+        assert_eq!(hostname.mtime(), 1700000000);
+
+        let localtime = sqfs.inode_from_path("/etc/localtime")?;
+        assert_eq!(localtime.symlink_target().unwrap().to_bytes(), b"/usr/share/zoneinfo/UTC");
+        let console = sqfs.inode_from_path("/dev/console")?;
+        assert!(console.is_char_dev());
+        assert_eq!(metadata::decode_dev(console.dev_number().unwrap()), (5, 1));
+        let sda = sqfs.inode_from_path("/dev/sda")?;
+        assert!(sda.is_block_dev());
+        assert_eq!(metadata::decode_dev(sda.dev_number().unwrap()), (8, 0));
+        assert!(sqfs.inode_from_path("/run/fifo")?.is_fifo());
+
+        // The root holds 5 directories of its own, and implicit parents get default attributes
+        let root = sqfs.root_inode()?;
+        assert_eq!(root.link_count(), 2 + 4);
+        assert_eq!(sqfs.inode_from_path("/usr")?.mode(), 0o40755);
+        Ok(())
+    }
+
+    #[test]
+    fn test_xattrs() -> io::Result<()> {
+        let mut writer = SquashFSWriter::new(Cursor::new(Vec::new()), WriterOptions::default())?;
+        let mut a = attrs(0o644);
+        a.xattrs.push((b"user.b".to_vec(), b"2".to_vec()));
+        a.xattrs.push((b"security.selinux".to_vec(), b"system_u:object_r:etc_t:s0".to_vec()));
+        writer.add_dir("/", a.clone())?;
+        writer.add_file("/file", a.clone(), &b"x"[..])?;
+        writer.add_symlink("/link", a.clone(), "file")?;
+        writer.add_file("/plain", attrs(0o644), &b"x"[..])?;
+        a.xattrs.push((b"system.posix_acl_access".to_vec(), Vec::new()));
+        assert!(writer.add_fifo("/fifo", a).is_err());
+        let mut sqfs = SquashFS::new(writer.finish()?)?;
+
+        // Xattr sets come back sorted by name
+        let expected = vec![
+            (b"security.selinux".to_vec(), b"system_u:object_r:etc_t:s0".to_vec()),
+            (b"user.b".to_vec(), b"2".to_vec()),
+        ];
+        assert_eq!(sqfs.root_inode()?.xattrs(&sqfs)?, expected);
+        assert_eq!(sqfs.inode_from_path("/file")?.xattrs(&sqfs)?, expected);
+        assert_eq!(sqfs.inode_from_path("/link")?.xattrs(&sqfs)?, expected);
+        assert!(sqfs.inode_from_path("/plain")?.xattrs(&sqfs)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_large_directory() -> io::Result<()> {
+        // Enough entries to need several directory headers and metadata blocks
+        let mut writer = SquashFSWriter::new(Cursor::new(Vec::new()), WriterOptions::default())?;
+        for n in 0..1000 {
+            writer.add_file(format!("/many/file-with-a-long-name-{n:04}"), attrs(0o644), format!("{n}").as_bytes())?;
+        }
+        let mut sqfs = SquashFS::new(writer.finish()?)?;
+        let entries: Vec<_> = sqfs.read_dir("/many")?.collect();
+        assert_eq!(entries.len(), 1000);
+        for (n, e) in entries.iter().enumerate() {
+            assert_eq!(e.file_name(), format!("file-with-a-long-name-{n:04}"));
+            let inode = sqfs.inode_from_entryref(e.inode_ref())?;
+            let mut content = String::new();
+            sqfs.open_file_inode(&inode)?.read_to_string(&mut content)?;
+            assert_eq!(content, format!("{n}"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_and_remove() -> io::Result<()> {
+        let mut writer = SquashFSWriter::new(Cursor::new(Vec::new()), WriterOptions::default())?;
+        writer.add_file("/a/b", attrs(0o644), &b"old"[..])?;
+        writer.add_file("/a/b", attrs(0o600), &b"new"[..])?;
+        writer.add_file("/a/c", attrs(0o644), &b"c"[..])?;
+        writer.add_dir("/a", attrs(0o700))?;
+        writer.remove("/a/c")?;
+        assert!(writer.add_file("/a/b/d", attrs(0o644), io::empty()).is_err());
+        assert!(writer.remove("/a/missing").is_err());
+        assert!(writer.add_hard_link("/x", "/a").is_err());
+        let mut sqfs = SquashFS::new(writer.finish()?)?;
+        assert_eq!(read_all(&mut sqfs, "/a/b")?, b"new");
+        assert_eq!(sqfs.inode_from_path("/a/b")?.permissions(), 0o600);
+        assert_eq!(sqfs.inode_from_path("/a")?.permissions(), 0o700);
+        assert!(sqfs.inode_from_path("/a/c").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_append() -> io::Result<()> {
+        let opts = WriterOptions { block_size: 4096, mod_time: 42, ..Default::default() };
+        let mut writer = SquashFSWriter::new(Cursor::new(Vec::new()), opts)?;
+        let big: Vec<u8> = (0..10000u32).map(|x| (x * 13 % 256) as u8).collect();
+        let mut xattr_attrs = attrs(0o644);
+        xattr_attrs.xattrs.push((b"user.tag".to_vec(), b"base".to_vec()));
+        writer.add_file("/etc/big", xattr_attrs, &big[..])?;
+        writer.add_file("/etc/small", attrs(0o644), &b"small"[..])?;
+        writer.add_file("/etc/replaced", attrs(0o644), &b"old"[..])?;
+        writer.add_hard_link("/etc/link", "/etc/big")?;
+        writer.add_symlink("/etc/sym", attrs(0o777), "small")?;
+        let mut image = writer.finish()?;
+        let base = image.get_ref().clone();
+        let base_end = SquashFS::new(&mut image)?.superblock().bytes_used as usize;
+        let base_root_number = SquashFS::new(&mut image)?.root_inode()?.inode_number();
+
+        // An append that is abandoned before it finishes leaves the image as it was
+        let mut writer = SquashFSWriter::append(&mut image)?;
+        writer.add_file("/etc/replaced", attrs(0o600), &big[..])?;
+        writer.remove("/etc/small")?;
+        drop(writer);
+        assert_eq!(image.get_ref()[..base_end], base[..base_end]);
+        assert_eq!(read_all(&mut SquashFS::new(&mut image)?, "/etc/small")?, b"small");
+
+        let mut writer = SquashFSWriter::append(image)?;
+        assert_eq!(writer.options().block_size, 4096);
+        writer.add_file("/etc/replaced", attrs(0o600), &b"new"[..])?;
+        writer.add_file("/etc/device.conf", attrs(0o644), &big[..5000])?;
+        writer.remove("/etc/small")?;
+        let mut image = writer.finish()?;
+        let end = image.position() as usize;
+        image.get_mut().truncate(end);
+        assert_eq!(image.get_ref()[SUPERBLOCK_SIZE as usize..base_end], base[SUPERBLOCK_SIZE as usize..base_end]);
+
+        let mut sqfs = SquashFS::new(image)?;
+        assert_eq!(sqfs.mod_time(), 42);
+        assert_eq!(sqfs.root_inode()?.inode_number(), base_root_number);
+        assert_eq!(read_all(&mut sqfs, "/etc/big")?, big);
+        assert_eq!(read_all(&mut sqfs, "/etc/link")?, big);
+        assert_eq!(read_all(&mut sqfs, "/etc/replaced")?, b"new");
+        assert_eq!(read_all(&mut sqfs, "/etc/device.conf")?, &big[..5000]);
+        assert!(sqfs.inode_from_path("/etc/small").is_err());
+        let inode = sqfs.inode_from_path("/etc/big")?;
+        assert_eq!(inode.link_count(), 2);
+        assert_eq!(inode.xattrs(&sqfs)?, [(b"user.tag".to_vec(), b"base".to_vec())]);
+        assert_eq!(inode.inode_number(), sqfs.inode_from_path("/etc/link")?.inode_number());
+        assert_eq!(sqfs.inode_from_path("/etc/sym")?.symlink_target().unwrap().to_bytes(), b"small");
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(feature = "flate2")]
+    fn test_append_compression_level() -> io::Result<()> {
+        let opts = WriterOptions { compressor: Compressor::Gzip, compression_level: Some(1), ..Default::default() };
+        let mut writer = SquashFSWriter::new(Cursor::new(Vec::new()), opts)?;
+        writer.add_file("/a", attrs(0o644), &b"a"[..])?;
+        let writer = SquashFSWriter::append(writer.finish()?)?;
+        assert_eq!(writer.options().compression_level, Some(1));
+
+        let mut writer = SquashFSWriter::new(Cursor::new(Vec::new()), WriterOptions::default())?;
+        writer.add_file("/a", attrs(0o644), &b"a"[..])?;
+        let writer = SquashFSWriter::append(writer.finish()?)?;
+        assert_eq!(writer.options().compression_level, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_table() -> io::Result<()> {
+        let opts = WriterOptions { export_table: true, ..Default::default() };
+        let mut writer = SquashFSWriter::new(Cursor::new(Vec::new()), opts)?;
+        for n in 0..300 {
+            writer.add_file(format!("/d{}/f{n}", n % 3), attrs(0o644), &b"x"[..])?;
+        }
+        writer.add_hard_link("/link", "/d0/f0")?;
+        writer.add_fifo("/fifo", attrs(0o600))?;
+        let image = writer.finish()?;
+        let mut sqfs = SquashFS::new(Cursor::new(image.get_ref().clone()))?;
+        for path in ["/", "/d1", "/d2/f299", "/link", "/fifo"] {
+            let inode = sqfs.inode_from_path(path)?;
+            let by_number = sqfs.inode_from_number(inode.inode_number())?;
+            assert_eq!(by_number.inode_number(), inode.inode_number());
+            assert_eq!(by_number.mode(), inode.mode());
+        }
+        assert!(sqfs.inode_from_number(0).is_err());
+        assert!(sqfs.inode_from_number(306).is_err());
+
+        // Appending keeps the export table, and removed entries leave no gaps in the numbering
+        let mut writer = SquashFSWriter::append(image)?;
+        writer.remove("/d1")?;
+        let mut sqfs = SquashFS::new(writer.finish()?)?;
+        let inode = sqfs.inode_from_path("/d2/f299")?;
+        assert!(inode.inode_number() <= 204);
+        assert_eq!(sqfs.inode_from_number(inode.inode_number())?.file_size(), Some(1));
+
+        let mut sqfs = SquashFS::new(SquashFSWriter::new(Cursor::new(Vec::new()), WriterOptions::default())?.finish()?)?;
+        assert!(sqfs.inode_from_number(1).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_inode_variants() -> io::Result<()> {
+        let mut writer = SquashFSWriter::new(Cursor::new(Vec::new()), WriterOptions::default())?;
+        let mut xattr_attrs = attrs(0o755);
+        xattr_attrs.xattrs.push((b"user.a".to_vec(), b"b".to_vec()));
+        writer.add_file("/plain", attrs(0o644), &b"x"[..])?;
+        writer.add_file("/linked", attrs(0o644), &b"x"[..])?;
+        writer.add_hard_link("/link", "/linked")?;
+        writer.add_file("/xattr", xattr_attrs.clone(), &b"x"[..])?;
+        writer.add_dir("/dir", attrs(0o755))?;
+        writer.add_dir("/xdir", xattr_attrs.clone())?;
+        writer.add_symlink("/sym", attrs(0o777), "plain")?;
+        writer.add_char_device("/xdev", xattr_attrs, 0)?;
+        let mut sqfs = SquashFS::new(writer.finish()?)?;
+        for (path, inode_type) in [
+            ("/plain", InodeType::BasicFile), ("/linked", InodeType::ExtFile), ("/xattr", InodeType::ExtFile),
+            ("/dir", InodeType::BasicDir), ("/xdir", InodeType::ExtDir), ("/sym", InodeType::BasicSymlink),
+            ("/xdev", InodeType::ExtCharDev),
+        ] {
+            assert_eq!(sqfs.inode_from_path(path)?.inode_type, inode_type, "{path}");
+        }
+        assert_eq!(sqfs.inode_from_path("/link")?.link_count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compressors_and_no_fragments() -> io::Result<()> {
+        let data: Vec<u8> = b"The quick brown fox jumps over the lazy dog. ".repeat(500);
+        let compressors = [
+            #[cfg(feature = "flate2")]
+            Compressor::Gzip,
+            #[cfg(feature = "lzma-rs")]
+            Compressor::Xz,
+            #[cfg(feature = "ruzstd")]
+            Compressor::Zstd,
+        ];
+        for compressor in compressors {
+            for no_fragments in [false, true] {
+                let opts = WriterOptions { compressor, no_fragments, block_size: 8192, ..Default::default() };
+                let mut writer = SquashFSWriter::new(Cursor::new(Vec::new()), opts)?;
+                writer.add_file("/text", attrs(0o644), &data[..])?;
+                writer.add_file("/short", attrs(0o644), &b"short"[..])?;
+                let mut sqfs = SquashFS::new(writer.finish()?)?;
+                assert_eq!(read_all(&mut sqfs, "/text")?, data);
+                assert_eq!(read_all(&mut sqfs, "/short")?, b"short");
+            }
+        }
+        Ok(())
+    }
+}
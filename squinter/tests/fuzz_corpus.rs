@@ -0,0 +1,32 @@
+//! Run the fuzz targets over their corpus in squinter/fuzz/corpus on every `cargo test`. The
+//! corpus holds generated seed images; any input that `cargo fuzz run <target>` finds to crash
+//! the reader should be added to it, so that it stays fixed.
+use std::fs;
+use std::path::Path;
+
+#[path = "../fuzz/src/lib.rs"]
+mod fuzz;
+
+fn run_corpus(target: &str, f: fn(&[u8])) -> std::io::Result<()> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus").join(target);
+    let mut count = 0;
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let data = fs::read(&path)?;
+        println!("{}", path.display());
+        f(&data);
+        count += 1;
+    }
+    assert!(count > 0, "The {target} corpus is empty");
+    Ok(())
+}
+
+#[test]
+fn test_read_corpus() -> std::io::Result<()> {
+    run_corpus("read", fuzz::read)
+}
+
+#[test]
+fn test_locate_corpus() -> std::io::Result<()> {
+    run_corpus("locate", fuzz::locate)
+}
@@ -5,7 +5,6 @@ use std::io::{Read, Seek, BufReader};
 use std::path::Path;
 use std::iter;
 
-use anyhow;
 use test_assets_ureq::{TestAssetDef, dl_test_files_backoff};
 
 use squinter::squashfs;